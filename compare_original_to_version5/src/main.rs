@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
@@ -8,6 +8,7 @@ use timsrust::{converters::ConvertableDomain, readers::{FrameReader, MetadataRea
 use rayon::prelude::*;
 use dashmap::DashMap;
 use crossbeam_channel::bounded;
+use timstof_common::{key_from_bounds, scan_for_index, scan_ranges, window_bounds, window_key, Threads, WindowKeyError, WindowSegments};
 use parking_lot::Mutex;
 
 // ============= 共享数据结构 =============
@@ -69,20 +70,6 @@ mod original_version {
         pub ms2: Vec<((u32, u32), TimsTOFData)>,
     }
     
-    #[inline]
-    fn quantize(x: f32) -> u32 { 
-        (x * 10_000.0).round() as u32 
-    }
-    
-    fn find_scan_for_index(index: usize, scan_offsets: &[usize]) -> usize {
-        for (scan, window) in scan_offsets.windows(2).enumerate() {
-            if index >= window[0] && index < window[1] {
-                return scan;
-            }
-        }
-        scan_offsets.len() - 1
-    }
-    
     pub fn read_timstof_data_original(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
         let total_start = Instant::now();
         
@@ -97,8 +84,10 @@ mod original_version {
         let n_frames = frames.len();
         println!("[ORIGINAL] Total frames to process: {}", n_frames);
         
-        println!("[ORIGINAL] Processing frames in parallel...");
-        let splits: Vec<FrameSplit> = (0..n_frames).into_par_iter().map(|idx| {
+        let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
+        
+        println!("[ORIGINAL] Processing frames in parallel ({} threads)...", pool.threads());
+        let splits: Vec<FrameSplit> = pool.install(|| (0..n_frames).into_par_iter().map(|idx| -> Result<FrameSplit, WindowKeyError> {
             let frame = frames.get(idx).expect("frame read");
             let rt_min = frame.rt_in_seconds as f32 / 60.0;
             let mut ms1 = TimsTOFData::new();
@@ -110,7 +99,7 @@ mod original_version {
                     ms1 = TimsTOFData::with_capacity(n_peaks);
                     for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
                         let mz = mz_cv.convert(tof as f64) as f32;
-                        let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
+                        let scan = scan_for_index(p_idx, &frame.scan_offsets);
                         let im = im_cv.convert(scan as f64) as f32;
                         ms1.rt_values_min.push(rt_min);
                         ms1.mobility_values.push(im);
@@ -125,15 +114,11 @@ mod original_version {
                     ms2_pairs.reserve(qs.isolation_mz.len());
                     for win in 0..qs.isolation_mz.len() {
                         if win >= qs.isolation_width.len() { break; }
-                        let prec_mz = qs.isolation_mz[win] as f32;
-                        let width = qs.isolation_width[win] as f32;
-                        let low = prec_mz - width * 0.5;
-                        let high = prec_mz + width * 0.5;
-                        let key = (quantize(low), quantize(high));
+                        let key = window_key(qs.isolation_mz[win], qs.isolation_width[win])?;
                         
                         let mut td = TimsTOFData::new();
                         for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                            let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
+                            let scan = scan_for_index(p_idx, &frame.scan_offsets);
                             if scan < qs.scan_starts[win] || scan > qs.scan_ends[win] { continue; }
                             let mz = mz_cv.convert(tof as f64) as f32;
                             let im = im_cv.convert(scan as f64) as f32;
@@ -149,13 +134,14 @@ mod original_version {
                 }
                 _ => {}
             }
-            FrameSplit { ms1, ms2: ms2_pairs }
-        }).collect();
+            Ok(FrameSplit { ms1, ms2: ms2_pairs })
+        }).collect::<Result<_, _>>())?;
         
         println!("[ORIGINAL] Merging data...");
-        let ms1_size_estimate: usize = splits.par_iter().map(|s| s.ms1.mz_values.len()).sum();
+        let ms1_size_estimate: usize = splits.iter().map(|s| s.ms1.mz_values.len()).sum();
         let mut global_ms1 = TimsTOFData::with_capacity(ms1_size_estimate);
-        let mut ms2_hash: HashMap<(u32,u32), TimsTOFData> = HashMap::new();
+        // BTreeMap：窗口按键升序输出，帧按索引顺序合并
+        let mut ms2_hash: BTreeMap<(u32,u32), TimsTOFData> = BTreeMap::new();
         
         for split in splits {
             global_ms1.rt_values_min.extend(split.ms1.rt_values_min);
@@ -171,10 +157,8 @@ mod original_version {
         }
         
         let mut ms2_vec = Vec::with_capacity(ms2_hash.len());
-        for ((q_low, q_high), td) in ms2_hash {
-            let low = q_low as f32 / 10_000.0;
-            let high = q_high as f32 / 10_000.0;
-            ms2_vec.push(((low, high), td));
+        for (key, td) in ms2_hash {
+            ms2_vec.push((window_bounds(key), td));
         }
         
         println!("[ORIGINAL] MS1 data points: {}", global_ms1.mz_values.len());
@@ -194,24 +178,12 @@ mod original_version {
 mod v5_fixed {
     use super::*;
     
-    #[derive(Clone)]  // Add this line
+    // 每一帧都必须发送一条消息（包括无数据的帧），否则按索引归并会在该帧处停住
+    #[derive(Clone)]
     enum ProcessedFrame {
-        MS1(usize, TimsTOFData),
-        MS2(usize, Vec<((u32, u32), TimsTOFData)>),
-    }
-    
-    #[inline]
-    fn quantize(x: f32) -> u32 { 
-        (x * 10_000.0).round() as u32 
-    }
-    
-    fn find_scan_for_index(index: usize, scan_offsets: &[usize]) -> usize {
-        for (scan, window) in scan_offsets.windows(2).enumerate() {
-            if index >= window[0] && index < window[1] {
-                return scan;
-            }
-        }
-        scan_offsets.len() - 1
+        MS1(TimsTOFData),
+        MS2(Vec<((u32, u32), TimsTOFData)>),
+        Empty,
     }
     
     pub fn read_timstof_data_v5_fixed(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
@@ -228,7 +200,9 @@ mod v5_fixed {
         let n_frames = frames.len();
         println!("[V5_FIXED] Total frames to process: {}", n_frames);
         
-        println!("[V5_FIXED] Processing frames in parallel with channel...");
+        let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
+        
+        println!("[V5_FIXED] Processing frames in parallel with channel ({} threads)...", pool.threads());
         
         let (sender, receiver) = bounded(2000);
         let processed_count = Arc::new(AtomicUsize::new(0));
@@ -240,28 +214,22 @@ mod v5_fixed {
         let processed_clone = Arc::clone(&processed_count);
         
         let aggregator_handle = std::thread::spawn(move || {
+            // 按帧索引寻址的归并缓冲：到达顺序任意，合并顺序始终为帧索引顺序
             let mut frame_buffer: Vec<Option<ProcessedFrame>> = vec![None; n_frames];
             let mut next_frame = 0usize;
             
-            while let Ok(frame_data) = receiver.recv() {
-                match frame_data {
-                    ProcessedFrame::MS1(idx, data) => {
-                        frame_buffer[idx] = Some(ProcessedFrame::MS1(idx, data));
-                    }
-                    ProcessedFrame::MS2(idx, pairs) => {
-                        frame_buffer[idx] = Some(ProcessedFrame::MS2(idx, pairs));
-                    }
-                }
+            while let Ok((idx, frame_data)) = receiver.recv() {
+                frame_buffer[idx] = Some(frame_data);
                 
                 while next_frame < n_frames {
                     if let Some(frame) = frame_buffer[next_frame].take() {
                         match frame {
-                            ProcessedFrame::MS1(_, data) => {
+                            ProcessedFrame::MS1(data) => {
                                 if !data.mz_values.is_empty() {
                                     ms1_acc_clone.lock().push(data);
                                 }
                             }
-                            ProcessedFrame::MS2(_, pairs) => {
+                            ProcessedFrame::MS2(pairs) => {
                                 for (key, mut data) in pairs {
                                     if !data.mz_values.is_empty() {
                                         ms2_map_clone.entry(key)
@@ -271,6 +239,7 @@ mod v5_fixed {
                                     }
                                 }
                             }
+                            ProcessedFrame::Empty => {}
                         }
                         next_frame += 1;
                         processed_clone.fetch_add(1, Ordering::Relaxed);
@@ -281,10 +250,13 @@ mod v5_fixed {
             }
         });
         
-        (0..n_frames).into_par_iter().for_each(|idx| {
+        let result = pool.install(|| (0..n_frames).into_par_iter().try_for_each(|idx| -> Result<(), WindowKeyError> {
             let frame = match frames.get(idx) {
                 Ok(f) => f,
-                Err(_) => return,
+                Err(_) => {
+                    let _ = sender.send((idx, ProcessedFrame::Empty));
+                    return Ok(());
+                }
             };
             
             let rt_min = frame.rt_in_seconds as f32 / 60.0;
@@ -294,64 +266,64 @@ mod v5_fixed {
                     let n_peaks = frame.tof_indices.len();
                     let mut ms1 = TimsTOFData::with_capacity(n_peaks);
                     
-                    for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                        let mz = mz_cv.convert(tof as f64) as f32;
-                        let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
+                    for (scan, peaks) in scan_ranges(&frame.scan_offsets, n_peaks) {
                         let im = im_cv.convert(scan as f64) as f32;
-                        ms1.rt_values_min.push(rt_min);
-                        ms1.mobility_values.push(im);
-                        ms1.mz_values.push(mz);
-                        ms1.intensity_values.push(intensity);
-                        ms1.frame_indices.push(frame.index as u32);
-                        ms1.scan_indices.push(scan as u32);
+                        for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter().zip(&frame.intensities[peaks]) {
+                            let mz = mz_cv.convert(tof as f64) as f32;
+                            ms1.rt_values_min.push(rt_min);
+                            ms1.mobility_values.push(im);
+                            ms1.mz_values.push(mz);
+                            ms1.intensity_values.push(intensity);
+                            ms1.frame_indices.push(frame.index as u32);
+                            ms1.scan_indices.push(scan as u32);
+                        }
                     }
                     
-                    if !ms1.mz_values.is_empty() {
-                        let _ = sender.send(ProcessedFrame::MS1(idx, ms1));
-                    }
+                    let _ = sender.send((idx, ProcessedFrame::MS1(ms1)));
                 }
                 MSLevel::MS2 => {
                     let qs = &frame.quadrupole_settings;
-                    let mut ms2_pairs = Vec::with_capacity(qs.isolation_mz.len());
+                    let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+                    let keys = (0..n_windows)
+                        .map(|win| window_key(qs.isolation_mz[win], qs.isolation_width[win]))
+                        .collect::<Result<Vec<_>, _>>()?;
                     
-                    for win in 0..qs.isolation_mz.len() {
-                        if win >= qs.isolation_width.len() { break; }
-                        
-                        let prec_mz = qs.isolation_mz[win] as f32;
-                        let width = qs.isolation_width[win] as f32;
-                        let low = prec_mz - width * 0.5;
-                        let high = prec_mz + width * 0.5;
-                        let key = (quantize(low), quantize(high));
-                        
-                        let mut td = TimsTOFData::new();
-                        for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                            let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
-                            if scan < qs.scan_starts[win] || scan > qs.scan_ends[win] { continue; }
-                            let mz = mz_cv.convert(tof as f64) as f32;
+                    // 单次遍历所有 scan；重叠窗口中的峰分别写入每个窗口
+                    let mut windows: Vec<TimsTOFData> = (0..n_windows).map(|_| TimsTOFData::new()).collect();
+                    for (first, last, targets) in WindowSegments::new(&qs.scan_starts[..n_windows], &qs.scan_ends[..n_windows]).iter() {
+                        for (scan, peaks) in scan_ranges(&frame.scan_offsets, frame.tof_indices.len()).within(first, last) {
                             let im = im_cv.convert(scan as f64) as f32;
-                            td.rt_values_min.push(rt_min);
-                            td.mobility_values.push(im);
-                            td.mz_values.push(mz);
-                            td.intensity_values.push(intensity);
-                            td.frame_indices.push(frame.index as u32);
-                            td.scan_indices.push(scan as u32);
-                        }
-                        
-                        if !td.mz_values.is_empty() {
-                            ms2_pairs.push((key, td));
+                            for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter().zip(&frame.intensities[peaks]) {
+                                let mz = mz_cv.convert(tof as f64) as f32;
+                                for &win in targets {
+                                    let td = &mut windows[win];
+                                    td.rt_values_min.push(rt_min);
+                                    td.mobility_values.push(im);
+                                    td.mz_values.push(mz);
+                                    td.intensity_values.push(intensity);
+                                    td.frame_indices.push(frame.index as u32);
+                                    td.scan_indices.push(scan as u32);
+                                }
+                            }
                         }
                     }
                     
-                    if !ms2_pairs.is_empty() {
-                        let _ = sender.send(ProcessedFrame::MS2(idx, ms2_pairs));
-                    }
+                    let ms2_pairs: Vec<_> = keys.into_iter().zip(windows)
+                        .filter(|(_, td)| !td.mz_values.is_empty())
+                        .collect();
+                    
+                    let _ = sender.send((idx, ProcessedFrame::MS2(ms2_pairs)));
+                }
+                _ => {
+                    let _ = sender.send((idx, ProcessedFrame::Empty));
                 }
-                _ => {}
             }
-        });
+            Ok(())
+        }));
         
         drop(sender);
         aggregator_handle.join().unwrap();
+        result?;
         
         println!("[V5_FIXED] Finalizing data structures...");
         
@@ -369,14 +341,13 @@ mod v5_fixed {
             global_ms1.scan_indices.extend(&chunk.scan_indices);
         }
         
-        let mut ms2_vec = Vec::with_capacity(ms2_map.len());
-        ms2_map.iter().for_each(|entry| {
-            let (q_low, q_high) = *entry.key();
-            let low = q_low as f32 / 10_000.0;
-            let high = q_high as f32 / 10_000.0;
-            let data = entry.value().lock().clone();
-            ms2_vec.push(((low, high), data));
-        });
+        let mut ms2_entries: Vec<_> = ms2_map.iter()
+            .map(|entry| (*entry.key(), entry.value().lock().clone()))
+            .collect();
+        ms2_entries.sort_unstable_by_key(|(key, _)| *key);
+        let ms2_vec: Vec<_> = ms2_entries.into_iter()
+            .map(|(key, data)| (window_bounds(key), data))
+            .collect();
         
         println!("[V5_FIXED] MS1 data points: {}", global_ms1.mz_values.len());
         println!("[V5_FIXED] MS2 windows: {}", ms2_vec.len());
@@ -490,29 +461,27 @@ fn compare_ms2_windows(windows1: &[((f32, f32), TimsTOFData)],
     let mut map2: HashMap<(u32, u32), &TimsTOFData> = HashMap::new();
     
     for ((low, high), data) in windows1 {
-        let key = ((low * 10_000.0).round() as u32, (high * 10_000.0).round() as u32);
+        let key = key_from_bounds((*low, *high));
         map1.insert(key, data);
     }
     
     for ((low, high), data) in windows2 {
-        let key = ((low * 10_000.0).round() as u32, (high * 10_000.0).round() as u32);
+        let key = key_from_bounds((*low, *high));
         map2.insert(key, data);
     }
     
     // 比较每个窗口
     for (key, data1) in &map1 {
+        let (low, high) = window_bounds(*key);
         match map2.get(key) {
             Some(data2) => {
-                let window_name = format!("MS2 window ({:.2}, {:.2})", 
-                                         key.0 as f32 / 10_000.0, 
-                                         key.1 as f32 / 10_000.0);
+                let window_name = format!("MS2 window ({:.2}, {:.2})", low, high);
                 if !compare_tims_data(data1, data2, &window_name) {
                     all_match = false;
                 }
             }
             None => {
-                println!("    ❌ Window ({:.2}, {:.2}) missing in second dataset", 
-                        key.0 as f32 / 10_000.0, key.1 as f32 / 10_000.0);
+                println!("    ❌ Window ({:.2}, {:.2}) missing in second dataset", low, high);
                 all_match = false;
             }
         }
@@ -521,8 +490,8 @@ fn compare_ms2_windows(windows1: &[((f32, f32), TimsTOFData)],
     // 检查第二个数据集中是否有额外的窗口
     for key in map2.keys() {
         if !map1.contains_key(key) {
-            println!("    ❌ Window ({:.2}, {:.2}) missing in first dataset", 
-                    key.0 as f32 / 10_000.0, key.1 as f32 / 10_000.0);
+            let (low, high) = window_bounds(*key);
+            println!("    ❌ Window ({:.2}, {:.2}) missing in first dataset", low, high);
             all_match = false;
         }
     }
@@ -531,12 +500,6 @@ fn compare_ms2_windows(windows1: &[((f32, f32), TimsTOFData)],
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    // 配置并行处理
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(32)
-        .build_global()
-        .unwrap();
-    
     // 设置数据文件路径
     let d_folder_path = "/storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/test_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d";
     
@@ -585,6 +548,45 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticRun};
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let original = original_version::read_timstof_data_original(dir.path()).unwrap();
+        golden::assert_golden("small", "original", &digest!(original));
+        let v5 = v5_fixed::read_timstof_data_v5_fixed(dir.path()).unwrap();
+        golden::assert_golden("small", "v5_fixed", &digest!(v5));
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let original = original_version::read_timstof_data_original(dir.path()).unwrap();
+        golden::assert_golden("overlapping", "original", &digest!(original));
+        let v5 = v5_fixed::read_timstof_data_v5_fixed(dir.path()).unwrap();
+        golden::assert_golden("overlapping", "v5_fixed", &digest!(v5));
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        determinism::assert_deterministic(
+            "original",
+            || original_version::read_timstof_data_original(dir.path()).unwrap(),
+            |data| fingerprint!(data),
+        );
+        determinism::assert_deterministic(
+            "v5_fixed",
+            || v5_fixed::read_timstof_data_v5_fixed(dir.path()).unwrap(),
+            |data| fingerprint!(data),
+        );
+    }
+}
+
 // Cargo.toml 依赖：
 /*
 [dependencies]
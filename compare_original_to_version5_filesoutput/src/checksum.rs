@@ -0,0 +1,270 @@
+// ============= 逐帧校验和与差异定位 =============
+// 加载时为每帧的 MS1 数据以及每个 MS2 窗口中来自该帧的数据各计算一个校验和，
+// 写入输出和摘要文件。两个输出的整体哈希不同时，按帧比较校验和即可定位出问题的帧和窗口，
+// 再只在这些帧内逐 scan 比较原始数据，得到具体的 scan。
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+use serde::{Serialize, Deserialize};
+
+use crate::{Precision, TimsTOFData, TimsTOFRawData};
+use timstof_common::{key_from_bounds, window_bounds, WindowKey};
+
+// 一帧对一个数据集（MS1 或某个 MS2 窗口）的贡献
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataChecksum {
+    pub peaks: usize,
+    // calculate_hash 的前16个十六进制字符（与顺序无关）
+    pub hash: String,
+}
+
+impl DataChecksum {
+    pub fn of<F: Precision>(data: &TimsTOFData<F>) -> Self {
+        DataChecksum {
+            peaks: data.len(),
+            hash: data.calculate_hash()[..16].to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameChecksum {
+    pub index: u32,
+    pub ms1: Option<DataChecksum>,
+    // 按窗口键升序
+    pub ms2: Vec<(WindowKey, DataChecksum)>,
+}
+
+impl FrameChecksum {
+    // 没有点的数据集不计入，因此各策略是否保留空窗口不影响结果
+    pub fn new<F: Precision>(index: u32, ms1: Option<&TimsTOFData<F>>, ms2: &[(WindowKey, TimsTOFData<F>)]) -> Self {
+        let mut windows: Vec<(WindowKey, DataChecksum)> = ms2.iter()
+            .filter(|(_, data)| !data.is_empty())
+            .map(|(key, data)| (*key, DataChecksum::of(data)))
+            .collect();
+        windows.sort_by_key(|(key, _)| *key);
+        FrameChecksum {
+            index,
+            ms1: ms1.filter(|data| !data.is_empty()).map(DataChecksum::of),
+            ms2: windows,
+        }
+    }
+
+    fn datasets(&self) -> impl Iterator<Item = (Dataset, &DataChecksum)> {
+        self.ms1.iter().map(|c| (Dataset::Ms1, c))
+            .chain(self.ms2.iter().map(|(key, c)| (Dataset::Ms2(*key), c)))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameChecksums {
+    // 按帧索引升序；不含没有任何点的帧
+    pub frames: Vec<FrameChecksum>,
+}
+
+impl FrameChecksums {
+    pub fn from_frames(frames: Vec<FrameChecksum>) -> Self {
+        let mut frames: Vec<FrameChecksum> = frames.into_iter()
+            .filter(|f| f.ms1.is_some() || !f.ms2.is_empty())
+            .collect();
+        frames.sort_by_key(|f| f.index);
+        FrameChecksums { frames }
+    }
+
+    // 从已加载的数据重新按帧分组计算；用于没有保存校验和的旧输出
+    pub fn from_raw<F: Precision>(raw: &TimsTOFRawData<F>) -> Self {
+        let mut frames: BTreeMap<u32, FrameParts<F>> = BTreeMap::new();
+        for (frame, data) in split_by_frame(&raw.ms1_data) {
+            frames.entry(frame).or_default().0 = Some(data);
+        }
+        for ((low, high), window) in &raw.ms2_windows {
+            let key = key_from_bounds((*low, *high));
+            for (frame, data) in split_by_frame(window) {
+                frames.entry(frame).or_default().1.push((key, data));
+            }
+        }
+        Self::from_frames(frames.into_iter()
+            .map(|(index, (ms1, ms2))| FrameChecksum::new(index, ms1.as_ref(), &ms2))
+            .collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // 每个数据集一行、按帧排序，两个摘要文件直接 diff 就能看出差异帧
+    pub fn write_summary(&self, out: &mut impl Write) -> io::Result<()> {
+        for frame in &self.frames {
+            for (dataset, checksum) in frame.datasets() {
+                writeln!(out, "Frame {} {}: {} points, hash: {}", frame.index, dataset, checksum.peaks, checksum.hash)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 一帧的 MS1 数据和各 MS2 窗口中来自该帧的数据
+type FrameParts<F> = (Option<TimsTOFData<F>>, Vec<(WindowKey, TimsTOFData<F>)>);
+
+// 按帧索引拆分一个数据集（保持帧内原有顺序）
+fn split_by_frame<F: Precision>(data: &TimsTOFData<F>) -> BTreeMap<u32, TimsTOFData<F>> {
+    let mut by_frame: BTreeMap<u32, TimsTOFData<F>> = BTreeMap::new();
+    let has_mz = !data.mz_values.is_empty();
+    let has_tof = !data.tof_indices.is_empty();
+    for i in 0..data.len() {
+        let part = by_frame.entry(data.frame_indices[i]).or_insert_with(TimsTOFData::new);
+        part.rt_values_min.push(data.rt_values_min[i]);
+        part.mobility_values.push(data.mobility_values[i]);
+        if has_mz {
+            part.mz_values.push(data.mz_values[i]);
+        }
+        if has_tof {
+            part.tof_indices.push(data.tof_indices[i]);
+        }
+        part.intensity_values.push(data.intensity_values[i]);
+        part.frame_indices.push(data.frame_indices[i]);
+        part.scan_indices.push(data.scan_indices[i]);
+    }
+    by_frame
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum Dataset {
+    Ms1,
+    Ms2(WindowKey),
+}
+
+impl fmt::Display for Dataset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dataset::Ms1 => write!(f, "MS1"),
+            Dataset::Ms2(key) => {
+                let (low, high) = window_bounds(*key);
+                write!(f, "MS2 ({:.4}, {:.4})", low, high)
+            }
+        }
+    }
+}
+
+// 一帧在一个数据集上的差异
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DatasetDivergence {
+    pub dataset: Dataset,
+    pub reference: Option<DataChecksum>,
+    pub candidate: Option<DataChecksum>,
+    // 点集不同的 scan（升序）
+    pub scans: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FrameDivergence {
+    pub index: u32,
+    pub datasets: Vec<DatasetDivergence>,
+}
+
+impl fmt::Display for FrameDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |c: &Option<DataChecksum>| match c {
+            Some(c) => format!("{} points {}", c.peaks, c.hash),
+            None => "absent".to_string(),
+        };
+        write!(f, "Frame {}:", self.index)?;
+        for d in &self.datasets {
+            write!(f, "\n  {}: {} vs {}; scans {:?}", d.dataset, describe(&d.reference), describe(&d.candidate), d.scans)?;
+        }
+        Ok(())
+    }
+}
+
+// 先按帧校验和找出差异帧，再在这些帧内逐 scan 比较数据
+pub fn bisect<F: Precision>(reference: &TimsTOFRawData<F>, candidate: &TimsTOFRawData<F>) -> Vec<FrameDivergence> {
+    let checksums = |raw: &TimsTOFRawData<F>| if raw.frame_checksums.is_empty() {
+        FrameChecksums::from_raw(raw)
+    } else {
+        raw.frame_checksums.clone()
+    };
+    let index = |checksums: &FrameChecksums| -> HashMap<(u32, Dataset), DataChecksum> {
+        checksums.frames.iter()
+            .flat_map(|f| f.datasets().map(move |(dataset, c)| ((f.index, dataset), c.clone())))
+            .collect()
+    };
+    let a = index(&checksums(reference));
+    let b = index(&checksums(candidate));
+
+    // 差异帧按数据集分组，每个数据集只扫描一遍原始数据
+    let mut diverging: BTreeMap<Dataset, BTreeSet<u32>> = BTreeMap::new();
+    for key in a.keys().chain(b.keys()) {
+        if a.get(key) != b.get(key) {
+            diverging.entry(key.1).or_default().insert(key.0);
+        }
+    }
+
+    let mut frames: BTreeMap<u32, Vec<DatasetDivergence>> = BTreeMap::new();
+    for (dataset, frame_set) in diverging {
+        let mut scans_a = scan_contents(dataset_data(reference, dataset), &frame_set);
+        let mut scans_b = scan_contents(dataset_data(candidate, dataset), &frame_set);
+        for &frame in &frame_set {
+            let mut scans: BTreeSet<u32> = BTreeSet::new();
+            let peaks_a = scans_a.remove(&frame).unwrap_or_default();
+            let peaks_b = scans_b.remove(&frame).unwrap_or_default();
+            for scan in peaks_a.keys().chain(peaks_b.keys()) {
+                if peaks_a.get(scan) != peaks_b.get(scan) {
+                    scans.insert(*scan);
+                }
+            }
+            frames.entry(frame).or_default().push(DatasetDivergence {
+                dataset,
+                reference: a.get(&(frame, dataset)).cloned(),
+                candidate: b.get(&(frame, dataset)).cloned(),
+                scans: scans.into_iter().collect(),
+            });
+        }
+    }
+    frames.into_iter().map(|(index, datasets)| FrameDivergence { index, datasets }).collect()
+}
+
+// 复现差异所需的最少帧（逗号分隔）
+pub fn reproducer_frames(divergences: &[FrameDivergence]) -> String {
+    divergences.iter().map(|d| d.index.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn dataset_data<F: Precision>(raw: &TimsTOFRawData<F>, dataset: Dataset) -> Option<&TimsTOFData<F>> {
+    match dataset {
+        Dataset::Ms1 => Some(&raw.ms1_data),
+        Dataset::Ms2(key) => raw.ms2_windows.iter()
+            .find(|((low, high), _)| key_from_bounds((*low, *high)) == key)
+            .map(|(_, data)| data),
+    }
+}
+
+// 一个点的逐位内容：(TOF, m/z, 强度, RT, 淌度)
+type PeakBits = (u32, u64, u32, u64, u32);
+
+// 指定帧内每个 scan 的点集（已排序，可直接比较）
+fn scan_contents<F: Precision>(data: Option<&TimsTOFData<F>>, frames: &BTreeSet<u32>) -> HashMap<u32, BTreeMap<u32, Vec<PeakBits>>> {
+    let mut contents: HashMap<u32, BTreeMap<u32, Vec<PeakBits>>> = HashMap::new();
+    let Some(data) = data else { return contents };
+    for i in 0..data.len() {
+        let frame = data.frame_indices[i];
+        if !frames.contains(&frame) {
+            continue;
+        }
+        contents.entry(frame).or_default().entry(data.scan_indices[i]).or_default().push((
+            data.tof_indices.get(i).copied().unwrap_or(0),
+            data.mz_values.get(i).map_or(0, |mz| mz.to_f64().to_bits()),
+            data.intensity_values[i],
+            data.rt_values_min[i].to_f64().to_bits(),
+            data.mobility_values[i].to_bits(),
+        ));
+    }
+    for scans in contents.values_mut() {
+        for peaks in scans.values_mut() {
+            peaks.sort_unstable();
+        }
+    }
+    contents
+}
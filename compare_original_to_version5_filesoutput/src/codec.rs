@@ -0,0 +1,438 @@
+// ============= 压缩的二进制输出 =============
+// save_binary 写出的 bincode 文件不压缩。save_compressed 按列写出：每列先做针对性的预处理
+// （按顺序排列的帧号、scan、TOF 列取差分；浮点列和强度列做字节重排，把各值的同一字节放在一起），
+// 再按 CHUNK_BYTES 分块，用 zstd 或 LZ4 并行压缩。load_binary 按文件头自动识别两种格式，
+// 读回的数据与原数据逐位一致。
+//
+// 文件布局：MAGIC | 压缩方式 u8 | zstd 级别 i32 | 头部块 | 每组列（MS1，然后各 MS2 窗口）的 7 个列块
+// 块：原始字节数 u64，然后每个分块为 压缩后字节数 u64 + 数据；整数均为小端
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::time::Instant;
+use bytemuck::Pod;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::FrameChecksums;
+use crate::frame_table::FrameTable;
+use crate::metadata::RunMetadata;
+use crate::{MzStorage, Precision, TimsTOFData, TimsTOFRawData};
+
+pub const MAGIC: &[u8; 8] = b"TTOFZ\x01\0\0";
+// save_binary 写出的未压缩文件：BINARY_MAGIC（末尾的 \x01 为格式版本）、精度名（1 字节长度 + 名字），
+// 之后是 bincode 编码的 TimsTOFRawData。
+// bincode 按位置解码，没有文件头的旧文件缺少后来加入的字段，读出来会错位，因此直接拒绝
+pub const BINARY_MAGIC: &[u8; 8] = b"TTOFB\x01\0\0";
+// 分块大小：各块独立压缩，并行压缩 / 解压的粒度
+const CHUNK_BYTES: usize = 4 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    // zstd 级别（1..=22）
+    Zstd(i32),
+    Lz4,
+}
+
+impl Compression {
+    pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+    // zstd、zstd:19 或 lz4
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once(':') {
+            None if spec == "zstd" => Ok(Compression::Zstd(Self::DEFAULT_ZSTD_LEVEL)),
+            None if spec == "lz4" => Ok(Compression::Lz4),
+            Some(("zstd", level)) => match level.parse::<i32>() {
+                Ok(level) if (1..=22).contains(&level) => Ok(Compression::Zstd(level)),
+                _ => Err(format!("zstd level must be 1..=22, got {:?}", level)),
+            },
+            _ => Err(format!("expected zstd, zstd:LEVEL or lz4, got {:?}", spec)),
+        }
+    }
+
+    fn compress(self, raw: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd(level) => zstd::bulk::compress(raw, level),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(raw)),
+        }
+    }
+
+    // out 的长度即原始字节数
+    fn decompress(self, compressed: &[u8], out: &mut [u8]) -> io::Result<()> {
+        let written = match self {
+            Compression::Zstd(_) => zstd::bulk::decompress_to_buffer(compressed, out)?,
+            Compression::Lz4 => lz4_flex::block::decompress_into(compressed, out).map_err(invalid_data)?,
+        };
+        if written != out.len() {
+            return Err(invalid_data(format!("chunk decompressed to {} bytes, expected {}", written, out.len())));
+        }
+        Ok(())
+    }
+
+    // 一个压缩分块最多能解压出的字节数，用于分配前核对文件中记录的长度：
+    // zstd 帧头带有原始字节数；LZ4 块中每个字节最多展开为 255 字节
+    fn max_decompressed_len(self, compressed: &[u8]) -> usize {
+        match self {
+            Compression::Zstd(_) => zstd::zstd_safe::get_frame_content_size(compressed)
+                .ok()
+                .flatten()
+                .map_or(0, |len| len as usize),
+            Compression::Lz4 => compressed.len().saturating_mul(255),
+        }
+    }
+
+    fn header(self) -> (u8, i32) {
+        match self {
+            Compression::Zstd(level) => (1, level),
+            Compression::Lz4 => (2, 0),
+        }
+    }
+
+    fn from_header(id: u8, level: i32) -> io::Result<Self> {
+        match id {
+            1 => Ok(Compression::Zstd(level)),
+            2 => Ok(Compression::Lz4),
+            _ => Err(invalid_data(format!("unknown compression id {}", id))),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd(level) => write!(f, "zstd level {}", level),
+            Compression::Lz4 => write!(f, "LZ4"),
+        }
+    }
+}
+
+// 一次压缩或解压：原始（bincode 列）字节数、文件中的字节数和耗时
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionReport {
+    pub compression: Compression,
+    pub raw_bytes: u64,
+    pub compressed_bytes: u64,
+    pub seconds: f64,
+}
+
+impl CompressionReport {
+    pub fn ratio(&self) -> f64 {
+        self.raw_bytes as f64 / self.compressed_bytes.max(1) as f64
+    }
+
+    // 按原始字节计的 MB/s（与文件大小的输出一样按 1 MB = 1_048_576 字节）
+    pub fn throughput_mb_s(&self) -> f64 {
+        self.raw_bytes as f64 / 1_048_576.0 / self.seconds.max(1e-9)
+    }
+}
+
+impl fmt::Display for CompressionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2} MB -> {:.2} MB ({:.2}x, {}) at {:.0} MB/s",
+               self.raw_bytes as f64 / 1_048_576.0, self.compressed_bytes as f64 / 1_048_576.0,
+               self.ratio(), self.compression, self.throughput_mb_s())
+    }
+}
+
+// 列的预处理
+#[derive(Clone, Copy)]
+enum Filter {
+    // 字节重排
+    Shuffle,
+    // u32 列：先取相邻差（回绕减法，任意顺序都可逆），再字节重排
+    DeltaShuffle,
+}
+
+#[derive(Serialize)]
+struct HeaderRef<'a> {
+    precision: &'a str,
+    metadata: &'a RunMetadata,
+    frame_table: &'a FrameTable,
+    frame_checksums: &'a FrameChecksums,
+    mz_storage: MzStorage,
+    window_bounds: Vec<(f32, f32)>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    precision: String,
+    metadata: RunMetadata,
+    frame_table: FrameTable,
+    frame_checksums: FrameChecksums,
+    mz_storage: MzStorage,
+    window_bounds: Vec<(f32, f32)>,
+}
+
+pub fn save<F: Precision>(data: &TimsTOFRawData<F>, path: &Path, compression: Compression) -> io::Result<CompressionReport> {
+    let start = Instant::now();
+    let mut writer = BlockWriter {
+        inner: BufWriter::new(File::create(path)?),
+        compression,
+        raw_bytes: 0,
+    };
+    let (id, level) = compression.header();
+    writer.inner.write_all(MAGIC)?;
+    writer.inner.write_all(&[id])?;
+    writer.inner.write_all(&level.to_le_bytes())?;
+
+    let header = HeaderRef {
+        precision: F::NAME,
+        metadata: &data.metadata,
+        frame_table: &data.frame_table,
+        frame_checksums: &data.frame_checksums,
+        mz_storage: data.mz_storage,
+        window_bounds: data.ms2_windows.iter().map(|(bounds, _)| *bounds).collect(),
+    };
+    writer.column(&bincode::serialize(&header).map_err(invalid_data)?, Filter::Shuffle)?;
+    writer.columns(&data.ms1_data)?;
+    for (_, td) in &data.ms2_windows {
+        writer.columns(td)?;
+    }
+    writer.inner.flush()?;
+    Ok(CompressionReport {
+        compression,
+        raw_bytes: writer.raw_bytes,
+        compressed_bytes: std::fs::metadata(path)?.len(),
+        seconds: start.elapsed().as_secs_f64(),
+    })
+}
+
+// 文件以 MAGIC 开头即为压缩格式（未压缩的文件以 BINARY_MAGIC 开头）
+pub fn is_compressed(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn write_binary_header<F: Precision>(writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(BINARY_MAGIC)?;
+    writer.write_all(&[F::NAME.len() as u8])?;
+    writer.write_all(F::NAME.as_bytes())
+}
+
+// 核对 save_binary 写出的文件头：格式版本和精度都须与读取方一致
+pub fn read_binary_header<F: Precision>(reader: &mut impl Read, path: &Path) -> io::Result<()> {
+    let mut magic = [0u8; BINARY_MAGIC.len()];
+    match reader.read_exact(&mut magic) {
+        Ok(()) if &magic == BINARY_MAGIC => {
+            let mut len = [0u8; 1];
+            reader.read_exact(&mut len)?;
+            let mut precision = vec![0u8; len[0] as usize];
+            reader.read_exact(&mut precision)?;
+            let precision = String::from_utf8_lossy(&precision);
+            if precision != F::NAME {
+                return Err(invalid_data(format!("{} holds {} data, not {}", path.display(), precision, F::NAME)));
+            }
+            Ok(())
+        }
+        Ok(()) if magic[..5] == BINARY_MAGIC[..5] => Err(invalid_data(format!(
+            "{} uses binary format version {}, this build reads version {}", path.display(), magic[5], BINARY_MAGIC[5],
+        ))),
+        Ok(()) => Err(invalid_data(format!(
+            "{} has no file header: it was written by an older version (or is not a TimsTOF binary file), load it again from the .d folder",
+            path.display(),
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(invalid_data(format!("{} is too short for a TimsTOF binary file", path.display()))),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn load<F: Precision>(path: &Path) -> io::Result<(TimsTOFRawData<F>, CompressionReport)> {
+    let start = Instant::now();
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut inner = BufReader::new(file);
+    let mut magic = [0u8; MAGIC.len()];
+    inner.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data(format!("{} is not a compressed TimsTOF file", path.display())));
+    }
+    let mut id = [0u8; 1];
+    let mut level = [0u8; 4];
+    inner.read_exact(&mut id)?;
+    inner.read_exact(&mut level)?;
+    let compression = Compression::from_header(id[0], i32::from_le_bytes(level))?;
+    // 之后的读取不超过文件的剩余部分，块长度据此核对
+    let remaining = file_len.saturating_sub((MAGIC.len() + id.len() + level.len()) as u64);
+    let mut reader = BlockReader { inner: inner.take(remaining), compression, raw_bytes: 0 };
+
+    let header: Header = bincode::deserialize(&reader.column::<u8>(Filter::Shuffle)?).map_err(invalid_data)?;
+    if header.precision != F::NAME {
+        return Err(invalid_data(format!("{} holds {} data, not {}", path.display(), header.precision, F::NAME)));
+    }
+    let ms1_data = reader.columns()?;
+    let ms2_windows = header.window_bounds
+        .into_iter()
+        .map(|bounds| Ok((bounds, reader.columns()?)))
+        .collect::<io::Result<_>>()?;
+    let data = TimsTOFRawData {
+        ms1_data,
+        ms2_windows,
+        metadata: header.metadata,
+        frame_table: header.frame_table,
+        frame_checksums: header.frame_checksums,
+        mz_storage: header.mz_storage,
+    };
+    let report = CompressionReport {
+        compression,
+        raw_bytes: reader.raw_bytes,
+        compressed_bytes: std::fs::metadata(path)?.len(),
+        seconds: start.elapsed().as_secs_f64(),
+    };
+    Ok((data, report))
+}
+
+struct BlockWriter<W> {
+    inner: W,
+    compression: Compression,
+    raw_bytes: u64,
+}
+
+impl<W: Write> BlockWriter<W> {
+    fn columns<F: Precision>(&mut self, data: &TimsTOFData<F>) -> io::Result<()> {
+        self.column(&data.rt_values_min, Filter::Shuffle)?;
+        self.column(&data.mobility_values, Filter::Shuffle)?;
+        self.column(&data.mz_values, Filter::Shuffle)?;
+        self.column(&data.intensity_values, Filter::Shuffle)?;
+        self.column(&data.frame_indices, Filter::DeltaShuffle)?;
+        self.column(&data.scan_indices, Filter::DeltaShuffle)?;
+        self.column(&data.tof_indices, Filter::DeltaShuffle)
+    }
+
+    fn column<T: Pod>(&mut self, values: &[T], filter: Filter) -> io::Result<()> {
+        let bytes: &[u8] = bytemuck::cast_slice(values);
+        let compression = self.compression;
+        let chunks = bytes
+            .par_chunks(CHUNK_BYTES)
+            .map(|chunk| compression.compress(&encode(chunk, size_of::<T>(), filter)))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.inner.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        for chunk in &chunks {
+            self.inner.write_all(&(chunk.len() as u64).to_le_bytes())?;
+            self.inner.write_all(chunk)?;
+        }
+        self.raw_bytes += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+struct BlockReader<R> {
+    inner: io::Take<R>,
+    compression: Compression,
+    raw_bytes: u64,
+}
+
+impl<R: Read> BlockReader<R> {
+    fn columns<F: Precision>(&mut self) -> io::Result<TimsTOFData<F>> {
+        Ok(TimsTOFData {
+            rt_values_min: self.column(Filter::Shuffle)?,
+            mobility_values: self.column(Filter::Shuffle)?,
+            mz_values: self.column(Filter::Shuffle)?,
+            intensity_values: self.column(Filter::Shuffle)?,
+            frame_indices: self.column(Filter::DeltaShuffle)?,
+            scan_indices: self.column(Filter::DeltaShuffle)?,
+            tof_indices: self.column(Filter::DeltaShuffle)?,
+        })
+    }
+
+    fn column<T: Pod>(&mut self, filter: Filter) -> io::Result<Vec<T>> {
+        let len = self.read_u64()? as usize;
+        if !len.is_multiple_of(size_of::<T>()) {
+            return Err(invalid_data(format!("column of {} bytes is not a whole number of values", len)));
+        }
+        // 长度来自文件，分配前先核对：每个分块至少占 8 字节的长度前缀
+        let n_chunks = len.div_ceil(CHUNK_BYTES);
+        if n_chunks as u64 > self.inner.limit() / 8 {
+            return Err(invalid_data(format!("column of {} bytes needs {} chunks, but only {} bytes remain",
+                                            len, n_chunks, self.inner.limit())));
+        }
+        let mut chunks = Vec::with_capacity(n_chunks);
+        for _ in 0..n_chunks {
+            let compressed_len = self.read_u64()? as usize;
+            // 不可压缩的数据压缩后也只比原始块稍大
+            if compressed_len > 2 * CHUNK_BYTES || compressed_len as u64 > self.inner.limit() {
+                return Err(invalid_data(format!("compressed chunk of {} bytes", compressed_len)));
+            }
+            let mut chunk = vec![0u8; compressed_len];
+            self.inner.read_exact(&mut chunk)?;
+            chunks.push(chunk);
+        }
+        let max_len: usize = chunks.iter().map(|chunk| self.compression.max_decompressed_len(chunk)).sum();
+        if len > max_len {
+            return Err(invalid_data(format!("column of {} bytes cannot come from chunks that decompress to at most {} bytes",
+                                            len, max_len)));
+        }
+
+        let mut values = vec![T::zeroed(); len / size_of::<T>()];
+        let compression = self.compression;
+        bytemuck::cast_slice_mut::<T, u8>(&mut values)
+            .par_chunks_mut(CHUNK_BYTES)
+            .zip(chunks.par_iter())
+            .try_for_each(|(out, chunk)| decode(compression, chunk, out, size_of::<T>(), filter))?;
+        self.raw_bytes += len as u64;
+        Ok(values)
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        self.inner.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+fn encode(chunk: &[u8], size: usize, filter: Filter) -> Vec<u8> {
+    match filter {
+        Filter::Shuffle => shuffle(chunk, size),
+        Filter::DeltaShuffle => {
+            let mut values: Vec<u32> = chunk.chunks_exact(size_of::<u32>()).map(bytemuck::pod_read_unaligned).collect();
+            for i in (1..values.len()).rev() {
+                values[i] = values[i].wrapping_sub(values[i - 1]);
+            }
+            shuffle(bytemuck::cast_slice(&values), size_of::<u32>())
+        }
+    }
+}
+
+// 分块起点是 CHUNK_BYTES 的整数倍，所以 out 按列元素对齐
+fn decode(compression: Compression, chunk: &[u8], out: &mut [u8], size: usize, filter: Filter) -> io::Result<()> {
+    let mut shuffled = vec![0u8; out.len()];
+    compression.decompress(chunk, &mut shuffled)?;
+    unshuffle(&shuffled, out, size);
+    if let Filter::DeltaShuffle = filter {
+        let values: &mut [u32] = bytemuck::cast_slice_mut(out);
+        for i in 1..values.len() {
+            values[i] = values[i].wrapping_add(values[i - 1]);
+        }
+    }
+    Ok(())
+}
+
+// 先放所有值的第 0 个字节，再放第 1 个字节……
+fn shuffle(bytes: &[u8], size: usize) -> Vec<u8> {
+    let n = bytes.len() / size;
+    let mut out = vec![0u8; bytes.len()];
+    for (i, value) in bytes.chunks_exact(size).enumerate() {
+        for (b, &byte) in value.iter().enumerate() {
+            out[b * n + i] = byte;
+        }
+    }
+    out
+}
+
+fn unshuffle(shuffled: &[u8], out: &mut [u8], size: usize) {
+    let n = out.len() / size;
+    for (i, value) in out.chunks_exact_mut(size).enumerate() {
+        for (b, byte) in value.iter_mut().enumerate() {
+            *byte = shuffled[b * n + i];
+        }
+    }
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,249 @@
+// ============= 紧凑内存表示 =============
+// rt_values_min 和 frame_indices 在同一帧的所有峰上重复同一个值，mobility_values 只取决于
+// scan_indices。紧凑表示把这三列换成帧游程（每帧一项：帧号、保留时间、累计点数）和
+// scan -> 1/K0 表，只保留 m/z、强度、scan（以及按 MzStorage 保留的 TOF）三列逐峰数据：
+// f32 且只存 m/z 时每个峰 12 字节，而平铺的 TimsTOFData 为 24 字节。
+// 被替换的三列通过迭代器（顺序展开）和 peak(i)（按游程二分查找）访问，其余列直接访问；
+// to_data 逐位还原平铺的列。from_data / into_compact 移动保留的列而不复制，
+// 转换时不会同时存在两份逐峰数据。
+use std::mem::size_of;
+
+use crate::checksum::FrameChecksums;
+use crate::frame_table::FrameTable;
+use crate::metadata::RunMetadata;
+use crate::{MzStorage, Precision, TimsTOFData, TimsTOFRawData};
+
+// 一个峰的全部列；未保存的 m/z / TOF 为 None
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Peak<F = f32> {
+    pub rt_min: F,
+    pub mobility: f32,
+    pub mz: Option<F>,
+    pub intensity: u32,
+    pub frame_index: u32,
+    pub scan: u32,
+    pub tof: Option<u32>,
+}
+
+impl<F: Precision> TimsTOFData<F> {
+    // 第 i 个峰（越界时 panic，与按下标访问列相同）
+    pub fn peak(&self, i: usize) -> Peak<F> {
+        Peak {
+            rt_min: self.rt_values_min[i],
+            mobility: self.mobility_values[i],
+            mz: self.mz_values.get(i).copied(),
+            intensity: self.intensity_values[i],
+            frame_index: self.frame_indices[i],
+            scan: self.scan_indices[i],
+            tof: self.tof_indices.get(i).copied(),
+        }
+    }
+
+    pub fn push(&mut self, peak: Peak<F>) {
+        self.rt_values_min.push(peak.rt_min);
+        self.mobility_values.push(peak.mobility);
+        if let Some(mz) = peak.mz {
+            self.mz_values.push(mz);
+        }
+        self.intensity_values.push(peak.intensity);
+        self.frame_indices.push(peak.frame_index);
+        self.scan_indices.push(peak.scan);
+        if let Some(tof) = peak.tof {
+            self.tof_indices.push(tof);
+        }
+    }
+}
+
+// 帧号和保留时间都不变的一段连续峰，end 为该段之后的峰序号
+#[derive(Debug, Clone, Copy)]
+struct FrameRun<F> {
+    frame_index: u32,
+    rt_min: F,
+    end: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactTimsTOFData<F = f32> {
+    storage: MzStorage,
+    runs: Vec<FrameRun<F>>,
+    // 按 scan 编号索引
+    mobility_table: Vec<f32>,
+    // 以下各列与 TimsTOFData 相同
+    pub mz_values: Vec<F>,
+    pub intensity_values: Vec<u32>,
+    pub scan_indices: Vec<u32>,
+    pub tof_indices: Vec<u32>,
+}
+
+impl<F: Precision> CompactTimsTOFData<F> {
+    // storage 为加载时的 m/z 保存方式；同一 scan 出现不同的 1/K0 时无法用表表示，返回错误
+    pub fn from_data(data: TimsTOFData<F>, storage: MzStorage) -> Result<Self, String> {
+        let mut runs: Vec<FrameRun<F>> = Vec::new();
+        for (i, (&frame_index, &rt_min)) in data.frame_indices.iter().zip(&data.rt_values_min).enumerate() {
+            match runs.last_mut() {
+                Some(run) if run.frame_index == frame_index && same_bits(run.rt_min, rt_min) => run.end = i + 1,
+                _ => runs.push(FrameRun { frame_index, rt_min, end: i + 1 }),
+            }
+        }
+
+        let table_len = data.scan_indices.iter().max().map_or(0, |&scan| scan as usize + 1);
+        let mut mobility_table = vec![f32::NAN; table_len];
+        let mut seen = vec![false; table_len];
+        for (&scan, &mobility) in data.scan_indices.iter().zip(&data.mobility_values) {
+            let scan = scan as usize;
+            if !seen[scan] {
+                seen[scan] = true;
+                mobility_table[scan] = mobility;
+            } else if mobility_table[scan].to_bits() != mobility.to_bits() {
+                return Err(format!("scan {} has mobilities {} and {}", scan, mobility_table[scan], mobility));
+            }
+        }
+
+        Ok(CompactTimsTOFData {
+            storage,
+            runs,
+            mobility_table,
+            mz_values: data.mz_values,
+            intensity_values: data.intensity_values,
+            scan_indices: data.scan_indices,
+            tof_indices: data.tof_indices,
+        })
+    }
+
+    // 展开回平铺的列（逐位一致）
+    pub fn to_data(&self) -> TimsTOFData<F> {
+        let mut data = TimsTOFData::with_storage(self.len(), self.storage);
+        self.peaks().for_each(|peak| data.push(peak));
+        data
+    }
+
+    pub fn len(&self) -> usize {
+        self.intensity_values.len()
+    }
+
+    pub fn mz_storage(&self) -> MzStorage {
+        self.storage
+    }
+
+    // 按容量计的堆内存，与 TimsTOFData::heap_bytes 可比
+    pub fn heap_bytes(&self) -> usize {
+        self.runs.capacity() * size_of::<FrameRun<F>>()
+            + self.mobility_table.capacity() * size_of::<f32>()
+            + self.mz_values.capacity() * size_of::<F>()
+            + (self.intensity_values.capacity() + self.scan_indices.capacity() + self.tof_indices.capacity()) * size_of::<u32>()
+    }
+
+    // 第 i 个峰（越界时 panic）
+    pub fn peak(&self, i: usize) -> Peak<F> {
+        let run = &self.runs[self.runs.partition_point(|run| run.end <= i)];
+        let scan = self.scan_indices[i];
+        Peak {
+            rt_min: run.rt_min,
+            mobility: self.mobility_table[scan as usize],
+            mz: self.mz_values.get(i).copied(),
+            intensity: self.intensity_values[i],
+            frame_index: run.frame_index,
+            scan,
+            tof: self.tof_indices.get(i).copied(),
+        }
+    }
+
+    // 按存储顺序遍历所有峰
+    pub fn peaks(&self) -> impl Iterator<Item = Peak<F>> + '_ {
+        self.rt_values_min()
+            .zip(self.frame_indices())
+            .zip(self.mobility_values())
+            .enumerate()
+            .map(|(i, ((rt_min, frame_index), mobility))| Peak {
+                rt_min,
+                mobility,
+                mz: self.mz_values.get(i).copied(),
+                intensity: self.intensity_values[i],
+                frame_index,
+                scan: self.scan_indices[i],
+                tof: self.tof_indices.get(i).copied(),
+            })
+    }
+
+    pub fn rt_values_min(&self) -> impl Iterator<Item = F> + '_ {
+        self.expand_runs(|run| run.rt_min)
+    }
+
+    pub fn frame_indices(&self) -> impl Iterator<Item = u32> + '_ {
+        self.expand_runs(|run| run.frame_index)
+    }
+
+    pub fn mobility_values(&self) -> impl Iterator<Item = f32> + '_ {
+        self.scan_indices.iter().map(|&scan| self.mobility_table[scan as usize])
+    }
+
+    // 每段游程的值重复其峰数次
+    fn expand_runs<'a, T: Copy + 'a>(&'a self, value: impl Fn(&FrameRun<F>) -> T + 'a) -> impl Iterator<Item = T> + 'a {
+        let starts = std::iter::once(0).chain(self.runs.iter().map(|run| run.end));
+        self.runs
+            .iter()
+            .zip(starts)
+            .flat_map(move |(run, start)| std::iter::repeat_n(value(run), run.end - start))
+    }
+}
+
+// 整次加载的紧凑表示；元数据、帧表和校验和原样保留
+#[derive(Debug, Clone)]
+pub struct CompactRawData<F = f32> {
+    pub ms1_data: CompactTimsTOFData<F>,
+    pub ms2_windows: Vec<((f32, f32), CompactTimsTOFData<F>)>,
+    pub metadata: RunMetadata,
+    pub frame_table: FrameTable,
+    pub frame_checksums: FrameChecksums,
+    pub mz_storage: MzStorage,
+}
+
+impl<F: Precision> TimsTOFRawData<F> {
+    // 逐个数据集转换，已转换的数据集的平铺列随即释放
+    pub fn into_compact(self) -> Result<CompactRawData<F>, String> {
+        let storage = self.mz_storage;
+        let ms1_data = CompactTimsTOFData::from_data(self.ms1_data, storage).map_err(|e| format!("MS1: {}", e))?;
+        let ms2_windows = self.ms2_windows
+            .into_iter()
+            .map(|((low, high), td)| {
+                CompactTimsTOFData::from_data(td, storage)
+                    .map(|compact| ((low, high), compact))
+                    .map_err(|e| format!("MS2 window ({:.4}, {:.4}): {}", low, high, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CompactRawData {
+            ms1_data,
+            ms2_windows,
+            metadata: self.metadata,
+            frame_table: self.frame_table,
+            frame_checksums: self.frame_checksums,
+            mz_storage: storage,
+        })
+    }
+}
+
+impl<F: Precision> CompactRawData<F> {
+    pub fn datasets(&self) -> impl Iterator<Item = &CompactTimsTOFData<F>> {
+        std::iter::once(&self.ms1_data).chain(self.ms2_windows.iter().map(|(_, td)| td))
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.datasets().map(CompactTimsTOFData::heap_bytes).sum()
+    }
+
+    pub fn to_data(&self) -> TimsTOFRawData<F> {
+        TimsTOFRawData {
+            ms1_data: self.ms1_data.to_data(),
+            ms2_windows: self.ms2_windows.iter().map(|(bounds, td)| (*bounds, td.to_data())).collect(),
+            metadata: self.metadata.clone(),
+            frame_table: self.frame_table.clone(),
+            frame_checksums: self.frame_checksums.clone(),
+            mz_storage: self.mz_storage,
+        }
+    }
+}
+
+// 逐位比较（f32 到 f64 的转换是单射）
+fn same_bits<F: Precision>(a: F, b: F) -> bool {
+    a.to_f64().to_bits() == b.to_f64().to_bits()
+}
@@ -0,0 +1,62 @@
+// ============= TOF / scan 换算（可选查找表） =============
+// TOF 索引不超过 DigitizerNumSamples，scan 不超过 NumScans；查找表按索引预先
+// 计算一次换算结果，所有工作线程共享同一份只读表。表中存的是换算器的原始
+// f64 结果，重新校准和精度转换仍在查表之后进行，所以输出与直接换算逐位一致
+use std::collections::HashMap;
+use timsrust::converters::{ConvertableDomain, Scan2ImConverter, Tof2MzConverter};
+use timstof_common::LookupTable;
+use crate::frame_table::FrameSqlInfo;
+use crate::metadata::RunMetadata;
+
+pub struct Converters {
+    mz_cv: Tof2MzConverter,
+    im_cv: Scan2ImConverter,
+    // 为空时直接换算
+    mz_table: LookupTable<f64>,
+    im_table: LookupTable<f32>,
+}
+
+impl Converters {
+    // 每个峰直接调用换算器
+    pub fn direct(mz_cv: Tof2MzConverter, im_cv: Scan2ImConverter) -> Self {
+        Converters {
+            mz_cv,
+            im_cv,
+            mz_table: LookupTable::default(),
+            im_table: LookupTable::default(),
+        }
+    }
+    
+    // 按 DigitizerNumSamples 和最大 NumScans 建表；元数据缺失时该轴退回直接换算
+    pub fn with_tables(
+        mz_cv: Tof2MzConverter,
+        im_cv: Scan2ImConverter,
+        metadata: &RunMetadata,
+        frame_info: &HashMap<usize, FrameSqlInfo>,
+    ) -> Self {
+        let tof_len = metadata.calibration.digitizer_num_samples.map_or(0, |n| n as usize + 1);
+        let scan_len = frame_info.values().map(|info| info.num_scans as usize).max().unwrap_or(0);
+        Converters {
+            mz_cv,
+            im_cv,
+            mz_table: LookupTable::build(tof_len, |tof| mz_cv.convert(tof as f64)),
+            im_table: LookupTable::build(scan_len, |scan| im_cv.convert(scan as f64) as f32),
+        }
+    }
+    
+    // 换算器的原始 m/z（未校准）；超出表范围的 TOF 索引直接换算
+    #[inline]
+    pub fn mz(&self, tof: u32) -> f64 {
+        self.mz_table.get_or_else(tof as usize, |tof| self.mz_cv.convert(tof as f64))
+    }
+    
+    #[inline]
+    pub fn mobility(&self, scan: usize) -> f32 {
+        self.im_table.get_or_else(scan, |scan| self.im_cv.convert(scan as f64) as f32)
+    }
+    
+    // (TOF 表长度, scan 表长度)
+    pub fn table_sizes(&self) -> (usize, usize) {
+        (self.mz_table.len(), self.im_table.len())
+    }
+}
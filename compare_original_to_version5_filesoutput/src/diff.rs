@@ -0,0 +1,441 @@
+// ============= 多版本输出比较（可配置容差） =============
+// 以第一个来源为基准，逐列比较其余来源（策略实时运行结果或已保存的 .bin 文件）。
+// 点的对齐：两边分别按 帧 -> scan -> TOF/m/z 排序后，在相同 (帧, scan) 内按位置配对，
+// 多出来的点计入 unmatched，不影响其余点的逐列比较。
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use serde::Serialize;
+
+use crate::checksum::{self, FrameDivergence};
+use crate::{Precision, TimsTOFData, TimsTOFRawData};
+use timstof_common::{key_from_bounds, window_bounds, WindowKey};
+
+// 每列最多记录的不匹配样例数（计数不受此限制）
+const MAX_SAMPLES: usize = 10;
+
+// 可比较的列元素类型
+pub trait ColumnValue: Copy {
+    fn as_f64(self) -> f64;
+    // 两值之间相隔的可表示数个数
+    fn ulps(self, other: Self) -> u64;
+}
+
+impl ColumnValue for f32 {
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn ulps(self, other: Self) -> u64 {
+        // 把位模式映射为单调整数，负数区间翻转
+        fn ordered(x: f32) -> i64 {
+            let bits = x.to_bits() as i32;
+            (if bits < 0 { i32::MIN - bits } else { bits }) as i64
+        }
+        if self.is_nan() || other.is_nan() {
+            return if self.is_nan() && other.is_nan() { 0 } else { u64::MAX };
+        }
+        ordered(self).abs_diff(ordered(other))
+    }
+}
+
+impl ColumnValue for f64 {
+    fn as_f64(self) -> f64 {
+        self
+    }
+
+    fn ulps(self, other: Self) -> u64 {
+        fn ordered(x: f64) -> i128 {
+            let bits = x.to_bits() as i64;
+            (if bits < 0 { i64::MIN - bits } else { bits }) as i128
+        }
+        if self.is_nan() || other.is_nan() {
+            return if self.is_nan() && other.is_nan() { 0 } else { u64::MAX };
+        }
+        u64::try_from(ordered(self).abs_diff(ordered(other))).unwrap_or(u64::MAX)
+    }
+}
+
+impl ColumnValue for u32 {
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn ulps(self, other: Self) -> u64 {
+        self.abs_diff(other) as u64
+    }
+}
+
+// 单列容差
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum Tolerance {
+    Exact,
+    Absolute(f64),
+    // 相对于两值中绝对值较大者
+    Relative(f64),
+    // 相对于基准值，单位 ppm（用于 m/z）
+    Ppm(f64),
+    Ulp(u64),
+}
+
+impl Tolerance {
+    // 解析 "exact" / "abs:1e-6" / "rel:1e-5" / "ppm:0.5" / "ulp:4"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (kind, value) = spec.split_once(':').unwrap_or((spec, ""));
+        let number = || value.parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0)
+            .ok_or_else(|| format!("invalid tolerance value in {:?}", spec));
+        match kind {
+            "exact" if value.is_empty() => Ok(Tolerance::Exact),
+            "abs" => Ok(Tolerance::Absolute(number()?)),
+            "rel" => Ok(Tolerance::Relative(number()?)),
+            "ppm" => Ok(Tolerance::Ppm(number()?)),
+            "ulp" => value.parse().map(Tolerance::Ulp).map_err(|_| format!("invalid ULP count in {:?}", spec)),
+            _ => Err(format!("unknown tolerance {:?} (expected exact, abs:X, rel:X, ppm:X or ulp:N)", spec)),
+        }
+    }
+
+    pub fn accepts<T: ColumnValue>(self, reference: T, candidate: T) -> bool {
+        let (a, b) = (reference.as_f64(), candidate.as_f64());
+        match self {
+            Tolerance::Exact => reference.ulps(candidate) == 0,
+            Tolerance::Absolute(tol) => (a - b).abs() <= tol,
+            Tolerance::Relative(tol) => (a - b).abs() <= tol * a.abs().max(b.abs()),
+            Tolerance::Ppm(tol) => (a - b).abs() <= tol * 1e-6 * a.abs(),
+            Tolerance::Ulp(n) => reference.ulps(candidate) <= n,
+        }
+    }
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tolerance::Exact => write!(f, "exact"),
+            Tolerance::Absolute(v) => write!(f, "abs:{}", v),
+            Tolerance::Relative(v) => write!(f, "rel:{}", v),
+            Tolerance::Ppm(v) => write!(f, "ppm:{}", v),
+            Tolerance::Ulp(v) => write!(f, "ulp:{}", v),
+        }
+    }
+}
+
+// 各列容差；帧、scan 用于对齐，TOF 索引总是精确比较
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Tolerances {
+    pub rt: Tolerance,
+    pub mobility: Tolerance,
+    pub mz: Tolerance,
+    pub intensity: Tolerance,
+}
+
+impl Default for Tolerances {
+    // 与旧版 compare_tims_data 的 1e-6 绝对容差一致，强度精确比较
+    fn default() -> Self {
+        Self {
+            rt: Tolerance::Absolute(1e-6),
+            mobility: Tolerance::Absolute(1e-6),
+            mz: Tolerance::Absolute(1e-6),
+            intensity: Tolerance::Exact,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    pub frame: u32,
+    pub scan: u32,
+    pub reference: f64,
+    pub candidate: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColumnDiff {
+    pub tolerance: Tolerance,
+    pub mismatches: usize,
+    // 所有配对点中的最大绝对差（含容差内的点）
+    pub max_abs_diff: f64,
+    pub samples: Vec<Mismatch>,
+}
+
+// 一个数据集（MS1 或单个 MS2 窗口）的比较结果
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DataDiff {
+    pub reference_points: usize,
+    pub candidate_points: usize,
+    pub matched_points: usize,
+    pub unmatched_reference: usize,
+    pub unmatched_candidate: usize,
+    pub columns: BTreeMap<&'static str, ColumnDiff>,
+    // 至少一侧没有该列（例如 TofOnly 输出没有 m/z）
+    pub skipped_columns: Vec<&'static str>,
+}
+
+impl DataDiff {
+    pub fn is_match(&self) -> bool {
+        self.unmatched_reference == 0
+            && self.unmatched_candidate == 0
+            && self.columns.values().all(|c| c.mismatches == 0)
+    }
+
+    pub fn mismatches(&self) -> usize {
+        self.columns.values().map(|c| c.mismatches).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WindowDiff {
+    pub low: f32,
+    pub high: f32,
+    pub in_reference: bool,
+    pub in_candidate: bool,
+    // 两边都有该窗口时才有
+    pub diff: Option<DataDiff>,
+}
+
+impl WindowDiff {
+    pub fn is_match(&self) -> bool {
+        self.in_reference && self.in_candidate && self.diff.as_ref().is_some_and(DataDiff::is_match)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PairDiff {
+    pub reference: String,
+    pub candidate: String,
+    pub matches: bool,
+    pub ms1: DataDiff,
+    pub ms2_windows: Vec<WindowDiff>,
+    // 逐帧校验和不同的帧（精确比较，不考虑容差）；仅在超出容差时计算
+    pub diverging_frames: Vec<FrameDivergence>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DiffReport {
+    pub generated: String,
+    pub precision: &'static str,
+    pub tolerances: Tolerances,
+    pub sources: Vec<String>,
+    pub matches: bool,
+    pub comparisons: Vec<PairDiff>,
+}
+
+impl DiffReport {
+    // sources[0] 为基准，其余逐个与之比较
+    pub fn build<F: Precision>(sources: &[(String, TimsTOFRawData<F>)], tolerances: &Tolerances) -> Self {
+        let comparisons: Vec<PairDiff> = match sources.split_first() {
+            Some(((ref_name, reference), rest)) => rest.iter()
+                .map(|(name, candidate)| compare_raw(ref_name, reference, name, candidate, tolerances))
+                .collect(),
+            None => Vec::new(),
+        };
+        Self {
+            generated: chrono::Local::now().to_rfc3339(),
+            precision: F::NAME,
+            tolerances: *tolerances,
+            sources: sources.iter().map(|(name, _)| name.clone()).collect(),
+            matches: comparisons.iter().all(|c| c.matches),
+            comparisons,
+        }
+    }
+
+    pub fn save_json(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(filename)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = &self.tolerances;
+        writeln!(f, "Tolerances: rt {}, mobility {}, mz {}, intensity {}", t.rt, t.mobility, t.mz, t.intensity)?;
+        for pair in &self.comparisons {
+            writeln!(f, "\n{} vs {}: {}", pair.reference, pair.candidate, if pair.matches { "✓ match" } else { "❌ differ" })?;
+            write_data_diff(f, "MS1", &pair.ms1)?;
+            let differing: Vec<&WindowDiff> = pair.ms2_windows.iter().filter(|w| !w.is_match()).collect();
+            writeln!(f, "  MS2: {} windows, {} differ", pair.ms2_windows.len(), differing.len())?;
+            for window in differing {
+                let label = format!("MS2 ({:.2}, {:.2})", window.low, window.high);
+                match &window.diff {
+                    Some(diff) => write_data_diff(f, &label, diff)?,
+                    None if window.in_reference => writeln!(f, "  {}: missing in {}", label, pair.candidate)?,
+                    None => writeln!(f, "  {}: only in {}", label, pair.candidate)?,
+                }
+            }
+            if !pair.diverging_frames.is_empty() {
+                writeln!(f, "  Diverging frames: {}", pair.diverging_frames.len())?;
+                writeln!(f, "  Minimal reproducer frames: {}", checksum::reproducer_frames(&pair.diverging_frames))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_data_diff(f: &mut fmt::Formatter<'_>, label: &str, diff: &DataDiff) -> fmt::Result {
+    writeln!(f, "  {}: {} vs {} points, {} paired, {}+{} unpaired, {} column mismatches",
+             label, diff.reference_points, diff.candidate_points, diff.matched_points,
+             diff.unmatched_reference, diff.unmatched_candidate, diff.mismatches())?;
+    for (name, column) in &diff.columns {
+        if column.mismatches > 0 {
+            writeln!(f, "    {}: {} mismatches (max |diff| {:e}, tolerance {})",
+                     name, column.mismatches, column.max_abs_diff, column.tolerance)?;
+        }
+    }
+    Ok(())
+}
+
+// 同一窗口在基准与待比较输出中的数据
+type WindowSides<'a, F> = (Option<&'a TimsTOFData<F>>, Option<&'a TimsTOFData<F>>);
+
+fn compare_raw<F: Precision>(
+    ref_name: &str,
+    reference: &TimsTOFRawData<F>,
+    name: &str,
+    candidate: &TimsTOFRawData<F>,
+    tolerances: &Tolerances,
+) -> PairDiff {
+    let ms1 = compare_data(&reference.ms1_data, &candidate.ms1_data, tolerances);
+
+    // 按量化键合并两边的窗口
+    let mut windows: BTreeMap<WindowKey, WindowSides<F>> = BTreeMap::new();
+    for ((low, high), data) in &reference.ms2_windows {
+        windows.entry(key_from_bounds((*low, *high))).or_default().0 = Some(data);
+    }
+    for ((low, high), data) in &candidate.ms2_windows {
+        windows.entry(key_from_bounds((*low, *high))).or_default().1 = Some(data);
+    }
+    let ms2_windows: Vec<WindowDiff> = windows.into_iter().map(|(key, sides)| {
+        let (low, high) = window_bounds(key);
+        WindowDiff {
+            low,
+            high,
+            in_reference: sides.0.is_some(),
+            in_candidate: sides.1.is_some(),
+            diff: match sides {
+                (Some(a), Some(b)) => Some(compare_data(a, b, tolerances)),
+                _ => None,
+            },
+        }
+    }).collect();
+
+    let matches = ms1.is_match() && ms2_windows.iter().all(WindowDiff::is_match);
+    PairDiff {
+        reference: ref_name.to_string(),
+        candidate: name.to_string(),
+        matches,
+        ms1,
+        ms2_windows,
+        diverging_frames: if matches { Vec::new() } else { checksum::bisect(reference, candidate) },
+    }
+}
+
+// 规范顺序下的索引：帧 -> scan -> TOF（或 m/z）-> 强度
+fn canonical_order<F: Precision>(data: &TimsTOFData<F>) -> Vec<usize> {
+    let has_mz = !data.mz_values.is_empty();
+    let has_tof = !data.tof_indices.is_empty();
+    let mut indices: Vec<usize> = (0..data.len()).collect();
+    indices.sort_by_key(|&i| (
+        data.frame_indices[i],
+        data.scan_indices[i],
+        if has_tof { data.tof_indices[i] } else { 0 },
+        if has_mz { data.mz_values[i].sort_key() } else { 0 },
+        data.intensity_values[i],
+    ));
+    indices
+}
+
+pub fn compare_data<F: Precision>(reference: &TimsTOFData<F>, candidate: &TimsTOFData<F>, tolerances: &Tolerances) -> DataDiff {
+    let order_a = canonical_order(reference);
+    let order_b = canonical_order(candidate);
+
+    // 在相同 (帧, scan) 内按位置配对
+    let mut pairs = Vec::with_capacity(order_a.len().min(order_b.len()));
+    let mut diff = DataDiff {
+        reference_points: reference.len(),
+        candidate_points: candidate.len(),
+        ..DataDiff::default()
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < order_a.len() && j < order_b.len() {
+        let (a, b) = (order_a[i], order_b[j]);
+        let key_a = (reference.frame_indices[a], reference.scan_indices[a]);
+        let key_b = (candidate.frame_indices[b], candidate.scan_indices[b]);
+        match key_a.cmp(&key_b) {
+            std::cmp::Ordering::Equal => {
+                pairs.push((a, b));
+                i += 1;
+                j += 1;
+            }
+            std::cmp::Ordering::Less => {
+                diff.unmatched_reference += 1;
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                diff.unmatched_candidate += 1;
+                j += 1;
+            }
+        }
+    }
+    diff.unmatched_reference += order_a.len() - i;
+    diff.unmatched_candidate += order_b.len() - j;
+    diff.matched_points = pairs.len();
+
+    let paired = Paired {
+        pairs: &pairs,
+        frames: &reference.frame_indices,
+        scans: &reference.scan_indices,
+        lengths: (reference.len(), candidate.len()),
+    };
+    let columns = [
+        ("rt", paired.compare(tolerances.rt, &reference.rt_values_min, &candidate.rt_values_min)),
+        ("mobility", paired.compare(tolerances.mobility, &reference.mobility_values, &candidate.mobility_values)),
+        ("mz", paired.compare(tolerances.mz, &reference.mz_values, &candidate.mz_values)),
+        ("intensity", paired.compare(tolerances.intensity, &reference.intensity_values, &candidate.intensity_values)),
+        ("tof", paired.compare(Tolerance::Exact, &reference.tof_indices, &candidate.tof_indices)),
+    ];
+    for (name, column) in columns {
+        match column {
+            Some(column) => { diff.columns.insert(name, column); }
+            None => diff.skipped_columns.push(name),
+        }
+    }
+    diff
+}
+
+// 已配对的点，以及报告不匹配位置所需的基准列
+struct Paired<'a> {
+    pairs: &'a [(usize, usize)],
+    frames: &'a [u32],
+    scans: &'a [u32],
+    lengths: (usize, usize),
+}
+
+impl Paired<'_> {
+    // 任一侧未保存该列（列长与点数不符）时返回 None
+    fn compare<T: ColumnValue>(&self, tolerance: Tolerance, reference: &[T], candidate: &[T]) -> Option<ColumnDiff> {
+        if (reference.len(), candidate.len()) != self.lengths {
+            return None;
+        }
+
+        let mut column = ColumnDiff { tolerance, mismatches: 0, max_abs_diff: 0.0, samples: Vec::new() };
+        for &(a, b) in self.pairs {
+            let (x, y) = (reference[a], candidate[b]);
+            let abs_diff = (x.as_f64() - y.as_f64()).abs();
+            if abs_diff > column.max_abs_diff {
+                column.max_abs_diff = abs_diff;
+            }
+            if !tolerance.accepts(x, y) {
+                column.mismatches += 1;
+                if column.samples.len() < MAX_SAMPLES {
+                    column.samples.push(Mismatch {
+                        frame: self.frames[a],
+                        scan: self.scans[a],
+                        reference: x.as_f64(),
+                        candidate: y.as_f64(),
+                    });
+                }
+            }
+        }
+        Some(column)
+    }
+}
@@ -0,0 +1,131 @@
+// ============= 逐帧信息表（QC / 与鉴定结果关联）=============
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Serialize, Deserialize};
+use timsrust::{Frame, MSLevel};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameRow {
+    pub index: u32,
+    pub rt_seconds: f64,
+    // 1 = MS1，2 = MS2，0 = 未知
+    pub ms_level: u8,
+    pub num_scans: u32,
+    pub num_peaks: u32,
+    pub summed_intensity: u64,
+    pub max_intensity: u32,
+    pub accumulation_time_ms: f64,
+    pub window_group: u8,
+}
+
+impl FrameRow {
+    pub fn from_frame(frame: &Frame, info: FrameSqlInfo) -> Self {
+        FrameRow {
+            index: frame.index as u32,
+            rt_seconds: frame.rt_in_seconds,
+            ms_level: match frame.ms_level {
+                MSLevel::MS1 => 1,
+                MSLevel::MS2 => 2,
+                _ => 0,
+            },
+            num_scans: info.num_scans,
+            num_peaks: frame.intensities.len() as u32,
+            summed_intensity: frame.intensities.iter().map(|&i| i as u64).sum(),
+            max_intensity: frame.intensities.iter().copied().max().unwrap_or(0),
+            accumulation_time_ms: info.accumulation_time_ms,
+            window_group: frame.window_group,
+        }
+    }
+}
+
+// Frame 结构里没有的列，直接从 Frames 表读取
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameSqlInfo {
+    pub num_scans: u32,
+    pub accumulation_time_ms: f64,
+}
+
+// 按 Frames.Id（即 frame.index）索引
+pub fn read_frame_sql_info(d_folder: &Path) -> Result<HashMap<usize, FrameSqlInfo>, Box<dyn Error>> {
+    let conn = Connection::open_with_flags(d_folder.join("analysis.tdf"), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("SELECT Id, NumScans, AccumulationTime FROM Frames")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)? as usize,
+            FrameSqlInfo {
+                num_scans: row.get::<_, i64>(1)? as u32,
+                accumulation_time_ms: row.get(2)?,
+            },
+        ))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameTable {
+    pub rows: Vec<FrameRow>,
+}
+
+impl FrameTable {
+    // 并行收集的行顺序不确定，统一按帧号排序
+    pub fn from_rows(mut rows: Vec<FrameRow>) -> Self {
+        rows.sort_unstable_by_key(|r| r.index);
+        FrameTable { rows }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn save_csv(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        writeln!(writer, "frame_index,rt_seconds,ms_level,num_scans,num_peaks,summed_intensity,max_intensity,accumulation_time_ms,window_group")?;
+        for r in &self.rows {
+            writeln!(writer, "{},{},{},{},{},{},{},{},{}",
+                     r.index, r.rt_seconds, r.ms_level, r.num_scans, r.num_peaks,
+                     r.summed_intensity, r.max_intensity, r.accumulation_time_ms, r.window_group)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "parquet")]
+    pub fn save_parquet(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        use std::sync::Arc;
+        use arrow_array::{ArrayRef, Float64Array, RecordBatch, UInt32Array, UInt64Array, UInt8Array};
+        use parquet::arrow::ArrowWriter;
+
+        let column = |f: fn(&FrameRow) -> u32| -> ArrayRef {
+            Arc::new(UInt32Array::from_iter_values(self.rows.iter().map(f)))
+        };
+        let batch = RecordBatch::try_from_iter([
+            ("frame_index", column(|r| r.index)),
+            ("rt_seconds", Arc::new(Float64Array::from_iter_values(self.rows.iter().map(|r| r.rt_seconds))) as ArrayRef),
+            ("ms_level", Arc::new(UInt8Array::from_iter_values(self.rows.iter().map(|r| r.ms_level))) as ArrayRef),
+            ("num_scans", column(|r| r.num_scans)),
+            ("num_peaks", column(|r| r.num_peaks)),
+            ("summed_intensity", Arc::new(UInt64Array::from_iter_values(self.rows.iter().map(|r| r.summed_intensity))) as ArrayRef),
+            ("max_intensity", column(|r| r.max_intensity)),
+            ("accumulation_time_ms", Arc::new(Float64Array::from_iter_values(self.rows.iter().map(|r| r.accumulation_time_ms))) as ArrayRef),
+            ("window_group", Arc::new(UInt8Array::from_iter_values(self.rows.iter().map(|r| r.window_group))) as ArrayRef),
+        ])?;
+
+        let mut writer = ArrowWriter::try_new(File::create(filename)?, batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    pub fn save_parquet(&self, _filename: &str) -> Result<(), Box<dyn Error>> {
+        Err("Parquet output requires building with `--features parquet`".into())
+    }
+}
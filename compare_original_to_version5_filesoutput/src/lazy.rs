@@ -0,0 +1,270 @@
+// ============= 按窗口懒加载 MS2 数据 =============
+// 下游打分一次只处理一个 MS2 窗口，而 TimsTOFRawData.ms2_windows 要求所有窗口同时驻留内存。
+// LazyRawData 打开时只读取窗口方案（DiaFrameMsMsWindows / DiaFrameMsMsInfo），
+// 请求某个窗口时只解码其窗口组的帧，只取该窗口的 scan 范围，换算方式与 V5 一次性加载相同，
+// 因此每个窗口与 read_timstof_data_v5_fixed 输出中的对应窗口逐位一致。
+// 最近使用的窗口保存在容量固定的 LRU 中；可选的缓存目录以列式文件保存已加载的窗口
+// （与溢写相同的格式），之后直接读文件，不再解码帧。
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use parking_lot::Mutex;
+use rusqlite::{Connection, OpenFlags};
+use timsrust::{readers::{FrameReader, MetadataReader}, MSLevel};
+use timstof_common::{scan_ranges, window_bounds, window_key, WindowKey};
+
+use crate::converters::Converters;
+use crate::frame_table;
+use crate::metadata::RunMetadata;
+use crate::spill;
+use crate::{convert_mz, LoadOptions, Precision, TimsTOFData};
+
+// 窗口方案中的一个 MS2 窗口
+#[derive(Debug, Clone)]
+pub struct LazyWindow {
+    pub key: WindowKey,
+    pub bounds: (f32, f32),
+    // 含有该窗口的帧在 FrameReader 中的序号，按帧顺序
+    frames: Vec<usize>,
+}
+
+impl LazyWindow {
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+pub struct LazyRawData<F = f32> {
+    d_folder: PathBuf,
+    frames: FrameReader,
+    converters: Converters,
+    options: LoadOptions,
+    // 按窗口键排序，与 ms2_windows 的顺序相同
+    windows: Vec<LazyWindow>,
+    cache_dir: Option<PathBuf>,
+    lru: Mutex<Lru<F>>,
+}
+
+impl<F: Precision> LazyRawData<F> {
+    // 读取窗口方案和换算器；lru_capacity 为内存中最多保留的窗口数（0 表示不保留）
+    pub fn open(d_folder: &Path, options: &LoadOptions, lru_capacity: usize) -> Result<Self, Box<dyn Error>> {
+        let meta = MetadataReader::new(d_folder.join("analysis.tdf"))?;
+        let converters = if options.lookup_tables {
+            let metadata = RunMetadata::read(d_folder)?;
+            let frame_info = frame_table::read_frame_sql_info(d_folder)?;
+            Converters::with_tables(meta.mz_converter, meta.im_converter, &metadata, &frame_info)
+        } else {
+            Converters::direct(meta.mz_converter, meta.im_converter)
+        };
+        Ok(LazyRawData {
+            d_folder: d_folder.to_path_buf(),
+            frames: FrameReader::new(d_folder)?,
+            converters,
+            options: options.clone(),
+            windows: read_window_scheme(d_folder)?,
+            cache_dir: None,
+            lru: Mutex::new(Lru::new(lru_capacity)),
+        })
+    }
+
+    // 把加载过的窗口保存到 dir（已有的缓存直接使用）。缓存记录运行标识（.d 目录、
+    // analysis.tdf 和 analysis.tdf_bin 的大小与修改时间、采集时间、帧数）以及精度、m/z 保存方式
+    // 和重新校准，与本句柄不一致的缓存目录返回错误，指出第一处不同
+    pub fn with_cache_dir(mut self, dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let manifest_path = dir.join("manifest");
+        fs::create_dir_all(dir)?;
+        match fs::read_to_string(&manifest_path) {
+            Ok(existing) if existing != manifest => {
+                let (written, current) = existing.lines().zip(manifest.lines())
+                    .find(|(written, current)| written != current)
+                    .unwrap_or((existing.as_str(), manifest.as_str()));
+                return Err(format!("{} was written for {:?}, not {:?}", dir.display(), written, current).into());
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => fs::write(&manifest_path, manifest)?,
+            Err(e) => return Err(e.into()),
+        }
+        self.cache_dir = Some(dir.to_path_buf());
+        Ok(self)
+    }
+
+    fn manifest(&self) -> Result<String, Box<dyn Error>> {
+        let file = |name: &str| -> std::io::Result<String> {
+            let meta = fs::metadata(self.d_folder.join(name))?;
+            let modified = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+            Ok(format!("{} {} bytes, modified {}.{:09}", name, meta.len(), modified.as_secs(), modified.subsec_nanos()))
+        };
+        let metadata = RunMetadata::read(&self.d_folder)?;
+        Ok(format!(
+            "run {}\n{}\n{}\nacquired {}\n{} frames\n{} {:?} {:?} {}\n",
+            fs::canonicalize(&self.d_folder)?.display(),
+            file("analysis.tdf")?,
+            file("analysis.tdf_bin")?,
+            metadata.acquisition_date_time.as_deref().unwrap_or("-"),
+            self.frames.len(),
+            F::NAME, self.options.mz_storage, self.options.recalibration, self.windows.len(),
+        ))
+    }
+
+    pub fn windows(&self) -> &[LazyWindow] {
+        &self.windows
+    }
+
+    // 当前保存在 LRU 中的窗口数
+    pub fn resident_windows(&self) -> usize {
+        self.lru.lock().entries.len()
+    }
+
+    // 第 index 个窗口的数据：依次查 LRU、缓存目录，最后从帧解码（并写入缓存目录）。
+    // 方案中的窗口可能没有任何峰，这样的窗口返回空数据（一次性加载的输出中不含它们）
+    pub fn window(&self, index: usize) -> Result<Arc<TimsTOFData<F>>, Box<dyn Error>> {
+        let window = self.windows.get(index)
+            .ok_or_else(|| format!("window {} out of range ({} windows)", index, self.windows.len()))?;
+        if let Some(data) = self.lru.lock().get(window.key) {
+            return Ok(data);
+        }
+
+        let cached = self.cache_dir.as_ref().map(|dir| dir.join(spill::window_dir_name(window.key)));
+        let data = match &cached {
+            Some(path) if path.is_dir() => spill::read_window(path)?,
+            _ => {
+                let data = self.decode(window)?;
+                if let Some(path) = &cached {
+                    spill::write_window(path, &data)?;
+                }
+                data
+            }
+        };
+        let data = Arc::new(data);
+        self.lru.lock().insert(window.key, Arc::clone(&data));
+        Ok(data)
+    }
+
+    // 与 V5 的 MS2 分支相同的换算；帧的其他窗口跳过，读不出的帧跳过
+    fn decode(&self, window: &LazyWindow) -> Result<TimsTOFData<F>, Box<dyn Error>> {
+        let storage = self.options.mz_storage;
+        let recalibration = self.options.recalibration.as_ref();
+        let converters = &self.converters;
+        let mut data = TimsTOFData::new();
+        for &idx in &window.frames {
+            let Ok(frame) = self.frames.get(idx) else {
+                continue;
+            };
+            if frame.ms_level != MSLevel::MS2 {
+                continue;
+            }
+            let rt_min = F::rt_minutes(frame.rt_in_seconds);
+            let qs = &frame.quadrupole_settings;
+            let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+            for win in 0..n_windows {
+                if window_key(qs.isolation_mz[win], qs.isolation_width[win])? != window.key {
+                    continue;
+                }
+                let (first, last) = (qs.scan_starts[win], qs.scan_ends[win]);
+                if first > last {
+                    continue;
+                }
+                for (scan, peaks) in scan_ranges(&frame.scan_offsets, frame.tof_indices.len()).within(first, last) {
+                    let im = converters.mobility(scan);
+                    for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter().zip(&frame.intensities[peaks]) {
+                        data.rt_values_min.push(rt_min);
+                        data.mobility_values.push(im);
+                        if storage.keeps_mz() {
+                            data.mz_values.push(convert_mz(converters, recalibration, tof, rt_min));
+                        }
+                        if storage.keeps_tof() {
+                            data.tof_indices.push(tof);
+                        }
+                        data.intensity_values.push(intensity);
+                        data.frame_indices.push(frame.index as u32);
+                        data.scan_indices.push(scan as u32);
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+// 窗口组 -> 隔离窗口键，帧 -> 窗口组；帧号换成 FrameReader 的序号（Frames 按 Id 排序）
+fn read_window_scheme(d_folder: &Path) -> Result<Vec<LazyWindow>, Box<dyn Error>> {
+    let conn = Connection::open_with_flags(d_folder.join("analysis.tdf"), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut stmt = conn.prepare("SELECT Id FROM Frames ORDER BY Id")?;
+    let positions: HashMap<i64, usize> = stmt
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .enumerate()
+        .map(|(idx, id)| id.map(|id| (id, idx)))
+        .collect::<Result<_, _>>()?;
+
+    let mut group_keys: HashMap<i64, Vec<WindowKey>> = HashMap::new();
+    let mut stmt = conn.prepare("SELECT WindowGroup, IsolationMz, IsolationWidth FROM DiaFrameMsMsWindows")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?)))?;
+    for row in rows {
+        let (group, mz, width) = row?;
+        let keys = group_keys.entry(group).or_default();
+        let key = window_key(mz, width)?;
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    let mut frames: BTreeMap<WindowKey, Vec<usize>> = BTreeMap::new();
+    for keys in group_keys.values() {
+        for &key in keys {
+            frames.entry(key).or_default();
+        }
+    }
+    let mut stmt = conn.prepare("SELECT Frame, WindowGroup FROM DiaFrameMsMsInfo ORDER BY Frame")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+    for row in rows {
+        let (frame, group) = row?;
+        let (Some(&idx), Some(keys)) = (positions.get(&frame), group_keys.get(&group)) else {
+            continue;
+        };
+        for key in keys {
+            frames.get_mut(key).expect("every group key has an entry").push(idx);
+        }
+    }
+
+    Ok(frames
+        .into_iter()
+        .map(|(key, mut frames)| {
+            frames.dedup();
+            LazyWindow { key, bounds: window_bounds(key), frames }
+        })
+        .collect())
+}
+
+// 最近使用的窗口在队首
+struct Lru<F> {
+    capacity: usize,
+    entries: VecDeque<(WindowKey, Arc<TimsTOFData<F>>)>,
+}
+
+impl<F> Lru<F> {
+    fn new(capacity: usize) -> Self {
+        Lru { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    fn get(&mut self, key: WindowKey) -> Option<Arc<TimsTOFData<F>>> {
+        let pos = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(pos)?;
+        let data = Arc::clone(&entry.1);
+        self.entries.push_front(entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, key: WindowKey, data: Arc<TimsTOFData<F>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.retain(|(k, _)| *k != key);
+        self.entries.push_front((key, data));
+        self.entries.truncate(self.capacity);
+    }
+}
@@ -1,8 +1,10 @@
-use std::collections::HashMap;
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write, BufReader, BufWriter};
-use std::path::Path;
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
@@ -11,22 +13,128 @@ use rayon::prelude::*;
 use dashmap::DashMap;
 use crossbeam_channel::bounded;
 use parking_lot::Mutex;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use bincode;
 use sha2::{Sha256, Digest};
+use timstof_common::{concat_parallel, first_unordered_peak, key_from_bounds, scan_for_index, scan_ranges, window_bounds, window_key, Threads, WindowKeyError, WindowSegments};
+
+mod checksum;
+mod codec;
+mod compact;
+mod converters;
+mod diff;
+mod frame_table;
+mod lazy;
+mod metadata;
+mod recalibration;
+mod spill;
+
+use checksum::{FrameChecksum, FrameChecksums};
+use codec::Compression;
+use converters::Converters;
+use diff::{ColumnValue, DiffReport, Tolerance, Tolerances};
+use frame_table::{FrameRow, FrameTable};
+use lazy::LazyRawData;
+use metadata::RunMetadata;
+use recalibration::{CalibrationDomain, CalibrationModel, Recalibration, RecalibrationConfig};
+use spill::{SpillStore, SpilledWindows};
+
+// ============= m/z 与保留时间列的精度 =============
+// f32 为默认（与旧版输出逐位一致）；f64 保留换算器原生的双精度结果，
+// 避免高质量数下的亚ppm误差以及长梯度下的保留时间截断
+pub trait Precision:
+    Copy + Send + Sync + Default + PartialEq + fmt::Debug + fmt::Display
+    + Serialize + DeserializeOwned + ColumnValue + bytemuck::Pod + 'static
+{
+    const NAME: &'static str;
+    
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+    // 秒 -> 分钟
+    fn rt_minutes(rt_in_seconds: f64) -> Self;
+    // 哈希前排序使用的整数键
+    fn sort_key(self) -> i64;
+    fn hash_into(self, hasher: &mut Sha256);
+}
+
+impl Precision for f32 {
+    const NAME: &'static str = "f32";
+    
+    #[inline]
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+    
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+    
+    // 保持旧版先转 f32 再除以 60 的计算顺序，保证输出逐位不变
+    #[inline]
+    fn rt_minutes(rt_in_seconds: f64) -> Self {
+        rt_in_seconds as f32 / 60.0
+    }
+    
+    // 沿用旧版的 mz * 1e6 键，已有的摘要哈希保持不变
+    #[inline]
+    fn sort_key(self) -> i64 {
+        (self * 1e6) as i64
+    }
+    
+    #[inline]
+    fn hash_into(self, hasher: &mut Sha256) {
+        hasher.update(self.to_le_bytes());
+    }
+}
+
+impl Precision for f64 {
+    const NAME: &'static str = "f64";
+    
+    #[inline]
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+    
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self
+    }
+    
+    #[inline]
+    fn rt_minutes(rt_in_seconds: f64) -> Self {
+        rt_in_seconds / 60.0
+    }
+    
+    // 按位全序键：不丢失精度（m/z 恒为正，正数的位模式与数值同序）
+    #[inline]
+    fn sort_key(self) -> i64 {
+        let bits = self.to_bits() as i64;
+        bits ^ (((bits >> 63) as u64) >> 1) as i64
+    }
+    
+    #[inline]
+    fn hash_into(self, hasher: &mut Sha256) {
+        hasher.update(self.to_le_bytes());
+    }
+}
 
 // ============= 数据结构（支持序列化）=============
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TimsTOFData {
-    pub rt_values_min: Vec<f32>,
+pub struct TimsTOFData<F = f32> {
+    pub rt_values_min: Vec<F>,
     pub mobility_values: Vec<f32>,
-    pub mz_values: Vec<f32>,
+    pub mz_values: Vec<F>,
     pub intensity_values: Vec<u32>,
     pub frame_indices: Vec<u32>,
     pub scan_indices: Vec<u32>,
+    // 原始TOF索引（仅在 MzStorage::ConvertedWithTof / TofOnly 时填充）；
+    // serde(default) 只对 JSON 有效，旧的 .bin 文件由 save_binary 的文件头识别并拒绝
+    #[serde(default)]
+    pub tof_indices: Vec<u32>,
 }
 
-impl TimsTOFData {
+impl<F: Precision> TimsTOFData<F> {
     pub fn new() -> Self {
         TimsTOFData {
             rt_values_min: Vec::new(),
@@ -35,6 +143,7 @@ impl TimsTOFData {
             intensity_values: Vec::new(),
             frame_indices: Vec::new(),
             scan_indices: Vec::new(),
+            tof_indices: Vec::new(),
         }
     }
     
@@ -46,9 +155,58 @@ impl TimsTOFData {
             intensity_values: Vec::with_capacity(capacity),
             frame_indices: Vec::with_capacity(capacity),
             scan_indices: Vec::with_capacity(capacity),
+            tof_indices: Vec::new(),
+        }
+    }
+    
+    // 按 m/z 存储方式预分配，只为实际会填充的列分配空间
+    pub fn with_storage(capacity: usize, storage: MzStorage) -> Self {
+        Self {
+            rt_values_min: Vec::with_capacity(capacity),
+            mobility_values: Vec::with_capacity(capacity),
+            mz_values: Vec::with_capacity(if storage.keeps_mz() { capacity } else { 0 }),
+            intensity_values: Vec::with_capacity(capacity),
+            frame_indices: Vec::with_capacity(capacity),
+            scan_indices: Vec::with_capacity(capacity),
+            tof_indices: Vec::with_capacity(if storage.keeps_tof() { capacity } else { 0 }),
         }
     }
     
+    // 数据点数量（TofOnly 模式下 mz_values 为空，不能再用它计数）
+    pub fn len(&self) -> usize {
+        self.intensity_values.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.intensity_values.is_empty()
+    }
+    
+    // 各列已分配的堆内存（按容量计，内存预算据此统计）
+    pub fn heap_bytes(&self) -> usize {
+        use std::mem::size_of;
+        (self.rt_values_min.capacity() + self.mz_values.capacity()) * size_of::<F>()
+            + self.mobility_values.capacity() * size_of::<f32>()
+            + (self.intensity_values.capacity() + self.frame_indices.capacity()
+                + self.scan_indices.capacity() + self.tof_indices.capacity()) * size_of::<u32>()
+    }
+    
+    // 把 TOF 索引换算成 m/z（用于 TofOnly 模式延后转换，或重新校准后刷新 m/z）；
+    // 没有保留 TOF 索引（MzStorage::Converted）时返回错误，数据不变
+    pub fn convert_tof_to_mz(&mut self, mz_cv: &(impl ConvertableDomain + Sync)) -> Result<(), String> {
+        self.check_tof_retained()?;
+        self.mz_values = self.tof_indices.par_iter()
+            .map(|&tof| F::from_f64(mz_cv.convert(tof as f64)))
+            .collect();
+        Ok(())
+    }
+    
+    fn check_tof_retained(&self) -> Result<(), String> {
+        if self.tof_indices.len() != self.len() {
+            return Err(format!("TOF indices were not retained ({} of {} peaks)", self.tof_indices.len(), self.len()));
+        }
+        Ok(())
+    }
+    
     fn merge_from(&mut self, other: &mut Self) {
         self.rt_values_min.append(&mut other.rt_values_min);
         self.mobility_values.append(&mut other.mobility_values);
@@ -56,80 +214,153 @@ impl TimsTOFData {
         self.intensity_values.append(&mut other.intensity_values);
         self.frame_indices.append(&mut other.frame_indices);
         self.scan_indices.append(&mut other.scan_indices);
+        self.tof_indices.append(&mut other.tof_indices);
     }
     
-    // 计算数据的哈希值
+    // 逐列拼接成长度恰好的列，每列并行复制；块的一列复制完即释放，整份数据不会同时存在两份
+    fn concat(mut chunks: Vec<Self>) -> Self {
+        macro_rules! column {
+            ($name:ident) => {
+                concat_parallel(chunks.iter_mut().map(|chunk| std::mem::take(&mut chunk.$name)).collect())
+            };
+        }
+        Self {
+            rt_values_min: column!(rt_values_min),
+            mobility_values: column!(mobility_values),
+            mz_values: column!(mz_values),
+            intensity_values: column!(intensity_values),
+            frame_indices: column!(frame_indices),
+            scan_indices: column!(scan_indices),
+            tof_indices: column!(tof_indices),
+        }
+    }
+    
+    // 第一个不满足 帧 -> scan -> TOF 顺序的点；有 TOF 列时按 TOF 检查，否则按 m/z
+    pub fn first_unordered_peak(&self) -> Option<usize> {
+        if !self.tof_indices.is_empty() {
+            first_unordered_peak(&self.frame_indices, &self.scan_indices, &self.tof_indices)
+        } else {
+            let mz_keys: Vec<i64> = self.mz_values.iter().map(|mz| mz.sort_key()).collect();
+            first_unordered_peak(&self.frame_indices, &self.scan_indices, &mz_keys)
+        }
+    }
+    
+    // 计算数据的哈希值（先排序，因此与存储顺序无关）
     pub fn calculate_hash(&self) -> String {
         let mut hasher = Sha256::new();
         
         // 先排序数据以确保顺序一致
-        let mut indices: Vec<usize> = (0..self.mz_values.len()).collect();
+        let has_mz = !self.mz_values.is_empty();
+        let has_tof = !self.tof_indices.is_empty();
+        let mut indices: Vec<usize> = (0..self.len()).collect();
         indices.sort_by_key(|&i| {
             (
                 self.frame_indices[i],
                 self.scan_indices[i],
-                (self.mz_values[i] * 1e6) as i64,
+                if has_mz { self.mz_values[i].sort_key() } else { 0 },
+                if has_tof { self.tof_indices[i] } else { 0 },
                 self.intensity_values[i]
             )
         });
         
-        // 按排序后的顺序计算哈希
+        // 按排序后的顺序计算哈希（TOF列放在最后，保证只含m/z的数据哈希与旧版一致）
         for &i in &indices {
-            hasher.update(self.rt_values_min[i].to_le_bytes());
+            self.rt_values_min[i].hash_into(&mut hasher);
             hasher.update(self.mobility_values[i].to_le_bytes());
-            hasher.update(self.mz_values[i].to_le_bytes());
+            if has_mz {
+                self.mz_values[i].hash_into(&mut hasher);
+            }
             hasher.update(self.intensity_values[i].to_le_bytes());
             hasher.update(self.frame_indices[i].to_le_bytes());
             hasher.update(self.scan_indices[i].to_le_bytes());
+            if has_tof {
+                hasher.update(self.tof_indices[i].to_le_bytes());
+            }
         }
         
         format!("{:x}", hasher.finalize())
     }
 }
 
+// 后加入的字段带 serde(default)，读旧版的 JSON 时取默认值；bincode 按位置解码，不受其影响，
+// 旧的 .bin 文件由 save_binary 的文件头（codec::BINARY_MAGIC）识别并拒绝
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TimsTOFRawData {
-    pub ms1_data: TimsTOFData,
-    pub ms2_windows: Vec<((f32, f32), TimsTOFData)>,
+pub struct TimsTOFRawData<F = f32> {
+    pub ms1_data: TimsTOFData<F>,
+    // 窗口边界来自量化键，始终为 f32
+    pub ms2_windows: Vec<((f32, f32), TimsTOFData<F>)>,
+    #[serde(default)]
+    pub metadata: RunMetadata,
+    #[serde(default)]
+    pub frame_table: FrameTable,
+    #[serde(default)]
+    pub frame_checksums: FrameChecksums,
+    // 加载时使用的 m/z 保存方式（不能从列推断：空数据集的各列都为空）
+    #[serde(default)]
+    pub mz_storage: MzStorage,
 }
 
-impl TimsTOFRawData {
+impl<F: Precision> TimsTOFRawData<F> {
+    // 对 MS1 和所有 MS2 窗口执行 TOF -> m/z 换算；先检查所有数据集都保留了 TOF 索引，
+    // 任何一个没有保留时返回错误，数据不变
+    pub fn convert_tof_to_mz(&mut self, mz_cv: &(impl ConvertableDomain + Sync)) -> Result<(), String> {
+        self.ms1_data.check_tof_retained().map_err(|e| format!("MS1: {}", e))?;
+        for ((low, high), td) in &self.ms2_windows {
+            td.check_tof_retained().map_err(|e| format!("MS2 window ({:.4}, {:.4}): {}", low, high, e))?;
+        }
+        self.ms1_data.convert_tof_to_mz(mz_cv)?;
+        for (_, td) in &mut self.ms2_windows {
+            td.convert_tof_to_mz(mz_cv)?;
+        }
+        self.mz_storage = MzStorage::ConvertedWithTof;
+        self.refresh_frame_checksums();
+        Ok(())
+    }
+    
+    // 加载后修改了数据（m/z 换算、重新校准）时重算逐帧校验和；加载时跳过的保持为空
+    pub fn refresh_frame_checksums(&mut self) {
+        if !self.frame_checksums.is_empty() {
+            self.frame_checksums = FrameChecksums::from_raw(self);
+        }
+    }
+    
+    // 检查规范顺序：各列按 帧 -> scan -> TOF 排列，MS2 窗口按键升序
+    pub fn check_canonical_order(&self) -> Result<(), String> {
+        check_canonical_order_of(&self.ms1_data, self.ms2_windows.iter().map(|(bounds, td)| Ok((*bounds, td))))
+    }
+    
     // 保存为二进制文件
     pub fn save_binary(&self, filename: &str) -> Result<(), Box<dyn Error>> {
-        println!("  Saving to binary file: {}", filename);
-        let file = File::create(filename)?;
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, self)?;
-        
-        // 计算文件大小
-        let metadata = std::fs::metadata(filename)?;
-        let file_size = metadata.len();
-        println!("    Binary file size: {:.2} MB", file_size as f64 / 1_048_576.0);
-        
+        write_binary::<F, _>(self, filename)
+    }
+    
+    // 按列压缩保存（见 codec.rs），打印压缩比和吞吐量
+    pub fn save_compressed(&self, filename: &str, compression: Compression) -> Result<(), Box<dyn Error>> {
+        println!("  Saving to compressed file: {}", filename);
+        let report = codec::save(self, Path::new(filename), compression)?;
+        println!("    Compressed file size: {:.2} MB", report.compressed_bytes as f64 / 1_048_576.0);
+        println!("    Compression: {}", report);
         Ok(())
     }
     
-    // 从二进制文件加载
+    // 从二进制文件加载（自动识别 save_compressed 写出的压缩文件）
     pub fn load_binary(filename: &str) -> Result<Self, Box<dyn Error>> {
         println!("  Loading from binary file: {}", filename);
+        if codec::is_compressed(Path::new(filename))? {
+            let (data, report) = codec::load(Path::new(filename))?;
+            println!("    Decompression: {}", report);
+            return Ok(data);
+        }
         let file = File::open(filename)?;
-        let reader = BufReader::new(file);
+        let mut reader = BufReader::new(file);
+        codec::read_binary_header::<F>(&mut reader, Path::new(filename))?;
         let data = bincode::deserialize_from(reader)?;
         Ok(data)
     }
     
     // 保存为JSON文件（可读性好，但文件较大）
     pub fn save_json(&self, filename: &str) -> Result<(), Box<dyn Error>> {
-        println!("  Saving to JSON file: {}", filename);
-        let json = serde_json::to_string_pretty(self)?;
-        let mut file = File::create(filename)?;
-        file.write_all(json.as_bytes())?;
-        
-        let metadata = std::fs::metadata(filename)?;
-        let file_size = metadata.len();
-        println!("    JSON file size: {:.2} MB", file_size as f64 / 1_048_576.0);
-        
-        Ok(())
+        write_json(self, filename)
     }
     
     // 从JSON文件加载
@@ -144,91 +375,305 @@ impl TimsTOFRawData {
     
     // 保存数据摘要（用于快速验证）
     pub fn save_summary(&self, filename: &str) -> Result<(), Box<dyn Error>> {
-        println!("  Saving summary to: {}", filename);
-        let mut file = File::create(filename)?;
-        
-        writeln!(file, "=== TimsTOF Data Summary ===")?;
-        writeln!(file, "MS1 Data Points: {}", self.ms1_data.mz_values.len())?;
-        writeln!(file, "MS1 Data Hash: {}", self.ms1_data.calculate_hash())?;
-        writeln!(file, "MS2 Windows: {}", self.ms2_windows.len())?;
-        
-        let total_ms2_points: usize = self.ms2_windows.iter()
-            .map(|(_, td)| td.mz_values.len()).sum();
-        writeln!(file, "Total MS2 Data Points: {}", total_ms2_points)?;
-        
-        // 计算每个MS2窗口的哈希
-        writeln!(file, "\n=== MS2 Window Hashes ===")?;
-        let mut windows_sorted = self.ms2_windows.clone();
-        windows_sorted.sort_by(|a, b| {
-            a.0.0.partial_cmp(&b.0.0).unwrap()
-                .then(a.0.1.partial_cmp(&b.0.1).unwrap())
-        });
-        
-        for ((low, high), data) in &windows_sorted {
-            writeln!(file, "Window ({:.4}, {:.4}): {} points, hash: {}", 
-                    low, high, data.mz_values.len(), 
-                    &data.calculate_hash()[..16])?; // 只显示前16个字符
+        let windows = self.ms2_windows.iter().map(|(bounds, td)| Ok((*bounds, td)));
+        write_summary(filename, &self.metadata, &self.ms1_data, self.mz_storage, &self.frame_table, &self.frame_checksums, windows)
+    }
+}
+
+// 内存预算模式的 V5 输出：字段与 TimsTOFRawData 相同，二进制和 JSON 序列化结果逐字节一致，
+// 但溢写过的 MS2 窗口留在溢写目录中，检查和保存时逐个读回
+#[derive(Serialize)]
+#[serde(bound = "F: Precision")]
+pub struct SpilledRawData<F = f32> {
+    pub ms1_data: TimsTOFData<F>,
+    pub ms2_windows: SpilledWindows<F>,
+    pub metadata: RunMetadata,
+    pub frame_table: FrameTable,
+    pub frame_checksums: FrameChecksums,
+    pub mz_storage: MzStorage,
+}
+
+impl<F: Precision> SpilledRawData<F> {
+    pub fn check_canonical_order(&self) -> Result<(), String> {
+        check_canonical_order_of(&self.ms1_data, self.windows())
+    }
+    
+    pub fn save_binary(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        write_binary::<F, _>(self, filename)
+    }
+    
+    pub fn save_json(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        write_json(self, filename)
+    }
+    
+    pub fn save_summary(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        write_summary(filename, &self.metadata, &self.ms1_data, self.mz_storage, &self.frame_table, &self.frame_checksums, self.windows())
+    }
+    
+    // 把所有溢写的窗口读回内存
+    pub fn into_raw_data(self) -> Result<TimsTOFRawData<F>, Box<dyn Error>> {
+        Ok(TimsTOFRawData {
+            ms1_data: self.ms1_data,
+            ms2_windows: self.ms2_windows.into_windows()?,
+            metadata: self.metadata,
+            frame_table: self.frame_table,
+            frame_checksums: self.frame_checksums,
+            mz_storage: self.mz_storage,
+        })
+    }
+    
+    // 逐个读回的 MS2 窗口
+    fn windows(&self) -> impl Iterator<Item = SpilledWindow<'_, F>> {
+        (0..self.ms2_windows.len()).map(|i| Ok((self.ms2_windows.bounds(i), self.ms2_windows.window(i)?)))
+    }
+}
+
+type SpilledWindow<'a, F> = io::Result<((f32, f32), Cow<'a, TimsTOFData<F>>)>;
+
+// 全部窗口都在内存中（例如经过紧凑表示往返之后）
+impl<F: Precision> From<TimsTOFRawData<F>> for SpilledRawData<F> {
+    fn from(data: TimsTOFRawData<F>) -> Self {
+        let windows = data.ms2_windows.into_iter().map(|(bounds, td)| (key_from_bounds(bounds), td)).collect();
+        SpilledRawData {
+            ms1_data: data.ms1_data,
+            ms2_windows: SpilledWindows::new(None, windows),
+            metadata: data.metadata,
+            frame_table: data.frame_table,
+            frame_checksums: data.frame_checksums,
+            mz_storage: data.mz_storage,
         }
-        
-        Ok(())
     }
 }
 
-// ============= 原始版本实现 =============
-mod original_version {
-    use super::*;
+// ============= TimsTOFRawData 与 SpilledRawData 共用的检查和输出 =============
+// windows 逐个给出 MS2 窗口，溢写的窗口读一个处理一个
+
+fn check_canonical_order_of<F: Precision, D: Borrow<TimsTOFData<F>>>(
+    ms1: &TimsTOFData<F>,
+    windows: impl Iterator<Item = io::Result<((f32, f32), D)>>,
+) -> Result<(), String> {
+    if let Some(i) = ms1.first_unordered_peak() {
+        return Err(format!("MS1 peak {} is out of frame/scan/TOF order", i));
+    }
+    let mut previous_key = None;
+    for window in windows {
+        let ((low, high), td) = window.map_err(|e| e.to_string())?;
+        let key = key_from_bounds((low, high));
+        if previous_key.is_some_and(|previous| previous >= key) {
+            return Err(format!("MS2 window ({:.4}, {:.4}) is out of key order", low, high));
+        }
+        previous_key = Some(key);
+        if let Some(i) = td.borrow().first_unordered_peak() {
+            return Err(format!("MS2 window ({:.4}, {:.4}) peak {} is out of frame/scan/TOF order", low, high, i));
+        }
+    }
+    Ok(())
+}
+
+fn write_binary<F: Precision, T: Serialize>(value: &T, filename: &str) -> Result<(), Box<dyn Error>> {
+    println!("  Saving to binary file: {}", filename);
+    let file = File::create(filename)?;
+    let mut writer = BufWriter::new(file);
+    codec::write_binary_header::<F>(&mut writer)?;
+    bincode::serialize_into(&mut writer, value)?;
+    writer.flush()?;
+    
+    // 计算文件大小
+    let metadata = std::fs::metadata(filename)?;
+    let file_size = metadata.len();
+    println!("    Binary file size: {:.2} MB", file_size as f64 / 1_048_576.0);
+    
+    Ok(())
+}
+
+// 直接写入文件，不先生成完整的 JSON 字符串
+fn write_json<T: Serialize>(value: &T, filename: &str) -> Result<(), Box<dyn Error>> {
+    println!("  Saving to JSON file: {}", filename);
+    let mut writer = BufWriter::new(File::create(filename)?);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.flush()?;
+    
+    let metadata = std::fs::metadata(filename)?;
+    let file_size = metadata.len();
+    println!("    JSON file size: {:.2} MB", file_size as f64 / 1_048_576.0);
+    
+    Ok(())
+}
+
+fn write_summary<F: Precision, D: Borrow<TimsTOFData<F>>>(
+    filename: &str,
+    metadata: &RunMetadata,
+    ms1: &TimsTOFData<F>,
+    mz_storage: MzStorage,
+    frame_table: &FrameTable,
+    frame_checksums: &FrameChecksums,
+    windows: impl Iterator<Item = io::Result<((f32, f32), D)>>,
+) -> Result<(), Box<dyn Error>> {
+    println!("  Saving summary to: {}", filename);
+    
+    // 计算每个MS2窗口的点数和哈希（只显示前16个字符）
+    let mut window_hashes = windows
+        .map(|window| {
+            let (bounds, data) = window?;
+            let data = data.borrow();
+            Ok((bounds, data.len(), data.calculate_hash()[..16].to_string()))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    window_hashes.sort_by(|a, b| {
+        a.0.0.partial_cmp(&b.0.0).unwrap()
+            .then(a.0.1.partial_cmp(&b.0.1).unwrap())
+    });
+    
+    let mut file = File::create(filename)?;
+    
+    writeln!(file, "=== Run Metadata ===")?;
+    writeln!(file, "{}", metadata)?;
+    writeln!(file)?;
+    
+    writeln!(file, "=== TimsTOF Data Summary ===")?;
+    writeln!(file, "MS1 Data Points: {}", ms1.len())?;
+    writeln!(file, "m/z Storage: {:?}", mz_storage)?;
+    writeln!(file, "m/z / RT Precision: {}", F::NAME)?;
+    writeln!(file, "MS1 Data Hash: {}", ms1.calculate_hash())?;
+    writeln!(file, "MS2 Windows: {}", window_hashes.len())?;
     
-    struct FrameSplit {
-        pub ms1: TimsTOFData,
-        pub ms2: Vec<((u32, u32), TimsTOFData)>,
+    let total_ms2_points: usize = window_hashes.iter()
+        .map(|(_, points, _)| points).sum();
+    writeln!(file, "Total MS2 Data Points: {}", total_ms2_points)?;
+    writeln!(file, "Frame Table Rows: {}", frame_table.len())?;
+    
+    writeln!(file, "\n=== MS2 Window Hashes ===")?;
+    for ((low, high), points, hash) in &window_hashes {
+        writeln!(file, "Window ({:.4}, {:.4}): {} points, hash: {}", low, high, points, hash)?;
+    }
+    
+    // 逐帧校验和：两个摘要文件 diff 后即可看到哪些帧、哪些窗口不同
+    writeln!(file, "\n=== Frame Checksums ===")?;
+    frame_checksums.write_summary(&mut file)?;
+    
+    Ok(())
+}
+
+// ============= 加载选项 =============
+// m/z 列的保存方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MzStorage {
+    // 只保存换算后的 m/z（与旧版输出一致）
+    #[default]
+    Converted,
+    // 同时保留原始TOF索引，便于之后重新校准或恢复精确值
+    ConvertedWithTof,
+    // 只保存TOF索引，m/z 延后由 convert_tof_to_mz 计算
+    TofOnly,
+}
+
+impl MzStorage {
+    #[inline]
+    pub fn keeps_mz(self) -> bool {
+        self != MzStorage::TofOnly
     }
     
     #[inline]
-    fn quantize(x: f32) -> u32 { 
-        (x * 10_000.0).round() as u32 
+    pub fn keeps_tof(self) -> bool {
+        self != MzStorage::Converted
     }
     
-    fn find_scan_for_index(index: usize, scan_offsets: &[usize]) -> usize {
-        for (scan, window) in scan_offsets.windows(2).enumerate() {
-            if index >= window[0] && index < window[1] {
-                return scan;
-            }
+    // 命令行写法：converted | with-tof | tof-only
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "converted" => Ok(MzStorage::Converted),
+            "with-tof" => Ok(MzStorage::ConvertedWithTof),
+            "tof-only" => Ok(MzStorage::TofOnly),
+            _ => Err(format!("--mz-storage expects converted, with-tof or tof-only, got {:?}", spec)),
         }
-        scan_offsets.len() - 1
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    pub mz_storage: MzStorage,
+    // 设置后在 TOF -> m/z 换算时直接应用校准
+    pub recalibration: Option<Recalibration>,
+    // 跳过逐帧校验和（只需要统计信息时节省一次排序和哈希）
+    pub skip_frame_checksums: bool,
+    // 用预先计算的 TOF -> m/z 和 scan -> 1/K0 查找表代替逐峰换算（结果逐位一致）；
+    // 原始版本始终直接换算，作为对照
+    pub lookup_tables: bool,
+    // 工作线程数：Auto 时若调用方已在 rayon 线程池中则直接使用该池，
+    // 否则按可用 CPU（含 cgroup 配额）、帧数和 I/O 等待比例建本地线程池
+    pub threads: Threads,
+    // MS2 窗口缓冲的内存预算（字节）：超出时 V5 把最大的窗口溢写到临时列式文件，输出不变。
+    // read_timstof_data_v5_spilled 的输出把溢写的部分留在磁盘上逐个窗口读回，
+    // read_timstof_data_v5_fixed 在加载结束后全部合并回内存。只统计 MS2 窗口缓冲，
+    // MS1 数据和处理中的帧（此时按小批处理）不计入；原始版本忽略此项
+    pub max_memory: Option<usize>,
+    // 溢写文件所在目录，默认系统临时目录（$TMPDIR，SLURM 作业中通常是节点本地盘）
+    pub spill_dir: Option<PathBuf>,
+}
+
+// TOF -> m/z 换算（若设置了重新校准则同时校正）
+#[inline]
+fn convert_mz<F: Precision>(converters: &Converters, recalibration: Option<&Recalibration>, tof: u32, rt_min: F) -> F {
+    let mz = converters.mz(tof);
+    match recalibration {
+        Some(r) => F::from_f64(r.correct(mz, tof, rt_min.to_f64())),
+        None => F::from_f64(mz),
+    }
+}
+
+// ============= 原始版本实现 =============
+mod original_version {
+    use super::*;
+    
+    struct FrameSplit<F> {
+        pub ms1: TimsTOFData<F>,
+        pub ms2: Vec<((u32, u32), TimsTOFData<F>)>,
+        pub row: FrameRow,
+        pub checksum: Option<FrameChecksum>,
     }
     
-    pub fn read_timstof_data_original(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
+    pub fn read_timstof_data_original<F: Precision>(d_folder: &Path, options: &LoadOptions) -> Result<TimsTOFRawData<F>, Box<dyn Error>> {
+        let storage = options.mz_storage;
+        let recalibration = options.recalibration.as_ref();
         let total_start = Instant::now();
         
         println!("[ORIGINAL] Initializing metadata readers...");
         let tdf_path = d_folder.join("analysis.tdf");
         let meta = MetadataReader::new(&tdf_path)?;
-        let mz_cv = Arc::new(meta.mz_converter);
-        let im_cv = Arc::new(meta.im_converter);
+        let metadata = RunMetadata::read(d_folder)?;
+        let frame_info = frame_table::read_frame_sql_info(d_folder)?;
+        let converters = Converters::direct(meta.mz_converter, meta.im_converter);
         
         println!("[ORIGINAL] Initializing frame reader...");
         let frames = FrameReader::new(d_folder)?;
         let n_frames = frames.len();
         println!("[ORIGINAL] Total frames to process: {}", n_frames);
         
-        println!("[ORIGINAL] Processing frames in parallel...");
-        let splits: Vec<FrameSplit> = (0..n_frames).into_par_iter().map(|idx| {
+        let pool = options.threads.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
+        
+        println!("[ORIGINAL] Processing frames in parallel ({} threads)...", pool.threads());
+        let splits: Vec<FrameSplit<F>> = pool.install(|| (0..n_frames).into_par_iter().map(|idx| -> Result<FrameSplit<F>, WindowKeyError> {
             let frame = frames.get(idx).expect("frame read");
-            let rt_min = frame.rt_in_seconds as f32 / 60.0;
+            let row = FrameRow::from_frame(&frame, frame_info.get(&frame.index).copied().unwrap_or_default());
+            let rt_min = F::rt_minutes(frame.rt_in_seconds);
             let mut ms1 = TimsTOFData::new();
-            let mut ms2_pairs: Vec<((u32,u32), TimsTOFData)> = Vec::new();
+            let mut ms2_pairs: Vec<((u32,u32), TimsTOFData<F>)> = Vec::new();
             
             match frame.ms_level {
                 MSLevel::MS1 => {
                     let n_peaks = frame.tof_indices.len();
-                    ms1 = TimsTOFData::with_capacity(n_peaks);
+                    ms1 = TimsTOFData::with_storage(n_peaks, storage);
                     for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                        let mz = mz_cv.convert(tof as f64) as f32;
-                        let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
-                        let im = im_cv.convert(scan as f64) as f32;
+                        let scan = scan_for_index(p_idx, &frame.scan_offsets);
+                        let im = converters.mobility(scan);
                         ms1.rt_values_min.push(rt_min);
                         ms1.mobility_values.push(im);
-                        ms1.mz_values.push(mz);
+                        if storage.keeps_mz() {
+                            ms1.mz_values.push(convert_mz(&converters, recalibration, tof, rt_min));
+                        }
+                        if storage.keeps_tof() {
+                            ms1.tof_indices.push(tof);
+                        }
                         ms1.intensity_values.push(intensity);
                         ms1.frame_indices.push(frame.index as u32);
                         ms1.scan_indices.push(scan as u32);
@@ -239,21 +684,21 @@ mod original_version {
                     ms2_pairs.reserve(qs.isolation_mz.len());
                     for win in 0..qs.isolation_mz.len() {
                         if win >= qs.isolation_width.len() { break; }
-                        let prec_mz = qs.isolation_mz[win] as f32;
-                        let width = qs.isolation_width[win] as f32;
-                        let low = prec_mz - width * 0.5;
-                        let high = prec_mz + width * 0.5;
-                        let key = (quantize(low), quantize(high));
+                        let key = window_key(qs.isolation_mz[win], qs.isolation_width[win])?;
                         
                         let mut td = TimsTOFData::new();
                         for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                            let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
+                            let scan = scan_for_index(p_idx, &frame.scan_offsets);
                             if scan < qs.scan_starts[win] || scan > qs.scan_ends[win] { continue; }
-                            let mz = mz_cv.convert(tof as f64) as f32;
-                            let im = im_cv.convert(scan as f64) as f32;
+                            let im = converters.mobility(scan);
                             td.rt_values_min.push(rt_min);
                             td.mobility_values.push(im);
-                            td.mz_values.push(mz);
+                            if storage.keeps_mz() {
+                                td.mz_values.push(convert_mz(&converters, recalibration, tof, rt_min));
+                            }
+                            if storage.keeps_tof() {
+                                td.tof_indices.push(tof);
+                            }
                             td.intensity_values.push(intensity);
                             td.frame_indices.push(frame.index as u32);
                             td.scan_indices.push(scan as u32);
@@ -263,21 +708,29 @@ mod original_version {
                 }
                 _ => {}
             }
-            FrameSplit { ms1, ms2: ms2_pairs }
-        }).collect();
+            let checksum = (!options.skip_frame_checksums)
+                .then(|| FrameChecksum::new(frame.index as u32, Some(&ms1), &ms2_pairs));
+            Ok(FrameSplit { ms1, ms2: ms2_pairs, row, checksum })
+        }).collect::<Result<_, _>>())?;
         
         println!("[ORIGINAL] Merging data...");
-        let ms1_size_estimate: usize = splits.par_iter().map(|s| s.ms1.mz_values.len()).sum();
-        let mut global_ms1 = TimsTOFData::with_capacity(ms1_size_estimate);
-        let mut ms2_hash: HashMap<(u32,u32), TimsTOFData> = HashMap::new();
+        let ms1_size_estimate: usize = splits.iter().map(|s| s.ms1.len()).sum();
+        let mut global_ms1 = TimsTOFData::with_storage(ms1_size_estimate, storage);
+        // BTreeMap：窗口按键升序输出，帧按索引顺序合并
+        let mut ms2_hash: BTreeMap<(u32,u32), TimsTOFData<F>> = BTreeMap::new();
+        let mut frame_rows = Vec::with_capacity(splits.len());
+        let mut frame_checksums = Vec::with_capacity(splits.len());
         
         for split in splits {
+            frame_rows.push(split.row);
+            frame_checksums.extend(split.checksum);
             global_ms1.rt_values_min.extend(split.ms1.rt_values_min);
             global_ms1.mobility_values.extend(split.ms1.mobility_values);
             global_ms1.mz_values.extend(split.ms1.mz_values);
             global_ms1.intensity_values.extend(split.ms1.intensity_values);
             global_ms1.frame_indices.extend(split.ms1.frame_indices);
             global_ms1.scan_indices.extend(split.ms1.scan_indices);
+            global_ms1.tof_indices.extend(split.ms1.tof_indices);
             
             for (key, mut td) in split.ms2 {
                 ms2_hash.entry(key).or_insert_with(TimsTOFData::new).merge_from(&mut td);
@@ -285,21 +738,26 @@ mod original_version {
         }
         
         let mut ms2_vec = Vec::with_capacity(ms2_hash.len());
-        for ((q_low, q_high), td) in ms2_hash {
-            let low = q_low as f32 / 10_000.0;
-            let high = q_high as f32 / 10_000.0;
-            ms2_vec.push(((low, high), td));
+        for (key, td) in ms2_hash {
+            ms2_vec.push((window_bounds(key), td));
         }
         
-        println!("[ORIGINAL] MS1 data points: {}", global_ms1.mz_values.len());
+        let frame_table = FrameTable::from_rows(frame_rows);
+        let frame_checksums = FrameChecksums::from_frames(frame_checksums);
+        
+        println!("[ORIGINAL] MS1 data points: {}", global_ms1.len());
         println!("[ORIGINAL] MS2 windows: {}", ms2_vec.len());
-        let total_ms2_points: usize = ms2_vec.iter().map(|(_, td)| td.mz_values.len()).sum();
+        let total_ms2_points: usize = ms2_vec.iter().map(|(_, td)| td.len()).sum();
         println!("[ORIGINAL] MS2 data points: {}", total_ms2_points);
         println!("[ORIGINAL] Total processing time: {:.3}s", total_start.elapsed().as_secs_f32());
         
         Ok(TimsTOFRawData {
             ms1_data: global_ms1,
             ms2_windows: ms2_vec,
+            metadata,
+            frame_table,
+            frame_checksums,
+            mz_storage: storage,
         })
     }
 }
@@ -308,83 +766,130 @@ mod original_version {
 mod v5_fixed {
     use super::*;
     
-    #[derive(Clone)]
-    enum ProcessedFrame {
-        MS1(usize, TimsTOFData),
-        MS2(usize, Vec<((u32, u32), TimsTOFData)>),
-    }
+    // 内存预算模式下每个工作线程每批处理的帧数
+    const FRAMES_IN_FLIGHT_PER_THREAD: usize = 2;
     
-    #[inline]
-    fn quantize(x: f32) -> u32 { 
-        (x * 10_000.0).round() as u32 
+    // 每一帧都必须发送一条消息（包括无数据的帧），否则按索引归并会在该帧处停住
+    #[derive(Clone)]
+    enum ProcessedFrame<F> {
+        MS1(TimsTOFData<F>),
+        MS2(Vec<((u32, u32), TimsTOFData<F>)>),
+        Empty,
     }
     
-    fn find_scan_for_index(index: usize, scan_offsets: &[usize]) -> usize {
-        for (scan, window) in scan_offsets.windows(2).enumerate() {
-            if index >= window[0] && index < window[1] {
-                return scan;
-            }
-        }
-        scan_offsets.len() - 1
+    // 设置了内存预算时，溢写过的窗口在加载结束后逐个合并回内存
+    pub fn read_timstof_data_v5_fixed<F: Precision>(d_folder: &Path, options: &LoadOptions) -> Result<TimsTOFRawData<F>, Box<dyn Error>> {
+        read_timstof_data_v5_spilled(d_folder, options)?.into_raw_data()
     }
     
-    pub fn read_timstof_data_v5_fixed(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
+    // 与 read_timstof_data_v5_fixed 相同，但溢写过的 MS2 窗口留在溢写目录中，需要时才逐个读回
+    pub fn read_timstof_data_v5_spilled<F: Precision>(d_folder: &Path, options: &LoadOptions) -> Result<SpilledRawData<F>, Box<dyn Error>> {
+        let storage = options.mz_storage;
+        let recalibration = options.recalibration.as_ref();
         let total_start = Instant::now();
         
         println!("[V5_FIXED] Initializing metadata readers...");
         let tdf_path = d_folder.join("analysis.tdf");
         let meta = MetadataReader::new(&tdf_path)?;
-        let mz_cv = Arc::new(meta.mz_converter);
-        let im_cv = Arc::new(meta.im_converter);
+        let metadata = RunMetadata::read(d_folder)?;
+        let frame_info = frame_table::read_frame_sql_info(d_folder)?;
+        let converters = if options.lookup_tables {
+            let converters = Converters::with_tables(meta.mz_converter, meta.im_converter, &metadata, &frame_info);
+            let (tof_len, scan_len) = converters.table_sizes();
+            println!("[V5_FIXED] Lookup tables: {} TOF / {} scan entries", tof_len, scan_len);
+            converters
+        } else {
+            Converters::direct(meta.mz_converter, meta.im_converter)
+        };
         
         println!("[V5_FIXED] Initializing frame reader...");
         let frames = Arc::new(FrameReader::new(d_folder)?);
         let n_frames = frames.len();
         println!("[V5_FIXED] Total frames to process: {}", n_frames);
         
-        println!("[V5_FIXED] Processing frames in parallel with channel...");
+        let pool = options.threads.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
+        
+        println!("[V5_FIXED] Processing frames in parallel with channel ({} threads)...", pool.threads());
         
-        let (sender, receiver) = bounded(2000);
+        // 内存预算模式下按批处理帧：rayon 把帧区间对半分给各线程，按帧索引归并时后半段的帧
+        // 会一直留在归并缓冲中；逐批处理并限制通道容量，处理中的帧不超过几批
+        let batch = match options.max_memory {
+            Some(_) => pool.threads() * FRAMES_IN_FLIGHT_PER_THREAD,
+            None => n_frames.max(1),
+        };
+        let (sender, receiver) = bounded(batch.min(2000));
         let processed_count = Arc::new(AtomicUsize::new(0));
         let ms1_accumulator = Arc::new(Mutex::new(Vec::with_capacity(n_frames)));
         let ms2_map = Arc::new(DashMap::with_capacity(100));
+        let frame_rows = Mutex::new(Vec::with_capacity(n_frames));
+        let frame_checksums = Mutex::new(Vec::with_capacity(n_frames));
         
         let ms1_acc_clone = Arc::clone(&ms1_accumulator);
         let ms2_map_clone = Arc::clone(&ms2_map);
         let processed_clone = Arc::clone(&processed_count);
         
-        let aggregator_handle = std::thread::spawn(move || {
-            let mut frame_buffer: Vec<Option<ProcessedFrame>> = vec![None; n_frames];
+        // 内存预算模式：聚合线程持有溢写目录，结束时交还
+        let max_memory = options.max_memory;
+        let mut spill_store = match max_memory {
+            Some(budget) => {
+                let store = SpillStore::new(options.spill_dir.as_deref())?;
+                println!("[V5_FIXED] MS2 memory budget: {:.1} MB, spilling to {}", budget as f64 / 1e6, store.path().display());
+                Some(store)
+            }
+            None => None,
+        };
+        
+        let aggregator_handle = std::thread::spawn(move || -> std::io::Result<Option<SpillStore>> {
+            // 按帧索引寻址的归并缓冲：到达顺序任意，合并顺序始终为帧索引顺序
+            let mut frame_buffer: Vec<Option<ProcessedFrame<F>>> = vec![None; n_frames];
             let mut next_frame = 0usize;
+            // 驻留内存的 MS2 窗口缓冲字节数（按容量）
+            let mut resident = 0usize;
             
-            while let Ok(frame_data) = receiver.recv() {
-                match frame_data {
-                    ProcessedFrame::MS1(idx, data) => {
-                        frame_buffer[idx] = Some(ProcessedFrame::MS1(idx, data));
-                    }
-                    ProcessedFrame::MS2(idx, pairs) => {
-                        frame_buffer[idx] = Some(ProcessedFrame::MS2(idx, pairs));
-                    }
-                }
+            while let Ok((idx, frame_data)) = receiver.recv() {
+                frame_buffer[idx] = Some(frame_data);
                 
                 while next_frame < n_frames {
                     if let Some(frame) = frame_buffer[next_frame].take() {
                         match frame {
-                            ProcessedFrame::MS1(_, data) => {
-                                if !data.mz_values.is_empty() {
+                            ProcessedFrame::MS1(data) => {
+                                if !data.is_empty() {
                                     ms1_acc_clone.lock().push(data);
                                 }
                             }
-                            ProcessedFrame::MS2(_, pairs) => {
+                            ProcessedFrame::MS2(pairs) => {
                                 for (key, mut data) in pairs {
-                                    if !data.mz_values.is_empty() {
-                                        ms2_map_clone.entry(key)
+                                    if !data.is_empty() {
+                                        let window = ms2_map_clone.entry(key)
                                             .or_insert_with(|| Arc::new(Mutex::new(TimsTOFData::new())))
-                                            .lock()
-                                            .merge_from(&mut data);
+                                            .clone();
+                                        let mut window = window.lock();
+                                        let before = window.heap_bytes();
+                                        window.merge_from(&mut data);
+                                        resident = resident + window.heap_bytes() - before;
+                                    }
+                                }
+                                // 超出预算时从最大的窗口开始溢写，直到降到预算的一半以下，避免每帧都写盘
+                                if let (Some(store), Some(budget)) = (spill_store.as_mut(), max_memory) {
+                                    if resident > budget {
+                                        let mut sizes: Vec<_> = ms2_map_clone.iter()
+                                            .map(|entry| (*entry.key(), entry.value().lock().heap_bytes()))
+                                            .collect();
+                                        sizes.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                                        for (key, bytes) in sizes {
+                                            if resident <= budget / 2 {
+                                                break;
+                                            }
+                                            let window = ms2_map_clone.get(&key).map(|entry| Arc::clone(entry.value()));
+                                            if let Some(window) = window {
+                                                store.spill(key, &mut window.lock())?;
+                                                resident -= bytes;
+                                            }
+                                        }
                                     }
                                 }
                             }
+                            ProcessedFrame::Empty => {}
                         }
                         next_frame += 1;
                         processed_clone.fetch_add(1, Ordering::Relaxed);
@@ -393,114 +898,142 @@ mod v5_fixed {
                     }
                 }
             }
+            Ok(spill_store)
         });
         
-        (0..n_frames).into_par_iter().for_each(|idx| {
+        let process_frame = |idx: usize| -> Result<(), WindowKeyError> {
             let frame = match frames.get(idx) {
                 Ok(f) => f,
-                Err(_) => return,
+                Err(_) => {
+                    let _ = sender.send((idx, ProcessedFrame::Empty));
+                    return Ok(());
+                }
             };
+            // 行在锁外构建（需要遍历帧内所有强度）
+            let row = FrameRow::from_frame(&frame, frame_info.get(&frame.index).copied().unwrap_or_default());
+            frame_rows.lock().push(row);
             
-            let rt_min = frame.rt_in_seconds as f32 / 60.0;
+            let rt_min = F::rt_minutes(frame.rt_in_seconds);
             
             match frame.ms_level {
                 MSLevel::MS1 => {
                     let n_peaks = frame.tof_indices.len();
-                    let mut ms1 = TimsTOFData::with_capacity(n_peaks);
+                    let mut ms1 = TimsTOFData::with_storage(n_peaks, storage);
                     
-                    for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                        let mz = mz_cv.convert(tof as f64) as f32;
-                        let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
-                        let im = im_cv.convert(scan as f64) as f32;
-                        ms1.rt_values_min.push(rt_min);
-                        ms1.mobility_values.push(im);
-                        ms1.mz_values.push(mz);
-                        ms1.intensity_values.push(intensity);
-                        ms1.frame_indices.push(frame.index as u32);
-                        ms1.scan_indices.push(scan as u32);
+                    for (scan, peaks) in scan_ranges(&frame.scan_offsets, n_peaks) {
+                        let im = converters.mobility(scan);
+                        for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter().zip(&frame.intensities[peaks]) {
+                            ms1.rt_values_min.push(rt_min);
+                            ms1.mobility_values.push(im);
+                            if storage.keeps_mz() {
+                                ms1.mz_values.push(convert_mz(&converters, recalibration, tof, rt_min));
+                            }
+                            if storage.keeps_tof() {
+                                ms1.tof_indices.push(tof);
+                            }
+                            ms1.intensity_values.push(intensity);
+                            ms1.frame_indices.push(frame.index as u32);
+                            ms1.scan_indices.push(scan as u32);
+                        }
                     }
                     
-                    if !ms1.mz_values.is_empty() {
-                        let _ = sender.send(ProcessedFrame::MS1(idx, ms1));
+                    // 先在锁外计算校验和，再入队
+                    if !options.skip_frame_checksums {
+                        let checksum = FrameChecksum::new(frame.index as u32, Some(&ms1), &[]);
+                        frame_checksums.lock().push(checksum);
                     }
+                    let _ = sender.send((idx, ProcessedFrame::MS1(ms1)));
                 }
                 MSLevel::MS2 => {
                     let qs = &frame.quadrupole_settings;
-                    let mut ms2_pairs = Vec::with_capacity(qs.isolation_mz.len());
+                    let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+                    let keys = (0..n_windows)
+                        .map(|win| window_key(qs.isolation_mz[win], qs.isolation_width[win]))
+                        .collect::<Result<Vec<_>, _>>()?;
                     
-                    for win in 0..qs.isolation_mz.len() {
-                        if win >= qs.isolation_width.len() { break; }
-                        
-                        let prec_mz = qs.isolation_mz[win] as f32;
-                        let width = qs.isolation_width[win] as f32;
-                        let low = prec_mz - width * 0.5;
-                        let high = prec_mz + width * 0.5;
-                        let key = (quantize(low), quantize(high));
-                        
-                        let mut td = TimsTOFData::new();
-                        for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                            let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
-                            if scan < qs.scan_starts[win] || scan > qs.scan_ends[win] { continue; }
-                            let mz = mz_cv.convert(tof as f64) as f32;
-                            let im = im_cv.convert(scan as f64) as f32;
-                            td.rt_values_min.push(rt_min);
-                            td.mobility_values.push(im);
-                            td.mz_values.push(mz);
-                            td.intensity_values.push(intensity);
-                            td.frame_indices.push(frame.index as u32);
-                            td.scan_indices.push(scan as u32);
-                        }
-                        
-                        if !td.mz_values.is_empty() {
-                            ms2_pairs.push((key, td));
+                    // 单次遍历所有 scan；重叠窗口中的峰只转换一次，分别写入每个窗口
+                    let mut windows: Vec<TimsTOFData<F>> = (0..n_windows).map(|_| TimsTOFData::new()).collect();
+                    for (first, last, targets) in WindowSegments::new(&qs.scan_starts[..n_windows], &qs.scan_ends[..n_windows]).iter() {
+                        for (scan, peaks) in scan_ranges(&frame.scan_offsets, frame.tof_indices.len()).within(first, last) {
+                            let im = converters.mobility(scan);
+                            for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter().zip(&frame.intensities[peaks]) {
+                                let mz = storage.keeps_mz().then(|| convert_mz(&converters, recalibration, tof, rt_min));
+                                for &win in targets {
+                                    let td = &mut windows[win];
+                                    td.rt_values_min.push(rt_min);
+                                    td.mobility_values.push(im);
+                                    if let Some(mz) = mz {
+                                        td.mz_values.push(mz);
+                                    }
+                                    if storage.keeps_tof() {
+                                        td.tof_indices.push(tof);
+                                    }
+                                    td.intensity_values.push(intensity);
+                                    td.frame_indices.push(frame.index as u32);
+                                    td.scan_indices.push(scan as u32);
+                                }
+                            }
                         }
                     }
                     
-                    if !ms2_pairs.is_empty() {
-                        let _ = sender.send(ProcessedFrame::MS2(idx, ms2_pairs));
+                    let ms2_pairs: Vec<_> = keys.into_iter().zip(windows)
+                        .filter(|(_, td)| !td.is_empty())
+                        .collect();
+                    
+                    if !options.skip_frame_checksums {
+                        let checksum = FrameChecksum::new(frame.index as u32, None, &ms2_pairs);
+                        frame_checksums.lock().push(checksum);
                     }
+                    let _ = sender.send((idx, ProcessedFrame::MS2(ms2_pairs)));
+                }
+                _ => {
+                    let _ = sender.send((idx, ProcessedFrame::Empty));
                 }
-                _ => {}
             }
+            Ok(())
+        };
+        let result = (0..n_frames).step_by(batch).try_for_each(|start| {
+            pool.install(|| (start..(start + batch).min(n_frames)).into_par_iter().try_for_each(process_frame))
         });
         
         drop(sender);
-        aggregator_handle.join().unwrap();
+        let spill_store = aggregator_handle.join().unwrap()?;
+        result?;
         
         println!("[V5_FIXED] Finalizing data structures...");
         
-        let ms1_chunks = ms1_accumulator.lock();
-        let mut global_ms1 = TimsTOFData::with_capacity(
-            ms1_chunks.iter().map(|c| c.mz_values.len()).sum()
-        );
+        // 取出各帧的 MS1 块逐列拼接（不是复制后保留原块），MS1 数据不会同时存在两份
+        let ms1_chunks = std::mem::take(&mut *ms1_accumulator.lock());
+        let global_ms1 = pool.install(|| TimsTOFData::concat(ms1_chunks));
         
-        for chunk in ms1_chunks.iter() {
-            global_ms1.rt_values_min.extend(&chunk.rt_values_min);
-            global_ms1.mobility_values.extend(&chunk.mobility_values);
-            global_ms1.mz_values.extend(&chunk.mz_values);
-            global_ms1.intensity_values.extend(&chunk.intensity_values);
-            global_ms1.frame_indices.extend(&chunk.frame_indices);
-            global_ms1.scan_indices.extend(&chunk.scan_indices);
-        }
-        
-        let mut ms2_vec = Vec::with_capacity(ms2_map.len());
-        ms2_map.iter().for_each(|entry| {
-            let (q_low, q_high) = *entry.key();
-            let low = q_low as f32 / 10_000.0;
-            let high = q_high as f32 / 10_000.0;
-            let data = entry.value().lock().clone();
-            ms2_vec.push(((low, high), data));
-        });
+        // 取出（而不是克隆）各窗口缓冲，避免 MS2 数据在内存中存在两份
+        let mut ms2_entries: Vec<_> = ms2_map.iter()
+            .map(|entry| (*entry.key(), std::mem::replace(&mut *entry.value().lock(), TimsTOFData::new())))
+            .collect();
+        ms2_entries.sort_unstable_by_key(|(key, _)| *key);
+        
+        // 内存预算模式：已溢写的部分留在磁盘上，与仍在内存中的尾部一起交给 SpilledWindows
+        if let Some(store) = &spill_store {
+            println!("[V5_FIXED] Spilled {:.1} MB from {} MS2 windows in {} writes",
+                store.bytes_written() as f64 / 1e6, store.spilled_windows(), store.spills());
+        }
+        let ms2_windows = SpilledWindows::new(spill_store, ms2_entries);
+        
+        let frame_table = FrameTable::from_rows(frame_rows.into_inner());
+        let frame_checksums = FrameChecksums::from_frames(frame_checksums.into_inner());
         
-        println!("[V5_FIXED] MS1 data points: {}", global_ms1.mz_values.len());
-        println!("[V5_FIXED] MS2 windows: {}", ms2_vec.len());
-        let total_ms2_points: usize = ms2_vec.iter().map(|(_, td)| td.mz_values.len()).sum();
-        println!("[V5_FIXED] MS2 data points: {}", total_ms2_points);
+        println!("[V5_FIXED] MS1 data points: {}", global_ms1.len());
+        println!("[V5_FIXED] MS2 windows: {}", ms2_windows.len());
+        println!("[V5_FIXED] MS2 data points: {}", ms2_windows.peaks());
         println!("[V5_FIXED] Total processing time: {:.3}s", total_start.elapsed().as_secs_f32());
         
-        Ok(TimsTOFRawData {
+        Ok(SpilledRawData {
             ms1_data: global_ms1,
-            ms2_windows: ms2_vec,
+            ms2_windows,
+            metadata,
+            frame_table,
+            frame_checksums,
+            mz_storage: storage,
         })
     }
 }
@@ -553,11 +1086,11 @@ fn compare_binary_files(file1: &str, file2: &str) -> Result<bool, Box<dyn Error>
     Ok(true)
 }
 
-fn compare_data_from_files(file1: &str, file2: &str) -> Result<bool, Box<dyn Error>> {
+fn compare_data_from_files<F: Precision>(file1: &str, file2: &str) -> Result<bool, Box<dyn Error>> {
     println!("\n  Loading and comparing data structures...");
     
-    let data1 = TimsTOFRawData::load_binary(file1)?;
-    let data2 = TimsTOFRawData::load_binary(file2)?;
+    let data1 = TimsTOFRawData::<F>::load_binary(file1)?;
+    let data2 = TimsTOFRawData::<F>::load_binary(file2)?;
     
     let mut all_match = true;
     
@@ -585,49 +1118,321 @@ fn compare_data_from_files(file1: &str, file2: &str) -> Result<bool, Box<dyn Err
         println!("    ✓ Same number of MS2 windows: {}", data1.ms2_windows.len());
         
         // 创建HashMap以匹配窗口
-        let mut map1: HashMap<(u32, u32), &TimsTOFData> = HashMap::new();
-        let mut map2: HashMap<(u32, u32), &TimsTOFData> = HashMap::new();
+        let mut map1: HashMap<(u32, u32), &TimsTOFData<F>> = HashMap::new();
+        let mut map2: HashMap<(u32, u32), &TimsTOFData<F>> = HashMap::new();
         
         for ((low, high), data) in &data1.ms2_windows {
-            let key = ((low * 10_000.0).round() as u32, (high * 10_000.0).round() as u32);
+            let key = key_from_bounds((*low, *high));
             map1.insert(key, data);
         }
         
         for ((low, high), data) in &data2.ms2_windows {
-            let key = ((low * 10_000.0).round() as u32, (high * 10_000.0).round() as u32);
+            let key = key_from_bounds((*low, *high));
             map2.insert(key, data);
         }
         
         // 比较每个窗口的哈希
         for (key, data1) in &map1 {
+            let (low, high) = window_bounds(*key);
             match map2.get(key) {
                 Some(data2) => {
                     let hash1 = data1.calculate_hash();
                     let hash2 = data2.calculate_hash();
                     if hash1 != hash2 {
-                        println!("    ❌ MS2 window ({:.2}, {:.2}) data differs", 
-                                key.0 as f32 / 10_000.0, key.1 as f32 / 10_000.0);
+                        println!("    ❌ MS2 window ({:.2}, {:.2}) data differs", low, high);
                         all_match = false;
                     }
                 }
                 None => {
-                    println!("    ❌ MS2 window ({:.2}, {:.2}) missing in second dataset", 
-                            key.0 as f32 / 10_000.0, key.1 as f32 / 10_000.0);
+                    println!("    ❌ MS2 window ({:.2}, {:.2}) missing in second dataset", low, high);
                     all_match = false;
                 }
             }
         }
     }
     
+    // 哈希不同：用逐帧校验和定位到帧、窗口和 scan
+    if !all_match {
+        print_divergences(&checksum::bisect(&data1, &data2));
+    }
+    
     Ok(all_match)
 }
 
+fn print_divergences(divergences: &[checksum::FrameDivergence]) {
+    const MAX_PRINTED: usize = 20;
+    
+    println!("\n  Frame-level bisection: {} diverging frames", divergences.len());
+    for divergence in divergences.iter().take(MAX_PRINTED) {
+        println!("    {}", divergence.to_string().replace('\n', "\n    "));
+    }
+    if divergences.len() > MAX_PRINTED {
+        println!("    ... {} more", divergences.len() - MAX_PRINTED);
+    }
+    if !divergences.is_empty() {
+        println!("  Minimal reproducer frames: {}", checksum::reproducer_frames(divergences));
+    }
+}
+
+// ============= 命令行 =============
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|a| a == flag)
+}
+
+// --threads auto|N，默认 auto
+fn threads_flag(args: &[String]) -> Result<Threads, Box<dyn Error>> {
+    match flag_value(args, "--threads") {
+        None | Some("auto") => Ok(Threads::Auto),
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Threads::Fixed(n)),
+            _ => Err(format!("--threads expects auto or a positive number, got {:?}", n).into()),
+        },
+    }
+}
+
+// --mz-storage converted|with-tof|tof-only，默认 converted
+fn mz_storage_flag(args: &[String]) -> Result<MzStorage, Box<dyn Error>> {
+    match flag_value(args, "--mz-storage") {
+        None => Ok(MzStorage::default()),
+        Some(spec) => Ok(MzStorage::parse(spec)?),
+    }
+}
+
+// --compress zstd|zstd:LEVEL|lz4：二进制输出按列压缩
+fn compress_flag(args: &[String]) -> Result<Option<Compression>, Box<dyn Error>> {
+    match flag_value(args, "--compress") {
+        None => Ok(None),
+        Some(spec) => Ok(Some(Compression::parse(spec)?)),
+    }
+}
+
+// --max-memory 48G：MS2 窗口缓冲的内存预算
+fn max_memory_flag(args: &[String]) -> Result<Option<usize>, Box<dyn Error>> {
+    match flag_value(args, "--max-memory") {
+        None => Ok(None),
+        Some(spec) => Ok(Some(spill::parse_size(spec)?)),
+    }
+}
+
+// recalibrate <d_folder> <calibrants.csv> [--domain mz|tof] [--model linear|quadratic] [--rt]
+//             [--tolerance-ppm X] [--output file.bin] [--compress zstd|zstd:LEVEL|lz4]
+//             [--threads auto|N] [--f64]
+fn run_recalibrate<F: Precision>(args: &[String]) -> Result<(), Box<dyn Error>> {
+    if args.len() < 2 {
+        return Err("usage: recalibrate <d_folder> <calibrants.csv> [--domain mz|tof] \
+                    [--model linear|quadratic] [--rt] [--tolerance-ppm X] [--output file.bin] [--threads auto|N] [--f64]".into());
+    }
+    let d_path = Path::new(&args[0]);
+    let calibrants = recalibration::load_calibrants(Path::new(&args[1]))?;
+    
+    let mut config = RecalibrationConfig {
+        domain: match flag_value(args, "--domain") {
+            None | Some("mz") => CalibrationDomain::Mz,
+            Some("tof") => CalibrationDomain::Tof,
+            Some(other) => return Err(format!("unknown calibration domain {:?}", other).into()),
+        },
+        model: match flag_value(args, "--model") {
+            None | Some("linear") => CalibrationModel::Linear,
+            Some("quadratic") => CalibrationModel::Quadratic,
+            Some(other) => return Err(format!("unknown calibration model {:?}", other).into()),
+        },
+        rt_dependent: has_flag(args, "--rt"),
+        ..RecalibrationConfig::default()
+    };
+    if let Some(tol) = flag_value(args, "--tolerance-ppm") {
+        config.match_tolerance_ppm = tol.parse()?;
+    }
+    
+    println!("========== TimsTOF m/z Recalibration ==========");
+    println!("Data folder: {}", d_path.display());
+    println!("Calibrants: {}", calibrants.len());
+    println!();
+    
+    let options = LoadOptions {
+        mz_storage: match config.domain {
+            CalibrationDomain::Tof => MzStorage::ConvertedWithTof,
+            CalibrationDomain::Mz => MzStorage::Converted,
+        },
+        threads: threads_flag(args)?,
+        ..LoadOptions::default()
+    };
+    let mut data = original_version::read_timstof_data_original::<F>(d_path, &options)?;
+    
+    let report = Recalibration::fit(&data.ms1_data, &calibrants, &config)?;
+    println!("\n{}", report);
+    
+    report.recalibration.apply_raw(&mut data)?;
+    if let Some(output) = flag_value(args, "--output") {
+        match compress_flag(args)? {
+            Some(compression) => data.save_compressed(output, compression)?,
+            None => data.save_binary(output)?,
+        }
+    }
+    
+    Ok(())
+}
+
+// 可按名称运行的加载策略
+const STRATEGIES: &[&str] = &["original", "v5_fixed"];
+
+fn run_strategy<F: Precision>(name: &str, d_folder: &Path, options: &LoadOptions) -> Result<TimsTOFRawData<F>, Box<dyn Error>> {
+    match name {
+        "original" => original_version::read_timstof_data_original(d_folder, options),
+        "v5_fixed" => v5_fixed::read_timstof_data_v5_fixed(d_folder, options),
+        _ => Err(format!("unknown strategy {:?} (available: {})", name, STRATEGIES.join(", ")).into()),
+    }
+}
+
+// 不以 -- 开头、且不是带值参数的值的参数
+fn positional_args<'a>(args: &'a [String], value_flags: &[&str]) -> Vec<&'a str> {
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if value_flags.contains(&arg.as_str()) {
+            iter.next();
+        } else if !arg.starts_with("--") {
+            positional.push(arg.as_str());
+        }
+    }
+    positional
+}
+
+// compare <source> <source>... [--d-folder <d_folder>] [--tol-rt X] [--tol-mobility X] [--tol-mz X]
+//         [--tol-intensity X] [--report diff.json] [--threads auto|N] [--mz-storage converted|with-tof|tof-only]
+//         [--max-memory SIZE] [--spill-dir DIR] [--f64]
+// source 为策略名（original、v5_fixed，需要 --d-folder）或已保存的 .bin 文件；第一个来源为基准。
+// 容差格式：exact | abs:X | rel:X | ppm:X | ulp:N
+fn run_compare<F: Precision>(args: &[String]) -> Result<(), Box<dyn Error>> {
+    const USAGE: &str = "usage: compare <strategy|file.bin> <strategy|file.bin>... [--d-folder <d_folder>] \
+                         [--tol-rt X] [--tol-mobility X] [--tol-mz X] [--tol-intensity X] [--report diff.json] [--threads auto|N] [--mz-storage converted|with-tof|tof-only] \
+                         [--max-memory SIZE] [--spill-dir DIR] [--f64]";
+    let sources = positional_args(args, &["--d-folder", "--tol-rt", "--tol-mobility", "--tol-mz", "--tol-intensity", "--report", "--threads", "--mz-storage", "--max-memory", "--spill-dir"]);
+    if sources.len() < 2 {
+        return Err(USAGE.into());
+    }
+    
+    let mut tolerances = Tolerances::default();
+    for (flag, tolerance) in [
+        ("--tol-rt", &mut tolerances.rt),
+        ("--tol-mobility", &mut tolerances.mobility),
+        ("--tol-mz", &mut tolerances.mz),
+        ("--tol-intensity", &mut tolerances.intensity),
+    ] {
+        if let Some(spec) = flag_value(args, flag) {
+            *tolerance = Tolerance::parse(spec)?;
+        }
+    }
+    
+    let d_folder = flag_value(args, "--d-folder").map(Path::new);
+    let options = LoadOptions {
+        threads: threads_flag(args)?,
+        mz_storage: mz_storage_flag(args)?,
+        max_memory: max_memory_flag(args)?,
+        spill_dir: flag_value(args, "--spill-dir").map(PathBuf::from),
+        ..LoadOptions::default()
+    };
+    let mut loaded = Vec::with_capacity(sources.len());
+    for source in sources {
+        let data = if STRATEGIES.contains(&source) {
+            let d_folder = d_folder.ok_or_else(|| format!("strategy {:?} needs --d-folder", source))?;
+            run_strategy::<F>(source, d_folder, &options)?
+        } else {
+            TimsTOFRawData::<F>::load_binary(source)?
+        };
+        loaded.push((source.to_string(), data));
+    }
+    
+    let report = DiffReport::build(&loaded, &tolerances);
+    println!("\n========== COMPARISON ==========");
+    print!("{}", report);
+    if let Some(path) = flag_value(args, "--report") {
+        report.save_json(path)?;
+        println!("\nReport written to {}", path);
+    }
+    
+    if report.matches {
+        Ok(())
+    } else {
+        Err("outputs differ beyond the configured tolerances".into())
+    }
+}
+
+// frame-table <d_folder> [--output frames.csv|frames.parquet] [--threads auto|N]
+fn run_frame_table(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let d_path = Path::new(args.first().ok_or("usage: frame-table <d_folder> [--output frames.csv|frames.parquet] [--threads auto|N]")?);
+    let output = flag_value(args, "--output").unwrap_or("frame_table.csv");
+    
+    // 只需要逐帧统计，跳过 m/z 换算和校验和
+    let options = LoadOptions {
+        mz_storage: MzStorage::TofOnly,
+        skip_frame_checksums: true,
+        threads: threads_flag(args)?,
+        ..LoadOptions::default()
+    };
+    let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(d_path, &options)?;
+    
+    println!("Writing {} frames to {}", data.frame_table.len(), output);
+    if output.ends_with(".parquet") {
+        data.frame_table.save_parquet(output)
+    } else {
+        data.frame_table.save_csv(output)
+    }
+}
+
+// windows <d_folder> [--cache-dir DIR] [--lru N] [--lut] [--mz-storage converted|with-tof|tof-only] [--f64]
+// 逐个窗口懒加载 MS2 数据，打印每个窗口的帧数、点数和加载时间；内存中最多保留 N 个窗口（默认 1）
+fn run_windows<F: Precision>(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let d_path = Path::new(args.first().ok_or("usage: windows <d_folder> [--cache-dir DIR] [--lru N] [--lut] [--mz-storage converted|with-tof|tof-only] [--f64]")?);
+    let capacity = match flag_value(args, "--lru") {
+        None => 1,
+        Some(n) => n.parse::<usize>().map_err(|_| format!("--lru expects a number, got {:?}", n))?,
+    };
+    let options = LoadOptions {
+        lookup_tables: has_flag(args, "--lut"),
+        mz_storage: mz_storage_flag(args)?,
+        ..LoadOptions::default()
+    };
+    let mut lazy = LazyRawData::<F>::open(d_path, &options, capacity)?;
+    if let Some(dir) = flag_value(args, "--cache-dir") {
+        lazy = lazy.with_cache_dir(Path::new(dir))?;
+    }
+    
+    println!("{} MS2 windows ({} precision, LRU of {})", lazy.windows().len(), F::NAME, capacity);
+    let total_start = Instant::now();
+    let mut total_peaks = 0;
+    for (i, window) in lazy.windows().iter().enumerate() {
+        let start = Instant::now();
+        let data = lazy.window(i)?;
+        total_peaks += data.len();
+        println!("  ({:.4}, {:.4})  {:>5} frames  {:>10} peaks  {:.3}s",
+                 window.bounds.0, window.bounds.1, window.frame_count(), data.len(), start.elapsed().as_secs_f64());
+    }
+    println!("{} peaks in {:.3}s, {} windows resident", total_peaks, total_start.elapsed().as_secs_f64(), lazy.resident_windows());
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    // 配置并行处理
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(32)
-        .build_global()
-        .unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    let use_f64 = has_flag(&args, "--f64");
+    match args.get(1).map(String::as_str) {
+        Some("recalibrate") => {
+            return if use_f64 { run_recalibrate::<f64>(&args[2..]) } else { run_recalibrate::<f32>(&args[2..]) };
+        }
+        Some("frame-table") => return run_frame_table(&args[2..]),
+        Some("windows") => {
+            return if use_f64 { run_windows::<f64>(&args[2..]) } else { run_windows::<f32>(&args[2..]) };
+        }
+        Some("compare") => {
+            return if use_f64 { run_compare::<f64>(&args[2..]) } else { run_compare::<f32>(&args[2..]) };
+        }
+        _ => {}
+    }
     
     // 设置数据文件路径
     let d_folder_path = "/storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/test_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d";
@@ -637,32 +1442,140 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Err(format!("Folder {:?} not found", d_path).into());
     }
     
+    // --deterministic：要求两个版本的输出满足规范顺序且二进制文件逐字节一致
+    let deterministic = has_flag(&args, "--deterministic");
+    // --compact：V5 输出在保存前经紧凑表示往返一次，并报告两种表示的内存
+    let compact = has_flag(&args, "--compact");
+    // --compress：另存一份按列压缩的 V5 输出，读回校验并报告压缩比和吞吐量
+    let compression = compress_flag(&args)?;
+    // --lut：V5 版本使用换算查找表，原始版本仍直接换算
+    // --threads：工作线程数，默认 auto
+    // --mz-storage：两个版本保存 m/z 的方式（converted | with-tof | tof-only），默认 converted
+    // --max-memory / --spill-dir：V5 版本 MS2 窗口缓冲的内存预算与溢写目录
+    let options = LoadOptions {
+        lookup_tables: has_flag(&args, "--lut"),
+        threads: threads_flag(&args)?,
+        mz_storage: mz_storage_flag(&args)?,
+        max_memory: max_memory_flag(&args)?,
+        spill_dir: flag_value(&args, "--spill-dir").map(PathBuf::from),
+        ..LoadOptions::default()
+    };
+    if (compact || compression.is_some()) && options.max_memory.is_some() {
+        return Err("--compact and --compress need all MS2 windows in memory and cannot be combined with --max-memory".into());
+    }
+    if use_f64 {
+        run_comparison::<f64>(d_path, deterministic, compact, compression, &options)
+    } else {
+        run_comparison::<f32>(d_path, deterministic, compact, compression, &options)
+    }
+}
+
+// 列被移入紧凑表示（不复制），展开后与原数据的 bincode 摘要比较；
+// 按游程随机访问的 peak(i) 与展开的列逐峰比较
+fn compact_round_trip<F: Precision>(data: TimsTOFRawData<F>) -> Result<TimsTOFRawData<F>, Box<dyn Error>> {
+    let digest = bincode_digest(&data)?;
+    let compact = data.into_compact()?;
+    let data = compact.to_data();
+    if bincode_digest(&data)? != digest {
+        return Err("compact encoding changed the data".into());
+    }
+    let (mut peaks, mut flat_bytes) = (0, 0);
+    let flat = std::iter::once(&data.ms1_data).chain(data.ms2_windows.iter().map(|(_, td)| td));
+    for (td, compact) in flat.zip(compact.datasets()) {
+        if let Some(i) = (0..td.len()).find(|&i| compact.peak(i) != td.peak(i)) {
+            return Err(format!("compact random access changed peak {}", i).into());
+        }
+        peaks += td.len();
+        flat_bytes += td.heap_bytes();
+    }
+    let compact_bytes = compact.heap_bytes();
+    let per_peak = |bytes: usize| bytes as f64 / peaks.max(1) as f64;
+    println!("[V5_FIXED] Compact encoding: {:.1} MB ({:.1} bytes/peak), flat columns {:.1} MB ({:.1} bytes/peak)",
+             compact_bytes as f64 / 1e6, per_peak(compact_bytes), flat_bytes as f64 / 1e6, per_peak(flat_bytes));
+    Ok(data)
+}
+
+// bincode 编码的 SHA256（流式计算，不生成完整的字节串）
+fn bincode_digest<T: Serialize>(value: &T) -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    bincode::serialize_into(&mut hasher, value)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// 在 --deterministic 模式下检查规范顺序
+fn check_order(label: &str, deterministic: bool, check: impl FnOnce() -> Result<(), String>) -> Result<(), Box<dyn Error>> {
+    if deterministic {
+        check().map_err(|e| format!("[{}] {}", label, e))?;
+        println!("[{}] Output is in canonical order", label);
+    }
+    Ok(())
+}
+
+fn run_comparison<F: Precision>(
+    d_path: &Path,
+    deterministic: bool,
+    compact: bool,
+    compression: Option<Compression>,
+    options: &LoadOptions,
+) -> Result<(), Box<dyn Error>> {
     // 创建输出目录
     std::fs::create_dir_all("./timstof_comparison_output")?;
     
     println!("========== TimsTOF File-Based Version Comparison Tool ==========");
-    println!("Data folder: {}", d_folder_path);
+    println!("Data folder: {}", d_path.display());
     println!("Output directory: ./timstof_comparison_output/");
+    println!("m/z / RT precision: {}", F::NAME);
+    println!("Deterministic mode: {}", deterministic);
+    println!("Lookup tables: {}", options.lookup_tables);
+    println!("Compact round trip (V5): {}", compact);
+    if let Some(compression) = compression {
+        println!("Compressed output (V5): {}", compression);
+    }
+    if let Some(budget) = options.max_memory {
+        println!("MS2 memory budget (V5): {:.1} MB (the original version still loads the whole run)", budget as f64 / 1e6);
+    }
     println!();
     
     // ===== 步骤1：运行原始版本并保存 =====
+    // 两个版本的输出保存后即释放，同一时刻只有一份在内存中
     println!(">>> STEP 1: Running ORIGINAL version and saving to files...");
-    let data_original = original_version::read_timstof_data_original(d_path)?;
+    let data_original = original_version::read_timstof_data_original::<F>(d_path, options)?;
+    check_order("ORIGINAL", deterministic, || data_original.check_canonical_order())?;
     
     println!("\n[ORIGINAL] Saving data to files...");
     data_original.save_binary("./timstof_comparison_output/original_data.bin")?;
     data_original.save_json("./timstof_comparison_output/original_data.json")?;
     data_original.save_summary("./timstof_comparison_output/original_summary.txt")?;
+    drop(data_original);
     println!();
     
     // ===== 步骤2：运行V5版本并保存 =====
+    // 内存预算模式下溢写过的 MS2 窗口留在溢写目录中，检查和保存时逐个读回
     println!(">>> STEP 2: Running V5_FIXED version and saving to files...");
-    let data_v5 = v5_fixed::read_timstof_data_v5_fixed(d_path)?;
+    let mut data_v5 = v5_fixed::read_timstof_data_v5_spilled::<F>(d_path, options)?;
+    check_order("V5_FIXED", deterministic, || data_v5.check_canonical_order())?;
+    // 紧凑表示和压缩输出需要所有 MS2 窗口同时在内存中，main 中已拒绝与 --max-memory 同时使用，
+    // 因此这里的 into_raw_data 不读回任何溢写文件
+    if compact {
+        data_v5 = compact_round_trip(data_v5.into_raw_data()?)?.into();
+    }
     
     println!("\n[V5_FIXED] Saving data to files...");
     data_v5.save_binary("./timstof_comparison_output/v5_fixed_data.bin")?;
     data_v5.save_json("./timstof_comparison_output/v5_fixed_data.json")?;
     data_v5.save_summary("./timstof_comparison_output/v5_fixed_summary.txt")?;
+    if let Some(compression) = compression {
+        let path = "./timstof_comparison_output/v5_fixed_data.tofz";
+        let data_v5 = data_v5.into_raw_data()?;
+        data_v5.save_compressed(path, compression)?;
+        let loaded = TimsTOFRawData::<F>::load_binary(path)?;
+        if bincode_digest(&loaded)? != bincode_digest(&data_v5)? {
+            return Err(format!("{} does not read back to the saved data", path).into());
+        }
+        println!("    ✓ Compressed file reads back identically");
+    } else {
+        drop(data_v5);
+    }
     println!();
     
     // ===== 步骤3：比较文件 =====
@@ -678,10 +1591,20 @@ fn main() -> Result<(), Box<dyn Error>> {
     // 如果二进制文件不同，进一步分析数据内容
     let content_match = if !binary_match {
         println!("\n>>> Binary files differ, analyzing data content...");
-        compare_data_from_files(
+        let hashes_match = compare_data_from_files::<F>(
             "./timstof_comparison_output/original_data.bin",
             "./timstof_comparison_output/v5_fixed_data.bin"
-        )?
+        )?;
+        
+        // 逐列差异（默认容差）写入 JSON，便于定位具体的列和窗口；需要两份完整的输出，从文件读回
+        let sources = [
+            ("original".to_string(), TimsTOFRawData::<F>::load_binary("./timstof_comparison_output/original_data.bin")?),
+            ("v5_fixed".to_string(), TimsTOFRawData::<F>::load_binary("./timstof_comparison_output/v5_fixed_data.bin")?),
+        ];
+        let diff_report = DiffReport::build(&sources, &Tolerances::default());
+        diff_report.save_json("./timstof_comparison_output/diff_report.json")?;
+        print!("{}", diff_report);
+        hashes_match
     } else {
         true
     };
@@ -693,6 +1616,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     writeln!(report, "TimsTOF Version Comparison Report")?;
     writeln!(report, "==================================")?;
     writeln!(report, "Generated: {}", chrono::Local::now())?;
+    writeln!(report, "Deterministic mode: {}", deterministic)?;
     writeln!(report)?;
     
     if binary_match {
@@ -700,6 +1624,11 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("   This means both versions produce exactly the same output.");
         writeln!(report, "Result: ✅ SUCCESS")?;
         writeln!(report, "Binary files are identical - both versions produce exactly the same output.")?;
+    } else if content_match && deterministic {
+        // 规范顺序下内容相同就必须逐字节相同
+        println!("❌ FAILURE: Data content matches but binary files differ in --deterministic mode!");
+        writeln!(report, "Result: ❌ FAILURE")?;
+        writeln!(report, "Data content matches but binary files differ; --deterministic requires identical bytes.")?;
     } else if content_match {
         println!("⚠️  WARNING: Binary files differ but data content matches!");
         println!("   This means the data is the same but stored in different order.");
@@ -722,13 +1651,819 @@ fn main() -> Result<(), Box<dyn Error>> {
     writeln!(report, "  - v5_fixed_data.json: JSON data (human-readable)")?;
     writeln!(report, "  - original_summary.txt: Data summary with hashes")?;
     writeln!(report, "  - v5_fixed_summary.txt: Data summary with hashes")?;
+    if !binary_match {
+        writeln!(report, "  - diff_report.json: Per-column and per-window differences")?;
+    }
     
     println!("\n📁 All output files saved to: ./timstof_comparison_output/");
     println!("   You can manually inspect the JSON and summary files for details.");
     
+    if deterministic && !binary_match {
+        return Err("--deterministic: outputs are not byte-identical".into());
+    }
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compact::CompactTimsTOFData;
+    use std::alloc::System;
+    use timstof_synthetic::memory::{self, PeakAlloc};
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticConfig, SyntheticRun};
+    
+    #[global_allocator]
+    static ALLOC: PeakAlloc<System> = PeakAlloc::new(System);
+    
+    fn sorted_windows<F: Precision>(data: &TimsTOFRawData<F>) -> Vec<(f32, f32, usize, String)> {
+        let mut windows: Vec<_> = data.ms2_windows.iter()
+            .map(|((low, high), td)| (*low, *high, td.len(), td.calculate_hash()))
+            .collect();
+        windows.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        windows
+    }
+    
+    #[test]
+    fn strategies_agree_on_synthetic_run() {
+        let run = SyntheticRun::small();
+        let dir = run.write_temp().unwrap();
+        let options = LoadOptions::default();
+        
+        let original = original_version::read_timstof_data_original::<f32>(dir.path(), &options).unwrap();
+        let v5 = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+        
+        let ms1_peaks: usize = run.ms1_frames().map(|f| f.peaks.len()).sum();
+        assert_eq!(original.ms1_data.len(), ms1_peaks);
+        assert_eq!(original.ms1_data.calculate_hash(), v5.ms1_data.calculate_hash());
+        
+        let windows = sorted_windows(&original);
+        assert_eq!(windows.len(), run.windows.len());
+        assert_eq!(windows, sorted_windows(&v5));
+        for window in &run.windows {
+            let low = (window.isolation_mz - window.isolation_width / 2.0) as f32;
+            let (_, _, len, _) = windows.iter().find(|w| (w.0 - low).abs() < 1e-3).unwrap();
+            assert_eq!(*len, run.window_peaks(window).len());
+        }
+    }
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let options = LoadOptions::default();
+        let original = original_version::read_timstof_data_original::<f32>(dir.path(), &options).unwrap();
+        golden::assert_golden("small", "original", &digest!(original));
+        let v5 = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+        golden::assert_golden("small", "v5_fixed", &digest!(v5));
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let run = SyntheticRun::overlapping();
+        let dir = run.write_temp().unwrap();
+        let options = LoadOptions::default();
+        let original = original_version::read_timstof_data_original::<f32>(dir.path(), &options).unwrap();
+        golden::assert_golden("overlapping", "original", &digest!(original));
+        let v5 = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+        golden::assert_golden("overlapping", "v5_fixed", &digest!(v5));
+        
+        // 重叠扫描中的峰在两个窗口里各出现一次
+        for window in &run.windows {
+            let key = window_key(window.isolation_mz, window.isolation_width).unwrap();
+            let (_, data) = v5.ms2_windows.iter().find(|(bounds, _)| key_from_bounds(*bounds) == key).unwrap();
+            assert_eq!(data.len(), run.window_peaks(window).len());
+        }
+    }
+    
+    #[test]
+    fn lookup_tables_are_bit_identical_to_direct_conversion() {
+        let run = SyntheticRun::overlapping();
+        let dir = run.write_temp().unwrap();
+        for mz_storage in [MzStorage::Converted, MzStorage::ConvertedWithTof] {
+            let direct = LoadOptions { mz_storage, ..LoadOptions::default() };
+            let tables = LoadOptions { lookup_tables: true, ..direct.clone() };
+            
+            let expected = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &direct).unwrap();
+            let actual = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &tables).unwrap();
+            assert_eq!(digest!(actual), digest!(expected));
+            
+            let expected = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &direct).unwrap();
+            let actual = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &tables).unwrap();
+            assert_eq!(actual.ms1_data.calculate_hash(), expected.ms1_data.calculate_hash());
+            assert_eq!(sorted_windows(&actual), sorted_windows(&expected));
+            let bits = |data: &TimsTOFRawData<f64>| data.ms1_data.mz_values.iter().map(|mz| mz.to_bits()).collect::<Vec<_>>();
+            assert_eq!(bits(&actual), bits(&expected));
+        }
+        
+        // 超出表范围的 TOF 索引和 scan 退回直接换算
+        let meta = MetadataReader::new(dir.path().join("analysis.tdf")).unwrap();
+        let metadata = RunMetadata::read(dir.path()).unwrap();
+        let frame_info = frame_table::read_frame_sql_info(dir.path()).unwrap();
+        let direct = Converters::direct(meta.mz_converter, meta.im_converter);
+        let tables = Converters::with_tables(meta.mz_converter, meta.im_converter, &metadata, &frame_info);
+        assert_eq!(tables.table_sizes(), (run.digitizer_num_samples as usize + 1, 100));
+        for tof in [0, 1, 123_456, run.digitizer_num_samples, run.digitizer_num_samples + 1, u32::MAX] {
+            assert_eq!(tables.mz(tof).to_bits(), direct.mz(tof).to_bits(), "tof {}", tof);
+        }
+        for scan in [0, 99, 100, 5000] {
+            assert_eq!(tables.mobility(scan).to_bits(), direct.mobility(scan).to_bits(), "scan {}", scan);
+        }
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let options = LoadOptions::default();
+        determinism::assert_deterministic(
+            "original",
+            || original_version::read_timstof_data_original::<f32>(dir.path(), &options).unwrap(),
+            |data| fingerprint!(data),
+        );
+        determinism::assert_deterministic(
+            "v5_fixed",
+            || v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap(),
+            |data| fingerprint!(data),
+        );
+        
+        let tof_only = LoadOptions { mz_storage: MzStorage::TofOnly, ..LoadOptions::default() };
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &tof_only).unwrap();
+        assert_eq!(data.check_canonical_order(), Ok(()));
+    }
+    
+    #[test]
+    fn fixed_thread_counts_use_a_local_pool() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let auto = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &LoadOptions::default()).unwrap();
+        for threads in [1, 3] {
+            let options = LoadOptions { threads: Threads::Fixed(threads), ..LoadOptions::default() };
+            let fixed = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+            assert_eq!(fingerprint!(fixed), fingerprint!(auto), "{} threads", threads);
+        }
+        
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(threads_flag(&args(&[])).unwrap(), Threads::Auto);
+        assert_eq!(threads_flag(&args(&["--threads", "auto"])).unwrap(), Threads::Auto);
+        assert_eq!(threads_flag(&args(&["--threads", "8"])).unwrap(), Threads::Fixed(8));
+        assert!(threads_flag(&args(&["--threads", "0"])).is_err());
+    }
+
+    #[test]
+    fn spilled_windows_read_back_in_order() {
+        let chunk = |frame: u32, n: u32| {
+            let mut data = TimsTOFData::<f32>::with_capacity(n as usize);
+            for i in 0..n {
+                data.rt_values_min.push(frame as f32);
+                data.mobility_values.push(1.0 - i as f32 * 0.01);
+                data.mz_values.push(400.0 + i as f32);
+                data.intensity_values.push(i * 10);
+                data.frame_indices.push(frame);
+                data.scan_indices.push(i);
+            }
+            data
+        };
+        let mut store = SpillStore::new(None).unwrap();
+        let key = (1_000, 2_000);
+        let (mut first, mut second) = (chunk(1, 3), chunk(2, 5));
+        let mut expected = first.clone();
+        expected.merge_from(&mut second.clone());
+        store.spill(key, &mut first).unwrap();
+        store.spill(key, &mut second).unwrap();
+        assert!(first.is_empty() && first.heap_bytes() == 0);
+        assert_eq!((store.spills(), store.spilled_windows()), (2, 1));
+        assert_eq!(store.bytes_written(), 8 * 24);
+
+        let loaded = store.take::<f32>(key, 4).unwrap().unwrap();
+        assert_eq!(loaded.calculate_hash(), expected.calculate_hash());
+        assert_eq!(loaded.frame_indices, expected.frame_indices);
+        assert!(loaded.tof_indices.is_empty());
+        assert_eq!(loaded.intensity_values.capacity(), 8 + 4);
+        assert!(store.take::<f32>(key, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_memory_sizes() {
+        assert_eq!(spill::parse_size("4096"), Ok(4096));
+        assert_eq!(spill::parse_size("64K"), Ok(64 << 10));
+        assert_eq!(spill::parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(spill::parse_size("48g"), Ok(48 << 30));
+        assert!(spill::parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn memory_budget_does_not_change_the_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let spill_dir = tempfile::tempdir().unwrap();
+        fn columns<F: Precision>(data: &TimsTOFRawData<F>) -> Vec<u8> {
+            bincode::serialize(&(&data.ms1_data, &data.ms2_windows)).unwrap()
+        }
+        for mz_storage in [MzStorage::Converted, MzStorage::ConvertedWithTof, MzStorage::TofOnly] {
+            let unlimited = LoadOptions { mz_storage, ..LoadOptions::default() };
+            let expected = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &unlimited).unwrap();
+            // 预算为 0 时每个 MS2 帧之后都溢写
+            for budget in [0, 16 << 10] {
+                let budgeted = LoadOptions {
+                    max_memory: Some(budget),
+                    spill_dir: Some(spill_dir.path().to_path_buf()),
+                    ..unlimited.clone()
+                };
+                let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &budgeted).unwrap();
+                assert!(columns(&data) == columns(&expected), "{:?}, budget {}", mz_storage, budget);
+                assert_eq!(data.frame_checksums, expected.frame_checksums);
+            }
+        }
+        // 溢写目录在加载结束后删除
+        assert_eq!(std::fs::read_dir(spill_dir.path()).unwrap().count(), 0);
+
+        let budgeted = LoadOptions { max_memory: Some(0), ..LoadOptions::default() };
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &budgeted).unwrap();
+        let expected = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &LoadOptions::default()).unwrap();
+        assert!(columns(&data) == columns(&expected), "f64");
+    }
+
+    #[test]
+    fn spilled_output_saves_like_the_loaded_output() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let file = |name: &str| out.path().join(name).to_str().unwrap().to_string();
+        let read = |name: &str| std::fs::read(file(name)).unwrap();
+        let options = LoadOptions { mz_storage: MzStorage::ConvertedWithTof, ..LoadOptions::default() };
+        let expected = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+        expected.save_binary(&file("expected.bin")).unwrap();
+        expected.save_json(&file("expected.json")).unwrap();
+        expected.save_summary(&file("expected.txt")).unwrap();
+
+        let budgeted = LoadOptions { max_memory: Some(0), ..options };
+        let spilled = v5_fixed::read_timstof_data_v5_spilled::<f32>(dir.path(), &budgeted).unwrap();
+        assert_eq!(spilled.ms2_windows.len(), expected.ms2_windows.len());
+        assert_eq!(spilled.ms2_windows.peaks(), expected.ms2_windows.iter().map(|(_, td)| td.len()).sum::<usize>());
+        assert!(spilled.ms2_windows.window(0).unwrap().is_empty() == expected.ms2_windows[0].1.is_empty());
+        spilled.check_canonical_order().unwrap();
+        spilled.save_binary(&file("spilled.bin")).unwrap();
+        spilled.save_json(&file("spilled.json")).unwrap();
+        spilled.save_summary(&file("spilled.txt")).unwrap();
+        assert!(read("spilled.bin") == read("expected.bin"));
+        assert!(read("spilled.json") == read("expected.json"));
+        assert_eq!(read("spilled.txt"), read("expected.txt"));
+        // 读回窗口不删除溢写文件，保存之后仍可整体合并回内存
+        let merged = spilled.into_raw_data().unwrap();
+        assert_eq!(bincode_digest(&merged).unwrap(), bincode_digest(&expected).unwrap());
+    }
+
+    // 溢写的窗口在加载结束时曾经全部读回内存；MS1 块也曾复制成第二份
+    #[test]
+    fn spilled_load_stays_within_the_budget() {
+        if !memory::isolated("tests::spilled_load_stays_within_the_budget") {
+            return;
+        }
+        let dir = SyntheticRun::generate(&SyntheticConfig {
+            cycles: 24,
+            window_groups: 4,
+            windows_per_group: 4,
+            peaks_per_frame: 20_000,
+            ..SyntheticConfig::default()
+        }).write_temp().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let budget = 4 << 20;
+        let options = LoadOptions { max_memory: Some(budget), skip_frame_checksums: true, threads: Threads::Fixed(2), ..LoadOptions::default() };
+
+        let baseline = ALLOC.current();
+        ALLOC.reset_peak();
+        let data = v5_fixed::read_timstof_data_v5_spilled::<f32>(dir.path(), &options).unwrap();
+        data.save_binary(out.path().join("spilled.bin").to_str().unwrap()).unwrap();
+        let peak = ALLOC.peak() - baseline;
+
+        let ms1 = data.ms1_data.heap_bytes();
+        let windows: Vec<usize> = (0..data.ms2_windows.len())
+            .map(|i| data.ms2_windows.window(i).unwrap().heap_bytes())
+            .collect();
+        let output = ms1 + windows.iter().sum::<usize>();
+        let largest_window = windows.iter().copied().max().unwrap();
+        println!("MS1 {} B, output {} B, largest window {} B, peak {} B", ms1, output, largest_window, peak);
+        assert!(windows.len() >= 16);
+        assert!(
+            peak <= ms1 + budget + 2 * largest_window + output / 10,
+            "peak {} B for {} B of MS1, a {} B budget and {} B windows", peak, ms1, budget, largest_window,
+        );
+        assert!(peak < output, "peak {} B, output {} B", peak, output);
+    }
+
+    #[test]
+    fn lazy_windows_match_eager_loading() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        for (mz_storage, lookup_tables) in [(MzStorage::Converted, false), (MzStorage::ConvertedWithTof, true), (MzStorage::TofOnly, false)] {
+            let options = LoadOptions { mz_storage, lookup_tables, ..LoadOptions::default() };
+            let eager = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+            let lazy = LazyRawData::<f32>::open(dir.path(), &options, 2).unwrap();
+            assert!(lazy.windows().iter().all(|w| w.frame_count() > 0));
+            
+            // 一次性加载的输出不含空窗口
+            let loaded: Vec<_> = (0..lazy.windows().len())
+                .map(|i| (lazy.windows()[i].bounds, lazy.window(i).unwrap()))
+                .filter(|(_, data)| !data.is_empty())
+                .collect();
+            assert_eq!(loaded.len(), eager.ms2_windows.len());
+            for ((bounds, data), (eager_bounds, eager_data)) in loaded.iter().zip(&eager.ms2_windows) {
+                assert_eq!(bounds, eager_bounds);
+                assert!(bincode::serialize(&**data).unwrap() == bincode::serialize(eager_data).unwrap(), "{:?} {:?}", mz_storage, bounds);
+            }
+            assert_eq!(lazy.resident_windows(), 2);
+        }
+        
+        let lazy = LazyRawData::<f64>::open(dir.path(), &LoadOptions::default(), 1).unwrap();
+        let eager = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &LoadOptions::default()).unwrap();
+        let first = lazy.windows().iter().position(|w| w.bounds == eager.ms2_windows[0].0).unwrap();
+        assert!(bincode::serialize(&*lazy.window(first).unwrap()).unwrap() == bincode::serialize(&eager.ms2_windows[0].1).unwrap());
+        assert!(lazy.window(lazy.windows().len()).is_err());
+    }
+    
+    #[test]
+    fn compact_encoding_round_trips() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        for mz_storage in [MzStorage::Converted, MzStorage::ConvertedWithTof, MzStorage::TofOnly] {
+            let options = LoadOptions { mz_storage, ..LoadOptions::default() };
+            let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+            let (mut peaks, mut compact_bytes) = (0, 0);
+            for td in std::iter::once(&data.ms1_data).chain(data.ms2_windows.iter().map(|(_, td)| td)) {
+                let compact = CompactTimsTOFData::from_data(td.clone(), data.mz_storage).unwrap();
+                assert_eq!(compact.mz_storage(), mz_storage);
+                assert!(bincode::serialize(&compact.to_data()).unwrap() == bincode::serialize(td).unwrap(), "{:?}", mz_storage);
+                assert!(compact.peaks().eq((0..td.len()).map(|i| td.peak(i))));
+                assert!((0..td.len()).all(|i| compact.peak(i) == td.peak(i)));
+                assert!(compact.rt_values_min().eq(td.rt_values_min.iter().copied()));
+                assert!(compact.frame_indices().eq(td.frame_indices.iter().copied()));
+                assert!(compact.mobility_values().eq(td.mobility_values.iter().copied()));
+                peaks += td.len();
+                compact_bytes += compact.heap_bytes();
+            }
+            // 每个峰 12 字节（保留 TOF 时 16 字节），另加每帧一个游程和 scan 表
+            let per_peak = if mz_storage.keeps_tof() { 16.0 } else { 12.0 };
+            assert!(compact_bytes as f64 / (peaks as f64) < per_peak + 1.0, "{:?}: {} bytes for {} peaks", mz_storage, compact_bytes, peaks);
+            
+            // 整次加载：保留的列被移动而不是复制
+            let (digest, mz_column) = (bincode_digest(&data).unwrap(), data.ms1_data.mz_values.as_ptr());
+            let flat_bytes: usize = std::iter::once(&data.ms1_data).chain(data.ms2_windows.iter().map(|(_, td)| td))
+                .map(TimsTOFData::heap_bytes)
+                .sum();
+            let compact = data.into_compact().unwrap();
+            if mz_storage.keeps_mz() {
+                assert_eq!(compact.ms1_data.mz_values.as_ptr(), mz_column);
+            }
+            assert!(compact.heap_bytes() < flat_bytes, "{:?}: {} of {} bytes", mz_storage, compact.heap_bytes(), flat_bytes);
+            assert_eq!(bincode_digest(&compact.to_data()).unwrap(), digest, "{:?}", mz_storage);
+        }
+        
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &LoadOptions::default()).unwrap();
+        let compact = CompactTimsTOFData::from_data(data.ms1_data.clone(), data.mz_storage).unwrap();
+        assert!(bincode::serialize(&compact.to_data()).unwrap() == bincode::serialize(&data.ms1_data).unwrap());
+        
+        // 1/K0 不是 scan 的函数时不能压缩
+        let mut data = data;
+        let td = &mut data.ms1_data;
+        let other = td.scan_indices.iter().skip(1).position(|&s| s == td.scan_indices[0]).unwrap() + 1;
+        td.mobility_values[other] += 0.01;
+        assert!(CompactTimsTOFData::from_data(td.clone(), data.mz_storage).is_err());
+        assert!(data.into_compact().unwrap_err().starts_with("MS1: scan"));
+    }
+    
+    #[test]
+    fn compressed_output_reads_back_identically() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let compressed = out.path().join("data.tofz");
+        let path = compressed.to_str().unwrap();
+        for mz_storage in [MzStorage::Converted, MzStorage::ConvertedWithTof, MzStorage::TofOnly] {
+            let options = LoadOptions { mz_storage, ..LoadOptions::default() };
+            let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+            for compression in [Compression::Zstd(3), Compression::Zstd(19), Compression::Lz4] {
+                let report = codec::save(&data, &compressed, compression).unwrap();
+                assert!(report.ratio() > 1.0, "{:?} {}", mz_storage, report);
+                let loaded = TimsTOFRawData::<f32>::load_binary(path).unwrap();
+                assert_eq!(bincode_digest(&loaded).unwrap(), bincode_digest(&data).unwrap(), "{:?} {}", mz_storage, compression);
+            }
+        }
+    }
+    
+    // 超过一个分块的列，值无序（差分回绕）
+    #[test]
+    fn compressed_columns_span_chunks_and_wrap() {
+        let out = tempfile::tempdir().unwrap();
+        let compressed = out.path().join("data.tofz");
+        let path = compressed.to_str().unwrap();
+        let mut data = TimsTOFRawData::<f64> {
+            ms1_data: TimsTOFData::new(),
+            ms2_windows: vec![((400.0, 425.0), TimsTOFData::new())],
+            metadata: RunMetadata::default(),
+            frame_table: FrameTable::default(),
+            frame_checksums: FrameChecksums::default(),
+            mz_storage: MzStorage::ConvertedWithTof,
+        };
+        let mut state = 0x9e37_79b9_u32;
+        for i in 0..1_200_000u32 {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            data.ms1_data.push(compact::Peak {
+                rt_min: (i / 5000) as f64 * 0.01,
+                mobility: 0.6 + (i % 900) as f32 * 1e-3,
+                mz: Some(100.0 + (state >> 8) as f64 * 1e-4),
+                intensity: state >> 20,
+                frame_index: i / 5000,
+                scan: i % 900,
+                tof: Some(state),
+            });
+        }
+        data.ms2_windows[0].1.push(data.ms1_data.peak(7));
+        codec::save(&data, &compressed, Compression::Lz4).unwrap();
+        let loaded = TimsTOFRawData::<f64>::load_binary(path).unwrap();
+        assert_eq!(bincode_digest(&loaded).unwrap(), bincode_digest(&data).unwrap());
+        assert!(TimsTOFRawData::<f32>::load_binary(path).is_err());
+    }
+    
+    // 文件中记录的列长度在分配前核对（MAGIC、压缩方式和级别共 13 字节之后是头部块的长度）
+    #[test]
+    fn compressed_column_lengths_are_checked_against_the_file() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let compressed = out.path().join("data.tofz");
+        let path = compressed.to_str().unwrap();
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &LoadOptions::default()).unwrap();
+        for compression in [Compression::Zstd(3), Compression::Lz4] {
+            codec::save(&data, &compressed, compression).unwrap();
+            let prefix = std::fs::read(&compressed).unwrap()[..13].to_vec();
+            let corrupt = |column_len: u64, chunks: &[&[u8]]| {
+                let mut bytes = prefix.clone();
+                bytes.extend(column_len.to_le_bytes());
+                for chunk in chunks {
+                    bytes.extend((chunk.len() as u64).to_le_bytes());
+                    bytes.extend(*chunk);
+                }
+                std::fs::write(&compressed, bytes).unwrap();
+                TimsTOFRawData::<f64>::load_binary(path).unwrap_err().to_string()
+            };
+            let err = corrupt(u64::MAX / 2, &[]);
+            assert!(err.contains("chunks, but only"), "{}", err);
+            let err = corrupt(8 << 20, &[&[0; 8], &[0; 8]]);
+            assert!(err.contains("cannot come from"), "{}", err);
+        }
+    }
+    
+    #[test]
+    fn parses_compression_flags() {
+        assert_eq!(Compression::parse("zstd"), Ok(Compression::Zstd(Compression::DEFAULT_ZSTD_LEVEL)));
+        assert_eq!(Compression::parse("zstd:19"), Ok(Compression::Zstd(19)));
+        assert_eq!(Compression::parse("lz4"), Ok(Compression::Lz4));
+        assert!(Compression::parse("zstd:0").is_err());
+        assert!(Compression::parse("gzip").is_err());
+    }
+    
+    // TofOnly 的输出延后换算后与加载时换算（ConvertedWithTof）的输出一致；没有 TOF 索引时返回错误
+    #[test]
+    fn tof_only_output_converts_to_mz_later() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let load = |mz_storage| {
+            let options = LoadOptions { mz_storage, ..LoadOptions::default() };
+            v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &options).unwrap()
+        };
+        let meta = MetadataReader::new(dir.path().join("analysis.tdf")).unwrap();
+        let mut data = load(MzStorage::TofOnly);
+        data.convert_tof_to_mz(&meta.mz_converter).unwrap();
+        assert_eq!(bincode_digest(&data).unwrap(), bincode_digest(&load(MzStorage::ConvertedWithTof)).unwrap());
+        
+        let mut converted = load(MzStorage::Converted);
+        let digest = bincode_digest(&converted).unwrap();
+        let err = converted.convert_tof_to_mz(&meta.mz_converter).unwrap_err();
+        assert!(err.starts_with("MS1: TOF indices were not retained"), "{}", err);
+        assert_eq!(bincode_digest(&converted).unwrap(), digest);
+    }
+    
+    #[test]
+    fn parses_mz_storage_flags() {
+        let args = |flags: &[&str]| flags.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        assert_eq!(mz_storage_flag(&args(&[])).unwrap(), MzStorage::Converted);
+        assert_eq!(mz_storage_flag(&args(&["--mz-storage", "with-tof"])).unwrap(), MzStorage::ConvertedWithTof);
+        assert_eq!(mz_storage_flag(&args(&["--mz-storage", "tof-only"])).unwrap(), MzStorage::TofOnly);
+        assert!(mz_storage_flag(&args(&["--mz-storage", "tof"])).is_err());
+        assert_eq!(positional_args(&args(&["a.bin", "--mz-storage", "tof-only", "b.bin"]), &["--mz-storage"]), ["a.bin", "b.bin"]);
+    }
+    
+    // save_binary 的文件头：没有文件头的旧文件和其他格式版本给出明确的错误，而不是错位解码
+    #[test]
+    fn binary_output_is_versioned() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let binary = out.path().join("data.bin");
+        let path = binary.to_str().unwrap();
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &LoadOptions::default()).unwrap();
+        data.save_binary(path).unwrap();
+        let bytes = std::fs::read(&binary).unwrap();
+        assert!(bytes.starts_with(codec::BINARY_MAGIC));
+        assert_eq!(bincode_digest(&TimsTOFRawData::<f32>::load_binary(path).unwrap()).unwrap(), bincode_digest(&data).unwrap());
+        
+        std::fs::write(&binary, &bytes[codec::BINARY_MAGIC.len()..]).unwrap();
+        let err = TimsTOFRawData::<f32>::load_binary(path).unwrap_err().to_string();
+        assert!(err.contains("no file header"), "{}", err);
+        
+        let mut newer = bytes.clone();
+        newer[5] += 1;
+        std::fs::write(&binary, newer).unwrap();
+        let err = TimsTOFRawData::<f32>::load_binary(path).unwrap_err().to_string();
+        assert!(err.contains("format version 2"), "{}", err);
+    }
+    
+    #[test]
+    fn binary_output_records_its_precision() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let binary = out.path().join("data.bin");
+        let path = binary.to_str().unwrap();
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f64>(dir.path(), &LoadOptions::default()).unwrap();
+        data.save_binary(path).unwrap();
+        assert_eq!(bincode_digest(&TimsTOFRawData::<f64>::load_binary(path).unwrap()).unwrap(), bincode_digest(&data).unwrap());
+        let err = TimsTOFRawData::<f32>::load_binary(path).unwrap_err().to_string();
+        assert!(err.contains("holds f64 data, not f32"), "{}", err);
+    }
+    
+    #[test]
+    fn lazy_windows_use_the_lru_and_the_cache_dir() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let options = LoadOptions::default();
+        let lazy = LazyRawData::<f32>::open(dir.path(), &options, 2).unwrap()
+            .with_cache_dir(cache.path()).unwrap();
+        let n = lazy.windows().len();
+        assert!(n >= 3);
+        
+        // 命中返回同一份数据；容量为 2 时第三个窗口把最久未用的窗口挤出
+        let first = lazy.window(0).unwrap();
+        assert!(Arc::ptr_eq(&first, &lazy.window(0).unwrap()));
+        let second = lazy.window(1).unwrap();
+        lazy.window(0).unwrap();
+        lazy.window(2).unwrap();
+        assert_eq!(lazy.resident_windows(), 2);
+        assert!(Arc::ptr_eq(&first, &lazy.window(0).unwrap()));
+        assert!(!Arc::ptr_eq(&second, &lazy.window(1).unwrap()));
+        let expected: Vec<TimsTOFData> = (0..n).map(|i| (*lazy.window(i).unwrap()).clone()).collect();
+        
+        // 同一份数据重新打开时从缓存目录读出同样的窗口；改动缓存中的强度可证明没有重新解码
+        let cached = LazyRawData::<f32>::open(dir.path(), &options, 0).unwrap()
+            .with_cache_dir(cache.path()).unwrap();
+        for (i, expected) in expected.iter().enumerate() {
+            let data = cached.window(i).unwrap();
+            assert!(bincode::serialize(&*data).unwrap() == bincode::serialize(expected).unwrap(), "window {}", i);
+        }
+        assert_eq!(cached.resident_windows(), 0);
+        let intensities = cache.path().join(spill::window_dir_name(cached.windows()[0].key)).join("intensity_values");
+        let mut bytes = std::fs::read(&intensities).unwrap();
+        bytes[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&intensities, bytes).unwrap();
+        assert_eq!(cached.window(0).unwrap().intensity_values[0], u32::MAX);
+        
+        // 选项不同的句柄不能复用该缓存
+        let tof_only = LoadOptions { mz_storage: MzStorage::TofOnly, ..LoadOptions::default() };
+        assert!(LazyRawData::<f32>::open(dir.path(), &tof_only, 1).unwrap().with_cache_dir(cache.path()).is_err());
+        assert!(LazyRawData::<f64>::open(dir.path(), &options, 1).unwrap().with_cache_dir(cache.path()).is_err());
+        
+        // 帧数据被替换（大小不变）后缓存作废
+        let tdf_bin = dir.path().join("analysis.tdf_bin");
+        let len = std::fs::metadata(&tdf_bin).unwrap().len() as usize;
+        std::fs::write(&tdf_bin, vec![0u8; len]).unwrap();
+        std::fs::File::options().write(true).open(&tdf_bin).unwrap()
+            .set_modified(std::time::UNIX_EPOCH).unwrap();
+        let err = LazyRawData::<f32>::open(dir.path(), &options, 1).unwrap().with_cache_dir(cache.path()).err().unwrap();
+        assert!(err.to_string().contains("analysis.tdf_bin"), "{}", err);
+        
+        // 同一个缓存目录不能用于另一次采集
+        let other = SyntheticRun::overlapping().write_temp().unwrap();
+        assert!(LazyRawData::<f32>::open(other.path(), &options, 1).unwrap().with_cache_dir(cache.path()).is_err());
+    }
+
+    // 同一个窗口同时写入缓存目录的线程都成功，读出完整的窗口，且不留下临时目录
+    #[test]
+    fn concurrent_window_writes_all_succeed() {
+        let cache = tempfile::tempdir().unwrap();
+        let path = cache.path().join(spill::window_dir_name((400_000, 425_000)));
+        let mut data = TimsTOFData::<f32>::new();
+        for i in 0..50_000u32 {
+            data.push(compact::Peak {
+                rt_min: 1.0,
+                mobility: 0.8,
+                mz: Some(400.0 + i as f32 * 1e-3),
+                intensity: i,
+                frame_index: i / 1000,
+                scan: i % 1000,
+                tof: None,
+            });
+        }
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| spill::write_window(&path, &data).unwrap());
+            }
+        });
+        let read = spill::read_window::<f32>(&path).unwrap();
+        assert!(bincode::serialize(&read).unwrap() == bincode::serialize(&data).unwrap());
+        assert_eq!(std::fs::read_dir(cache.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn summary_reports_the_storage_used_for_an_empty_run() {
+        let mut run = SyntheticRun::small();
+        run.frames.iter_mut().for_each(|frame| frame.peaks.clear());
+        let dir = run.write_temp().unwrap();
+        let options = LoadOptions { mz_storage: MzStorage::TofOnly, ..LoadOptions::default() };
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+        assert!(data.ms1_data.is_empty() && data.ms2_windows.is_empty());
+        assert_eq!(data.mz_storage, MzStorage::TofOnly);
+        
+        let out = tempfile::tempdir().unwrap();
+        let binary = out.path().join("data.bin");
+        data.save_binary(binary.to_str().unwrap()).unwrap();
+        assert_eq!(TimsTOFRawData::<f32>::load_binary(binary.to_str().unwrap()).unwrap().mz_storage, MzStorage::TofOnly);
+        let summary = out.path().join("summary.txt");
+        data.save_summary(summary.to_str().unwrap()).unwrap();
+        assert!(std::fs::read_to_string(&summary).unwrap().contains("m/z Storage: TofOnly"));
+    }
+    
+    #[test]
+    fn frames_after_an_empty_frame_are_merged() {
+        let mut run = SyntheticRun::small();
+        let empty = run.frames.iter().position(|f| f.is_ms1()).unwrap();
+        run.frames[empty].peaks.clear();
+        let dir = run.write_temp().unwrap();
+        
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &LoadOptions::default()).unwrap();
+        let ms1_peaks: usize = run.ms1_frames().map(|f| f.peaks.len()).sum();
+        assert_eq!(data.ms1_data.len(), ms1_peaks);
+        assert_eq!(data.check_canonical_order(), Ok(()));
+    }
+    
+    #[test]
+    fn compare_counts_differences_per_column_and_window() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let options = LoadOptions::default();
+        let original = run_strategy::<f32>("original", dir.path(), &options).unwrap();
+        let v5 = run_strategy::<f32>("v5_fixed", dir.path(), &options).unwrap();
+        
+        let mut perturbed = v5.clone();
+        for mz in &mut perturbed.ms1_data.mz_values[..3] {
+            *mz += 1e-3;
+        }
+        let shortened = &mut perturbed.ms2_windows[0].1;
+        shortened.rt_values_min.pop();
+        shortened.mobility_values.pop();
+        shortened.mz_values.pop();
+        shortened.intensity_values.pop();
+        shortened.frame_indices.pop();
+        shortened.scan_indices.pop();
+        let dropped = perturbed.ms2_windows.pop().unwrap().0;
+        
+        let sources = vec![
+            ("original".to_string(), original),
+            ("v5_fixed".to_string(), v5),
+            ("perturbed".to_string(), perturbed),
+        ];
+        let report = DiffReport::build(&sources, &Tolerances::default());
+        assert!(!report.matches);
+        assert!(report.comparisons[0].matches);
+        
+        let diff = &report.comparisons[1];
+        assert_eq!(diff.ms1.columns["mz"].mismatches, 3);
+        assert_eq!(diff.ms1.columns["rt"].mismatches, 0);
+        assert_eq!(diff.ms1.skipped_columns, ["tof"]);
+        assert_eq!(diff.ms2_windows[0].diff.as_ref().unwrap().unmatched_reference, 1);
+        let missing = diff.ms2_windows.last().unwrap();
+        assert_eq!((missing.low, missing.high, missing.in_candidate), (dropped.0, dropped.1, false));
+        
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["comparisons"][1]["ms1"]["columns"]["mz"]["mismatches"], 3);
+        assert_eq!(json["tolerances"]["mz"]["kind"], "absolute");
+        
+        // 合成数据 m/z >= 100，1e-3 Th 不超过 10 ppm
+        let ppm = Tolerances { mz: Tolerance::parse("ppm:20").unwrap(), ..Tolerances::default() };
+        assert_eq!(DiffReport::build(&sources, &ppm).comparisons[1].ms1.columns["mz"].mismatches, 0);
+    }
+    
+    #[test]
+    fn frame_checksums_are_recorded_during_loading() {
+        let run = SyntheticRun::small();
+        let dir = run.write_temp().unwrap();
+        let options = LoadOptions::default();
+        let original = run_strategy::<f32>("original", dir.path(), &options).unwrap();
+        let v5 = run_strategy::<f32>("v5_fixed", dir.path(), &options).unwrap();
+        
+        assert_eq!(original.frame_checksums.len(), run.frames.len());
+        assert_eq!(original.frame_checksums, v5.frame_checksums);
+        assert_eq!(original.frame_checksums, FrameChecksums::from_raw(&original));
+        assert!(checksum::bisect(&original, &v5).is_empty());
+        
+        let summary = dir.path().join("summary.txt");
+        original.save_summary(summary.to_str().unwrap()).unwrap();
+        let summary = std::fs::read_to_string(summary).unwrap();
+        let ms2_datasets: usize = original.frame_checksums.frames.iter().map(|f| f.ms2.len()).sum();
+        assert_eq!(summary.lines().filter(|l| l.starts_with("Frame ") && l.contains("hash: ")).count(), run.ms1_frames().count() + ms2_datasets);
+        
+        let skipped = LoadOptions { skip_frame_checksums: true, ..LoadOptions::default() };
+        assert!(run_strategy::<f32>("v5_fixed", dir.path(), &skipped).unwrap().frame_checksums.is_empty());
+    }
+    
+    #[test]
+    fn bisection_pinpoints_diverging_frames_scans_and_windows() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let options = LoadOptions::default();
+        let original = run_strategy::<f32>("original", dir.path(), &options).unwrap();
+        
+        let mut perturbed = original.clone();
+        let ms1_point = perturbed.ms1_data.len() / 2;
+        perturbed.ms1_data.intensity_values[ms1_point] += 1;
+        let (bounds, window) = &mut perturbed.ms2_windows[1];
+        window.mz_values[0] += 0.5;
+        let ms2_frame = (window.frame_indices[0], window.scan_indices[0], key_from_bounds(*bounds));
+        let ms1_frame = (perturbed.ms1_data.frame_indices[ms1_point], perturbed.ms1_data.scan_indices[ms1_point]);
+        perturbed.refresh_frame_checksums();
+        
+        let divergences = checksum::bisect(&original, &perturbed);
+        let mut found: Vec<(u32, checksum::Dataset, Vec<u32>)> = divergences.iter()
+            .flat_map(|f| f.datasets.iter().map(move |d| (f.index, d.dataset, d.scans.clone())))
+            .collect();
+        found.sort();
+        let mut expected = vec![
+            (ms1_frame.0, checksum::Dataset::Ms1, vec![ms1_frame.1]),
+            (ms2_frame.0, checksum::Dataset::Ms2(ms2_frame.2), vec![ms2_frame.1]),
+        ];
+        expected.sort();
+        assert_eq!(found, expected);
+        
+        let mut frames = [ms1_frame.0, ms2_frame.0];
+        frames.sort();
+        assert_eq!(checksum::reproducer_frames(&divergences), format!("{},{}", frames[0], frames[1]));
+        
+        // 没有保存校验和的旧输出：从数据重新计算后得到相同结果
+        let mut legacy = perturbed.clone();
+        legacy.frame_checksums = FrameChecksums::default();
+        assert_eq!(checksum::bisect(&original, &legacy), divergences);
+        
+        let sources = [("original".to_string(), original), ("perturbed".to_string(), perturbed)];
+        let report = DiffReport::build(&sources, &Tolerances::default());
+        assert_eq!(report.comparisons[0].diverging_frames, divergences);
+    }
+    
+    #[test]
+    fn tolerances_parse_and_apply() {
+        assert_eq!(Tolerance::parse("exact"), Ok(Tolerance::Exact));
+        assert_eq!(Tolerance::parse("abs:1e-6"), Ok(Tolerance::Absolute(1e-6)));
+        assert_eq!(Tolerance::parse("ulp:2"), Ok(Tolerance::Ulp(2)));
+        assert!(Tolerance::parse("ppm:-1").is_err());
+        assert!(Tolerance::parse("abs").is_err());
+        assert!(Tolerance::parse("fuzzy:1").is_err());
+        
+        let next = f32::from_bits(500.0f32.to_bits() + 1);
+        assert!(!Tolerance::Exact.accepts(500.0f32, next));
+        assert!(Tolerance::Ulp(1).accepts(500.0f32, next));
+        let tiny = f32::from_bits(1);
+        assert_eq!((-0.0f32).ulps(0.0), 0);
+        assert_eq!((-tiny).ulps(tiny), 2);
+        assert!(Tolerance::Ppm(1.0).accepts(1000.0f64, 1000.0009));
+        assert!(!Tolerance::Ppm(1.0).accepts(1000.0f64, 1000.0011));
+        assert!(Tolerance::Relative(0.1).accepts(100u32, 110));
+        assert!(Tolerance::Exact.accepts(f32::NAN, f32::NAN));
+        assert!(!Tolerance::Absolute(1.0).accepts(f32::NAN, 1.0));
+    }
+    
+    #[test]
+    fn rejects_inverted_isolation_windows() {
+        let mut run = SyntheticRun::small();
+        run.windows[0].isolation_width = -25.0;
+        let dir = run.write_temp().unwrap();
+        let options = LoadOptions::default();
+        
+        let err = original_version::read_timstof_data_original::<f32>(dir.path(), &options).unwrap_err();
+        assert!(err.to_string().contains("inverted"), "{}", err);
+        assert!(v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).is_err());
+    }
+    
+    #[test]
+    fn metadata_and_frame_table_match_synthetic_run() {
+        let run = SyntheticRun::small();
+        let dir = run.write_temp().unwrap();
+        let options = LoadOptions { mz_storage: MzStorage::TofOnly, ..LoadOptions::default() };
+        let data = v5_fixed::read_timstof_data_v5_fixed::<f32>(dir.path(), &options).unwrap();
+        
+        let meta = &data.metadata;
+        assert_eq!(meta.frame_count, run.frames.len());
+        assert_eq!(meta.ms1_frame_count, run.ms1_frames().count());
+        assert_eq!(meta.ms2_frame_count, run.ms2_frames().count());
+        assert_eq!(meta.mz_acquisition_range, Some(run.mz_range));
+        assert_eq!(meta.polarity.as_deref(), Some("+"));
+        assert_eq!(meta.calibration.mz_calibration.len(), 1);
+        
+        assert_eq!(data.frame_table.len(), run.frames.len());
+        for (row, frame) in data.frame_table.rows.iter().zip(&run.frames) {
+            assert_eq!(row.index, frame.id);
+            assert_eq!(row.ms_level, if frame.is_ms1() { 1 } else { 2 });
+            assert_eq!(row.num_scans, frame.num_scans);
+            assert_eq!(row.num_peaks as usize, frame.peaks.len());
+            assert_eq!(row.max_intensity, frame.peaks.iter().map(|p| p.intensity).max().unwrap());
+            assert_eq!(row.window_group, frame.window_group.unwrap_or(0));
+        }
+    }
+}
+
 // Cargo.toml 依赖：
 /*
 [dependencies]
@@ -742,6 +2477,21 @@ serde_json = "1.0"
 bincode = "1.3"
 sha2 = "0.10"
 chrono = "0.4"
+timstof-common = { path = "../timstof_common" }
+rusqlite = { version = "0.31", features = ["bundled"] }
+# 内存预算模式：MS2 窗口溢写到临时列式文件
+bytemuck = "1.14"
+tempfile = "3"
+# 压缩的二进制输出（--compress）
+zstd = "0.13"
+lz4_flex = "0.11"
+
+# 可选：frame-table 的 Parquet 输出
+arrow-array = { version = "53", optional = true }
+parquet = { version = "53", optional = true, default-features = false, features = ["arrow", "snap"] }
+
+[features]
+parquet = ["dep:parquet", "dep:arrow-array"]
 
 [profile.release]
 opt-level = 3
@@ -0,0 +1,186 @@
+// ============= analysis.tdf 采集元数据 =============
+// timsrust 的 MetadataReader 只提供换算器，这里直接读 SQLite 表补齐运行信息
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use rusqlite::{Connection, OpenFlags, types::ValueRef};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationInfo {
+    // MzCalibration 表的每一行（列名 -> 数值）
+    pub mz_calibration: Vec<BTreeMap<String, f64>>,
+    // TimsCalibration 表的每一行（列名 -> 数值）
+    pub tims_calibration: Vec<BTreeMap<String, f64>>,
+    pub digitizer_num_samples: Option<u32>,
+    pub tims_compression_type: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunMetadata {
+    pub instrument_name: Option<String>,
+    pub instrument_serial_number: Option<String>,
+    pub acquisition_software: Option<String>,
+    pub acquisition_date_time: Option<String>,
+    pub method_name: Option<String>,
+    pub sample_name: Option<String>,
+    // "+"、"-" 或 "mixed"
+    pub polarity: Option<String>,
+    pub mz_acquisition_range: Option<(f64, f64)>,
+    pub mobility_acquisition_range: Option<(f64, f64)>,
+    pub frame_count: usize,
+    pub ms1_frame_count: usize,
+    pub ms2_frame_count: usize,
+    pub other_frame_count: usize,
+    pub first_frame_rt_s: f64,
+    pub last_frame_rt_s: f64,
+    pub gradient_length_min: f64,
+    pub calibration: CalibrationInfo,
+    // GlobalMetadata 表原样保留，便于查找上面没有单独列出的键
+    pub global_metadata: BTreeMap<String, String>,
+}
+
+impl RunMetadata {
+    // 读取 <d_folder>/analysis.tdf
+    pub fn read(d_folder: &Path) -> Result<Self, Box<dyn Error>> {
+        let tdf_path = d_folder.join("analysis.tdf");
+        let conn = Connection::open_with_flags(&tdf_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        let global_metadata = read_global_metadata(&conn)?;
+        let get = |key: &str| global_metadata.get(key).cloned();
+        let get_f64 = |key: &str| global_metadata.get(key).and_then(|v| v.parse::<f64>().ok());
+        let range = |lower: &str, upper: &str| match (get_f64(lower), get_f64(upper)) {
+            (Some(lo), Some(hi)) => Some((lo, hi)),
+            _ => None,
+        };
+
+        let mut metadata = RunMetadata {
+            instrument_name: get("InstrumentName"),
+            instrument_serial_number: get("InstrumentSerialNumber"),
+            acquisition_software: match (get("AcquisitionSoftware"), get("AcquisitionSoftwareVersion")) {
+                (Some(name), Some(version)) => Some(format!("{} {}", name, version)),
+                (name, version) => name.or(version),
+            },
+            acquisition_date_time: get("AcquisitionDateTime"),
+            method_name: get("MethodName").or_else(|| get("Method")),
+            sample_name: get("SampleName"),
+            mz_acquisition_range: range("MzAcqRangeLower", "MzAcqRangeUpper"),
+            mobility_acquisition_range: range("OneOverK0AcqRangeLower", "OneOverK0AcqRangeUpper"),
+            calibration: CalibrationInfo {
+                mz_calibration: read_numeric_table(&conn, "MzCalibration")?,
+                tims_calibration: read_numeric_table(&conn, "TimsCalibration")?,
+                digitizer_num_samples: get_f64("DigitizerNumSamples").map(|v| v as u32),
+                tims_compression_type: get_f64("TimsCompressionType").map(|v| v as u32),
+            },
+            ..Default::default()
+        };
+
+        // Frames.MsMsType：0 = MS1，8 = ddaPASEF，9 = diaPASEF
+        let mut stmt = conn.prepare("SELECT MsMsType, COUNT(*), MIN(Time), MAX(Time) FROM Frames GROUP BY MsMsType")?;
+        let mut rows = stmt.query([])?;
+        let mut first_rt = f64::INFINITY;
+        let mut last_rt = f64::NEG_INFINITY;
+        while let Some(row) = rows.next()? {
+            let msms_type: i64 = row.get(0)?;
+            let count = row.get::<_, i64>(1)? as usize;
+            match msms_type {
+                0 => metadata.ms1_frame_count += count,
+                8 | 9 => metadata.ms2_frame_count += count,
+                _ => metadata.other_frame_count += count,
+            }
+            metadata.frame_count += count;
+            first_rt = first_rt.min(row.get(2)?);
+            last_rt = last_rt.max(row.get(3)?);
+        }
+        if metadata.frame_count > 0 {
+            metadata.first_frame_rt_s = first_rt;
+            metadata.last_frame_rt_s = last_rt;
+            metadata.gradient_length_min = (last_rt - first_rt) / 60.0;
+        }
+
+        let polarities: Vec<String> = conn.prepare("SELECT DISTINCT Polarity FROM Frames")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<_, _>>()?;
+        metadata.polarity = match polarities.len() {
+            0 => None,
+            1 => Some(polarities[0].clone()),
+            _ => Some("mixed".to_string()),
+        };
+
+        Ok(metadata)
+    }
+}
+
+impl fmt::Display for RunMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        writeln!(f, "Instrument: {} (S/N {})", text(&self.instrument_name), text(&self.instrument_serial_number))?;
+        writeln!(f, "Acquisition Software: {}", text(&self.acquisition_software))?;
+        writeln!(f, "Acquisition Date: {}", text(&self.acquisition_date_time))?;
+        writeln!(f, "Method: {}", text(&self.method_name))?;
+        writeln!(f, "Sample: {}", text(&self.sample_name))?;
+        writeln!(f, "Polarity: {}", text(&self.polarity))?;
+        match self.mz_acquisition_range {
+            Some((lo, hi)) => writeln!(f, "m/z Acquisition Range: {:.4} - {:.4}", lo, hi)?,
+            None => writeln!(f, "m/z Acquisition Range: -")?,
+        }
+        match self.mobility_acquisition_range {
+            Some((lo, hi)) => writeln!(f, "1/K0 Acquisition Range: {:.4} - {:.4}", lo, hi)?,
+            None => writeln!(f, "1/K0 Acquisition Range: -")?,
+        }
+        writeln!(f, "Frames: {} (MS1 {}, MS2 {}, other {})",
+                 self.frame_count, self.ms1_frame_count, self.ms2_frame_count, self.other_frame_count)?;
+        writeln!(f, "Gradient Length: {:.2} min ({:.1}s - {:.1}s)",
+                 self.gradient_length_min, self.first_frame_rt_s, self.last_frame_rt_s)?;
+        writeln!(f, "m/z Calibrations: {}", self.calibration.mz_calibration.len())?;
+        write!(f, "TIMS Calibrations: {}", self.calibration.tims_calibration.len())
+    }
+}
+
+fn read_global_metadata(conn: &Connection) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT Key, Value FROM GlobalMetadata")?;
+    let mut rows = stmt.query([])?;
+    let mut map = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let key: String = row.get(0)?;
+        let value = match row.get_ref(1)? {
+            ValueRef::Null => continue,
+            ValueRef::Integer(v) => v.to_string(),
+            ValueRef::Real(v) => v.to_string(),
+            ValueRef::Text(v) | ValueRef::Blob(v) => String::from_utf8_lossy(v).into_owned(),
+        };
+        map.insert(key, value);
+    }
+    Ok(map)
+}
+
+// 读取只含数值列的校准表；表不存在时返回空（老版本采集软件没有 TimsCalibration）
+fn read_numeric_table(conn: &Connection, table: &str) -> Result<Vec<BTreeMap<String, f64>>, Box<dyn Error>> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {}", table))?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut rows = stmt.query([])?;
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut entry = BTreeMap::new();
+        for (i, name) in columns.iter().enumerate() {
+            let value = match row.get_ref(i)? {
+                ValueRef::Integer(v) => v as f64,
+                ValueRef::Real(v) => v,
+                _ => continue,
+            };
+            entry.insert(name.clone(), value);
+        }
+        result.push(entry);
+    }
+    Ok(result)
+}
@@ -0,0 +1,655 @@
+// ============= 采集后 m/z 重新校准 =============
+// 用已知校准物（或已鉴定肽段）的理论 m/z 拟合 ppm 误差模型：
+//   err_ppm = c0 + c1*u + [c2*u^2] + [c3*r]
+// 其中 u 是标准化后的 TOF 索引或 m/z，r 是标准化后的保留时间。
+// 校正：mz' = mz / (1 + err_ppm * 1e-6)
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use rayon::prelude::*;
+
+use crate::{Precision, TimsTOFData, TimsTOFRawData};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationDomain {
+    // 以TOF索引为自变量（需要保留 tof_indices）
+    Tof,
+    // 以换算后的 m/z 为自变量
+    Mz,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationModel {
+    Linear,
+    Quadratic,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecalibrationConfig {
+    pub domain: CalibrationDomain,
+    pub model: CalibrationModel,
+    // 是否加入随保留时间线性漂移的项
+    pub rt_dependent: bool,
+    // 校准物与峰匹配的容差
+    pub match_tolerance_ppm: f64,
+    // 校准物带保留时间时的匹配容差（分钟）
+    pub rt_tolerance_min: f64,
+    // 至少需要的匹配观测数
+    pub min_observations: usize,
+}
+
+impl Default for RecalibrationConfig {
+    fn default() -> Self {
+        Self {
+            domain: CalibrationDomain::Mz,
+            model: CalibrationModel::Linear,
+            rt_dependent: false,
+            match_tolerance_ppm: 20.0,
+            rt_tolerance_min: 1.0,
+            min_observations: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Calibrant {
+    pub mz: f64,
+    // 已鉴定肽段可提供保留时间以减少误匹配；锁质量类校准物留空
+    pub rt_min: Option<f64>,
+}
+
+// 读取校准物列表：每行 "m/z[,rt_min]"，支持逗号/制表符/空格分隔，# 开头为注释，允许一行表头
+pub fn load_calibrants(path: &Path) -> Result<Vec<Calibrant>, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut calibrants = Vec::new();
+    let mut header_skipped = false;
+
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split([',', '\t', ' ']).filter(|f| !f.is_empty());
+        let mz_field = fields.next().unwrap_or_default();
+        let mz: f64 = match mz_field.parse() {
+            Ok(v) => v,
+            Err(_) if calibrants.is_empty() && !header_skipped => {
+                header_skipped = true;
+                continue;
+            }
+            Err(_) => {
+                return Err(format!("{}:{}: invalid m/z {:?}", path.display(), line_no + 1, mz_field).into());
+            }
+        };
+        let rt_min = match fields.next() {
+            Some(f) => Some(f.parse::<f64>().map_err(|_| {
+                format!("{}:{}: invalid retention time {:?}", path.display(), line_no + 1, f)
+            })?),
+            None => None,
+        };
+
+        calibrants.push(Calibrant { mz, rt_min });
+    }
+
+    Ok(calibrants)
+}
+
+// 单个匹配观测
+#[derive(Debug, Clone, Copy)]
+struct Observation {
+    x: f64,
+    rt_min: f64,
+    observed_mz: f64,
+    reference_mz: f64,
+}
+
+impl Observation {
+    fn ppm_error(&self, mz: f64) -> f64 {
+        (mz - self.reference_mz) / self.reference_mz * 1e6
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PpmStats {
+    pub count: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub mean_abs: f64,
+    pub std_dev: f64,
+}
+
+impl PpmStats {
+    fn from_errors(errors: &[f64]) -> Self {
+        if errors.is_empty() {
+            return Self::default();
+        }
+        let n = errors.len() as f64;
+        let mean = errors.iter().sum::<f64>() / n;
+        let mean_abs = errors.iter().map(|e| e.abs()).sum::<f64>() / n;
+        let std_dev = (errors.iter().map(|e| (e - mean).powi(2)).sum::<f64>() / n).sqrt();
+        Self {
+            count: errors.len(),
+            mean,
+            median: median(errors),
+            mean_abs,
+            std_dev,
+        }
+    }
+}
+
+impl fmt::Display for PpmStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "n={} mean={:+.3} median={:+.3} mean|err|={:.3} sd={:.3} ppm",
+               self.count, self.mean, self.median, self.mean_abs, self.std_dev)
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Recalibration {
+    pub domain: CalibrationDomain,
+    pub model: CalibrationModel,
+    pub rt_dependent: bool,
+    coefficients: Vec<f64>,
+    // 自变量标准化参数，保证正规方程数值稳定
+    x_center: f64,
+    x_scale: f64,
+    rt_center: f64,
+    rt_scale: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecalibrationReport {
+    pub recalibration: Recalibration,
+    pub calibrants_matched: usize,
+    pub observations: usize,
+    pub rejected_outliers: usize,
+    pub before: PpmStats,
+    pub after: PpmStats,
+}
+
+impl fmt::Display for RecalibrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Recalibration ({:?} domain, {:?} model{})",
+                 self.recalibration.domain, self.recalibration.model,
+                 if self.recalibration.rt_dependent { ", RT-dependent" } else { "" })?;
+        writeln!(f, "  Calibrants matched: {}", self.calibrants_matched)?;
+        writeln!(f, "  Observations used: {} ({} outliers rejected)",
+                 self.observations, self.rejected_outliers)?;
+        writeln!(f, "  Coefficients: {:?}", self.recalibration.coefficients)?;
+        writeln!(f, "  Before: {}", self.before)?;
+        write!(f, "  After:  {}", self.after)
+    }
+}
+
+impl Recalibration {
+    // 在给定数据（通常是MS1）上拟合校准模型
+    pub fn fit<F: Precision>(
+        data: &TimsTOFData<F>,
+        calibrants: &[Calibrant],
+        config: &RecalibrationConfig,
+    ) -> Result<RecalibrationReport, Box<dyn Error>> {
+        let observations = match_calibrants(data, calibrants, config)?;
+        let calibrants_matched = {
+            let mut refs: Vec<u64> = observations.iter().map(|o| o.reference_mz.to_bits()).collect();
+            refs.sort_unstable();
+            refs.dedup();
+            refs.len()
+        };
+
+        let mut recalibration = Recalibration::unfitted(&observations, config);
+        let n_coefficients = recalibration.n_coefficients();
+        let min_observations = config.min_observations.max(n_coefficients + 1);
+        if observations.len() < min_observations {
+            return Err(format!("only {} calibrant observations matched within {} ppm, need at least {}",
+                               observations.len(), config.match_tolerance_ppm, min_observations).into());
+        }
+
+        recalibration.coefficients = recalibration.solve(&observations)
+            .ok_or("calibration fit is singular; try a simpler model")?;
+
+        // 一轮基于MAD的离群点剔除后重新拟合
+        let residuals: Vec<f64> = observations.iter()
+            .map(|o| o.ppm_error(recalibration.correct_x(o.observed_mz, o.x, o.rt_min)))
+            .collect();
+        let center = median(&residuals);
+        let mad = median(&residuals.iter().map(|r| (r - center).abs()).collect::<Vec<_>>());
+        let limit = (4.0 * 1.4826 * mad).max(1e-3);
+        let inliers: Vec<Observation> = observations.iter().zip(&residuals)
+            .filter(|(_, r)| (*r - center).abs() <= limit)
+            .map(|(o, _)| *o)
+            .collect();
+        let mut rejected_outliers = observations.len() - inliers.len();
+        let used = match recalibration.solve(&inliers) {
+            Some(coefficients) if rejected_outliers > 0 && inliers.len() >= min_observations => {
+                recalibration.coefficients = coefficients;
+                inliers
+            }
+            _ => {
+                rejected_outliers = 0;
+                observations
+            }
+        };
+
+        let before: Vec<f64> = used.iter().map(|o| o.ppm_error(o.observed_mz)).collect();
+        let after: Vec<f64> = used.iter()
+            .map(|o| o.ppm_error(recalibration.correct_x(o.observed_mz, o.x, o.rt_min)))
+            .collect();
+
+        Ok(RecalibrationReport {
+            recalibration,
+            calibrants_matched,
+            observations: used.len(),
+            rejected_outliers,
+            before: PpmStats::from_errors(&before),
+            after: PpmStats::from_errors(&after),
+        })
+    }
+
+    fn unfitted(observations: &[Observation], config: &RecalibrationConfig) -> Self {
+        let (x_center, x_scale) = center_and_scale(observations.iter().map(|o| o.x));
+        let (rt_center, rt_scale) = center_and_scale(observations.iter().map(|o| o.rt_min));
+        Self {
+            domain: config.domain,
+            model: config.model,
+            rt_dependent: config.rt_dependent,
+            coefficients: Vec::new(),
+            x_center,
+            x_scale,
+            rt_center,
+            rt_scale,
+        }
+    }
+
+    fn n_coefficients(&self) -> usize {
+        let polynomial = match self.model {
+            CalibrationModel::Linear => 2,
+            CalibrationModel::Quadratic => 3,
+        };
+        polynomial + self.rt_dependent as usize
+    }
+
+    fn design_row(&self, x: f64, rt_min: f64, row: &mut Vec<f64>) {
+        let u = (x - self.x_center) / self.x_scale;
+        row.clear();
+        row.push(1.0);
+        row.push(u);
+        if self.model == CalibrationModel::Quadratic {
+            row.push(u * u);
+        }
+        if self.rt_dependent {
+            row.push((rt_min - self.rt_center) / self.rt_scale);
+        }
+    }
+
+    // 最小二乘：解正规方程 (A^T A) c = A^T y
+    fn solve(&self, observations: &[Observation]) -> Option<Vec<f64>> {
+        let k = self.n_coefficients();
+        let mut ata = vec![vec![0.0f64; k]; k];
+        let mut aty = vec![0.0f64; k];
+        let mut row = Vec::with_capacity(k);
+
+        for o in observations {
+            self.design_row(o.x, o.rt_min, &mut row);
+            let y = o.ppm_error(o.observed_mz);
+            for i in 0..k {
+                aty[i] += row[i] * y;
+                for j in 0..k {
+                    ata[i][j] += row[i] * row[j];
+                }
+            }
+        }
+
+        solve_linear_system(ata, aty)
+    }
+
+    #[inline]
+    pub fn predict_ppm(&self, x: f64, rt_min: f64) -> f64 {
+        let u = (x - self.x_center) / self.x_scale;
+        let mut err = self.coefficients[0] + self.coefficients[1] * u;
+        let mut next = 2;
+        if self.model == CalibrationModel::Quadratic {
+            err += self.coefficients[next] * u * u;
+            next += 1;
+        }
+        if self.rt_dependent {
+            err += self.coefficients[next] * (rt_min - self.rt_center) / self.rt_scale;
+        }
+        err
+    }
+
+    #[inline]
+    fn correct_x(&self, mz: f64, x: f64, rt_min: f64) -> f64 {
+        mz / (1.0 + self.predict_ppm(x, rt_min) * 1e-6)
+    }
+
+    // 校正单个峰；Mz 域时 tof 参数被忽略
+    #[inline]
+    pub fn correct(&self, mz: f64, tof: u32, rt_min: f64) -> f64 {
+        let x = match self.domain {
+            CalibrationDomain::Tof => tof as f64,
+            CalibrationDomain::Mz => mz,
+        };
+        self.correct_x(mz, x, rt_min)
+    }
+
+    // 对已加载的数据原地校正 m/z
+    pub fn apply<F: Precision>(&self, data: &mut TimsTOFData<F>) -> Result<(), Box<dyn Error>> {
+        if data.mz_values.len() != data.len() {
+            return Err("m/z values are missing; convert TOF indices before recalibrating".into());
+        }
+        if self.domain == CalibrationDomain::Tof && data.tof_indices.len() != data.len() {
+            return Err("TOF-domain recalibration needs MzStorage::ConvertedWithTof".into());
+        }
+
+        let tof_indices = &data.tof_indices;
+        let rt_values = &data.rt_values_min;
+        data.mz_values.par_iter_mut().enumerate().for_each(|(i, mz)| {
+            let tof = tof_indices.get(i).copied().unwrap_or(0);
+            *mz = F::from_f64(self.correct(mz.to_f64(), tof, rt_values[i].to_f64()));
+        });
+        Ok(())
+    }
+
+    pub fn apply_raw<F: Precision>(&self, raw: &mut TimsTOFRawData<F>) -> Result<(), Box<dyn Error>> {
+        self.apply(&mut raw.ms1_data)?;
+        for (_, td) in &mut raw.ms2_windows {
+            self.apply(td)?;
+        }
+        raw.refresh_frame_checksums();
+        Ok(())
+    }
+}
+
+fn center_and_scale(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+    if !min.is_finite() || max - min <= f64::EPSILON {
+        return (if min.is_finite() { min } else { 0.0 }, 1.0);
+    }
+    ((min + max) / 2.0, (max - min) / 2.0)
+}
+
+// 高斯消元（部分主元）
+fn solve_linear_system(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..n {
+            let factor = a[row][col] / a[col][col];
+            let (upper, lower) = a.split_at_mut(row);
+            for (target, &source) in lower[0][col..].iter_mut().zip(&upper[col][col..]) {
+                *target -= factor * source;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+// 为每个校准物在每一帧中找容差内最强的峰
+fn match_calibrants<F: Precision>(
+    data: &TimsTOFData<F>,
+    calibrants: &[Calibrant],
+    config: &RecalibrationConfig,
+) -> Result<Vec<Observation>, Box<dyn Error>> {
+    if data.mz_values.len() != data.len() {
+        return Err("m/z values are missing; convert TOF indices before recalibrating".into());
+    }
+    if config.domain == CalibrationDomain::Tof && data.tof_indices.len() != data.len() {
+        return Err("TOF-domain recalibration needs MzStorage::ConvertedWithTof".into());
+    }
+
+    let mz = &data.mz_values;
+    let mut order: Vec<u32> = (0..data.len() as u32).collect();
+    order.par_sort_unstable_by(|&a, &b| mz[a as usize].to_f64().total_cmp(&mz[b as usize].to_f64()));
+
+    let observations = calibrants.par_iter().flat_map_iter(|cal| {
+        let tolerance = cal.mz * config.match_tolerance_ppm * 1e-6;
+        let lo = order.partition_point(|&i| mz[i as usize].to_f64() < cal.mz - tolerance);
+        let hi = order.partition_point(|&i| mz[i as usize].to_f64() <= cal.mz + tolerance);
+
+        let mut best_per_frame: BTreeMap<u32, usize> = BTreeMap::new();
+        for &i in &order[lo..hi] {
+            let i = i as usize;
+            if let Some(rt) = cal.rt_min {
+                if (data.rt_values_min[i].to_f64() - rt).abs() > config.rt_tolerance_min { continue; }
+            }
+            best_per_frame.entry(data.frame_indices[i])
+                .and_modify(|best| {
+                    if data.intensity_values[i] > data.intensity_values[*best] { *best = i; }
+                })
+                .or_insert(i);
+        }
+
+        best_per_frame.into_values().map(move |i| Observation {
+            x: match config.domain {
+                CalibrationDomain::Tof => data.tof_indices[i] as f64,
+                CalibrationDomain::Mz => mz[i].to_f64(),
+            },
+            rt_min: data.rt_values_min[i].to_f64(),
+            observed_mz: mz[i].to_f64(),
+            reference_mz: cal.mz,
+        })
+    }).collect();
+
+    Ok(observations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAMES: u32 = 40;
+    const CALIBRANTS: usize = 12;
+
+    fn rt_of(frame: u32) -> f64 {
+        10.0 + frame as f64 * 0.5
+    }
+
+    fn reference_mz(calibrant: usize) -> f64 {
+        300.0 + 70.0 * calibrant as f64
+    }
+
+    fn calibrants() -> Vec<Calibrant> {
+        (0..CALIBRANTS).map(|i| Calibrant { mz: reference_mz(i), rt_min: None }).collect()
+    }
+
+    // 每帧每个校准物一个峰，peak(校准物序号, 帧号) 给出其 (m/z, TOF)；
+    // 旁边另有一个更弱的峰（+15 ppm），匹配时应取最强的峰
+    fn run(peak: impl Fn(usize, u32) -> (f64, u32)) -> TimsTOFData<f64> {
+        let mut data = TimsTOFData::new();
+        for frame in 0..FRAMES {
+            for i in 0..CALIBRANTS {
+                let (mz, tof) = peak(i, frame);
+                for (mz, tof, intensity) in [(mz, tof, 1000), (mz * (1.0 + 15e-6), tof + 1, 10)] {
+                    data.rt_values_min.push(rt_of(frame));
+                    data.mobility_values.push(0.9);
+                    data.mz_values.push(mz);
+                    data.intensity_values.push(intensity);
+                    data.frame_indices.push(frame);
+                    data.scan_indices.push(100);
+                    data.tof_indices.push(tof);
+                }
+            }
+        }
+        data
+    }
+
+    // 校准物的 TOF 均匀分布在 [20000, 185000]
+    fn tof_of(calibrant: usize) -> u32 {
+        20_000 + 15_000 * calibrant as u32
+    }
+
+    // 与 Recalibration 相同的标准化：按匹配到的自变量范围映射到 [-1, 1]
+    fn normalized(value: f64, min: f64, max: f64) -> f64 {
+        (value - (min + max) / 2.0) / ((max - min) / 2.0)
+    }
+
+    fn normalized_tof(tof: u32) -> f64 {
+        normalized(tof as f64, tof_of(0) as f64, tof_of(CALIBRANTS - 1) as f64)
+    }
+
+    fn normalized_rt(frame: u32) -> f64 {
+        normalized(rt_of(frame), rt_of(0), rt_of(FRAMES - 1))
+    }
+
+    fn assert_coefficients(actual: &[f64], expected: &[f64], tolerance: f64) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < tolerance, "coefficients {:?}, expected {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn recovers_a_quadratic_tof_drift_with_rt_term() {
+        let c = [3.0, -4.0, 2.5, 1.5];
+        let drift = |tof: u32, frame: u32| {
+            let u = normalized_tof(tof);
+            c[0] + c[1] * u + c[2] * u * u + c[3] * normalized_rt(frame)
+        };
+        let data = run(|i, frame| (reference_mz(i) * (1.0 + drift(tof_of(i), frame) * 1e-6), tof_of(i)));
+        let config = RecalibrationConfig {
+            domain: CalibrationDomain::Tof,
+            model: CalibrationModel::Quadratic,
+            rt_dependent: true,
+            ..RecalibrationConfig::default()
+        };
+
+        let report = Recalibration::fit(&data, &calibrants(), &config).unwrap();
+        assert_coefficients(&report.recalibration.coefficients, &c, 1e-6);
+        assert_eq!(report.calibrants_matched, CALIBRANTS);
+        assert_eq!(report.observations, CALIBRANTS * FRAMES as usize);
+        assert_eq!(report.rejected_outliers, 0);
+        assert!(report.before.mean_abs > 1.0, "{}", report.before);
+        assert!(report.after.mean_abs < 1e-6, "{}", report.after);
+
+        // 校正后的校准物峰回到理论 m/z
+        let mut corrected = data.clone();
+        report.recalibration.apply(&mut corrected).unwrap();
+        for (k, &mz) in corrected.mz_values.iter().enumerate().step_by(2) {
+            let reference = reference_mz(k / 2 % CALIBRANTS);
+            assert!(((mz - reference) / reference * 1e6).abs() < 1e-6, "peak {}: {} vs {}", k, mz, reference);
+        }
+    }
+
+    #[test]
+    fn recovers_a_quadratic_mz_drift() {
+        // Mz 域以观测 m/z 为自变量：固定观测值，由误差反推理论值
+        let observed = |i: usize| 300.0 + 70.0 * i as f64;
+        let c = [-2.0, 5.0, -1.5];
+        let drift = |mz: f64| {
+            let u = normalized(mz, observed(0), observed(CALIBRANTS - 1));
+            c[0] + c[1] * u + c[2] * u * u
+        };
+        let data = run(|i, _| (observed(i), 0));
+        let calibrants: Vec<Calibrant> = (0..CALIBRANTS)
+            .map(|i| Calibrant { mz: observed(i) / (1.0 + drift(observed(i)) * 1e-6), rt_min: None })
+            .collect();
+        let config = RecalibrationConfig { model: CalibrationModel::Quadratic, ..RecalibrationConfig::default() };
+
+        let report = Recalibration::fit(&data, &calibrants, &config).unwrap();
+        assert_coefficients(&report.recalibration.coefficients, &c, 1e-6);
+        assert!(report.after.mean_abs < 1e-6, "{}", report.after);
+    }
+
+    #[test]
+    fn rejects_outliers_before_refitting() {
+        let c = [2.0, -3.0];
+        // 确定性的 ±0.5 ppm 噪声；校准物 5 在前 8 帧偏离 +12 ppm
+        let noise = |i: usize, frame: u32| ((i * 7919 + frame as usize * 104_729) % 1000) as f64 / 1000.0 - 0.5;
+        let outlier = |i: usize, frame: u32| if i == 5 && frame < 8 { 12.0 } else { 0.0 };
+        let data = run(|i, frame| {
+            let err = c[0] + c[1] * normalized_tof(tof_of(i)) + noise(i, frame) + outlier(i, frame);
+            (reference_mz(i) * (1.0 + err * 1e-6), tof_of(i))
+        });
+        let config = RecalibrationConfig { domain: CalibrationDomain::Tof, ..RecalibrationConfig::default() };
+
+        let report = Recalibration::fit(&data, &calibrants(), &config).unwrap();
+        assert_eq!(report.rejected_outliers, 8);
+        assert_eq!(report.observations, CALIBRANTS * FRAMES as usize - 8);
+        assert_coefficients(&report.recalibration.coefficients, &c, 0.1);
+        assert!(report.after.mean_abs < 0.5, "{}", report.after);
+    }
+
+    #[test]
+    fn tof_domain_needs_retained_tof_indices() {
+        let data = run(|i, _| (reference_mz(i) * (1.0 + 2e-6), tof_of(i)));
+        let tof = RecalibrationConfig { domain: CalibrationDomain::Tof, ..RecalibrationConfig::default() };
+        let recalibration = Recalibration::fit(&data, &calibrants(), &tof).unwrap().recalibration;
+        assert_eq!(recalibration.domain, CalibrationDomain::Tof);
+
+        // Converted：没有 TOF 列
+        let mut converted = data.clone();
+        converted.tof_indices.clear();
+        let err = Recalibration::fit(&converted, &calibrants(), &tof).unwrap_err();
+        assert!(err.to_string().contains("MzStorage::ConvertedWithTof"), "{}", err);
+        assert!(recalibration.apply(&mut converted).is_err());
+        assert!(Recalibration::fit(&converted, &calibrants(), &RecalibrationConfig::default()).is_ok());
+
+        // TofOnly：没有 m/z 列，任何域都不能拟合或校正
+        let mut tof_only = data.clone();
+        tof_only.mz_values.clear();
+        for config in [tof, RecalibrationConfig::default()] {
+            let err = Recalibration::fit(&tof_only, &calibrants(), &config).unwrap_err();
+            assert!(err.to_string().contains("m/z values are missing"), "{}", err);
+        }
+        assert!(recalibration.apply(&mut tof_only).is_err());
+    }
+
+    #[test]
+    fn too_few_observations_is_an_error() {
+        let data = run(|i, _| (reference_mz(i), tof_of(i)));
+        let config = RecalibrationConfig { min_observations: 100, ..RecalibrationConfig::default() };
+        let err = Recalibration::fit(&data, &calibrants()[..2], &config).unwrap_err();
+        assert_eq!(err.to_string(), "only 80 calibrant observations matched within 20 ppm, need at least 100");
+
+        // 没有峰落在容差内
+        let far = [Calibrant { mz: 5000.0, rt_min: None }];
+        assert!(Recalibration::fit(&data, &far, &RecalibrationConfig::default()).is_err());
+    }
+
+    #[test]
+    fn calibrant_lists_skip_one_header_and_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("calibrants.csv");
+        let load = |text: &str| {
+            std::fs::write(&path, text).unwrap();
+            load_calibrants(&path)
+        };
+
+        let calibrants = load("# lock masses\nmz,rt\n\n445.12003\n  # inline comment\n622.02896, 12.5\n922.0098\t30\n").unwrap();
+        let parsed: Vec<(f64, Option<f64>)> = calibrants.iter().map(|c| (c.mz, c.rt_min)).collect();
+        assert_eq!(parsed, [(445.12003, None), (622.02896, Some(12.5)), (922.0098, Some(30.0))]);
+
+        // 只跳过一行表头；错误带行号
+        let err = load("mz\nrt\n500.0\n").unwrap_err();
+        assert!(err.to_string().ends_with(":2: invalid m/z \"rt\""), "{}", err);
+        let err = load("# comment\n500.0\nabc\n").unwrap_err();
+        assert!(err.to_string().ends_with(":3: invalid m/z \"abc\""), "{}", err);
+        let err = load("500.0\n600.0,late\n").unwrap_err();
+        assert!(err.to_string().ends_with(":2: invalid retention time \"late\""), "{}", err);
+    }
+}
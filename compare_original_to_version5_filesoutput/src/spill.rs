@@ -0,0 +1,283 @@
+// ============= MS2 窗口溢写（内存预算模式）与列式窗口文件 =============
+// 设置 LoadOptions::max_memory 后，V5 的聚合线程统计驻留内存的 MS2 窗口缓冲大小，
+// 超出预算时把最大的几个窗口追加写入临时目录中的列式文件并释放其缓冲：
+// 每个窗口一个子目录，每列一个文件，按帧顺序追加原始字节。
+// 加载结束后窗口仍留在磁盘上（SpilledWindows），需要时才逐个读回（预留内存中剩余部分的容量）
+// 再接上剩余部分，因此输出与不设预算时逐位一致，而 MS2 数据任何时候最多只有一个窗口完整驻留内存。
+// LazyRawData 的窗口缓存使用同样的目录格式（write_window / read_window）。
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use bytemuck::Pod;
+use serde::ser::{Error as _, Serialize, SerializeSeq, Serializer};
+use tempfile::TempDir;
+
+use crate::{Precision, TimsTOFData};
+use timstof_common::{window_bounds, WindowKey};
+
+pub struct SpillStore {
+    dir: TempDir,
+    // 每个窗口已写入磁盘的点数
+    spilled: BTreeMap<WindowKey, usize>,
+    bytes_written: u64,
+    spills: usize,
+}
+
+impl SpillStore {
+    // parent 为空时使用系统临时目录（$TMPDIR）
+    pub fn new(parent: Option<&Path>) -> io::Result<Self> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("timstof-spill-");
+        let dir = match parent {
+            Some(parent) => builder.tempdir_in(parent)?,
+            None => builder.tempdir()?,
+        };
+        Ok(SpillStore { dir, spilled: BTreeMap::new(), bytes_written: 0, spills: 0 })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn spills(&self) -> usize {
+        self.spills
+    }
+
+    pub fn spilled_windows(&self) -> usize {
+        self.spilled.len()
+    }
+
+    // 把窗口缓冲追加到该窗口列文件的末尾，并释放缓冲
+    pub fn spill<F: Precision>(&mut self, key: WindowKey, data: &mut TimsTOFData<F>) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let dir = self.dir.path().join(window_dir_name(key));
+        fs::create_dir_all(&dir)?;
+        self.bytes_written += append_columns(&dir, data)?;
+        *self.spilled.entry(key).or_default() += data.len();
+        self.spills += 1;
+        *data = TimsTOFData::new();
+        Ok(())
+    }
+
+    // 已溢写到磁盘的点数（未溢写过的窗口为 0）
+    pub fn spilled_peaks(&self, key: WindowKey) -> usize {
+        self.spilled.get(&key).copied().unwrap_or(0)
+    }
+
+    // 读回窗口已溢写的全部数据（各列额外预留 reserve 个点的容量），文件保留；
+    // 未溢写过的窗口返回 None
+    pub fn read<F: Precision>(&self, key: WindowKey, reserve: usize) -> io::Result<Option<TimsTOFData<F>>> {
+        let Some(&peaks) = self.spilled.get(&key) else {
+            return Ok(None);
+        };
+        read_columns(&self.dir.path().join(window_dir_name(key)), peaks, reserve).map(Some)
+    }
+
+    // 同 read，读完删除其文件
+    pub fn take<F: Precision>(&mut self, key: WindowKey, reserve: usize) -> io::Result<Option<TimsTOFData<F>>> {
+        let data = self.read(key, reserve)?;
+        if self.spilled.remove(&key).is_some() {
+            fs::remove_dir_all(self.dir.path().join(window_dir_name(key)))?;
+        }
+        Ok(data)
+    }
+}
+
+// TimsTOFRawData::ms2_windows 的元素
+type Window<F> = ((f32, f32), TimsTOFData<F>);
+
+// 加载结束时的 MS2 窗口（按输出顺序）：每个窗口由溢写在磁盘上的部分和仍在内存中的尾部组成，
+// window(i) 时才读回并接上尾部。序列化为与 Vec<((f32, f32), TimsTOFData<F>)> 相同的序列，
+// 一次只读回一个窗口。没有溢写目录时所有窗口都完整地在内存中
+pub struct SpilledWindows<F> {
+    store: Option<SpillStore>,
+    tails: Vec<(WindowKey, TimsTOFData<F>)>,
+}
+
+impl<F: Precision> SpilledWindows<F> {
+    pub fn new(store: Option<SpillStore>, tails: Vec<(WindowKey, TimsTOFData<F>)>) -> Self {
+        SpilledWindows { store, tails }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tails.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tails.is_empty()
+    }
+
+    pub fn bounds(&self, index: usize) -> (f32, f32) {
+        window_bounds(self.tails[index].0)
+    }
+
+    // 所有窗口的点数（不读回）
+    pub fn peaks(&self) -> usize {
+        self.tails.iter()
+            .map(|(key, tail)| tail.len() + self.store.as_ref().map_or(0, |store| store.spilled_peaks(*key)))
+            .sum()
+    }
+
+    // 第 index 个窗口的完整数据；未溢写的窗口直接借用内存中的数据
+    pub fn window(&self, index: usize) -> io::Result<Cow<'_, TimsTOFData<F>>> {
+        let (key, tail) = &self.tails[index];
+        match &self.store {
+            Some(store) => match store.read::<F>(*key, tail.len())? {
+                Some(mut spilled) => {
+                    spilled.merge_from(&mut tail.clone());
+                    Ok(Cow::Owned(spilled))
+                }
+                None => Ok(Cow::Borrowed(tail)),
+            },
+            None => Ok(Cow::Borrowed(tail)),
+        }
+    }
+
+    // 把所有窗口读回内存（每读回一个窗口即删除其文件）
+    pub fn into_windows(self) -> io::Result<Vec<Window<F>>> {
+        let SpilledWindows { mut store, tails } = self;
+        tails.into_iter()
+            .map(|(key, mut tail)| {
+                let spilled = match store.as_mut() {
+                    Some(store) => store.take::<F>(key, tail.len())?,
+                    None => None,
+                };
+                let data = match spilled {
+                    Some(mut spilled) => {
+                        spilled.merge_from(&mut tail);
+                        spilled
+                    }
+                    None => tail,
+                };
+                Ok((window_bounds(key), data))
+            })
+            .collect()
+    }
+}
+
+impl<F: Precision> Serialize for SpilledWindows<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for index in 0..self.len() {
+            let data = self.window(index).map_err(S::Error::custom)?;
+            seq.serialize_element(&(self.bounds(index), &*data))?;
+        }
+        seq.end()
+    }
+}
+
+// 窗口目录名：量化键的两个分量
+pub fn window_dir_name((low, high): WindowKey) -> String {
+    format!("{}_{}", low, high)
+}
+
+// 把一个窗口完整写成 dir 下的列文件；先写到同级的唯一临时目录再改名，中断时不会留下不完整的窗口。
+// 多个线程或进程可能同时写同一个窗口（内容相同）：目标已存在即视为成功，后到的临时目录被删除
+pub fn write_window<F: Precision>(dir: &Path, data: &TimsTOFData<F>) -> io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    let parent = dir.parent().unwrap_or(Path::new("."));
+    let partial = tempfile::Builder::new().prefix(".partial-").tempdir_in(parent)?;
+    append_columns(partial.path(), data)?;
+    match fs::rename(partial.path(), dir) {
+        Ok(()) => Ok(()),
+        Err(_) if dir.is_dir() => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// 读取 write_window 写出的窗口；点数由始终填充的强度列决定
+pub fn read_window<F: Precision>(dir: &Path) -> io::Result<TimsTOFData<F>> {
+    let peaks = match fs::metadata(dir.join("intensity_values")) {
+        Ok(meta) => meta.len() as usize / std::mem::size_of::<u32>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    read_columns(dir, peaks, 0)
+}
+
+fn append_columns<F: Precision>(dir: &Path, data: &TimsTOFData<F>) -> io::Result<u64> {
+    Ok(append_column(dir, "rt_values_min", &data.rt_values_min)?
+        + append_column(dir, "mobility_values", &data.mobility_values)?
+        + append_column(dir, "mz_values", &data.mz_values)?
+        + append_column(dir, "intensity_values", &data.intensity_values)?
+        + append_column(dir, "frame_indices", &data.frame_indices)?
+        + append_column(dir, "scan_indices", &data.scan_indices)?
+        + append_column(dir, "tof_indices", &data.tof_indices)?)
+}
+
+fn read_columns<F: Precision>(dir: &Path, peaks: usize, reserve: usize) -> io::Result<TimsTOFData<F>> {
+    Ok(TimsTOFData {
+        rt_values_min: read_column(dir, "rt_values_min", peaks, reserve)?,
+        mobility_values: read_column(dir, "mobility_values", peaks, reserve)?,
+        mz_values: read_column(dir, "mz_values", peaks, reserve)?,
+        intensity_values: read_column(dir, "intensity_values", peaks, reserve)?,
+        frame_indices: read_column(dir, "frame_indices", peaks, reserve)?,
+        scan_indices: read_column(dir, "scan_indices", peaks, reserve)?,
+        tof_indices: read_column(dir, "tof_indices", peaks, reserve)?,
+    })
+}
+
+// 未填充的列（按 MzStorage 不保留的 m/z 或 TOF）不写文件
+fn append_column<T: Pod>(dir: &Path, name: &str, values: &[T]) -> io::Result<u64> {
+    if values.is_empty() {
+        return Ok(0);
+    }
+    let bytes: &[u8] = bytemuck::cast_slice(values);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(name))?
+        .write_all(bytes)?;
+    Ok(bytes.len() as u64)
+}
+
+// 列文件要么不存在（该列未填充），要么恰好有 peaks 个值
+fn read_column<T: Pod>(dir: &Path, name: &str, peaks: usize, reserve: usize) -> io::Result<Vec<T>> {
+    let path = dir.join(name);
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let expected = (peaks * std::mem::size_of::<T>()) as u64;
+    let actual = file.metadata()?.len();
+    if actual != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} bytes, expected {} for {} peaks", path.display(), actual, expected, peaks),
+        ));
+    }
+    let mut values = Vec::with_capacity(peaks + reserve);
+    values.resize(peaks, T::zeroed());
+    file.read_exact(bytemuck::cast_slice_mut(&mut values))?;
+    Ok(values)
+}
+
+// 解析 --max-memory 的大小：纯数字为字节，可带 K/M/G/T 后缀（1024 进制，可再跟 B 或 iB）
+pub fn parse_size(spec: &str) -> Result<usize, String> {
+    let spec = spec.trim();
+    let upper = spec.to_ascii_uppercase();
+    let number = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (digits, shift) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 10),
+        Some('M') => (&number[..number.len() - 1], 20),
+        Some('G') => (&number[..number.len() - 1], 30),
+        Some('T') => (&number[..number.len() - 1], 40),
+        _ => (number, 0),
+    };
+    let value: f64 = digits.trim().parse().map_err(|_| format!("invalid size {:?}", spec))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("invalid size {:?}", spec));
+    }
+    Ok((value * (1u64 << shift) as f64) as usize)
+}
@@ -0,0 +1,131 @@
+//! MS2 aggregation through one aggregator thread vs. in parallel, 1–64 threads.
+//!
+//! Run with `cargo bench --bench ms2_aggregation`. Each frame is "processed"
+//! by computing m/z for its peaks and splitting them into windows, roughly
+//! the per-peak work of a loader once a frame is decoded, so merging is a
+//! real share of the time. `aggregator_thread` is v2/v5 before
+//! `timstof_common::aggregate`: workers send frames over a bounded channel to
+//! one thread that reorders them and appends them to their windows.
+//! `parallel` collects the frames by index and merges with `group_by_window`
+//! and `concat_parallel`. Thread counts above the machine's core count only
+//! oversubscribe it, so read the curve up to the number of cores.
+
+use std::collections::HashMap;
+use std::sync::mpsc::sync_channel;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rayon::prelude::*;
+use timstof_common::{concat_parallel, group_by_window};
+
+const FRAMES: usize = 400;
+const WINDOWS_PER_FRAME: u32 = 8;
+const PEAKS_PER_WINDOW: usize = 500;
+const CHANNEL_BUFFER_SIZE: usize = 2000;
+
+#[derive(Default)]
+struct Chunk {
+    rt_values_min: Vec<f32>,
+    mobility_values: Vec<f32>,
+    mz_values: Vec<f32>,
+    intensity_values: Vec<u32>,
+    frame_indices: Vec<u32>,
+    scan_indices: Vec<u32>,
+}
+
+impl Chunk {
+    fn append(&mut self, other: Chunk) {
+        self.rt_values_min.extend(other.rt_values_min);
+        self.mobility_values.extend(other.mobility_values);
+        self.mz_values.extend(other.mz_values);
+        self.intensity_values.extend(other.intensity_values);
+        self.frame_indices.extend(other.frame_indices);
+        self.scan_indices.extend(other.scan_indices);
+    }
+
+    fn concat(mut chunks: Vec<Chunk>) -> Chunk {
+        macro_rules! column {
+            ($name:ident) => {
+                concat_parallel(chunks.iter_mut().map(|c| std::mem::take(&mut c.$name)).collect())
+            };
+        }
+        Chunk {
+            rt_values_min: column!(rt_values_min),
+            mobility_values: column!(mobility_values),
+            mz_values: column!(mz_values),
+            intensity_values: column!(intensity_values),
+            frame_indices: column!(frame_indices),
+            scan_indices: column!(scan_indices),
+        }
+    }
+}
+
+fn process_frame(frame: usize) -> Vec<(u32, Chunk)> {
+    let mut state = frame as u64 ^ 0x2545_f491_4f6c_dd1d;
+    (0..WINDOWS_PER_FRAME)
+        .map(|window| {
+            let mut chunk = Chunk::default();
+            for peak in 0..PEAKS_PER_WINDOW {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                let tof = ((state >> 33) % 400_000) as f64;
+                chunk.rt_values_min.push(frame as f32 * 0.01);
+                chunk.mobility_values.push(1.6 - peak as f32 * 1e-3);
+                chunk.mz_values.push((10.0 + tof * 7.8e-5).powi(2) as f32);
+                chunk.intensity_values.push((state >> 50) as u32);
+                chunk.frame_indices.push(frame as u32);
+                chunk.scan_indices.push(peak as u32);
+            }
+            (window, chunk)
+        })
+        .collect()
+}
+
+fn aggregator_thread() -> Vec<(u32, Chunk)> {
+    let (sender, receiver) = sync_channel(CHANNEL_BUFFER_SIZE);
+    let aggregator = std::thread::spawn(move || {
+        let mut pending: Vec<Option<Vec<(u32, Chunk)>>> = (0..FRAMES).map(|_| None).collect();
+        let mut next_frame = 0;
+        let mut windows: HashMap<u32, Chunk> = HashMap::new();
+        while let Ok((idx, pairs)) = receiver.recv() {
+            pending[idx] = Some(pairs);
+            while let Some(pairs) = pending.get_mut(next_frame).and_then(Option::take) {
+                for (key, chunk) in pairs {
+                    windows.entry(key).or_default().append(chunk);
+                }
+                next_frame += 1;
+            }
+        }
+        let mut windows: Vec<_> = windows.into_iter().collect();
+        windows.sort_unstable_by_key(|(key, _)| *key);
+        windows
+    });
+    (0..FRAMES).into_par_iter().for_each_with(sender, |sender, idx| {
+        let _ = sender.send((idx, process_frame(idx)));
+    });
+    aggregator.join().unwrap()
+}
+
+fn parallel() -> Vec<(u32, Chunk)> {
+    let frames: Vec<_> = (0..FRAMES).into_par_iter().map(process_frame).collect();
+    group_by_window(frames)
+        .into_iter()
+        .map(|(key, chunks)| (key, Chunk::concat(chunks)))
+        .collect()
+}
+
+fn bench_aggregation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ms2_aggregation");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((FRAMES * WINDOWS_PER_FRAME as usize * PEAKS_PER_WINDOW) as u64));
+    for threads in [1usize, 2, 4, 8, 16, 32, 64] {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        group.bench_function(BenchmarkId::new("aggregator_thread", threads), |b| {
+            b.iter(|| black_box(pool.install(aggregator_thread)))
+        });
+        group.bench_function(BenchmarkId::new("parallel", threads), |b| {
+            b.iter(|| black_box(pool.install(parallel)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_aggregation);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+//! Per-peak calibration vs. a precomputed TOF→m/z table on a realistic frame.
+//!
+//! Run with `cargo bench --bench mz_lookup`. The calibration has the form of
+//! timsrust's `Tof2MzConverter`, `(intercept + slope · tof)²`; real
+//! converters may be costlier, which only widens the gap.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use timstof_common::LookupTable;
+
+const DIGITIZER_NUM_SAMPLES: usize = 397_922;
+
+#[derive(Clone, Copy)]
+struct Calibration {
+    intercept: f64,
+    slope: f64,
+}
+
+impl Calibration {
+    #[inline]
+    fn convert(self, tof: u32) -> f64 {
+        (self.intercept + self.slope * tof as f64).powi(2)
+    }
+}
+
+// TOF indices of a frame: spread over the whole digitizer range and
+// ascending within each of about 900 scans, as timsrust decodes them.
+fn frame_tofs(num_peaks: usize) -> Vec<u32> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut tofs: Vec<u32> = (0..num_peaks)
+        .map(|_| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            ((state >> 33) % DIGITIZER_NUM_SAMPLES as u64) as u32
+        })
+        .collect();
+    for scan in tofs.chunks_mut(num_peaks.div_ceil(900)) {
+        scan.sort_unstable();
+    }
+    tofs
+}
+
+fn bench_mz_column(c: &mut Criterion) {
+    let calibration = Calibration {
+        intercept: 100f64.sqrt(),
+        slope: (1700f64.sqrt() - 100f64.sqrt()) / DIGITIZER_NUM_SAMPLES as f64,
+    };
+    let mut group = c.benchmark_group("mz_column");
+    group.bench_function("build_table", |b| b.iter(|| {
+        black_box(LookupTable::build(DIGITIZER_NUM_SAMPLES + 1, |tof| calibration.convert(tof as u32)))
+    }));
+
+    let table = LookupTable::build(DIGITIZER_NUM_SAMPLES + 1, |tof| calibration.convert(tof as u32));
+    for num_peaks in [10_000usize, 200_000] {
+        let tofs = frame_tofs(num_peaks);
+        let mut mz = Vec::with_capacity(num_peaks);
+        group.throughput(Throughput::Elements(num_peaks as u64));
+
+        group.bench_with_input(BenchmarkId::new("convert", num_peaks), &tofs, |b, tofs| b.iter(|| {
+            mz.clear();
+            mz.extend(tofs.iter().map(|&tof| black_box(calibration).convert(tof) as f32));
+            black_box(&mz);
+        }));
+        group.bench_with_input(BenchmarkId::new("lookup_table", num_peaks), &tofs, |b, tofs| b.iter(|| {
+            mz.clear();
+            mz.extend(tofs.iter().map(|&tof| table.get_or_else(tof as usize, |tof| calibration.convert(tof as u32)) as f32));
+            black_box(&mz);
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_mz_column);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+//! Per-peak scan lookup vs. the scan-ordered sweep on a realistic frame.
+//!
+//! Run with `cargo bench --bench scan_lookup`. `v5_binary_unsafe` is the
+//! lookup v5 used before the strategies switched to [`scan_ranges`].
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use timstof_common::{scan_for_index, scan_ranges};
+
+const NUM_SCANS: usize = 927;
+
+// Scan sizes of a dense frame: about a third of the scans are empty.
+fn frame_offsets(num_peaks: usize) -> Vec<usize> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let weights: Vec<u64> = (0..NUM_SCANS)
+        .map(|_| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            let w = state >> 58;
+            if w < 21 { 0 } else { w }
+        })
+        .collect();
+    let total: u64 = weights.iter().sum();
+    let mut offsets = Vec::with_capacity(NUM_SCANS);
+    let mut acc = 0u64;
+    for w in weights {
+        offsets.push((acc * num_peaks as u64 / total) as usize);
+        acc += w;
+    }
+    offsets
+}
+
+#[inline(always)]
+fn v5_binary_unsafe(index: usize, scan_offsets: &[usize]) -> usize {
+    unsafe {
+        let mut left = 0;
+        let mut right = scan_offsets.len() - 1;
+        while left < right {
+            let mid = left + ((right - left + 1) >> 1);
+            if *scan_offsets.get_unchecked(mid) <= index {
+                left = mid;
+            } else {
+                right = mid - 1;
+            }
+        }
+        left
+    }
+}
+
+// Each variant fills the scan column the way a frame processor does.
+fn bench_scan_column(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan_column");
+    for num_peaks in [10_000usize, 200_000] {
+        let offsets = frame_offsets(num_peaks);
+        let mut scans = Vec::with_capacity(num_peaks);
+        group.throughput(Throughput::Elements(num_peaks as u64));
+
+        group.bench_with_input(BenchmarkId::new("v5_binary_unsafe", num_peaks), &offsets, |b, offsets| b.iter(|| {
+            scans.clear();
+            scans.extend((0..num_peaks).map(|i| v5_binary_unsafe(i, black_box(offsets)) as u32));
+            black_box(&scans);
+        }));
+        group.bench_with_input(BenchmarkId::new("scan_for_index", num_peaks), &offsets, |b, offsets| b.iter(|| {
+            scans.clear();
+            scans.extend((0..num_peaks).map(|i| scan_for_index(i, black_box(offsets)) as u32));
+            black_box(&scans);
+        }));
+        group.bench_with_input(BenchmarkId::new("scan_ranges", num_peaks), &offsets, |b, offsets| b.iter(|| {
+            scans.clear();
+            for (scan, range) in scan_ranges(black_box(offsets), num_peaks) {
+                scans.extend(std::iter::repeat_n(scan as u32, range.len()));
+            }
+            black_box(&scans);
+        }));
+    }
+    group.finish();
+}
+
+// An MS2 frame split into quadrupole windows of about 150 scans each.
+fn bench_ms2_windows(c: &mut Criterion) {
+    let num_peaks = 200_000;
+    let offsets = frame_offsets(num_peaks);
+    let windows: Vec<(usize, usize)> = (0..6).map(|w| (w * 150, w * 150 + 149)).collect();
+    let mut group = c.benchmark_group("ms2_windows");
+    group.throughput(Throughput::Elements(num_peaks as u64));
+
+    group.bench_function("v5_binary_unsafe", |b| b.iter(|| {
+        let mut kept = 0usize;
+        for &(first, last) in &windows {
+            kept += (0..num_peaks).filter(|&i| (first..=last).contains(&v5_binary_unsafe(i, black_box(&offsets)))).count();
+        }
+        black_box(kept)
+    }));
+    group.bench_function("scan_ranges", |b| b.iter(|| {
+        let mut kept = 0usize;
+        for &(first, last) in &windows {
+            kept += scan_ranges(black_box(&offsets), num_peaks).within(first, last).map(|(_, range)| range.len()).sum::<usize>();
+        }
+        black_box(kept)
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan_column, bench_ms2_windows);
+criterion_main!(benches);
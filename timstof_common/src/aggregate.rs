@@ -0,0 +1,92 @@
+//! Merging per-frame MS2 results into windows without an aggregator thread.
+//!
+//! v2 and v5 used to send every processed frame through a bounded channel to
+//! one aggregator thread, which reordered frames and appended each of them to
+//! its windows: however many workers decoded frames, all merging happened on
+//! one core. Instead, workers now return their frame's chunks through an
+//! indexed `collect`, which keeps frame order without any reordering, and the
+//! merge runs in two steps:
+//!
+//! 1. [`group_by_window`] moves each chunk (not its peaks) into the list of
+//!    its window, in frame order.
+//! 2. [`concat_parallel`] sizes a window's column from the chunk lengths, a
+//!    [`PeakLayout`], and copies every chunk into its slice of the column in
+//!    parallel, freeing each chunk as soon as it is copied.
+//!
+//! Columns are concatenated one at a time, so the merge holds at most one
+//! column twice (its chunks and its destination) on top of the output.
+
+use std::collections::BTreeMap;
+use rayon::prelude::*;
+
+use crate::layout::PeakLayout;
+
+/// The chunks of every window, in frame order, windows sorted by key.
+///
+/// `frames` yields each frame's `(window key, chunk)` pairs in frame order.
+pub fn group_by_window<K, T, F>(frames: impl IntoIterator<Item = F>) -> Vec<(K, Vec<T>)>
+where
+    K: Ord,
+    F: IntoIterator<Item = (K, T)>,
+{
+    let mut windows: BTreeMap<K, Vec<T>> = BTreeMap::new();
+    for (key, chunk) in frames.into_iter().flatten() {
+        windows.entry(key).or_default().push(chunk);
+    }
+    windows.into_iter().collect()
+}
+
+/// `chunks` concatenated into one column of exactly their total length,
+/// copied in parallel on the current rayon pool.
+pub fn concat_parallel<T>(chunks: Vec<Vec<T>>) -> Vec<T>
+where
+    T: Copy + Default + Send + Sync,
+{
+    let layout = PeakLayout::new(chunks.iter().map(Vec::len));
+    let mut column = vec![T::default(); layout.total()];
+    layout.split_mut(&mut column)
+        .into_par_iter()
+        .zip(chunks)
+        .for_each(|(slots, chunk)| slots.copy_from_slice(&chunk));
+    column
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn concatenates_in_order(chunks in prop::collection::vec(prop::collection::vec(any::<u32>(), 0..20), 0..50)) {
+            let expected: Vec<u32> = chunks.concat();
+            let column = concat_parallel(chunks);
+            prop_assert_eq!(column.capacity(), expected.len());
+            prop_assert_eq!(column, expected);
+        }
+    }
+
+    #[test]
+    fn groups_chunks_by_window_in_frame_order() {
+        let frames = vec![
+            vec![(2, "f0w2"), (1, "f0w1")],
+            vec![],
+            vec![(1, "f2w1")],
+            vec![(3, "f3w3"), (2, "f3w2")],
+        ];
+        assert_eq!(
+            group_by_window(frames),
+            vec![(1, vec!["f0w1", "f2w1"]), (2, vec!["f0w2", "f3w2"]), (3, vec!["f3w3"])],
+        );
+    }
+
+    #[test]
+    fn matches_on_any_pool_size() {
+        let chunks: Vec<Vec<f32>> = (0..200).map(|i| (0..i % 7).map(|j| (i * 10 + j) as f32).collect()).collect();
+        let expected = chunks.concat();
+        for threads in [1, 3, 8] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            assert_eq!(pool.install(|| concat_parallel(chunks.clone())), expected);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Exact output sizing from the per-frame peak counts in `analysis.tdf`.
+//!
+//! `Frames.NumPeaks` gives the size of every frame before any of them is
+//! decompressed. The optimized strategies used to extrapolate the output size
+//! from the first 50–100 frames and an assumed MS1:MS2 frame ratio, which is
+//! wrong for runs with a long MS1-only wash or another cycle design, and then
+//! grew their buffers whenever the guess fell short. A [`PeakLayout`] is the
+//! prefix sum of the exact counts: a column is allocated once at
+//! [`total`](PeakLayout::total) peaks and cut into one disjoint slice per
+//! frame, which the frame workers fill in place, in parallel, without any
+//! merge step.
+
+use std::ops::Range;
+
+/// Where each frame's peaks start in an output column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeakLayout {
+    starts: Vec<usize>,
+}
+
+impl PeakLayout {
+    /// Frame `i` holds the `i`-th count; frames that contribute no peaks to
+    /// the output count 0.
+    pub fn new(counts: impl IntoIterator<Item = usize>) -> Self {
+        let mut total = 0;
+        let starts = std::iter::once(0)
+            .chain(counts.into_iter().map(|count| {
+                total += count;
+                total
+            }))
+            .collect();
+        PeakLayout { starts }
+    }
+
+    pub fn n_frames(&self) -> usize {
+        self.starts.len() - 1
+    }
+
+    /// Peaks of all frames together, the length of every output column.
+    pub fn total(&self) -> usize {
+        self.starts[self.n_frames()]
+    }
+
+    /// Positions of frame `frame`'s peaks in an output column.
+    pub fn range(&self, frame: usize) -> Range<usize> {
+        self.starts[frame]..self.starts[frame + 1]
+    }
+
+    /// Cuts `column` into one slice per frame, in frame order.
+    ///
+    /// # Panics
+    ///
+    /// If `column` does not hold exactly [`total`](Self::total) elements.
+    pub fn split_mut<'a, T>(&self, mut column: &'a mut [T]) -> Vec<&'a mut [T]> {
+        assert_eq!(column.len(), self.total(), "column length does not match the layout");
+        self.starts
+            .windows(2)
+            .map(|pair| {
+                let (head, tail) = std::mem::take(&mut column).split_at_mut(pair[1] - pair[0]);
+                column = tail;
+                head
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn slices_tile_the_column(counts in prop::collection::vec(prop_oneof![Just(0usize), 1usize..50], 0..100)) {
+            let layout = PeakLayout::new(counts.iter().copied());
+            prop_assert_eq!(layout.n_frames(), counts.len());
+            prop_assert_eq!(layout.total(), counts.iter().sum::<usize>());
+
+            let mut column = vec![usize::MAX; layout.total()];
+            for (frame, slice) in layout.split_mut(&mut column).into_iter().enumerate() {
+                prop_assert_eq!(slice.len(), counts[frame]);
+                slice.fill(frame);
+            }
+            for frame in 0..counts.len() {
+                prop_assert!(column[layout.range(frame)].iter().all(|&f| f == frame));
+            }
+            prop_assert!(column.iter().all(|&f| f != usize::MAX));
+        }
+    }
+
+    #[test]
+    fn empty_layout() {
+        let layout = PeakLayout::new([]);
+        assert_eq!((layout.n_frames(), layout.total()), (0, 0));
+        assert!(layout.split_mut::<u32>(&mut []).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "column length does not match the layout")]
+    fn rejects_columns_of_another_length() {
+        PeakLayout::new([2, 3]).split_mut(&mut [0u32; 4]);
+    }
+}
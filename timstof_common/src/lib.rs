@@ -0,0 +1,24 @@
+//! Definitions every loader strategy must agree on.
+//!
+//! Each strategy crate keeps its own pipeline, but anything that decides
+//! *what* ends up in `TimsTOFRawData` (as opposed to how fast) lives here, so
+//! an optimization cannot change the output by re-implementing it slightly
+//! differently.
+
+pub mod aggregate;
+pub mod layout;
+pub mod lookup;
+pub mod order;
+pub mod scan;
+pub mod segments;
+pub mod threads;
+pub mod window_key;
+
+pub use aggregate::{concat_parallel, group_by_window};
+pub use layout::PeakLayout;
+pub use lookup::LookupTable;
+pub use order::first_unordered_peak;
+pub use scan::{scan_for_index, scan_ranges, ScanRanges};
+pub use segments::WindowSegments;
+pub use threads::{Threads, WorkerPool};
+pub use window_key::{key_from_bounds, window_bounds, window_key, WindowKey, WindowKeyError, WINDOW_KEY_SCALE};
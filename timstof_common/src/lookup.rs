@@ -0,0 +1,77 @@
+//! Precomputed conversions for bounded integer axes.
+//!
+//! TOF indices are bounded by the digitizer's sample count and scans by the
+//! frame's `NumScans`, yet the loaders evaluate the calibration function once
+//! per peak, repeating the same few hundred thousand evaluations for every
+//! frame. A [`LookupTable`] evaluates the function once per index up front.
+//! Each entry is the function's own result, so a lookup is bit-identical to
+//! calling it; the table is immutable, so one copy is shared by every frame
+//! worker.
+
+/// `f(index)` for every index in `0..len`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LookupTable<T> {
+    values: Vec<T>,
+}
+
+impl<T: Copy> LookupTable<T> {
+    /// Evaluates `f` for every index in `0..len`.
+    pub fn build(len: usize, f: impl Fn(usize) -> T) -> Self {
+        LookupTable { values: (0..len).map(f).collect() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The precomputed value, or `None` past the end of the table.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<T> {
+        self.values.get(index).copied()
+    }
+
+    /// The precomputed value, or `f(index)` past the end of the table, e.g.
+    /// for a TOF index beyond the digitizer's nominal sample count.
+    #[inline]
+    pub fn get_or_else(&self, index: usize, f: impl FnOnce(usize) -> T) -> T {
+        match self.values.get(index) {
+            Some(&value) => value,
+            None => f(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Same form as timsrust's Tof2MzConverter.
+    fn tof_to_mz(tof: usize) -> f64 {
+        let (intercept, slope) = (100f64.sqrt(), (1700f64.sqrt() - 100f64.sqrt()) / 400_000.0);
+        (intercept + slope * tof as f64).powi(2)
+    }
+
+    proptest! {
+        #[test]
+        fn lookup_is_bit_identical_to_conversion(len in 0usize..5000, index in 0usize..6000) {
+            let table = LookupTable::build(len, tof_to_mz);
+            prop_assert_eq!(table.len(), len);
+            prop_assert_eq!(table.get_or_else(index, tof_to_mz).to_bits(), tof_to_mz(index).to_bits());
+            prop_assert_eq!(table.get(index).is_some(), index < len);
+        }
+    }
+
+    #[test]
+    fn falls_back_past_the_end() {
+        let table = LookupTable::build(3, |i| i as f32 * 0.5);
+        assert_eq!(table.get(2), Some(1.0));
+        assert_eq!(table.get(3), None);
+        assert_eq!(table.get_or_else(10, |i| i as f32), 10.0);
+        assert!(LookupTable::<f32>::default().is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+//! Canonical peak order of a loaded run.
+//!
+//! Every strategy emits the peaks of `ms1_data` and of each MS2 window in
+//! frame order, then scan order within a frame, then TOF order within a scan
+//! (the order peaks are stored in `analysis.tdf_bin`), and lists
+//! `ms2_windows` by ascending [`WindowKey`](crate::WindowKey). This holds for
+//! any thread count, so two loads of the same run are identical column by
+//! column, not just after sorting.
+//!
+//! Parallel strategies get there by merging per-frame results by frame index
+//! rather than in completion order.
+
+use std::cmp::Ordering;
+
+/// Index of the first peak that breaks frame → scan → `within_scan` order, or
+/// `None` if the columns are in canonical order.
+///
+/// `within_scan` is the TOF column, or m/z when TOF was not kept (m/z is
+/// monotonic in TOF, though neighbouring TOF indices may round to the same
+/// f32, so ties are accepted). Pass an empty slice to check only frame and
+/// scan order.
+pub fn first_unordered_peak<T: PartialOrd>(frame_indices: &[u32], scan_indices: &[u32], within_scan: &[T]) -> Option<usize> {
+    assert_eq!(frame_indices.len(), scan_indices.len(), "column lengths differ");
+    assert!(within_scan.is_empty() || within_scan.len() == frame_indices.len(), "column lengths differ");
+
+    (1..frame_indices.len()).find(|&i| {
+        let previous = (frame_indices[i - 1], scan_indices[i - 1]);
+        let current = (frame_indices[i], scan_indices[i]);
+        // NaN in `within_scan` compares as `None` and counts as out of order.
+        current < previous
+            || (current == previous
+                && !within_scan.is_empty()
+                && matches!(within_scan[i - 1].partial_cmp(&within_scan[i]), Some(Ordering::Greater) | None))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_canonical_order() {
+        let frames = [1, 1, 1, 2, 2, 4];
+        let scans = [0, 0, 3, 0, 7, 1];
+        let mz = [500.0f32, 500.0, 200.0, 900.0, 100.0, 100.0];
+        assert_eq!(first_unordered_peak(&frames, &scans, &mz), None);
+        assert_eq!(first_unordered_peak::<u32>(&frames, &scans, &[]), None);
+        assert_eq!(first_unordered_peak::<u32>(&[], &[], &[]), None);
+    }
+
+    #[test]
+    fn reports_first_violation() {
+        assert_eq!(first_unordered_peak::<u32>(&[2, 1], &[0, 0], &[]), Some(1));
+        assert_eq!(first_unordered_peak::<u32>(&[1, 1, 1], &[0, 5, 4], &[]), Some(2));
+        assert_eq!(first_unordered_peak(&[1, 1, 1], &[0, 0, 0], &[10u32, 12, 11]), Some(2));
+        assert_eq!(first_unordered_peak(&[1, 1], &[0, 0], &[f32::NAN, 1.0]), Some(1));
+    }
+}
@@ -0,0 +1,246 @@
+//! Mapping a peak index within a frame to its scan.
+//!
+//! `Frame::scan_offsets` holds the index of the first peak of every scan, so
+//! it is non-decreasing and has one entry per scan. An empty scan has the same
+//! offset as the scan after it, and the last scan runs to the end of the frame.
+//!
+//! The strategies used to carry four lookups that disagreed on exactly those
+//! cases: the original linear scan, `slice::binary_search` in v1/v2 (which is
+//! documented to return any of several equal offsets, i.e. possibly an empty
+//! scan, and only picks the last one by accident of the current std
+//! implementation), and the hand-rolled searches in v3/v4/v5 (which underflow
+//! on an empty offset array). [`scan_for_index`] is the one they all call now.
+//!
+//! Peaks are stored scan by scan, so a frame processor does not need a lookup
+//! per peak at all: [`scan_ranges`] walks the offsets once and yields each
+//! non-empty scan with its slice of peak indices, O(peaks + scans) instead of
+//! O(peaks · log scans). The optimized strategies use the sweep; the original
+//! loader keeps the per-peak lookup as an independent reference.
+
+use std::ops::Range;
+
+/// Scan that peak `index` belongs to: the last scan whose first peak is at or
+/// before `index`.
+///
+/// Empty scans are never returned. An index past the last scan's first peak
+/// belongs to the last scan, as in the original loader. An empty
+/// `scan_offsets` (a frame without scans) yields scan 0.
+#[inline]
+pub fn scan_for_index(index: usize, scan_offsets: &[usize]) -> usize {
+    scan_offsets.partition_point(|&offset| offset <= index).saturating_sub(1)
+}
+
+/// Non-empty scans of a frame in scan order, each with the range of peak
+/// indices it holds.
+///
+/// Agrees with [`scan_for_index`] for every peak in `0..num_peaks`: peaks
+/// before the second offset belong to scan 0, and the last scan runs to
+/// `num_peaks`. An empty `scan_offsets` is a single scan 0.
+pub fn scan_ranges(scan_offsets: &[usize], num_peaks: usize) -> ScanRanges<'_> {
+    ScanRanges {
+        scan_offsets,
+        num_peaks,
+        next: 0,
+        end: scan_offsets.len().max(1),
+    }
+}
+
+/// Iterator returned by [`scan_ranges`].
+#[derive(Debug, Clone)]
+pub struct ScanRanges<'a> {
+    scan_offsets: &'a [usize],
+    num_peaks: usize,
+    next: usize,
+    end: usize,
+}
+
+impl ScanRanges<'_> {
+    /// Restricts the sweep to scans `first..=last`, e.g. the scans of one
+    /// quadrupole window. Scans outside are skipped without being visited.
+    pub fn within(mut self, first: usize, last: usize) -> Self {
+        self.next = self.next.max(first);
+        self.end = self.end.min(last.saturating_add(1));
+        self
+    }
+}
+
+impl Iterator for ScanRanges<'_> {
+    type Item = (usize, Range<usize>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.end {
+            let scan = self.next;
+            self.next += 1;
+            let start = if scan == 0 { 0 } else { self.scan_offsets[scan].min(self.num_peaks) };
+            let end = self.scan_offsets.get(scan + 1).map_or(self.num_peaks, |&end| end.min(self.num_peaks));
+            if start < end {
+                return Some((scan, start..end));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.end.saturating_sub(self.next)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // The original loader's lookup, the reference for every other one.
+    fn linear(index: usize, scan_offsets: &[usize]) -> usize {
+        for (scan, window) in scan_offsets.windows(2).enumerate() {
+            if index >= window[0] && index < window[1] {
+                return scan;
+            }
+        }
+        scan_offsets.len() - 1
+    }
+
+    // v1/v2 before this module.
+    fn legacy_std_binary_search(index: usize, scan_offsets: &[usize]) -> usize {
+        match scan_offsets.binary_search(&index) {
+            Ok(pos) => pos,
+            Err(pos) => pos.saturating_sub(1),
+        }
+    }
+
+    // v3/v4/v5 before this module.
+    fn legacy_hand_rolled(index: usize, scan_offsets: &[usize]) -> usize {
+        let mut left = 0;
+        let mut right = scan_offsets.len() - 1;
+        while left < right {
+            let mid = left + (right - left).div_ceil(2);
+            if scan_offsets[mid] <= index {
+                left = mid;
+            } else {
+                right = mid - 1;
+            }
+        }
+        left
+    }
+
+    fn offsets_from_sizes(sizes: &[usize]) -> Vec<usize> {
+        sizes.iter()
+            .scan(0, |next, &size| {
+                let offset = *next;
+                *next += size;
+                Some(offset)
+            })
+            .collect()
+    }
+
+    // Scan sizes with plenty of empty scans, including all-empty frames.
+    fn scan_sizes() -> impl Strategy<Value = Vec<usize>> {
+        prop::collection::vec(prop_oneof![3 => Just(0usize), 2 => 1usize..4, 1 => 4usize..50], 1..200)
+    }
+
+    // Scan sizes without empty scans, so the offsets are strictly increasing.
+    fn non_empty_scan_sizes() -> impl Strategy<Value = Vec<usize>> {
+        prop::collection::vec(prop_oneof![2 => 1usize..4, 1 => 4usize..50], 1..200)
+    }
+
+    proptest! {
+        #[test]
+        fn matches_linear_reference(sizes in scan_sizes(), past_end in 0usize..8) {
+            let offsets = offsets_from_sizes(&sizes);
+            let peaks: usize = sizes.iter().sum();
+            for index in 0..peaks + past_end {
+                prop_assert_eq!(scan_for_index(index, &offsets), linear(index, &offsets), "peak {}", index);
+            }
+        }
+
+        // Switching the strategies over does not change their output on
+        // frames that have scans.
+        #[test]
+        fn legacy_lookups_agree_when_offsets_are_non_empty(sizes in scan_sizes(), past_end in 0usize..8) {
+            let offsets = offsets_from_sizes(&sizes);
+            let peaks: usize = sizes.iter().sum();
+            for index in 0..peaks + past_end {
+                let scan = scan_for_index(index, &offsets);
+                prop_assert_eq!(legacy_hand_rolled(index, &offsets), scan, "peak {}", index);
+            }
+        }
+
+        // Which of several equal offsets `binary_search` finds is unspecified,
+        // so the v1/v2 lookup is only compared where no offset repeats.
+        #[test]
+        fn legacy_std_lookup_agrees_when_offsets_are_distinct(sizes in non_empty_scan_sizes(), past_end in 0usize..8) {
+            let offsets = offsets_from_sizes(&sizes);
+            let peaks: usize = sizes.iter().sum();
+            for index in 0..peaks + past_end {
+                prop_assert_eq!(legacy_std_binary_search(index, &offsets), scan_for_index(index, &offsets), "peak {}", index);
+            }
+        }
+
+        #[test]
+        fn returns_the_scan_containing_each_peak(sizes in scan_sizes()) {
+            let offsets = offsets_from_sizes(&sizes);
+            let mut index = 0;
+            for (scan, &size) in sizes.iter().enumerate() {
+                for _ in 0..size {
+                    prop_assert_eq!(scan_for_index(index, &offsets), scan);
+                    index += 1;
+                }
+            }
+        }
+
+        #[test]
+        fn sweep_agrees_with_lookup(sizes in scan_sizes(), window in (0usize..250, 0usize..250)) {
+            let offsets = offsets_from_sizes(&sizes);
+            let peaks: usize = sizes.iter().sum();
+
+            let mut next_peak = 0;
+            for (scan, range) in scan_ranges(&offsets, peaks) {
+                prop_assert!(!range.is_empty());
+                prop_assert_eq!(range.start, next_peak);
+                next_peak = range.end;
+                for index in range {
+                    prop_assert_eq!(scan_for_index(index, &offsets), scan, "peak {}", index);
+                }
+            }
+            prop_assert_eq!(next_peak, peaks);
+
+            let (first, last) = window;
+            let swept: Vec<usize> = scan_ranges(&offsets, peaks).within(first, last).flat_map(|(_, range)| range).collect();
+            let looked_up: Vec<usize> = (0..peaks)
+                .filter(|&index| (first..=last).contains(&scan_for_index(index, &offsets)))
+                .collect();
+            prop_assert_eq!(swept, looked_up);
+        }
+    }
+
+    #[test]
+    fn sweeps_frames_without_peaks_or_scans() {
+        assert_eq!(scan_ranges(&[0, 0, 0], 0).count(), 0);
+        assert_eq!(scan_ranges(&[], 0).count(), 0);
+        assert_eq!(scan_ranges(&[], 3).collect::<Vec<_>>(), vec![(0, 0..3)]);
+        assert_eq!(scan_ranges(&[0, 2, 2, 2, 5, 5], 7).collect::<Vec<_>>(), vec![(0, 0..2), (3, 2..5), (5, 5..7)]);
+        assert_eq!(scan_ranges(&[0, 2, 2, 2, 5, 5], 7).within(1, 4).collect::<Vec<_>>(), vec![(3, 2..5)]);
+        assert_eq!(scan_ranges(&[0, 2, 5], 7).within(4, 1).count(), 0);
+    }
+
+    #[test]
+    fn skips_empty_scans() {
+        // Scans 1, 2 and 4 are empty.
+        let offsets = [0, 2, 2, 2, 5, 5];
+        let expected = [0, 0, 3, 3, 3, 5, 5];
+        for (index, &scan) in expected.iter().enumerate() {
+            assert_eq!(scan_for_index(index, &offsets), scan);
+            assert_eq!(linear(index, &offsets), scan);
+        }
+    }
+
+    #[test]
+    fn handles_frames_without_peaks_or_scans() {
+        assert_eq!(scan_for_index(0, &[0, 0, 0]), 2);
+        assert_eq!(scan_for_index(0, &[0]), 0);
+        assert_eq!(scan_for_index(0, &[]), 0);
+        assert_eq!(scan_for_index(7, &[]), 0);
+        assert!(std::panic::catch_unwind(|| legacy_hand_rolled(0, &[])).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Routing the scans of an MS2 frame to its quadrupole windows in one pass.
+//!
+//! A DIA frame has several isolation windows, each covering an inclusive scan
+//! range from `QuadrupoleSettings::scan_starts/scan_ends`. Filtering the whole
+//! frame once per window costs one pass per window; instead the scan axis is
+//! cut at every window boundary into [`WindowSegments`], runs of scans that
+//! belong to the same set of windows. A frame processor sweeps the segments in
+//! scan order, converts each peak once and appends it to every window of its
+//! segment, so overlapping windows each get their copy and every window still
+//! receives its peaks in scan order.
+
+use std::ops::Range;
+
+use crate::scan::scan_ranges;
+
+/// The windows of one MS2 frame, cut into disjoint scan segments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowSegments {
+    n_windows: usize,
+    segments: Vec<Segment>,
+    windows: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    first: usize,
+    last: usize,
+    windows: Range<usize>,
+}
+
+impl WindowSegments {
+    /// Window `w` covers scans `scan_starts[w]..=scan_ends[w]`. A window with
+    /// `scan_starts[w] > scan_ends[w]` covers no scan; extra entries in the
+    /// longer slice are ignored.
+    pub fn new(scan_starts: &[usize], scan_ends: &[usize]) -> Self {
+        let n_windows = scan_starts.len().min(scan_ends.len());
+        let mut bounds: Vec<usize> = Vec::with_capacity(2 * n_windows);
+        for w in 0..n_windows {
+            if scan_starts[w] <= scan_ends[w] {
+                bounds.push(scan_starts[w]);
+                bounds.push(scan_ends[w].saturating_add(1));
+            }
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut segments: Vec<Segment> = Vec::with_capacity(bounds.len());
+        let mut windows = Vec::new();
+        for pair in bounds.windows(2) {
+            let (first, last) = (pair[0], pair[1] - 1);
+            let start = windows.len();
+            windows.extend((0..n_windows).filter(|&w| scan_starts[w] <= first && last <= scan_ends[w]));
+            if windows.len() > start {
+                segments.push(Segment { first, last, windows: start..windows.len() });
+            }
+        }
+        WindowSegments { n_windows, segments, windows }
+    }
+
+    /// Number of windows, including those that cover no scan.
+    pub fn n_windows(&self) -> usize {
+        self.n_windows
+    }
+
+    /// `(first scan, last scan, windows)` for each segment in scan order. The
+    /// window indices of a segment are ascending and never empty.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, &[usize])> + '_ {
+        self.segments.iter().map(|s| (s.first, s.last, &self.windows[s.windows.clone()]))
+    }
+
+    /// Windows that contain `scan`, ascending.
+    pub fn windows_of(&self, scan: usize) -> &[usize] {
+        let i = self.segments.partition_point(|s| s.last < scan);
+        match self.segments.get(i) {
+            Some(s) if s.first <= scan => &self.windows[s.windows.clone()],
+            _ => &[],
+        }
+    }
+
+    /// Number of peaks each window receives from a frame, for sizing the
+    /// per-window buffers exactly.
+    pub fn peak_counts(&self, scan_offsets: &[usize], num_peaks: usize) -> Vec<usize> {
+        let mut counts = vec![0; self.n_windows];
+        for (first, last, windows) in self.iter() {
+            let peaks: usize = scan_ranges(scan_offsets, num_peaks).within(first, last).map(|(_, r)| r.len()).sum();
+            for &w in windows {
+                counts[w] += peaks;
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn naive_windows_of(scan: usize, starts: &[usize], ends: &[usize]) -> Vec<usize> {
+        (0..starts.len().min(ends.len())).filter(|&w| starts[w] <= scan && scan <= ends[w]).collect()
+    }
+
+    // Windows that overlap, nest, repeat, touch and invert.
+    fn windows() -> impl Strategy<Value = (Vec<usize>, Vec<usize>)> {
+        prop::collection::vec((0usize..120, 0usize..60), 0..12).prop_map(|ws| {
+            ws.into_iter().map(|(start, len)| (start, (start + len).saturating_sub(5))).unzip()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn segments_route_each_scan_to_its_windows((starts, ends) in windows()) {
+            let segments = WindowSegments::new(&starts, &ends);
+            for scan in 0..200 {
+                prop_assert_eq!(segments.windows_of(scan).to_vec(), naive_windows_of(scan, &starts, &ends), "scan {}", scan);
+            }
+
+            let mut previous_last = None;
+            for (first, last, windows) in segments.iter() {
+                prop_assert!(first <= last && !windows.is_empty());
+                prop_assert!(previous_last.is_none_or(|p| p < first));
+                previous_last = Some(last);
+                for scan in [first, last] {
+                    prop_assert_eq!(windows.to_vec(), naive_windows_of(scan, &starts, &ends));
+                }
+            }
+        }
+
+        #[test]
+        fn one_pass_matches_filtering_per_window(
+            (starts, ends) in windows(),
+            sizes in prop::collection::vec(0usize..4, 0..150),
+        ) {
+            let offsets: Vec<usize> = sizes.iter().scan(0, |next, &n| { let o = *next; *next += n; Some(o) }).collect();
+            let peaks: usize = sizes.iter().sum();
+            let segments = WindowSegments::new(&starts, &ends);
+
+            let mut routed = vec![Vec::new(); starts.len()];
+            for (first, last, windows) in segments.iter() {
+                for (scan, range) in scan_ranges(&offsets, peaks).within(first, last) {
+                    for peak in range {
+                        for &w in windows {
+                            routed[w].push((scan, peak));
+                        }
+                    }
+                }
+            }
+
+            for w in 0..starts.len() {
+                let filtered: Vec<(usize, usize)> = scan_ranges(&offsets, peaks)
+                    .filter(|(scan, _)| starts[w] <= *scan && *scan <= ends[w])
+                    .flat_map(|(scan, range)| range.map(move |peak| (scan, peak)))
+                    .collect();
+                prop_assert_eq!(&routed[w], &filtered, "window {}", w);
+            }
+            let counts: Vec<usize> = routed.iter().map(Vec::len).collect();
+            prop_assert_eq!(segments.peak_counts(&offsets, peaks), counts);
+        }
+    }
+
+    #[test]
+    fn overlapping_windows_share_scans() {
+        // Window 1 overlaps both neighbours; window 3 is inverted.
+        let segments = WindowSegments::new(&[0, 40, 90, 10], &[49, 99, 149, 5]);
+        let expected: Vec<(usize, usize, Vec<usize>)> = vec![
+            (0, 39, vec![0]),
+            (40, 49, vec![0, 1]),
+            (50, 89, vec![1]),
+            (90, 99, vec![1, 2]),
+            (100, 149, vec![2]),
+        ];
+        let actual: Vec<(usize, usize, Vec<usize>)> = segments.iter().map(|(f, l, w)| (f, l, w.to_vec())).collect();
+        assert_eq!(actual, expected);
+        assert!(segments.windows_of(150).is_empty());
+        assert_eq!(segments.n_windows(), 4);
+        assert!(WindowSegments::new(&[], &[]).iter().next().is_none());
+    }
+}
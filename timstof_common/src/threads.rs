@@ -0,0 +1,263 @@
+//! Sizing the worker pool of a load from the machine and the run.
+//!
+//! The strategies used to call `build_global` with a constant 16 or 32
+//! threads: too many for a SLURM job granted a few CPUs, too few to hide I/O
+//! on network storage, and impossible for a program that already has its own
+//! rayon pool to embed. A load now runs on a [`WorkerPool`]:
+//!
+//! - inside a rayon pool (the caller's `install`), that pool as is;
+//! - otherwise a local pool of [`plan_threads`] threads: the CPUs this
+//!   process may use ([`available_cpus`], the smaller of the affinity mask
+//!   and the cgroup CPU quota), scaled up when frames spend part of their
+//!   decode time waiting on I/O ([`probe_io_fraction`]) and capped for runs
+//!   with few frames.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+/// Fewest frames worth a thread of their own.
+pub const MIN_FRAMES_PER_THREAD: usize = 8;
+/// Highest I/O wait fraction [`plan_threads`] compensates for (4× the CPUs).
+pub const MAX_IO_FRACTION: f64 = 0.75;
+/// Frames decoded by [`probe_io_fraction`].
+pub const PROBE_FRAMES: usize = 8;
+
+/// How many worker threads a load uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Threads {
+    /// The caller's rayon pool when called inside one, otherwise a local pool
+    /// sized by [`plan_threads`].
+    #[default]
+    Auto,
+    /// A local pool of exactly this many threads.
+    Fixed(usize),
+}
+
+impl Threads {
+    /// The pool a load of `frames` frames runs on. `probe` returns the I/O
+    /// wait fraction of decoding a frame and is only called when a pool is
+    /// sized automatically.
+    pub fn pool(self, frames: usize, probe: impl FnOnce() -> f64) -> Result<WorkerPool, ThreadPoolBuildError> {
+        let threads = match self {
+            Threads::Auto if rayon::current_thread_index().is_some() => return Ok(WorkerPool::Current),
+            Threads::Auto => plan_threads(available_cpus(), frames, probe()),
+            Threads::Fixed(threads) => threads.max(1),
+        };
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("timstof-worker-{}", i))
+            .build()?;
+        Ok(WorkerPool::Local(pool))
+    }
+
+    /// The pool every loader decodes its frames on. `decode(idx)` decodes
+    /// frame `idx` and drops it; [`Threads::Auto`] times it to size the pool
+    /// from the CPUs this process may use and how much of a frame's decode is
+    /// spent waiting on the disk, and runs on the caller's pool when called
+    /// from inside one.
+    pub fn pool_for_frames(self, frames: usize, decode: impl FnMut(usize)) -> Result<WorkerPool, ThreadPoolBuildError> {
+        self.pool(frames, || probe_io_fraction(frames, decode))
+    }
+}
+
+/// Where a load's parallel work runs; dropping a local pool joins its threads.
+#[derive(Debug)]
+pub enum WorkerPool {
+    /// The rayon pool the load was called from.
+    Current,
+    Local(ThreadPool),
+}
+
+impl WorkerPool {
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match self {
+            WorkerPool::Current => f(),
+            WorkerPool::Local(pool) => pool.install(f),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        match self {
+            WorkerPool::Current => rayon::current_num_threads(),
+            WorkerPool::Local(pool) => pool.current_num_threads(),
+        }
+    }
+}
+
+/// Threads for `frames` frames on `cpus` CPUs when a fraction `io_fraction`
+/// of each frame's decode time is spent waiting on I/O: enough threads that
+/// the CPUs stay busy while some wait (`cpus / (1 - io_fraction)`), at most
+/// one per [`MIN_FRAMES_PER_THREAD`] frames, at least one.
+pub fn plan_threads(cpus: usize, frames: usize, io_fraction: f64) -> usize {
+    let io_fraction = if io_fraction.is_finite() { io_fraction.clamp(0.0, MAX_IO_FRACTION) } else { 0.0 };
+    let busy = (cpus.max(1) as f64 / (1.0 - io_fraction)).round() as usize;
+    busy.min(frames.div_ceil(MIN_FRAMES_PER_THREAD)).max(1)
+}
+
+/// CPUs this process may use: `available_parallelism` (the affinity mask,
+/// e.g. a SLURM cpuset) capped by the CPU quota of its cgroup, if any.
+pub fn available_cpus() -> usize {
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    match cgroup_cpu_quota() {
+        Some(quota) => parallelism.min(quota).max(1),
+        None => parallelism,
+    }
+}
+
+/// Share of a frame's decode time spent waiting on I/O, estimated by
+/// decoding up to [`PROBE_FRAMES`] frames spread over the run twice: the
+/// first pass reads them from storage, the second from the page cache, so
+/// the difference is the I/O wait. Close to 0 when the run is already cached.
+pub fn probe_io_fraction(frames: usize, mut decode: impl FnMut(usize)) -> f64 {
+    let sample: Vec<usize> = (0..PROBE_FRAMES.min(frames)).map(|i| i * frames / PROBE_FRAMES.min(frames)).collect();
+    let mut pass = || {
+        let start = Instant::now();
+        sample.iter().for_each(|&frame| decode(frame));
+        start.elapsed()
+    };
+    let cold = pass();
+    let warm = pass();
+    io_fraction(cold, warm)
+}
+
+fn io_fraction(cold: Duration, warm: Duration) -> f64 {
+    if cold.is_zero() {
+        return 0.0;
+    }
+    (1.0 - warm.as_secs_f64() / cold.as_secs_f64()).clamp(0.0, 1.0)
+}
+
+// Whole CPUs granted by the tightest quota on the way from this process's
+// cgroup to the root (v2 `cpu.max`, or v1 `cpu.cfs_quota_us`).
+fn cgroup_cpu_quota() -> Option<usize> {
+    let membership = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let read = |path: PathBuf| std::fs::read_to_string(path).ok();
+    let mut quotas = Vec::new();
+    for (root, group) in cgroup_dirs(&membership) {
+        for dir in Path::new(&group).ancestors() {
+            let dir = root.join(dir.strip_prefix("/").unwrap_or(dir));
+            if let Some(quota) = read(dir.join("cpu.max")).and_then(|max| parse_cpu_max(&max)) {
+                quotas.push(quota);
+            }
+            if let (Some(quota), Some(period)) = (read(dir.join("cpu.cfs_quota_us")), read(dir.join("cpu.cfs_period_us"))) {
+                quotas.extend(parse_cfs_quota(&quota, &period));
+            }
+        }
+    }
+    quotas.into_iter().min()
+}
+
+// `(mount point, cgroup path)` of the CPU controller for each line of
+// /proc/self/cgroup: `0::/path` (v2) or `N:cpu,cpuacct:/path` (v1).
+fn cgroup_dirs(membership: &str) -> Vec<(PathBuf, String)> {
+    membership
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+            let root = if controllers.is_empty() {
+                PathBuf::from("/sys/fs/cgroup")
+            } else if controllers.split(',').any(|c| c == "cpu") {
+                PathBuf::from("/sys/fs/cgroup").join(controllers)
+            } else {
+                return None;
+            };
+            Some((root, path.to_string()))
+        })
+        .collect()
+}
+
+// v2 `cpu.max`: `"<quota> <period>"` in microseconds, or `"max <period>"`.
+fn parse_cpu_max(contents: &str) -> Option<usize> {
+    let mut fields = contents.split_whitespace();
+    let quota = fields.next()?.parse::<u64>().ok()?;
+    let period = fields.next()?.parse::<u64>().ok()?;
+    whole_cpus(quota, period)
+}
+
+// v1: `cpu.cfs_quota_us` is -1 without a quota.
+fn parse_cfs_quota(quota: &str, period: &str) -> Option<usize> {
+    let quota = quota.trim().parse::<i64>().ok().filter(|&q| q > 0)?;
+    whole_cpus(quota as u64, period.trim().parse().ok()?)
+}
+
+fn whole_cpus(quota: u64, period: u64) -> Option<usize> {
+    (period > 0).then(|| quota.div_ceil(period).max(1) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_for_cpus_frames_and_io() {
+        assert_eq!(plan_threads(32, 60_000, 0.0), 32);
+        assert_eq!(plan_threads(32, 60_000, 0.5), 64);
+        assert_eq!(plan_threads(32, 60_000, 0.99), 128);
+        assert_eq!(plan_threads(32, 60_000, f64::NAN), 32);
+        assert_eq!(plan_threads(32, 40, 0.0), 5);
+        assert_eq!(plan_threads(4, 0, 0.0), 1);
+        assert_eq!(plan_threads(0, 100, 0.0), 1);
+    }
+
+    #[test]
+    fn parses_cgroup_quotas() {
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max("400000 100000\n"), Some(4));
+        assert_eq!(parse_cpu_max("150000 100000"), Some(2));
+        assert_eq!(parse_cpu_max("1000 100000"), Some(1));
+        assert_eq!(parse_cfs_quota("-1\n", "100000\n"), None);
+        assert_eq!(parse_cfs_quota("800000\n", "100000\n"), Some(8));
+        assert_eq!(parse_cfs_quota("800000", "0"), None);
+    }
+
+    #[test]
+    fn finds_the_cpu_controller() {
+        let v1 = "4:memory:/slurm/job_1\n2:cpu,cpuacct:/slurm/uid_1/job_1/step_batch\n1:cpuset:/slurm\n";
+        assert_eq!(
+            cgroup_dirs(v1),
+            vec![(PathBuf::from("/sys/fs/cgroup/cpu,cpuacct"), "/slurm/uid_1/job_1/step_batch".to_string())],
+        );
+        assert_eq!(cgroup_dirs("0::/system.slice/job.scope\n"), vec![(PathBuf::from("/sys/fs/cgroup"), "/system.slice/job.scope".to_string())]);
+    }
+
+    #[test]
+    fn estimates_io_wait_from_cold_and_warm_passes() {
+        assert_eq!(io_fraction(Duration::from_millis(40), Duration::from_millis(10)), 0.75);
+        assert_eq!(io_fraction(Duration::from_millis(10), Duration::from_millis(12)), 0.0);
+        assert_eq!(io_fraction(Duration::ZERO, Duration::ZERO), 0.0);
+
+        let mut decoded = Vec::new();
+        probe_io_fraction(100, |frame| decoded.push(frame));
+        assert_eq!(decoded.len(), 2 * PROBE_FRAMES);
+        assert!(decoded.iter().all(|&frame| frame < 100));
+        probe_io_fraction(0, |_| panic!("no frames to decode"));
+    }
+
+    #[test]
+    fn uses_the_callers_pool() {
+        assert!(available_cpus() >= 1);
+        let outer = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        let pool = outer.install(|| Threads::Auto.pool(1000, || unreachable!())).unwrap();
+        assert!(matches!(pool, WorkerPool::Current));
+        assert_eq!(outer.install(|| pool.threads()), 3);
+
+        let fixed = Threads::Fixed(2).pool(1000, || unreachable!()).unwrap();
+        assert_eq!(fixed.threads(), 2);
+        assert_eq!(fixed.install(rayon::current_num_threads), 2);
+    }
+
+    #[test]
+    fn probes_frames_only_when_sizing_a_pool() {
+        let outer = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        let pool = outer.install(|| Threads::Auto.pool_for_frames(1000, |_| unreachable!())).unwrap();
+        assert!(matches!(pool, WorkerPool::Current));
+        assert_eq!(Threads::Fixed(2).pool_for_frames(1000, |_| unreachable!()).unwrap().threads(), 2);
+
+        let mut decoded = 0;
+        let pool = Threads::Auto.pool_for_frames(100, |_| decoded += 1).unwrap();
+        assert!(pool.threads() >= 1);
+        assert_eq!(decoded, 2 * PROBE_FRAMES);
+    }
+}
@@ -0,0 +1,172 @@
+//! Quantized MS2 isolation-window keys.
+//!
+//! Strategies group MS2 peaks by isolation window. The window bounds are f32,
+//! so they are bucketed into integer keys of 1e-4 Th before hashing. The
+//! original loader computed the bounds in f32 and rounded to nearest, and this
+//! module keeps exactly that arithmetic so existing outputs are unchanged.
+//! v4/v5 used to truncate with `to_int_unchecked`, which could put a window in
+//! a different bucket and was undefined behaviour for negative or NaN bounds.
+
+use std::error::Error;
+use std::fmt;
+
+/// `(low, high)` bounds in units of `1 / WINDOW_KEY_SCALE` Th.
+pub type WindowKey = (u32, u32);
+
+/// Keys are bucketed at 1e-4 Th.
+pub const WINDOW_KEY_SCALE: f32 = 10_000.0;
+
+/// Why a quadrupole isolation window was rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowKeyError {
+    /// Isolation m/z or width is NaN or infinite.
+    NonFinite { isolation_mz: f64, isolation_width: f64 },
+    /// `high <= low`, i.e. zero or negative width.
+    Inverted { low: f32, high: f32 },
+    /// Lower bound below 0 Th.
+    Negative { low: f32 },
+    /// Upper bound does not fit in a `u32` key.
+    OutOfRange { high: f32 },
+}
+
+impl fmt::Display for WindowKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            WindowKeyError::NonFinite { isolation_mz, isolation_width } => write!(
+                f, "isolation window is not finite (m/z {}, width {})", isolation_mz, isolation_width
+            ),
+            WindowKeyError::Inverted { low, high } => write!(
+                f, "isolation window is empty or inverted ({} - {})", low, high
+            ),
+            WindowKeyError::Negative { low } => write!(f, "isolation window starts below 0 Th ({})", low),
+            WindowKeyError::OutOfRange { high } => write!(f, "isolation window upper bound {} is out of range", high),
+        }
+    }
+}
+
+impl Error for WindowKeyError {}
+
+/// Window key for one entry of `QuadrupoleSettings`.
+///
+/// The bounds are `mz ± width / 2` computed in f32, then rounded to the nearest
+/// multiple of 1e-4 Th (ties away from zero).
+pub fn window_key(isolation_mz: f64, isolation_width: f64) -> Result<WindowKey, WindowKeyError> {
+    if !isolation_mz.is_finite() || !isolation_width.is_finite() {
+        return Err(WindowKeyError::NonFinite { isolation_mz, isolation_width });
+    }
+    let prec_mz = isolation_mz as f32;
+    let width = isolation_width as f32;
+    let low = prec_mz - width * 0.5;
+    let high = prec_mz + width * 0.5;
+
+    // NaN bounds come from inf - inf when the f64 -> f32 cast overflows.
+    if low.is_nan() || high.is_nan() || high <= low {
+        return Err(WindowKeyError::Inverted { low, high });
+    }
+    if low < 0.0 {
+        return Err(WindowKeyError::Negative { low });
+    }
+    match (quantize(low), quantize(high)) {
+        (Some(q_low), Some(q_high)) => Ok((q_low, q_high)),
+        _ => Err(WindowKeyError::OutOfRange { high }),
+    }
+}
+
+/// Bounds in Th for a key, as stored in `TimsTOFRawData::ms2_windows`.
+#[inline]
+pub fn window_bounds(key: WindowKey) -> (f32, f32) {
+    (key.0 as f32 / WINDOW_KEY_SCALE, key.1 as f32 / WINDOW_KEY_SCALE)
+}
+
+/// Re-quantizes stored bounds, for matching windows between loaded outputs.
+///
+/// f32 bounds cannot resolve 1e-4 Th above a few hundred Th, so the result may
+/// be one unit off the key the bounds came from; identical bounds always give
+/// identical keys, which is all matching needs. Invalid bounds map to
+/// `u32::MAX` so they never match a real window.
+#[inline]
+pub fn key_from_bounds(bounds: (f32, f32)) -> WindowKey {
+    (quantize(bounds.0).unwrap_or(u32::MAX), quantize(bounds.1).unwrap_or(u32::MAX))
+}
+
+#[inline]
+fn quantize(x: f32) -> Option<u32> {
+    let scaled = (x * WINDOW_KEY_SCALE).round();
+    // u32::MAX as f32 rounds up to 2^32, so the bound must be strict.
+    (scaled >= 0.0 && scaled < u32::MAX as f32).then_some(scaled as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // What the strategies computed before they shared this module.
+    fn legacy_rounding(mz: f64, width: f64) -> WindowKey {
+        let (prec_mz, width) = (mz as f32, width as f32);
+        let (low, high) = (prec_mz - width * 0.5, prec_mz + width * 0.5);
+        ((low * 10_000.0).round() as u32, (high * 10_000.0).round() as u32)
+    }
+
+    fn legacy_truncation(mz: f64, width: f64) -> WindowKey {
+        let (prec_mz, width) = (mz as f32, width as f32);
+        let (low, high) = (prec_mz - width * 0.5, prec_mz + width * 0.5);
+        ((low * 10_000.0) as u32, (high * 10_000.0) as u32)
+    }
+
+    proptest! {
+        #[test]
+        fn matches_original_rounding(mz in 50.0f64..5000.0, width in 0.01f64..100.0) {
+            prop_assert_eq!(window_key(mz, width).unwrap(), legacy_rounding(mz, width));
+        }
+
+        #[test]
+        fn keys_are_ordered_and_round_trip(mz in 50.0f64..1700.0, width in 0.01f64..100.0) {
+            let key = window_key(mz, width).unwrap();
+            prop_assert!(key.0 < key.1);
+
+            let (low, high) = window_bounds(key);
+            let (prec_mz, w) = (mz as f32, width as f32);
+            let tolerance = 0.5 / WINDOW_KEY_SCALE + 1e-3;
+            prop_assert!((low - (prec_mz - w * 0.5)).abs() <= tolerance);
+            prop_assert!((high - (prec_mz + w * 0.5)).abs() <= tolerance);
+            let requantized = key_from_bounds((low, high));
+            prop_assert!(requantized.0.abs_diff(key.0) <= 1 && requantized.1.abs_diff(key.1) <= 1);
+        }
+
+        #[test]
+        fn rejects_inverted_windows(mz in 50.0f64..5000.0, width in -100.0f64..=0.0) {
+            let is_inverted = matches!(window_key(mz, width), Err(WindowKeyError::Inverted { .. }));
+            prop_assert!(is_inverted);
+        }
+
+        #[test]
+        fn rejects_negative_windows(mz in -5000.0f64..0.0, width in 0.01f64..100.0) {
+            prop_assert!(window_key(mz, width).is_err());
+        }
+
+        #[test]
+        fn never_panics(mz in any::<f64>(), width in any::<f64>()) {
+            if let Ok((low, high)) = window_key(mz, width) {
+                prop_assert!(low < high);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_non_finite_windows() {
+        for (mz, width) in [(f64::NAN, 25.0), (500.0, f64::NAN), (f64::INFINITY, 25.0), (500.0, f64::NEG_INFINITY)] {
+            assert!(matches!(window_key(mz, width), Err(WindowKeyError::NonFinite { .. })));
+        }
+        assert!(matches!(window_key(1e300, 25.0), Err(WindowKeyError::Inverted { .. })));
+        assert!(matches!(window_key(1e6, 25.0), Err(WindowKeyError::OutOfRange { .. })));
+    }
+
+    #[test]
+    fn truncation_used_to_pick_a_different_bucket() {
+        // Lower bound 400.00005: the scaled f32 value has a fractional part >= 0.5.
+        let (mz, width) = (412.5, 24.9999);
+        assert_eq!(window_key(mz, width).unwrap(), legacy_rounding(mz, width));
+        assert_ne!(legacy_rounding(mz, width), legacy_truncation(mz, width));
+    }
+}
@@ -0,0 +1,103 @@
+//! Checks that a loader's output does not depend on the rayon thread count.
+//!
+//! [`assert_deterministic`] loads the same run on pools of
+//! [`THREAD_COUNTS`] workers and requires every load to be in the canonical
+//! order of `timstof_common::order` and identical column by column. Unlike
+//! [`golden`](crate::golden) digests, [`Fingerprint`] hashes peaks in the
+//! order they are stored, so a strategy that merges frames in completion
+//! order fails here even when its sorted content is right.
+
+use sha2::{Digest as _, Sha256};
+use timstof_common::first_unordered_peak;
+use crate::golden::Columns;
+
+/// Pool sizes every loader is run with. 1 is the sequential reference.
+pub const THREAD_COUNTS: [usize; 4] = [1, 2, 3, 8];
+
+/// Builds a [`Fingerprint`] from any value with `ms1_data` and `ms2_windows` fields.
+#[macro_export]
+macro_rules! fingerprint {
+    ($raw:expr) => {{
+        let raw = &$raw;
+        let mut fingerprint = $crate::determinism::Fingerprint::default();
+        fingerprint.ms1($crate::columns!(raw.ms1_data));
+        for ((low, high), data) in raw.ms2_windows.iter() {
+            fingerprint.ms2_window(*low, *high, $crate::columns!(data));
+        }
+        fingerprint
+    }};
+}
+
+/// Order-sensitive summary of one loader's output.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Fingerprint {
+    ms1: String,
+    ms2: Vec<((f32, f32), String)>,
+    violations: Vec<String>,
+}
+
+impl Fingerprint {
+    pub fn ms1(&mut self, columns: Columns) {
+        self.check_order("ms1", &columns);
+        self.ms1 = hash_in_order(&columns);
+    }
+
+    pub fn ms2_window(&mut self, low: f32, high: f32, columns: Columns) {
+        let name = format!("ms2 {:.4} {:.4}", low, high);
+        if let Some(((prev_low, prev_high), _)) = self.ms2.last() {
+            if (*prev_low, *prev_high).partial_cmp(&(low, high)) != Some(std::cmp::Ordering::Less) {
+                self.violations.push(format!("{} listed after {:.4} {:.4}", name, prev_low, prev_high));
+            }
+        }
+        self.check_order(&name, &columns);
+        self.ms2.push(((low, high), hash_in_order(&columns)));
+    }
+
+    /// Where the output breaks canonical order; empty if it does not.
+    pub fn violations(&self) -> &[String] {
+        &self.violations
+    }
+
+    fn check_order(&mut self, name: &str, c: &Columns) {
+        if let Some(i) = first_unordered_peak(c.frame_indices, c.scan_indices, c.mz_values) {
+            self.violations.push(format!(
+                "{} peak {} (frame {}, scan {}) follows frame {}, scan {}",
+                name, i, c.frame_indices[i], c.scan_indices[i], c.frame_indices[i - 1], c.scan_indices[i - 1]
+            ));
+        }
+    }
+}
+
+fn hash_in_order(c: &Columns) -> String {
+    let mut hasher = Sha256::new();
+    for value in c.rt_values_min.iter().chain(c.mobility_values).chain(c.mz_values) {
+        hasher.update(value.to_le_bytes());
+    }
+    for value in c.intensity_values.iter().chain(c.frame_indices).chain(c.scan_indices) {
+        hasher.update(value.to_le_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Runs `load` once per entry of [`THREAD_COUNTS`] and panics unless every
+/// output is canonically ordered and has the same [`Fingerprint`].
+pub fn assert_deterministic<T: Send>(strategy: &str, load: impl Fn() -> T + Sync, fingerprint: impl Fn(&T) -> Fingerprint) {
+    let mut reference: Option<(usize, Fingerprint)> = None;
+    for threads in THREAD_COUNTS {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let actual = fingerprint(&pool.install(&load));
+        assert!(
+            actual.violations.is_empty(),
+            "[{}] {} threads: output is not in canonical order:\n  {}",
+            strategy, threads, actual.violations.join("\n  ")
+        );
+        match &reference {
+            None => reference = Some((threads, actual)),
+            Some((reference_threads, expected)) => assert!(
+                actual == *expected,
+                "[{}] output with {} threads differs from output with {} threads",
+                strategy, threads, reference_threads
+            ),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Golden output hashes shared by every loader strategy.
+//!
+//! Each crate reduces its `TimsTOFRawData` to a [`Digest`] with [`digest!`] and
+//! calls [`assert_golden`]. Hashes use the same order-normalized SHA-256 as
+//! `TimsTOFData::calculate_hash` in the comparison tool, so a golden mismatch
+//! and a summary-file mismatch mean the same thing.
+//!
+//! After an intentional output change, re-bless with
+//! `UPDATE_GOLDEN=1 cargo test` in any one crate and commit the updated file.
+
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use sha2::{Digest as _, Sha256};
+
+/// Borrowed view of one `TimsTOFData` (f32 m/z, no TOF column).
+pub struct Columns<'a> {
+    pub rt_values_min: &'a [f32],
+    pub mobility_values: &'a [f32],
+    pub mz_values: &'a [f32],
+    pub intensity_values: &'a [u32],
+    pub frame_indices: &'a [u32],
+    pub scan_indices: &'a [u32],
+}
+
+/// Builds [`Columns`] from any value with the usual `TimsTOFData` fields.
+#[macro_export]
+macro_rules! columns {
+    ($data:expr) => {
+        $crate::golden::Columns {
+            rt_values_min: &$data.rt_values_min,
+            mobility_values: &$data.mobility_values,
+            mz_values: &$data.mz_values,
+            intensity_values: &$data.intensity_values,
+            frame_indices: &$data.frame_indices,
+            scan_indices: &$data.scan_indices,
+        }
+    };
+}
+
+/// Builds a [`Digest`] from any value with `ms1_data` and `ms2_windows` fields.
+#[macro_export]
+macro_rules! digest {
+    ($raw:expr) => {{
+        let raw = &$raw;
+        let mut digest = $crate::golden::Digest::default();
+        digest.ms1($crate::columns!(raw.ms1_data));
+        for ((low, high), data) in raw.ms2_windows.iter() {
+            digest.ms2_window(*low, *high, $crate::columns!(data));
+        }
+        digest
+    }};
+}
+
+/// Same ordering and byte layout as `calculate_hash` for f32 data without TOF.
+pub fn hash_columns(c: &Columns) -> String {
+    let n = c.intensity_values.len();
+    assert!(
+        [c.rt_values_min.len(), c.mobility_values.len(), c.mz_values.len(), c.frame_indices.len(), c.scan_indices.len()]
+            .iter()
+            .all(|&len| len == n),
+        "column lengths differ"
+    );
+
+    let mut indices: Vec<usize> = (0..n).collect();
+    indices.sort_by_key(|&i| {
+        (c.frame_indices[i], c.scan_indices[i], (c.mz_values[i] * 1e6) as i64, 0u32, c.intensity_values[i])
+    });
+
+    let mut hasher = Sha256::new();
+    for &i in &indices {
+        hasher.update(c.rt_values_min[i].to_le_bytes());
+        hasher.update(c.mobility_values[i].to_le_bytes());
+        hasher.update(c.mz_values[i].to_le_bytes());
+        hasher.update(c.intensity_values[i].to_le_bytes());
+        hasher.update(c.frame_indices[i].to_le_bytes());
+        hasher.update(c.scan_indices[i].to_le_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Order-normalized summary of one loader's output.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Digest {
+    ms1: Option<(usize, String)>,
+    ms2: Vec<(String, usize, String)>,
+}
+
+impl Digest {
+    pub fn ms1(&mut self, columns: Columns) {
+        self.ms1 = Some((columns.intensity_values.len(), hash_columns(&columns)));
+    }
+
+    pub fn ms2_window(&mut self, low: f32, high: f32, columns: Columns) {
+        let key = format!("{:.4} {:.4}", low, high);
+        self.ms2.push((key, columns.intensity_values.len(), hash_columns(&columns)));
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let (n, hash) = self.ms1.clone().unwrap_or_default();
+        writeln!(out, "ms1 {} {}", n, hash).unwrap();
+        let mut windows = self.ms2.clone();
+        windows.sort();
+        for (key, n, hash) in windows {
+            writeln!(out, "ms2 {} {} {}", key, n, hash).unwrap();
+        }
+        out
+    }
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("golden").join(format!("{}.txt", name))
+}
+
+/// Compares `digest` with `golden/<name>.txt`; rewrites the file instead when
+/// `UPDATE_GOLDEN` is set.
+pub fn assert_golden(name: &str, strategy: &str, digest: &Digest) {
+    let path = golden_path(name);
+    let actual = digest.render();
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, &actual).unwrap();
+        eprintln!("[{}] blessed {}", strategy, path.display());
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!("cannot read golden file {}: {} (run with UPDATE_GOLDEN=1 to create it)", path.display(), e)
+    });
+    if expected != actual {
+        let mut report = format!("[{}] output differs from {}\n", strategy, path.display());
+        let expected_lines: Vec<&str> = expected.lines().collect();
+        let actual_lines: Vec<&str> = actual.lines().collect();
+        for i in 0..expected_lines.len().max(actual_lines.len()) {
+            let e = expected_lines.get(i).copied().unwrap_or("<missing>");
+            let a = actual_lines.get(i).copied().unwrap_or("<missing>");
+            if e != a {
+                writeln!(report, "  expected: {}\n  actual:   {}", e, a).unwrap();
+            }
+        }
+        panic!("{}", report);
+    }
+}
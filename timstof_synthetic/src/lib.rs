@@ -0,0 +1,472 @@
+//! Synthetic Bruker TDF datasets for tests.
+//!
+//! Writes a small diaPASEF `.d` folder — `analysis.tdf` (SQLite) plus a
+//! zstd-compressed `analysis.tdf_bin` — whose peaks are known up front, so
+//! every loader in this repository can be exercised end to end without the
+//! real acquisition on the HPC storage.
+//!
+//! ```no_run
+//! let run = timstof_synthetic::SyntheticRun::small();
+//! let dir = run.write_temp().unwrap();
+//! // read_timstof_data(dir.path()) ...
+//! ```
+//!
+//! The [`golden`] module holds the stored output hashes every loader strategy
+//! is checked against, [`determinism`] checks that the output does not
+//! depend on the thread count, and [`memory`] measures a load's peak heap.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use rusqlite::{params, Connection};
+
+pub mod determinism;
+pub mod golden;
+pub mod memory;
+
+/// `Frames.MsMsType` of an MS1 frame.
+pub const MSMS_TYPE_MS1: u8 = 0;
+/// `Frames.MsMsType` of a diaPASEF MS2 frame.
+pub const MSMS_TYPE_DIA: u8 = 9;
+/// `Frames.ScanMode` of a diaPASEF acquisition.
+pub const SCAN_MODE_DIA: u8 = 9;
+/// `GlobalMetadata.TimsCompressionType` for zstd-compressed frame blobs.
+pub const COMPRESSION_ZSTD: u8 = 2;
+
+/// One peak of a frame, in raw (index) units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Peak {
+    pub scan: u32,
+    pub tof: u32,
+    pub intensity: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticFrame {
+    /// `Frames.Id`; 1-based like real acquisitions.
+    pub id: u32,
+    pub rt_seconds: f64,
+    pub msms_type: u8,
+    /// `DiaFrameMsMsInfo.WindowGroup` for MS2 frames.
+    pub window_group: Option<u8>,
+    pub num_scans: u32,
+    pub accumulation_time_ms: f64,
+    /// Sorted by `(scan, tof)`, no duplicate `(scan, tof)` pairs.
+    pub peaks: Vec<Peak>,
+}
+
+impl SyntheticFrame {
+    pub fn is_ms1(&self) -> bool {
+        self.msms_type == MSMS_TYPE_MS1
+    }
+}
+
+/// One row of `DiaFrameMsMsWindows`. The scan range is inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiaWindow {
+    pub window_group: u8,
+    pub scan_begin: u32,
+    pub scan_end: u32,
+    pub isolation_mz: f64,
+    pub isolation_width: f64,
+    pub collision_energy: f64,
+}
+
+impl DiaWindow {
+    pub fn contains_scan(&self, scan: u32) -> bool {
+        scan >= self.scan_begin && scan <= self.scan_end
+    }
+}
+
+/// Shape of a generated run.
+#[derive(Debug, Clone)]
+pub struct SyntheticConfig {
+    /// Number of DIA cycles; each cycle is one MS1 frame followed by one MS2
+    /// frame per window group.
+    pub cycles: usize,
+    pub window_groups: u8,
+    pub windows_per_group: usize,
+    pub num_scans: u32,
+    pub peaks_per_frame: usize,
+    pub frame_period_s: f64,
+    /// Scans each window extends into its neighbours' ranges; 0 tiles the
+    /// scan axis without overlap.
+    pub window_overlap_scans: u32,
+    pub seed: u64,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        SyntheticConfig {
+            cycles: 4,
+            window_groups: 2,
+            windows_per_group: 2,
+            num_scans: 100,
+            peaks_per_frame: 200,
+            frame_period_s: 0.1,
+            window_overlap_scans: 0,
+            seed: 42,
+        }
+    }
+}
+
+/// A complete synthetic acquisition. Fields are public so tests can derive
+/// expectations from exactly what was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticRun {
+    pub frames: Vec<SyntheticFrame>,
+    pub windows: Vec<DiaWindow>,
+    pub mz_range: (f64, f64),
+    pub mobility_range: (f64, f64),
+    pub digitizer_num_samples: u32,
+}
+
+impl SyntheticRun {
+    /// The default run: 4 cycles, 2 window groups of 2 windows, 200 peaks per frame.
+    pub fn small() -> Self {
+        Self::generate(&SyntheticConfig::default())
+    }
+
+    /// Like [`small`](Self::small) but with 3 windows per group that overlap
+    /// their neighbours by 10 scans, so some peaks belong to two windows.
+    pub fn overlapping() -> Self {
+        Self::generate(&SyntheticConfig {
+            windows_per_group: 3,
+            window_overlap_scans: 10,
+            ..SyntheticConfig::default()
+        })
+    }
+
+    /// Deterministic for a given config (including `seed`).
+    pub fn generate(config: &SyntheticConfig) -> Self {
+        let digitizer_num_samples = 400_000;
+        let mut rng = XorShift::new(config.seed);
+
+        let mut windows = Vec::new();
+        for group in 1..=config.window_groups {
+            for w in 0..config.windows_per_group {
+                let slot = (w * config.window_groups as usize + (group as usize - 1)) as f64;
+                windows.push(DiaWindow {
+                    window_group: group,
+                    scan_begin: ((w as u32 * config.num_scans) / config.windows_per_group as u32)
+                        .saturating_sub(config.window_overlap_scans),
+                    scan_end: (((w as u32 + 1) * config.num_scans) / config.windows_per_group as u32 - 1 + config.window_overlap_scans)
+                        .min(config.num_scans - 1),
+                    isolation_mz: 412.5 + 25.0 * slot,
+                    isolation_width: 25.0,
+                    collision_energy: 20.0 + slot,
+                });
+            }
+        }
+
+        let mut frames = Vec::new();
+        for _ in 0..config.cycles {
+            for group in 0..=config.window_groups {
+                let id = frames.len() as u32 + 1;
+                let mut peaks: Vec<Peak> = (0..config.peaks_per_frame)
+                    .map(|_| Peak {
+                        scan: rng.below(config.num_scans as u64) as u32,
+                        tof: 1 + rng.below(digitizer_num_samples as u64 - 1) as u32,
+                        intensity: 10 + rng.below(10_000) as u32,
+                    })
+                    .collect();
+                peaks.sort_unstable();
+                peaks.dedup_by_key(|p| (p.scan, p.tof));
+                frames.push(SyntheticFrame {
+                    id,
+                    rt_seconds: 60.0 + id as f64 * config.frame_period_s,
+                    msms_type: if group == 0 { MSMS_TYPE_MS1 } else { MSMS_TYPE_DIA },
+                    window_group: (group > 0).then_some(group),
+                    num_scans: config.num_scans,
+                    accumulation_time_ms: 100.0,
+                    peaks,
+                });
+            }
+        }
+
+        SyntheticRun {
+            frames,
+            windows,
+            mz_range: (100.0, 1700.0),
+            mobility_range: (0.6, 1.6),
+            digitizer_num_samples,
+        }
+    }
+
+    pub fn ms1_frames(&self) -> impl Iterator<Item = &SyntheticFrame> {
+        self.frames.iter().filter(|f| f.is_ms1())
+    }
+
+    pub fn ms2_frames(&self) -> impl Iterator<Item = &SyntheticFrame> {
+        self.frames.iter().filter(|f| !f.is_ms1())
+    }
+
+    /// `(frame id, peak)` for every peak a loader should assign to `window`.
+    pub fn window_peaks(&self, window: &DiaWindow) -> Vec<(u32, Peak)> {
+        self.ms2_frames()
+            .filter(|f| f.window_group == Some(window.window_group))
+            .flat_map(|f| f.peaks.iter().filter(|p| window.contains_scan(p.scan)).map(move |&p| (f.id, p)))
+            .collect()
+    }
+
+    /// Writes `analysis.tdf` and `analysis.tdf_bin` into `d_folder`, creating it if needed.
+    pub fn write(&self, d_folder: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(d_folder)?;
+
+        let mut bin = BufWriter::new(File::create(d_folder.join("analysis.tdf_bin"))?);
+        let mut offsets = Vec::with_capacity(self.frames.len());
+        let mut offset = 0u64;
+        for frame in &self.frames {
+            let blob = encode_frame(frame)?;
+            offsets.push(offset);
+            bin.write_all(&blob)?;
+            offset += blob.len() as u64;
+        }
+        bin.flush()?;
+
+        let tdf_path = d_folder.join("analysis.tdf");
+        if tdf_path.exists() {
+            std::fs::remove_file(&tdf_path)?;
+        }
+        let mut conn = Connection::open(&tdf_path)?;
+        conn.execute_batch(SCHEMA)?;
+        let tx = conn.transaction()?;
+
+        let global: [(&str, String); 13] = [
+            ("SchemaType", "TDF".into()),
+            ("InstrumentName", "timsTOF Pro (synthetic)".into()),
+            ("InstrumentSerialNumber", "0000000".into()),
+            ("AcquisitionSoftware", "timstof-synthetic".into()),
+            ("AcquisitionSoftwareVersion", env!("CARGO_PKG_VERSION").into()),
+            ("AcquisitionDateTime", "2024-01-01T00:00:00.000+00:00".into()),
+            ("MethodName", "synthetic_diaPASEF.m".into()),
+            ("MzAcqRangeLower", self.mz_range.0.to_string()),
+            ("MzAcqRangeUpper", self.mz_range.1.to_string()),
+            ("OneOverK0AcqRangeLower", self.mobility_range.0.to_string()),
+            ("OneOverK0AcqRangeUpper", self.mobility_range.1.to_string()),
+            ("DigitizerNumSamples", self.digitizer_num_samples.to_string()),
+            ("TimsCompressionType", COMPRESSION_ZSTD.to_string()),
+        ];
+        for (key, value) in &global {
+            tx.execute("INSERT INTO GlobalMetadata (Key, Value) VALUES (?1, ?2)", params![key, value])?;
+        }
+
+        tx.execute(
+            "INSERT INTO MzCalibration (Id, ModelType, DigitizerTimebase, DigitizerDelay, T1, T2, dC1, dC2, C0, C1, C2, C3, C4) \
+             VALUES (1, 1, 0.2, 0.0, 25.0, 25.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0)",
+            [],
+        )?;
+        tx.execute(
+            "INSERT INTO TimsCalibration (Id, ModelType, C0, C1, C2, C3, C4, C5, C6, C7, C8, C9) \
+             VALUES (1, 2, 1.0, ?1, ?2, ?3, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0)",
+            params![self.frames.first().map_or(0, |f| f.num_scans), self.mobility_range.0, self.mobility_range.1],
+        )?;
+
+        for (frame, tims_id) in self.frames.iter().zip(&offsets) {
+            let max_intensity = frame.peaks.iter().map(|p| p.intensity).max().unwrap_or(0);
+            let summed: u64 = frame.peaks.iter().map(|p| p.intensity as u64).sum();
+            tx.execute(
+                "INSERT INTO Frames (Id, Time, Polarity, ScanMode, MsMsType, TimsId, MaxIntensity, SummedIntensities, \
+                 NumScans, NumPeaks, MzCalibration, T1, T2, TimsCalibration, PropertyGroup, AccumulationTime, RampTime) \
+                 VALUES (?1, ?2, '+', ?3, ?4, ?5, ?6, ?7, ?8, ?9, 1, 25.0, 25.0, 1, 1, ?10, ?10)",
+                params![
+                    frame.id, frame.rt_seconds, SCAN_MODE_DIA, frame.msms_type, *tims_id as i64,
+                    max_intensity, summed as i64, frame.num_scans, frame.peaks.len() as i64,
+                    frame.accumulation_time_ms,
+                ],
+            )?;
+            if let Some(group) = frame.window_group {
+                tx.execute("INSERT INTO DiaFrameMsMsInfo (Frame, WindowGroup) VALUES (?1, ?2)", params![frame.id, group])?;
+            }
+        }
+
+        for group in self.windows.iter().map(|w| w.window_group).collect::<std::collections::BTreeSet<_>>() {
+            tx.execute("INSERT INTO DiaFrameMsMsWindowGroups (Id) VALUES (?1)", params![group])?;
+        }
+        for w in &self.windows {
+            tx.execute(
+                "INSERT INTO DiaFrameMsMsWindows (WindowGroup, ScanNumBegin, ScanNumEnd, IsolationMz, IsolationWidth, CollisionEnergy) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![w.window_group, w.scan_begin, w.scan_end, w.isolation_mz, w.isolation_width, w.collision_energy],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Writes the run into `<tempdir>/synthetic.d`; the folder is removed when the guard drops.
+    pub fn write_temp(&self) -> Result<SyntheticDir, Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("synthetic.d");
+        self.write(&path)?;
+        Ok(SyntheticDir { _dir: dir, path })
+    }
+}
+
+/// A synthetic `.d` folder inside a temporary directory.
+pub struct SyntheticDir {
+    _dir: tempfile::TempDir,
+    path: PathBuf,
+}
+
+impl SyntheticDir {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE GlobalMetadata (Key TEXT PRIMARY KEY, Value TEXT);
+CREATE TABLE Frames (
+    Id INTEGER PRIMARY KEY, Time REAL NOT NULL, Polarity CHAR(1) NOT NULL, ScanMode INTEGER NOT NULL,
+    MsMsType INTEGER NOT NULL, TimsId INTEGER, MaxIntensity INTEGER NOT NULL, SummedIntensities INTEGER NOT NULL,
+    NumScans INTEGER NOT NULL, NumPeaks INTEGER NOT NULL, MzCalibration INTEGER NOT NULL, T1 REAL NOT NULL,
+    T2 REAL NOT NULL, TimsCalibration INTEGER NOT NULL, PropertyGroup INTEGER, AccumulationTime REAL NOT NULL,
+    RampTime REAL NOT NULL
+);
+CREATE TABLE MzCalibration (
+    Id INTEGER PRIMARY KEY, ModelType INTEGER NOT NULL, DigitizerTimebase REAL NOT NULL, DigitizerDelay REAL NOT NULL,
+    T1 REAL NOT NULL, T2 REAL NOT NULL, dC1 REAL NOT NULL, dC2 REAL NOT NULL,
+    C0 REAL NOT NULL, C1 REAL NOT NULL, C2 REAL NOT NULL, C3 REAL NOT NULL, C4 REAL
+);
+CREATE TABLE TimsCalibration (
+    Id INTEGER PRIMARY KEY, ModelType INTEGER NOT NULL, C0, C1, C2, C3, C4, C5, C6, C7, C8, C9
+);
+CREATE TABLE DiaFrameMsMsWindowGroups (Id INTEGER PRIMARY KEY);
+CREATE TABLE DiaFrameMsMsWindows (
+    WindowGroup INTEGER NOT NULL, ScanNumBegin INTEGER NOT NULL, ScanNumEnd INTEGER NOT NULL,
+    IsolationMz REAL NOT NULL, IsolationWidth REAL NOT NULL, CollisionEnergy REAL NOT NULL,
+    PRIMARY KEY (WindowGroup, ScanNumBegin)
+);
+CREATE TABLE DiaFrameMsMsInfo (Frame INTEGER PRIMARY KEY, WindowGroup INTEGER NOT NULL);
+";
+
+/// Frame blob layout in `analysis.tdf_bin`: `u32` total byte count, `u32` scan
+/// count, then the zstd-compressed body. The body is a byte-shuffled `u32`
+/// array: the scan count, `2 * peaks` for scans `0..n-1` (the last scan takes
+/// the rest), then `(tof delta, intensity)` pairs where TOF indices are
+/// stored `+1` and delta-coded within each scan.
+fn encode_frame(frame: &SyntheticFrame) -> Result<Vec<u8>, Box<dyn Error>> {
+    let num_scans = frame.num_scans as usize;
+    let mut per_scan = vec![0u32; num_scans];
+    for p in &frame.peaks {
+        per_scan[p.scan as usize] += 1;
+    }
+
+    let mut words = Vec::with_capacity(num_scans + 2 * frame.peaks.len());
+    words.push(frame.num_scans);
+    words.extend(per_scan[..num_scans - 1].iter().map(|&n| 2 * n));
+    let mut previous = (u32::MAX, 0u32);
+    for p in &frame.peaks {
+        let base = if p.scan == previous.0 { previous.1 } else { 0 };
+        words.push(p.tof + 1 - base);
+        words.push(p.intensity);
+        previous = (p.scan, p.tof + 1);
+    }
+
+    let n = words.len();
+    let mut shuffled = vec![0u8; 4 * n];
+    for (i, word) in words.iter().enumerate() {
+        for (b, byte) in word.to_le_bytes().into_iter().enumerate() {
+            shuffled[b * n + i] = byte;
+        }
+    }
+
+    let compressed = zstd::encode_all(shuffled.as_slice(), 0)?;
+    let mut blob = Vec::with_capacity(8 + compressed.len());
+    blob.extend_from_slice(&(8 + compressed.len() as u32).to_le_bytes());
+    blob.extend_from_slice(&frame.num_scans.to_le_bytes());
+    blob.extend_from_slice(&compressed);
+    Ok(blob)
+}
+
+/// Small deterministic generator so the crate needs no `rand` dependency.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        XorShift(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timsrust::readers::{FrameReader, MetadataReader};
+    use timsrust::MSLevel;
+
+    #[test]
+    fn generation_is_deterministic() {
+        assert_eq!(SyntheticRun::small(), SyntheticRun::small());
+        let other = SyntheticRun::generate(&SyntheticConfig { seed: 7, ..SyntheticConfig::default() });
+        assert_ne!(SyntheticRun::small().frames, other.frames);
+    }
+
+    #[test]
+    fn windows_tile_the_scan_range() {
+        let run = SyntheticRun::small();
+        for group in 1..=2u8 {
+            let mut ranges: Vec<_> = run.windows.iter()
+                .filter(|w| w.window_group == group)
+                .map(|w| (w.scan_begin, w.scan_end))
+                .collect();
+            ranges.sort_unstable();
+            assert_eq!(ranges, vec![(0, 49), (50, 99)]);
+        }
+    }
+
+    #[test]
+    fn overlapping_windows_share_scans() {
+        let run = SyntheticRun::overlapping();
+        let ranges: Vec<_> = run.windows.iter()
+            .filter(|w| w.window_group == 1)
+            .map(|w| (w.scan_begin, w.scan_end))
+            .collect();
+        assert_eq!(ranges, vec![(0, 42), (23, 75), (56, 99)]);
+
+        let assigned: usize = run.windows.iter().map(|w| run.window_peaks(w).len()).sum();
+        let ms2_peaks: usize = run.ms2_frames().map(|f| f.peaks.len()).sum();
+        assert!(assigned > ms2_peaks);
+    }
+
+    #[test]
+    fn timsrust_reads_back_every_peak() {
+        let run = SyntheticRun::small();
+        let dir = run.write_temp().unwrap();
+
+        let meta = MetadataReader::new(dir.path().join("analysis.tdf")).unwrap();
+        assert_eq!(meta.compression_type, COMPRESSION_ZSTD);
+
+        let frames = FrameReader::new(dir.path()).unwrap();
+        assert_eq!(frames.len(), run.frames.len());
+        for expected in &run.frames {
+            let frame = frames.get(expected.id as usize - 1).unwrap();
+            assert_eq!(frame.index, expected.id as usize);
+            assert_eq!(frame.rt_in_seconds, expected.rt_seconds);
+            assert_eq!(frame.ms_level, if expected.is_ms1() { MSLevel::MS1 } else { MSLevel::MS2 });
+
+            let tofs: Vec<u32> = expected.peaks.iter().map(|p| p.tof).collect();
+            let intensities: Vec<u32> = expected.peaks.iter().map(|p| p.intensity).collect();
+            assert_eq!(frame.tof_indices, tofs);
+            assert_eq!(frame.intensities, intensities);
+
+            for (i, p) in expected.peaks.iter().enumerate() {
+                let scan = timstof_common::scan_for_index(i, &frame.scan_offsets);
+                assert_eq!(scan as u32, p.scan);
+            }
+        }
+    }
+}
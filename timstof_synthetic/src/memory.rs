@@ -0,0 +1,154 @@
+//! Peak heap usage of a loader, for tests that bound its memory.
+//!
+//! A loader crate installs [`PeakAlloc`] around its usual allocator as the
+//! test binary's `#[global_allocator]`, then brackets a load with
+//! [`reset_peak`](PeakAlloc::reset_peak) and [`peak`](PeakAlloc::peak). Live
+//! heap bytes are counted rather than the process RSS, which also holds the
+//! test harness and whatever the allocator keeps cached after a free, so the
+//! bound does not depend on the allocator's reuse policy. The counters are
+//! process-wide: run the measuring test through [`isolated`] so no other test
+//! allocates at the same time.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: PeakAlloc<MiMalloc> = PeakAlloc::new(MiMalloc);
+//!
+//! #[test]
+//! fn bounded() {
+//!     if !memory::isolated("tests::bounded") {
+//!         return;
+//!     }
+//!     ALLOC.reset_peak();
+//!     let data = read_timstof_data(dir.path()).unwrap();
+//!     assert!(ALLOC.peak() - baseline < ...);
+//! }
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Set in the child process started by [`isolated`].
+const ISOLATED_ENV: &str = "TIMSTOF_SYNTHETIC_ISOLATED";
+
+/// `A`, counting live bytes and their high-water mark.
+pub struct PeakAlloc<A> {
+    inner: A,
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl<A> PeakAlloc<A> {
+    pub const fn new(inner: A) -> Self {
+        PeakAlloc { inner, current: AtomicUsize::new(0), peak: AtomicUsize::new(0) }
+    }
+
+    /// Bytes allocated and not yet freed.
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Highest [`current`](Self::current) since the last [`reset_peak`](Self::reset_peak).
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_peak(&self) {
+        self.peak.store(self.current(), Ordering::Relaxed);
+    }
+
+    fn grow(&self, bytes: usize) {
+        let current = self.current.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn shrink(&self, bytes: usize) {
+        self.current.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for PeakAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            self.grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            self.grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.shrink(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Counted as a fresh allocation while the old block may still be live.
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.grow(new_size);
+            self.shrink(layout.size());
+        }
+        new_ptr
+    }
+}
+
+/// Runs test `name` (its full path, e.g. `"tests::bounded"`) alone in a
+/// fresh process of the current test binary.
+///
+/// Returns `true` inside that process, where the test should do its work, and
+/// `false` in the calling test once the child has passed. Panics with the
+/// child's output if it failed.
+pub fn isolated(name: &str) -> bool {
+    if std::env::var_os(ISOLATED_ENV).is_some() {
+        return true;
+    }
+    let exe = std::env::current_exe().expect("test binary path");
+    let output = Command::new(exe)
+        .args([name, "--exact", "--test-threads=1", "--nocapture"])
+        .env(ISOLATED_ENV, "1")
+        .output()
+        .expect("failed to start the isolated test");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success() && stdout.contains("1 passed"),
+        "isolated test {} failed:\n{}\n{}",
+        name,
+        stdout,
+        String::from_utf8_lossy(&output.stderr),
+    );
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    #[global_allocator]
+    static ALLOC: PeakAlloc<System> = PeakAlloc::new(System);
+
+    #[test]
+    fn tracks_the_high_water_mark() {
+        if !isolated("memory::tests::tracks_the_high_water_mark") {
+            return;
+        }
+        let baseline = ALLOC.current();
+        ALLOC.reset_peak();
+        let mut a = vec![0u8; 1 << 20];
+        a.resize(3 << 20, 1);
+        drop(a);
+        let b = vec![0u8; 1 << 20];
+        assert!(ALLOC.peak() - baseline >= 3 << 20);
+        assert!(ALLOC.peak() - baseline < 5 << 20);
+        assert!(ALLOC.current() - baseline >= 1 << 20);
+        drop(b);
+    }
+}
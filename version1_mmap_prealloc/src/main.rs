@@ -1,12 +1,15 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
-use timsrust::{converters::ConvertableDomain, readers::{FrameReader, MetadataReader}, MSLevel};
+use timsrust::{converters::ConvertableDomain, readers::MetadataReader, MSLevel};
 use rayon::prelude::*;
+use timstof_common::{scan_ranges, window_bounds, window_key, PeakLayout, Threads, WindowSegments};
 
-const NUM_THREADS: usize = 32;
+mod tdf;
+
+use tdf::MmapFrameReader;
 
 #[derive(Debug, Clone)]
 pub struct TimsTOFData {
@@ -52,13 +55,35 @@ impl TimsTOFData {
         data
     }
     
-    fn merge_from(&mut self, other: &mut Self) {
-        self.rt_values_min.append(&mut other.rt_values_min);
-        self.mobility_values.append(&mut other.mobility_values);
-        self.mz_values.append(&mut other.mz_values);
-        self.intensity_values.append(&mut other.intensity_values);
-        self.frame_indices.append(&mut other.frame_indices);
-        self.scan_indices.append(&mut other.scan_indices);
+    /// `len` zeroed peaks, to be overwritten in place frame by frame.
+    pub fn zeroed(len: usize) -> Self {
+        Self {
+            rt_values_min: vec![0.0; len],
+            mobility_values: vec![0.0; len],
+            mz_values: vec![0.0; len],
+            intensity_values: vec![0; len],
+            frame_indices: vec![0; len],
+            scan_indices: vec![0; len],
+        }
+    }
+    
+    fn split_frames(&mut self, layout: &PeakLayout) -> Vec<FramePeaks<'_>> {
+        let mut rt_values_min = layout.split_mut(&mut self.rt_values_min).into_iter();
+        let mut mobility_values = layout.split_mut(&mut self.mobility_values).into_iter();
+        let mut mz_values = layout.split_mut(&mut self.mz_values).into_iter();
+        let mut intensity_values = layout.split_mut(&mut self.intensity_values).into_iter();
+        let mut frame_indices = layout.split_mut(&mut self.frame_indices).into_iter();
+        let mut scan_indices = layout.split_mut(&mut self.scan_indices).into_iter();
+        (0..layout.n_frames())
+            .map(|_| FramePeaks {
+                rt_values_min: rt_values_min.next().unwrap(),
+                mobility_values: mobility_values.next().unwrap(),
+                mz_values: mz_values.next().unwrap(),
+                intensity_values: intensity_values.next().unwrap(),
+                frame_indices: frame_indices.next().unwrap(),
+                scan_indices: scan_indices.next().unwrap(),
+            })
+            .collect()
     }
     
     fn extend_from(&mut self, other: &Self) {
@@ -77,36 +102,14 @@ pub struct TimsTOFRawData {
     pub ms2_windows: Vec<((f32, f32), TimsTOFData)>,
 }
 
-struct FrameSplit {
-    pub ms1: TimsTOFData,
-    pub ms2: Vec<((u32, u32), TimsTOFData)>,
-}
-
-#[inline]
-fn quantize(x: f32) -> u32 { 
-    (x * 10_000.0).round() as u32 
-}
-
-#[inline]
-fn find_scan_for_index_binary(index: usize, scan_offsets: &[usize]) -> usize {
-    match scan_offsets.binary_search(&index) {
-        Ok(pos) => pos,
-        Err(pos) => pos.saturating_sub(1),
-    }
-}
-
-fn estimate_total_peaks(frames: &FrameReader) -> usize {
-    let sample_size = std::cmp::min(100, frames.len());
-    let sample_sum: usize = (0..sample_size)
-        .into_par_iter()
-        .map(|idx| {
-            frames.get(idx)
-                .map(|f| f.tof_indices.len())
-                .unwrap_or(0)
-        })
-        .sum();
-    
-    (sample_sum * frames.len()) / sample_size
+// One frame's slots in the MS1 columns.
+struct FramePeaks<'a> {
+    rt_values_min: &'a mut [f32],
+    mobility_values: &'a mut [f32],
+    mz_values: &'a mut [f32],
+    intensity_values: &'a mut [u32],
+    frame_indices: &'a mut [u32],
+    scan_indices: &'a mut [u32],
 }
 
 pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
@@ -120,127 +123,126 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     let im_cv = Arc::new(meta.im_converter);
     println!("  Metadata initialization: {:.3}s", meta_start.elapsed().as_secs_f32());
     
-    println!("Initializing frame reader...");
+    println!("Memory-mapping analysis.tdf_bin...");
     let frame_reader_start = Instant::now();
-    let frames = FrameReader::new(d_folder)?;
+    let frames = MmapFrameReader::new(d_folder)?;
     let n_frames = frames.len();
     println!("  Frame reader initialization: {:.3}s", frame_reader_start.elapsed().as_secs_f32());
     println!("  Total frames to process: {}", n_frames);
     
-    println!("Estimating data size for pre-allocation...");
-    let estimate_start = Instant::now();
-    let estimated_peaks = estimate_total_peaks(&frames);
-    let ms1_estimate = estimated_peaks / 2;
-    let ms2_estimate = estimated_peaks / 2;
-    println!("  Estimated total peaks: ~{}", estimated_peaks);
-    println!("  Pre-allocation estimation: {:.3}s", estimate_start.elapsed().as_secs_f32());
+    // Frames.NumPeaks sizes the MS1 columns exactly before any frame is
+    // decoded; each worker fills its frame's slots in place.
+    println!("Sizing MS1 output from Frames.NumPeaks...");
+    let ms1_layout = PeakLayout::new(frames.peak_counts(MSLevel::MS1));
+    let mut global_ms1 = TimsTOFData::zeroed(ms1_layout.total());
+    println!("  MS1 peaks: {}", ms1_layout.total());
     
-    println!("Processing frames in parallel with {} threads...", NUM_THREADS);
+    let mut buffer = Vec::new();
+    let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get_with(idx, &mut buffer); })?;
+    let n_threads = pool.threads();
+    
+    println!("Processing frames in parallel with {} threads...", n_threads);
     let process_start = Instant::now();
     
-    let chunk_size = (n_frames + NUM_THREADS - 1) / NUM_THREADS;
-    let splits: Vec<FrameSplit> = (0..n_frames)
+    let chunk_size = n_frames.div_ceil(n_threads);
+    let splits: Vec<Vec<((u32, u32), TimsTOFData)>> = pool.install(|| global_ms1.split_frames(&ms1_layout)
         .into_par_iter()
+        .enumerate()
         .with_min_len(chunk_size)
-        .map(|idx| {
-            let frame = frames.get(idx).expect("frame read");
+        .map_init(Vec::new, |buffer, (idx, ms1)| -> Result<_, Box<dyn Error + Send + Sync>> {
+            let frame = frames.get_with(idx, buffer)?;
             let rt_min = frame.rt_in_seconds as f32 / 60.0;
-            let mut ms1 = TimsTOFData::new();
             let mut ms2_pairs: Vec<((u32,u32), TimsTOFData)> = Vec::new();
             
             match frame.ms_level {
                 MSLevel::MS1 => {
                     let n_peaks = frame.tof_indices.len();
-                    ms1 = TimsTOFData::with_capacity(n_peaks);
+                    if n_peaks != ms1.mz_values.len() {
+                        return Err(format!("frame {}: decoded {} peaks, Frames.NumPeaks has {}",
+                            frame.index, n_peaks, ms1.mz_values.len()).into());
+                    }
                     
-                    let scan_offsets = &frame.scan_offsets;
-                    for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter()
-                        .zip(frame.intensities.iter()).enumerate() {
-                        let mz = mz_cv.convert(tof as f64) as f32;
-                        let scan = find_scan_for_index_binary(p_idx, scan_offsets);
+                    for (scan, peaks) in scan_ranges(&frame.scan_offsets, n_peaks) {
                         let im = im_cv.convert(scan as f64) as f32;
-                        
-                        ms1.rt_values_min.push(rt_min);
-                        ms1.mobility_values.push(im);
-                        ms1.mz_values.push(mz);
-                        ms1.intensity_values.push(intensity);
-                        ms1.frame_indices.push(frame.index as u32);
-                        ms1.scan_indices.push(scan as u32);
+                        for i in peaks {
+                            ms1.rt_values_min[i] = rt_min;
+                            ms1.mobility_values[i] = im;
+                            ms1.mz_values[i] = mz_cv.convert(frame.tof_indices[i] as f64) as f32;
+                            ms1.intensity_values[i] = frame.intensities[i];
+                            ms1.frame_indices[i] = frame.index as u32;
+                            ms1.scan_indices[i] = scan as u32;
+                        }
                     }
                 }
                 MSLevel::MS2 => {
                     let qs = &frame.quadrupole_settings;
-                    let n_windows = qs.isolation_mz.len();
-                    ms2_pairs.reserve_exact(n_windows);
+                    let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+                    let keys = (0..n_windows)
+                        .map(|win| window_key(qs.isolation_mz[win], qs.isolation_width[win]))
+                        .collect::<Result<Vec<_>, _>>()?;
                     
-                    for win in 0..n_windows {
-                        if win >= qs.isolation_width.len() { break; }
-                        let prec_mz = qs.isolation_mz[win] as f32;
-                        let width = qs.isolation_width[win] as f32;
-                        let low = prec_mz - width * 0.5;
-                        let high = prec_mz + width * 0.5;
-                        let key = (quantize(low), quantize(high));
-                        
-                        let scan_start = qs.scan_starts[win];
-                        let scan_end = qs.scan_ends[win];
-                        let window_peaks: usize = frame.tof_indices.iter()
-                            .zip(frame.scan_offsets.windows(2))
-                            .filter(|(_, window)| {
-                                let scan = window[0];
-                                scan >= scan_start && scan <= scan_end
-                            })
-                            .count();
-                        
-                        let mut td = TimsTOFData::with_capacity(window_peaks);
-                        
-                        for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter()
-                            .zip(frame.intensities.iter()).enumerate() {
-                            let scan = find_scan_for_index_binary(p_idx, &frame.scan_offsets);
-                            if scan < scan_start || scan > scan_end { continue; }
-                            
-                            let mz = mz_cv.convert(tof as f64) as f32;
+                    // One pass over the scans; peaks in overlapping windows go to each of them.
+                    let n_peaks = frame.tof_indices.len();
+                    let segments = WindowSegments::new(&qs.scan_starts[..n_windows], &qs.scan_ends[..n_windows]);
+                    let mut windows: Vec<TimsTOFData> = segments.peak_counts(&frame.scan_offsets, n_peaks)
+                        .into_iter()
+                        .map(TimsTOFData::with_capacity)
+                        .collect();
+                    
+                    for (first, last, targets) in segments.iter() {
+                        for (scan, peaks) in scan_ranges(&frame.scan_offsets, n_peaks).within(first, last) {
                             let im = im_cv.convert(scan as f64) as f32;
-                            td.rt_values_min.push(rt_min);
-                            td.mobility_values.push(im);
-                            td.mz_values.push(mz);
-                            td.intensity_values.push(intensity);
-                            td.frame_indices.push(frame.index as u32);
-                            td.scan_indices.push(scan as u32);
+                            for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter()
+                                .zip(&frame.intensities[peaks]) {
+                                let mz = mz_cv.convert(tof as f64) as f32;
+                                for &win in targets {
+                                    let td = &mut windows[win];
+                                    td.rt_values_min.push(rt_min);
+                                    td.mobility_values.push(im);
+                                    td.mz_values.push(mz);
+                                    td.intensity_values.push(intensity);
+                                    td.frame_indices.push(frame.index as u32);
+                                    td.scan_indices.push(scan as u32);
+                                }
+                            }
                         }
-                        ms2_pairs.push((key, td));
                     }
+                    ms2_pairs = keys.into_iter().zip(windows).collect();
                 }
                 _ => {}
             }
-            FrameSplit { ms1, ms2: ms2_pairs }
+            Ok(ms2_pairs)
         })
-        .collect();
+        .collect::<Result<_, _>>())
+        .map_err(|e| e as Box<dyn Error>)?;
     println!("  Frame processing: {:.3}s", process_start.elapsed().as_secs_f32());
     
-    println!("Merging data with pre-allocated buffers...");
+    println!("Merging MS2 windows into pre-allocated buffers...");
     let merge_start = Instant::now();
     
-    let actual_ms1_size: usize = splits.par_iter().map(|s| s.ms1.mz_values.len()).sum();
-    let mut global_ms1 = TimsTOFData::preallocate_exact(actual_ms1_size);
-    
-    let mut ms2_hash: HashMap<(u32,u32), TimsTOFData> = HashMap::with_capacity(100);
-    
+    // Windows split frames by scan, so their sizes are only known once the
+    // frames are decoded: the per-frame chunks are grouped by key in frame
+    // order (windows come out sorted by key), then each window is allocated
+    // once at the sum of its chunks and filled in parallel with the others.
+    let mut ms2_chunks: BTreeMap<(u32,u32), Vec<TimsTOFData>> = BTreeMap::new();
     for split in splits {
-        global_ms1.extend_from(&split.ms1);
-        
-        for (key, mut td) in split.ms2 {
-            ms2_hash.entry(key)
-                .and_modify(|existing| existing.merge_from(&mut td))
-                .or_insert(td);
+        for (key, td) in split {
+            ms2_chunks.entry(key).or_default().push(td);
         }
     }
     
-    let mut ms2_vec = Vec::with_capacity(ms2_hash.len());
-    for ((q_low, q_high), td) in ms2_hash {
-        let low = q_low as f32 / 10_000.0;
-        let high = q_high as f32 / 10_000.0;
-        ms2_vec.push(((low, high), td));
-    }
+    let ms2_vec: Vec<((f32, f32), TimsTOFData)> = pool.install(|| ms2_chunks.into_iter()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(key, chunks)| {
+            let total = chunks.iter().map(|td| td.mz_values.len()).sum();
+            let mut window = TimsTOFData::preallocate_exact(total);
+            for td in &chunks {
+                window.extend_from(td);
+            }
+            (window_bounds(key), window)
+        })
+        .collect());
     println!("  Data merging: {:.3}s", merge_start.elapsed().as_secs_f32());
     
     println!("\n========== Data Summary ==========");
@@ -258,11 +260,6 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(NUM_THREADS)
-        .build_global()
-        .unwrap();
-    
     let d_folder_path = "/storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/test_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d";
     
     let d_path = Path::new(d_folder_path);
@@ -272,7 +269,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     
     println!("========== TimsTOF .d File Reader V1 (Memory-Mapped + Pre-allocation) ==========");
     println!("Data folder: {}", d_folder_path);
-    println!("Parallel threads: {}", NUM_THREADS);
+    println!("Parallel threads: auto ({} CPUs available)", timstof_common::threads::available_cpus());
     println!();
     
     let _raw_data = read_timstof_data(d_path)?;
@@ -280,4 +277,74 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("\n========== Processing Complete ==========");
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticFrame, SyntheticRun};
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("small", "v1", &digest!(data));
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("overlapping", "v1", &digest!(data));
+    }
+    
+    fn assert_exactly_sized(data: &TimsTOFData) {
+        let n = data.mz_values.len();
+        for (column, capacity, len) in [
+            ("rt_values_min", data.rt_values_min.capacity(), data.rt_values_min.len()),
+            ("mobility_values", data.mobility_values.capacity(), data.mobility_values.len()),
+            ("mz_values", data.mz_values.capacity(), data.mz_values.len()),
+            ("intensity_values", data.intensity_values.capacity(), data.intensity_values.len()),
+            ("frame_indices", data.frame_indices.capacity(), data.frame_indices.len()),
+            ("scan_indices", data.scan_indices.capacity(), data.scan_indices.len()),
+        ] {
+            assert_eq!((len, capacity), (n, n), "{}", column);
+        }
+    }
+    
+    // A long MS1-only wash at the end breaks any MS1:MS2 ratio guessed from
+    // the first frames.
+    #[test]
+    fn sizes_columns_exactly_with_an_ms1_only_wash() {
+        let mut run = SyntheticRun::small();
+        let ms1: Vec<_> = run.ms1_frames().cloned().collect();
+        let period = run.frames[1].rt_seconds - run.frames[0].rt_seconds;
+        for template in ms1.iter().cycle().take(30) {
+            let last = run.frames.last().unwrap();
+            let (id, rt_seconds) = (last.id + 1, last.rt_seconds + period);
+            run.frames.push(SyntheticFrame { id, rt_seconds, ..template.clone() });
+        }
+        let dir = run.write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        
+        assert_exactly_sized(&data.ms1_data);
+        for frame in run.ms1_frames() {
+            let loaded = data.ms1_data.frame_indices.iter().filter(|&&f| f == frame.id).count();
+            assert_eq!(loaded, frame.peaks.len(), "frame {}", frame.id);
+        }
+        assert_eq!(data.ms1_data.mz_values.len(), run.ms1_frames().map(|f| f.peaks.len()).sum::<usize>());
+        for (_, window) in &data.ms2_windows {
+            assert_exactly_sized(window);
+        }
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        determinism::assert_deterministic(
+            "v1",
+            || read_timstof_data(dir.path()).unwrap(),
+            |data| fingerprint!(data),
+        );
+    }
+}
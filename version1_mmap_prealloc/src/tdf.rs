@@ -0,0 +1,365 @@
+//! Frame decoding straight from a memory-mapped `analysis.tdf_bin`.
+//!
+//! `timsrust::FrameReader` copies each compressed frame into its own buffer
+//! before decompressing it. [`MmapFrameReader`] maps the file once and hands
+//! zstd the slice of the mapping at the frame's `Frames.TimsId` offset; the
+//! decompressed size is known from `NumScans`/`NumPeaks`, so each worker
+//! decompresses into one reused buffer. The output is the same as
+//! `FrameReader`'s for the fields the loader uses.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use memmap2::Mmap;
+use rusqlite::{Connection, OpenFlags};
+use timsrust::{MSLevel, QuadrupoleSettings};
+
+/// `GlobalMetadata.TimsCompressionType` of zstd frame blobs, the only layout
+/// this reader decodes.
+const COMPRESSION_ZSTD: u32 = 2;
+
+/// One frame, decoded.
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    /// `Frames.Id`.
+    pub index: usize,
+    pub rt_in_seconds: f64,
+    pub ms_level: MSLevel,
+    /// Isolation windows of the frame's DIA window group (empty for MS1).
+    pub quadrupole_settings: Arc<QuadrupoleSettings>,
+    /// Index of the first peak of every scan, as in `timsrust::Frame`.
+    pub scan_offsets: Vec<usize>,
+    pub tof_indices: Vec<u32>,
+    pub intensities: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameRow {
+    id: usize,
+    rt_in_seconds: f64,
+    msms_type: i64,
+    tims_id: usize,
+    num_scans: usize,
+    num_peaks: usize,
+}
+
+impl FrameRow {
+    fn ms_level(&self) -> MSLevel {
+        match self.msms_type {
+            0 => MSLevel::MS1,
+            8 | 9 => MSLevel::MS2,
+            _ => MSLevel::Unknown,
+        }
+    }
+}
+
+pub struct MmapFrameReader {
+    bin: Mmap,
+    rows: Vec<FrameRow>,
+    window_groups: HashMap<usize, i64>,
+    settings: HashMap<i64, Arc<QuadrupoleSettings>>,
+}
+
+impl MmapFrameReader {
+    pub fn new(d_folder: &Path) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open_with_flags(d_folder.join("analysis.tdf"), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        let compression: Option<String> = conn
+            .query_row("SELECT Value FROM GlobalMetadata WHERE Key = 'TimsCompressionType'", [], |row| row.get(0))
+            .ok();
+        if compression.as_deref().and_then(|v| v.parse::<u32>().ok()) != Some(COMPRESSION_ZSTD) {
+            return Err(format!("unsupported TimsCompressionType {:?}, expected {}", compression, COMPRESSION_ZSTD).into());
+        }
+
+        let mut rows = Vec::new();
+        let mut stmt = conn.prepare("SELECT Id, Time, MsMsType, TimsId, NumScans, NumPeaks FROM Frames ORDER BY Id")?;
+        let mut frames = stmt.query([])?;
+        while let Some(row) = frames.next()? {
+            let id: i64 = row.get(0)?;
+            let context = format!("frame {}", id);
+            rows.push(FrameRow {
+                id: to_usize(id, "Frames.Id", &context)?,
+                rt_in_seconds: row.get(1)?,
+                msms_type: row.get(2)?,
+                tims_id: to_usize(row.get(3)?, "Frames.TimsId", &context)?,
+                num_scans: to_usize(row.get(4)?, "Frames.NumScans", &context)?,
+                num_peaks: to_usize(row.get(5)?, "Frames.NumPeaks", &context)?,
+            });
+        }
+
+        // DIA window tables are absent from DDA acquisitions.
+        let has_dia_tables: bool = conn.query_row(
+            "SELECT COUNT(*) = 2 FROM sqlite_master WHERE type = 'table' AND name IN ('DiaFrameMsMsInfo', 'DiaFrameMsMsWindows')",
+            [],
+            |row| row.get(0),
+        )?;
+        let mut window_groups = HashMap::new();
+        let mut settings: HashMap<i64, QuadrupoleSettings> = HashMap::new();
+        if has_dia_tables {
+            let mut stmt = conn.prepare("SELECT Frame, WindowGroup FROM DiaFrameMsMsInfo")?;
+            let mut groups = stmt.query([])?;
+            while let Some(row) = groups.next()? {
+                let (frame, group): (i64, i64) = (row.get(0)?, row.get(1)?);
+                window_groups.insert(to_usize(frame, "DiaFrameMsMsInfo.Frame", format!("window group {}", group))?, group);
+            }
+            let mut stmt = conn.prepare(
+                "SELECT WindowGroup, ScanNumBegin, ScanNumEnd, IsolationMz, IsolationWidth, CollisionEnergy \
+                 FROM DiaFrameMsMsWindows ORDER BY WindowGroup, ScanNumBegin",
+            )?;
+            let mut windows = stmt.query([])?;
+            while let Some(row) = windows.next()? {
+                let group: i64 = row.get(0)?;
+                let context = format!("window group {}", group);
+                let index = to_usize(group, "DiaFrameMsMsWindows.WindowGroup", &context)?;
+                let qs = settings.entry(group)
+                    .or_insert_with(|| QuadrupoleSettings { index, ..Default::default() });
+                qs.scan_starts.push(to_usize(row.get(1)?, "DiaFrameMsMsWindows.ScanNumBegin", &context)?);
+                qs.scan_ends.push(to_usize(row.get(2)?, "DiaFrameMsMsWindows.ScanNumEnd", &context)?);
+                qs.isolation_mz.push(row.get(3)?);
+                qs.isolation_width.push(row.get(4)?);
+                qs.collision_energy.push(row.get(5)?);
+            }
+        }
+
+        let file = File::open(d_folder.join("analysis.tdf_bin"))?;
+        // SAFETY: the mapping is read-only and acquisitions are not modified
+        // while they are being read.
+        let bin = unsafe { Mmap::map(&file)? };
+
+        Ok(MmapFrameReader {
+            bin,
+            rows,
+            window_groups,
+            settings: settings.into_iter().map(|(group, qs)| (group, Arc::new(qs))).collect(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// `Frames.NumPeaks` of every frame at `level` and 0 for the others, in
+    /// frame order, without decompressing anything.
+    pub fn peak_counts(&self, level: MSLevel) -> impl Iterator<Item = usize> + '_ {
+        self.rows.iter().map(move |row| if row.ms_level() == level { row.num_peaks } else { 0 })
+    }
+
+    /// Decodes frame `index`, decompressing into `buffer`, which callers keep
+    /// across frames to avoid an allocation per frame.
+    pub fn get_with(&self, index: usize, buffer: &mut Vec<u8>) -> io::Result<RawFrame> {
+        let row = *self.rows.get(index)
+            .ok_or_else(|| invalid(format!("frame {} out of range ({} frames)", index, self.rows.len())))?;
+        let window_group = self.window_groups.get(&row.id).copied().unwrap_or(0);
+        let mut frame = RawFrame {
+            index: row.id,
+            rt_in_seconds: row.rt_in_seconds,
+            ms_level: row.ms_level(),
+            quadrupole_settings: self.settings.get(&window_group).cloned().unwrap_or_default(),
+            scan_offsets: Vec::new(),
+            tof_indices: Vec::new(),
+            intensities: Vec::new(),
+        };
+
+        // timsrust does not decode frames without peaks.
+        if row.num_peaks == 0 {
+            frame.scan_offsets = vec![0; row.num_scans.max(1)];
+            return Ok(frame);
+        }
+        decode_frame(self.blob(&row)?, row.num_scans, row.num_peaks, buffer, &mut frame)?;
+        Ok(frame)
+    }
+
+    // The compressed body of a frame: `u32` byte count (header included),
+    // `u32` scan count, then zstd data.
+    fn blob(&self, row: &FrameRow) -> io::Result<&[u8]> {
+        let past_end = || invalid(format!("frame {}: TimsId {} is past the end of analysis.tdf_bin", row.id, row.tims_id));
+        let body = row.tims_id.checked_add(8).ok_or_else(past_end)?;
+        let header = self.bin.get(row.tims_id..body).ok_or_else(past_end)?;
+        let byte_count = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let truncated = || invalid(format!("frame {}: blob of {} bytes is truncated", row.id, byte_count));
+        let end = row.tims_id.checked_add(byte_count.max(8)).ok_or_else(truncated)?;
+        self.bin.get(body..end).ok_or_else(truncated)
+    }
+}
+
+// The decompressed body is a byte-shuffled `u32` array (byte `b` of word `i`
+// at `b * n + i`): the scan count, then twice the peak count of scans
+// `0..num_scans - 1`, then `(tof, intensity)` pairs. TOF indices are stored
+// `+1` and delta-coded within each scan.
+fn decode_frame(
+    compressed: &[u8],
+    num_scans: usize,
+    num_peaks: usize,
+    buffer: &mut Vec<u8>,
+    frame: &mut RawFrame,
+) -> io::Result<()> {
+    let n = num_peaks.checked_mul(2).and_then(|words| words.checked_add(num_scans));
+    let n_bytes = n.and_then(|n| n.checked_mul(4))
+        .ok_or_else(|| invalid(format!("frame {}: {} scans and {} peaks overflow the frame size", frame.index, num_scans, num_peaks)))?;
+    let n = n_bytes / 4;
+    // A blob that records its decompressed size is checked before the buffer grows.
+    if let Ok(Some(size)) = zstd::zstd_safe::get_frame_content_size(compressed) {
+        if size != n_bytes as u64 {
+            return Err(invalid(format!(
+                "frame {}: blob holds {} bytes, expected {} for {} scans and {} peaks",
+                frame.index, size, n_bytes, num_scans, num_peaks,
+            )));
+        }
+    }
+    buffer.resize(n_bytes, 0);
+    let written = zstd::bulk::decompress_to_buffer(compressed, &mut buffer[..])?;
+    if written != n_bytes {
+        return Err(invalid(format!(
+            "frame {}: decompressed {} bytes, expected {} for {} scans and {} peaks",
+            frame.index, written, n_bytes, num_scans, num_peaks,
+        )));
+    }
+    let bytes = &buffer[..];
+    let word = |i: usize| u32::from_le_bytes([bytes[i], bytes[n + i], bytes[2 * n + i], bytes[3 * n + i]]);
+
+    let mut scan_offsets = Vec::with_capacity(num_scans.max(1));
+    scan_offsets.push(0);
+    for scan in 1..num_scans {
+        scan_offsets.push(scan_offsets[scan - 1] + (word(scan) / 2) as usize);
+    }
+    if scan_offsets.last().is_some_and(|&last| last > num_peaks) {
+        return Err(invalid(format!("frame {}: scan sizes exceed NumPeaks {}", frame.index, num_peaks)));
+    }
+
+    let mut tof_indices = Vec::with_capacity(num_peaks);
+    let mut intensities = Vec::with_capacity(num_peaks);
+    for scan in 0..scan_offsets.len() {
+        let end = scan_offsets.get(scan + 1).copied().unwrap_or(num_peaks);
+        let mut tof = 0u32;
+        for peak in scan_offsets[scan]..end {
+            tof = tof.wrapping_add(word(num_scans + 2 * peak));
+            tof_indices.push(tof.wrapping_sub(1));
+            intensities.push(word(num_scans + 2 * peak + 1));
+        }
+    }
+
+    frame.scan_offsets = scan_offsets;
+    frame.tof_indices = tof_indices;
+    frame.intensities = intensities;
+    Ok(())
+}
+
+// SQLite integers that index or size something must be non-negative and fit
+// in a usize.
+fn to_usize(value: i64, column: &str, context: impl fmt::Display) -> io::Result<usize> {
+    usize::try_from(value).map_err(|_| invalid(format!("{}: invalid {} {}", context, column, value)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timsrust::readers::FrameReader;
+    use timstof_synthetic::SyntheticRun;
+
+    fn assert_same_frames(d_folder: &Path) {
+        let reference = FrameReader::new(d_folder).unwrap();
+        let mmap = MmapFrameReader::new(d_folder).unwrap();
+        assert_eq!(mmap.len(), reference.len());
+
+        let mut buffer = Vec::new();
+        for idx in 0..reference.len() {
+            let expected = reference.get(idx).unwrap();
+            let actual = mmap.get_with(idx, &mut buffer).unwrap();
+            assert_eq!(actual.index, expected.index);
+            assert_eq!(actual.rt_in_seconds.to_bits(), expected.rt_in_seconds.to_bits());
+            assert_eq!(actual.ms_level, expected.ms_level);
+            assert_eq!(*actual.quadrupole_settings, *expected.quadrupole_settings, "frame {}", actual.index);
+            assert_eq!(actual.scan_offsets, expected.scan_offsets, "frame {}", actual.index);
+            assert_eq!(actual.tof_indices, expected.tof_indices, "frame {}", actual.index);
+            assert_eq!(actual.intensities, expected.intensities, "frame {}", actual.index);
+        }
+    }
+
+    #[test]
+    fn decodes_the_same_frames_as_frame_reader() {
+        for run in [SyntheticRun::small(), SyntheticRun::overlapping()] {
+            let dir = run.write_temp().unwrap();
+            assert_same_frames(dir.path());
+        }
+    }
+
+    #[test]
+    fn decodes_frames_without_peaks() {
+        let mut run = SyntheticRun::small();
+        run.frames[0].peaks.clear();
+        let ms2 = run.frames.iter().position(|f| !f.is_ms1()).unwrap();
+        run.frames[ms2].peaks.clear();
+        let dir = run.write_temp().unwrap();
+        assert_same_frames(dir.path());
+    }
+
+    #[test]
+    fn rejects_truncated_blobs() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let bin = dir.path().join("analysis.tdf_bin");
+        let len = std::fs::metadata(&bin).unwrap().len();
+        File::options().write(true).open(&bin).unwrap().set_len(len - 16).unwrap();
+
+        let reader = MmapFrameReader::new(dir.path()).unwrap();
+        let last = reader.len() - 1;
+        let mut buffer = Vec::new();
+        let err = reader.get_with(last, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(reader.get_with(0, &mut buffer).is_ok());
+        assert!(reader.get_with(reader.len(), &mut buffer).is_err());
+    }
+
+    fn update(d_folder: &Path, sql: &str) {
+        Connection::open(d_folder.join("analysis.tdf")).unwrap().execute_batch(sql).unwrap();
+    }
+
+    #[test]
+    fn rejects_negative_and_oversized_counts() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        update(dir.path(), "UPDATE Frames SET NumScans = -1 WHERE Id = 1");
+        let err = MmapFrameReader::new(dir.path()).err().unwrap();
+        assert_eq!(err.to_string(), "frame 1: invalid Frames.NumScans -1");
+
+        update(dir.path(), &format!("UPDATE Frames SET NumScans = 10, NumPeaks = {} WHERE Id = 1", i64::MAX));
+        let reader = MmapFrameReader::new(dir.path()).unwrap();
+        let err = reader.get_with(0, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("overflow"), "{}", err);
+    }
+
+    // WindowGroup used to be truncated to u8, so groups 1 and 257 shared
+    // their isolation windows.
+    #[test]
+    fn window_groups_are_not_truncated() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let before = MmapFrameReader::new(dir.path()).unwrap();
+        let mut buffer = Vec::new();
+        let expected: Vec<_> = (0..before.len()).map(|idx| before.get_with(idx, &mut buffer).unwrap().quadrupole_settings).collect();
+        assert!(expected.iter().any(|qs| qs.index == 2));
+
+        update(dir.path(), "
+            UPDATE DiaFrameMsMsInfo SET WindowGroup = WindowGroup + 256 WHERE WindowGroup = 1;
+            UPDATE DiaFrameMsMsWindows SET WindowGroup = WindowGroup + 256 WHERE WindowGroup = 1;
+            UPDATE DiaFrameMsMsInfo SET WindowGroup = 1 WHERE WindowGroup = 2;
+            UPDATE DiaFrameMsMsWindows SET WindowGroup = 1 WHERE WindowGroup = 2;
+        ");
+        let after = MmapFrameReader::new(dir.path()).unwrap();
+        for (idx, expected) in expected.iter().enumerate() {
+            let actual = after.get_with(idx, &mut buffer).unwrap().quadrupole_settings;
+            let renamed = match expected.index {
+                1 => 257,
+                2 => 1,
+                index => index,
+            };
+            assert_eq!(*actual, QuadrupoleSettings { index: renamed, ..(**expected).clone() }, "frame {}", idx);
+        }
+    }
+}
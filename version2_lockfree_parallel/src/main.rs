@@ -1,16 +1,10 @@
 use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 use timsrust::{converters::ConvertableDomain, readers::{FrameReader, MetadataReader}, MSLevel};
 use rayon::prelude::*;
-use dashmap::DashMap;
-use crossbeam_channel::{bounded, Sender, Receiver};
-use parking_lot::Mutex;
-
-const NUM_THREADS: usize = 32;
-const CHANNEL_BUFFER_SIZE: usize = 1000;
+use timstof_common::{concat_parallel, group_by_window, scan_ranges, window_bounds, window_key, Threads, WindowKeyError, WindowSegments};
 
 #[derive(Debug, Clone)]
 pub struct TimsTOFData {
@@ -45,13 +39,21 @@ impl TimsTOFData {
         }
     }
     
-    fn merge_from(&mut self, other: TimsTOFData) {
-        self.rt_values_min.extend(other.rt_values_min);
-        self.mobility_values.extend(other.mobility_values);
-        self.mz_values.extend(other.mz_values);
-        self.intensity_values.extend(other.intensity_values);
-        self.frame_indices.extend(other.frame_indices);
-        self.scan_indices.extend(other.scan_indices);
+    // Concatenates chunks column by column, each column copied in parallel.
+    fn concat(mut chunks: Vec<TimsTOFData>) -> Self {
+        macro_rules! column {
+            ($name:ident) => {
+                concat_parallel(chunks.iter_mut().map(|chunk| std::mem::take(&mut chunk.$name)).collect())
+            };
+        }
+        Self {
+            rt_values_min: column!(rt_values_min),
+            mobility_values: column!(mobility_values),
+            mz_values: column!(mz_values),
+            intensity_values: column!(intensity_values),
+            frame_indices: column!(frame_indices),
+            scan_indices: column!(scan_indices),
+        }
     }
 }
 
@@ -61,22 +63,11 @@ pub struct TimsTOFRawData {
     pub ms2_windows: Vec<((f32, f32), TimsTOFData)>,
 }
 
+// One result per frame index, collected in frame order.
 enum ProcessedFrame {
     MS1(TimsTOFData),
     MS2(Vec<((u32, u32), TimsTOFData)>),
-}
-
-#[inline]
-fn quantize(x: f32) -> u32 { 
-    (x * 10_000.0).round() as u32 
-}
-
-#[inline]
-fn find_scan_for_index_binary(index: usize, scan_offsets: &[usize]) -> usize {
-    match scan_offsets.binary_search(&index) {
-        Ok(pos) => pos,
-        Err(pos) => pos.saturating_sub(1),
-    }
+    Empty,
 }
 
 fn process_frame_worker(
@@ -84,11 +75,10 @@ fn process_frame_worker(
     frames: &FrameReader,
     mz_cv: Arc<impl ConvertableDomain>,
     im_cv: Arc<impl ConvertableDomain>,
-    sender: Sender<ProcessedFrame>,
-) {
+) -> Result<ProcessedFrame, WindowKeyError> {
     let frame = match frames.get(frame_idx) {
         Ok(f) => f,
-        Err(_) => return,
+        Err(_) => return Ok(ProcessedFrame::Empty),
     };
     
     let rt_min = frame.rt_in_seconds as f32 / 60.0;
@@ -98,61 +88,58 @@ fn process_frame_worker(
             let n_peaks = frame.tof_indices.len();
             let mut ms1 = TimsTOFData::with_capacity(n_peaks);
             
-            for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter()
-                .zip(frame.intensities.iter()).enumerate() {
-                let mz = mz_cv.convert(tof as f64) as f32;
-                let scan = find_scan_for_index_binary(p_idx, &frame.scan_offsets);
+            for (scan, peaks) in scan_ranges(&frame.scan_offsets, n_peaks) {
                 let im = im_cv.convert(scan as f64) as f32;
-                
-                ms1.rt_values_min.push(rt_min);
-                ms1.mobility_values.push(im);
-                ms1.mz_values.push(mz);
-                ms1.intensity_values.push(intensity);
-                ms1.frame_indices.push(frame.index as u32);
-                ms1.scan_indices.push(scan as u32);
+                for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter()
+                    .zip(&frame.intensities[peaks]) {
+                    let mz = mz_cv.convert(tof as f64) as f32;
+                    
+                    ms1.rt_values_min.push(rt_min);
+                    ms1.mobility_values.push(im);
+                    ms1.mz_values.push(mz);
+                    ms1.intensity_values.push(intensity);
+                    ms1.frame_indices.push(frame.index as u32);
+                    ms1.scan_indices.push(scan as u32);
+                }
             }
             
-            let _ = sender.send(ProcessedFrame::MS1(ms1));
+            Ok(ProcessedFrame::MS1(ms1))
         }
         MSLevel::MS2 => {
             let qs = &frame.quadrupole_settings;
-            let mut ms2_pairs = Vec::with_capacity(qs.isolation_mz.len());
+            let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+            let keys = (0..n_windows)
+                .map(|win| window_key(qs.isolation_mz[win], qs.isolation_width[win]))
+                .collect::<Result<Vec<_>, _>>()?;
             
-            for win in 0..qs.isolation_mz.len() {
-                if win >= qs.isolation_width.len() { break; }
-                
-                let prec_mz = qs.isolation_mz[win] as f32;
-                let width = qs.isolation_width[win] as f32;
-                let low = prec_mz - width * 0.5;
-                let high = prec_mz + width * 0.5;
-                let key = (quantize(low), quantize(high));
-                
-                let mut td = TimsTOFData::new();
-                for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter()
-                    .zip(frame.intensities.iter()).enumerate() {
-                    let scan = find_scan_for_index_binary(p_idx, &frame.scan_offsets);
-                    if scan < qs.scan_starts[win] || scan > qs.scan_ends[win] { continue; }
-                    
-                    let mz = mz_cv.convert(tof as f64) as f32;
+            // One pass over the scans; peaks in overlapping windows go to each of them.
+            let mut windows: Vec<TimsTOFData> = (0..n_windows).map(|_| TimsTOFData::new()).collect();
+            for (first, last, targets) in WindowSegments::new(&qs.scan_starts[..n_windows], &qs.scan_ends[..n_windows]).iter() {
+                for (scan, peaks) in scan_ranges(&frame.scan_offsets, frame.tof_indices.len()).within(first, last) {
                     let im = im_cv.convert(scan as f64) as f32;
-                    td.rt_values_min.push(rt_min);
-                    td.mobility_values.push(im);
-                    td.mz_values.push(mz);
-                    td.intensity_values.push(intensity);
-                    td.frame_indices.push(frame.index as u32);
-                    td.scan_indices.push(scan as u32);
-                }
-                
-                if !td.mz_values.is_empty() {
-                    ms2_pairs.push((key, td));
+                    for (&tof, &intensity) in frame.tof_indices[peaks.clone()].iter()
+                        .zip(&frame.intensities[peaks]) {
+                        let mz = mz_cv.convert(tof as f64) as f32;
+                        for &win in targets {
+                            let td = &mut windows[win];
+                            td.rt_values_min.push(rt_min);
+                            td.mobility_values.push(im);
+                            td.mz_values.push(mz);
+                            td.intensity_values.push(intensity);
+                            td.frame_indices.push(frame.index as u32);
+                            td.scan_indices.push(scan as u32);
+                        }
+                    }
                 }
             }
             
-            if !ms2_pairs.is_empty() {
-                let _ = sender.send(ProcessedFrame::MS2(ms2_pairs));
-            }
+            let ms2_pairs: Vec<_> = keys.into_iter().zip(windows)
+                .filter(|(_, td)| !td.mz_values.is_empty())
+                .collect();
+            
+            Ok(ProcessedFrame::MS2(ms2_pairs))
         }
-        _ => {}
+        _ => Ok(ProcessedFrame::Empty),
     }
 }
 
@@ -174,76 +161,46 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     println!("  Frame reader initialization: {:.3}s", frame_reader_start.elapsed().as_secs_f32());
     println!("  Total frames to process: {}", n_frames);
     
-    println!("Processing frames with lock-free parallel aggregation ({} threads)...", NUM_THREADS);
-    let process_start = Instant::now();
-    
-    let (sender, receiver) = bounded(CHANNEL_BUFFER_SIZE);
-    let processed_count = Arc::new(AtomicUsize::new(0));
-    
-    let ms1_accumulator = Arc::new(Mutex::new(Vec::new()));
-    let ms2_map = Arc::new(DashMap::new());
+    let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
     
-    let ms1_acc_clone = Arc::clone(&ms1_accumulator);
-    let ms2_map_clone = Arc::clone(&ms2_map);
-    let processed_clone = Arc::clone(&processed_count);
-    
-    let aggregator_handle = std::thread::spawn(move || {
-        while let Ok(frame_data) = receiver.recv() {
-            match frame_data {
-                ProcessedFrame::MS1(data) => {
-                    ms1_acc_clone.lock().push(data);
-                }
-                ProcessedFrame::MS2(pairs) => {
-                    for (key, data) in pairs {
-                        ms2_map_clone.entry(key)
-                            .or_insert_with(|| Arc::new(Mutex::new(TimsTOFData::new())))
-                            .lock()
-                            .merge_from(data);
-                    }
-                }
-            }
-            processed_clone.fetch_add(1, Ordering::Relaxed);
-        }
-    });
+    println!("Processing frames with parallel aggregation ({} threads)...", pool.threads());
+    let process_start = Instant::now();
     
-    (0..n_frames).into_par_iter().for_each(|idx| {
+    // No aggregator thread: results come back through an indexed collect, in
+    // frame order for any thread count, and are merged in parallel below.
+    let processed = pool.install(|| (0..n_frames).into_par_iter().map(|idx| {
         let frames_ref = &*frames;
         let mz_cv_clone = Arc::clone(&mz_cv);
         let im_cv_clone = Arc::clone(&im_cv);
-        let sender_clone = sender.clone();
         
-        process_frame_worker(idx, frames_ref, mz_cv_clone, im_cv_clone, sender_clone);
-    });
-    
-    drop(sender);
-    aggregator_handle.join().unwrap();
+        process_frame_worker(idx, frames_ref, mz_cv_clone, im_cv_clone)
+    }).collect::<Result<Vec<_>, _>>())?;
     
     println!("  Frame processing: {:.3}s", process_start.elapsed().as_secs_f32());
-    println!("  Frames processed: {}", processed_count.load(Ordering::Relaxed));
+    println!("  Frames processed: {}", processed.len());
     
-    println!("Finalizing data structures...");
+    println!("Merging frames in parallel...");
     let finalize_start = Instant::now();
     
-    let ms1_chunks = ms1_accumulator.lock();
-    let total_ms1_size: usize = ms1_chunks.iter().map(|c| c.mz_values.len()).sum();
-    let mut global_ms1 = TimsTOFData::with_capacity(total_ms1_size);
-    for chunk in ms1_chunks.iter() {
-        global_ms1.rt_values_min.extend(&chunk.rt_values_min);
-        global_ms1.mobility_values.extend(&chunk.mobility_values);
-        global_ms1.mz_values.extend(&chunk.mz_values);
-        global_ms1.intensity_values.extend(&chunk.intensity_values);
-        global_ms1.frame_indices.extend(&chunk.frame_indices);
-        global_ms1.scan_indices.extend(&chunk.scan_indices);
-    }
-    
-    let mut ms2_vec = Vec::with_capacity(ms2_map.len());
-    for entry in ms2_map.iter() {
-        let (q_low, q_high) = *entry.key();
-        let low = q_low as f32 / 10_000.0;
-        let high = q_high as f32 / 10_000.0;
-        let data = entry.value().lock().clone();
-        ms2_vec.push(((low, high), data));
+    // Chunks are moved into per-window lists in frame order, then each column
+    // is sized from its chunks and filled by parallel copies.
+    let mut ms1_chunks = Vec::new();
+    let mut ms2_frames = Vec::new();
+    for frame in processed {
+        match frame {
+            ProcessedFrame::MS1(data) => ms1_chunks.push(data),
+            ProcessedFrame::MS2(pairs) => ms2_frames.push(pairs),
+            ProcessedFrame::Empty => {}
+        }
     }
+    let (global_ms1, ms2_vec) = pool.install(|| {
+        let global_ms1 = TimsTOFData::concat(ms1_chunks);
+        let ms2_vec: Vec<_> = group_by_window(ms2_frames)
+            .into_iter()
+            .map(|(key, chunks)| (window_bounds(key), TimsTOFData::concat(chunks)))
+            .collect();
+        (global_ms1, ms2_vec)
+    });
     
     println!("  Data finalization: {:.3}s", finalize_start.elapsed().as_secs_f32());
     
@@ -262,11 +219,6 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(NUM_THREADS)
-        .build_global()
-        .unwrap();
-    
     let d_folder_path = "/storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/test_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d";
     
     let d_path = Path::new(d_folder_path);
@@ -276,7 +228,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     
     println!("========== TimsTOF .d File Reader V2 (Lock-free Parallel Aggregation) ==========");
     println!("Data folder: {}", d_folder_path);
-    println!("Parallel threads: {}", NUM_THREADS);
+    println!("Parallel threads: auto ({} CPUs available)", timstof_common::threads::available_cpus());
     println!();
     
     let _raw_data = read_timstof_data(d_path)?;
@@ -284,4 +236,34 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("\n========== Processing Complete ==========");
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticRun};
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("small", "v2", &digest!(data));
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("overlapping", "v2", &digest!(data));
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        determinism::assert_deterministic(
+            "v2",
+            || read_timstof_data(dir.path()).unwrap(),
+            |data| fingerprint!(data),
+        );
+    }
+}
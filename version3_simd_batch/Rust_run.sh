@@ -10,5 +10,4 @@
 # module
 module load gcc
 cd /storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/accelerate_raw_data_reading/version3_simd_batch
-export RUSTFLAGS="-C target-cpu=znver2"
 cargo run --release
\ No newline at end of file
@@ -0,0 +1,403 @@
+//! Vectorized conversion kernels, selected at runtime.
+//!
+//! timsrust's converters are affine in the index: `a + b·scan` for mobility
+//! and `(a + b·tof)²` for m/z, evaluated in f64 and narrowed to f32 here. The
+//! kernels evaluate exactly those operations lane by lane (separate multiply
+//! and add, no FMA, round-to-nearest narrowing), so they are bit-identical to
+//! calling the converter per peak. The instruction set is detected once at
+//! startup, so the binary no longer needs `-C target-cpu` to use it.
+
+use std::sync::OnceLock;
+use timsrust::converters::ConvertableDomain;
+use timstof_common::{window_key, WindowKey, WindowKeyError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isa {
+    Scalar,
+    Avx2,
+    Avx512,
+    Neon,
+}
+
+impl Isa {
+    /// Best instruction set of the running CPU.
+    pub fn detect() -> Isa {
+        static ISA: OnceLock<Isa> = OnceLock::new();
+        *ISA.get_or_init(|| {
+            [Isa::Avx512, Isa::Avx2, Isa::Neon].into_iter().find(|isa| isa.is_supported()).unwrap_or(Isa::Scalar)
+        })
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Isa::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Isa::Scalar => "scalar",
+            Isa::Avx2 => "AVX2",
+            Isa::Avx512 => "AVX-512",
+            Isa::Neon => "NEON",
+        }
+    }
+}
+
+/// `intercept + slope·x`, squared for m/z, narrowed to f32.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AffineKernel {
+    intercept: f64,
+    slope: f64,
+    square: bool,
+    isa: Isa,
+}
+
+impl AffineKernel {
+    /// timsrust builds the TOF converter from `MzAcqRangeLower/Upper` and the
+    /// maximum TOF index, which `Metadata` does not expose; it is recovered by
+    /// inverting the upper bound. `None` if the result does not reproduce the
+    /// converter (e.g. a different calibration model).
+    pub fn tof_to_mz(cv: &impl ConvertableDomain, lower_mz: f64, upper_mz: f64) -> Option<Self> {
+        let intercept = lower_mz.sqrt();
+        let tof_max = cv.invert(upper_mz).round();
+        let slope = (upper_mz.sqrt() - intercept) / tof_max;
+        AffineKernel { intercept, slope, square: true, isa: Isa::detect() }.verified(cv, tof_max)
+    }
+
+    /// Same for the scan converter, which runs from `upper_im` at scan 0 down
+    /// to `lower_im` at the maximum scan.
+    pub fn scan_to_mobility(cv: &impl ConvertableDomain, lower_im: f64, upper_im: f64) -> Option<Self> {
+        let scan_max = cv.invert(lower_im).round();
+        let slope = (lower_im - upper_im) / scan_max;
+        AffineKernel { intercept: upper_im, slope, square: false, isa: Isa::detect() }.verified(cv, scan_max)
+    }
+
+    fn verified(self, cv: &impl ConvertableDomain, max: f64) -> Option<Self> {
+        if !(max >= 1.0 && max < u32::MAX as f64) {
+            return None;
+        }
+        let span = 2 * max as u64;
+        let probes = (0..=256).map(|i| (span * i / 256) as u32).chain([1, 1 << 31, u32::MAX]);
+        let mut reproduced = true;
+        for x in probes {
+            reproduced &= self.scalar(x).to_bits() == (cv.convert(x as f64) as f32).to_bits();
+        }
+        reproduced.then_some(self)
+    }
+
+    #[cfg(test)]
+    fn with_isa(self, isa: Isa) -> Self {
+        AffineKernel { isa, ..self }
+    }
+
+    #[inline]
+    fn scalar(&self, x: u32) -> f32 {
+        let y = self.intercept + self.slope * x as f64;
+        (if self.square { y * y } else { y }) as f32
+    }
+
+    fn convert_scalar(&self, input: &[u32], output: &mut [f32]) {
+        for (out, &x) in output.iter_mut().zip(input) {
+            *out = self.scalar(x);
+        }
+    }
+
+    pub fn convert(&self, input: &[u32], output: &mut [f32]) {
+        assert_eq!(input.len(), output.len());
+        match self.isa {
+            // SAFETY: `isa` is only ever a variant whose features were detected.
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx512 => unsafe { x86::affine_avx512(self, input, output) },
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 => unsafe { x86::affine_avx2(self, input, output) },
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => unsafe { neon::affine(self, input, output) },
+            _ => self.convert_scalar(input, output),
+        }
+    }
+}
+
+/// A converter in batch form: the vectorized kernel when its coefficients
+/// could be recovered, the converter itself per value otherwise.
+pub struct BatchConverter<C> {
+    converter: C,
+    kernel: Option<AffineKernel>,
+}
+
+impl<C: ConvertableDomain> BatchConverter<C> {
+    pub fn tof_to_mz(converter: C, lower_mz: f64, upper_mz: f64) -> Self {
+        let kernel = AffineKernel::tof_to_mz(&converter, lower_mz, upper_mz);
+        BatchConverter { converter, kernel }
+    }
+
+    pub fn scan_to_mobility(converter: C, lower_im: f64, upper_im: f64) -> Self {
+        let kernel = AffineKernel::scan_to_mobility(&converter, lower_im, upper_im);
+        BatchConverter { converter, kernel }
+    }
+
+    pub fn is_vectorized(&self) -> bool {
+        self.kernel.is_some_and(|k| k.isa != Isa::Scalar)
+    }
+
+    /// Appends the conversion of every value in `input` to `output`.
+    pub fn extend(&self, input: &[u32], output: &mut Vec<f32>) {
+        match &self.kernel {
+            Some(kernel) => {
+                let start = output.len();
+                output.resize(start + input.len(), 0.0);
+                kernel.convert(input, &mut output[start..]);
+            }
+            None => output.extend(input.iter().map(|&x| self.converter.convert(x as f64) as f32)),
+        }
+    }
+}
+
+/// Keys of a frame's isolation windows, with the same results (and the same
+/// first error) as calling [`window_key`] on each window in turn.
+pub fn window_keys(isolation_mz: &[f64], isolation_width: &[f64]) -> Result<Vec<WindowKey>, WindowKeyError> {
+    window_keys_with(Isa::detect(), isolation_mz, isolation_width)
+}
+
+fn window_keys_with(isa: Isa, isolation_mz: &[f64], isolation_width: &[f64]) -> Result<Vec<WindowKey>, WindowKeyError> {
+    let n = isolation_mz.len().min(isolation_width.len());
+    let mut keys = Vec::with_capacity(n);
+    let mut quantized = [(0u32, 0u32, false); 4];
+    let mut i = 0;
+    while i + 4 <= n {
+        let (mz, width) = (&isolation_mz[i..i + 4], &isolation_width[i..i + 4]);
+        match isa {
+            // SAFETY: as in `AffineKernel::convert`.
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 | Isa::Avx512 => unsafe { x86::window_keys4(mz, width, &mut quantized) },
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => unsafe { neon::window_keys4(mz, width, &mut quantized) },
+            _ => break,
+        }
+        // Lanes the kernel cannot key (invalid, or beyond its 2^31 range)
+        // go through `window_key`, which also produces the error.
+        for (lane, &(low, high, valid)) in quantized.iter().enumerate() {
+            keys.push(if valid { (low, high) } else { window_key(mz[lane], width[lane])? });
+        }
+        i += 4;
+    }
+    for w in i..n {
+        keys.push(window_key(isolation_mz[w], isolation_width[w])?);
+    }
+    Ok(keys)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+    use super::AffineKernel;
+    use timstof_common::WINDOW_KEY_SCALE;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn affine_avx2(kernel: &AffineKernel, input: &[u32], output: &mut [f32]) {
+        let intercept = _mm256_set1_pd(kernel.intercept);
+        let slope = _mm256_set1_pd(kernel.slope);
+        // u32 -> f64 through the signed conversion: flip the sign bit, convert,
+        // add 2^31 back. Every step is exact.
+        let sign = _mm_set1_epi32(i32::MIN);
+        let bias = _mm256_set1_pd(2_147_483_648.0);
+        let n = input.len() / 4 * 4;
+        for i in (0..n).step_by(4) {
+            let x = _mm_xor_si128(_mm_loadu_si128(input.as_ptr().add(i) as *const __m128i), sign);
+            let x = _mm256_add_pd(_mm256_cvtepi32_pd(x), bias);
+            let mut y = _mm256_add_pd(intercept, _mm256_mul_pd(slope, x));
+            if kernel.square {
+                y = _mm256_mul_pd(y, y);
+            }
+            _mm_storeu_ps(output.as_mut_ptr().add(i), _mm256_cvtpd_ps(y));
+        }
+        kernel.convert_scalar(&input[n..], &mut output[n..]);
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn affine_avx512(kernel: &AffineKernel, input: &[u32], output: &mut [f32]) {
+        let intercept = _mm512_set1_pd(kernel.intercept);
+        let slope = _mm512_set1_pd(kernel.slope);
+        let n = input.len() / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let x = _mm512_cvtepu32_pd(_mm256_loadu_si256(input.as_ptr().add(i) as *const __m256i));
+            let mut y = _mm512_add_pd(intercept, _mm512_mul_pd(slope, x));
+            if kernel.square {
+                y = _mm512_mul_pd(y, y);
+            }
+            _mm256_storeu_ps(output.as_mut_ptr().add(i), _mm512_cvtpd_ps(y));
+        }
+        kernel.convert_scalar(&input[n..], &mut output[n..]);
+    }
+
+    // Four windows: bounds in f32 as `window_key` computes them, rounded half
+    // away from zero as trunc + (fraction >= 0.5). Lanes are only valid for
+    // finite, ordered, non-negative bounds whose keys fit in an i32.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn window_keys4(mz: &[f64], width: &[f64], out: &mut [(u32, u32, bool); 4]) {
+        let mz = _mm256_cvtpd_ps(_mm256_loadu_pd(mz.as_ptr()));
+        let half_width = _mm_mul_ps(_mm256_cvtpd_ps(_mm256_loadu_pd(width.as_ptr())), _mm_set1_ps(0.5));
+        let low = _mm_sub_ps(mz, half_width);
+        let high = _mm_add_ps(mz, half_width);
+        let scale = _mm_set1_ps(WINDOW_KEY_SCALE);
+        let (low, high) = (_mm_mul_ps(low, scale), _mm_mul_ps(high, scale));
+
+        let valid = _mm_and_ps(
+            _mm_and_ps(_mm_cmpgt_ps(high, low), _mm_cmpge_ps(low, _mm_setzero_ps())),
+            _mm_cmplt_ps(high, _mm_set1_ps(2_147_483_648.0)),
+        );
+        let round = |x: __m128| {
+            let t = _mm_cvttps_epi32(x);
+            let up = _mm_cmpge_ps(_mm_sub_ps(x, _mm_cvtepi32_ps(t)), _mm_set1_ps(0.5));
+            _mm_sub_epi32(t, _mm_castps_si128(up))
+        };
+        let (mut lows, mut highs) = ([0u32; 4], [0u32; 4]);
+        _mm_storeu_si128(lows.as_mut_ptr() as *mut __m128i, round(low));
+        _mm_storeu_si128(highs.as_mut_ptr() as *mut __m128i, round(high));
+        let valid = _mm_movemask_ps(valid);
+        for lane in 0..4 {
+            out[lane] = (lows[lane], highs[lane], valid & (1 << lane) != 0);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+    use super::AffineKernel;
+    use timstof_common::WINDOW_KEY_SCALE;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn affine(kernel: &AffineKernel, input: &[u32], output: &mut [f32]) {
+        let intercept = vdupq_n_f64(kernel.intercept);
+        let slope = vdupq_n_f64(kernel.slope);
+        let n = input.len() / 4 * 4;
+        for i in (0..n).step_by(4) {
+            let x = vld1q_u32(input.as_ptr().add(i));
+            let mut low = vaddq_f64(intercept, vmulq_f64(slope, vcvtq_f64_u64(vmovl_u32(vget_low_u32(x)))));
+            let mut high = vaddq_f64(intercept, vmulq_f64(slope, vcvtq_f64_u64(vmovl_u32(vget_high_u32(x)))));
+            if kernel.square {
+                low = vmulq_f64(low, low);
+                high = vmulq_f64(high, high);
+            }
+            vst1q_f32(output.as_mut_ptr().add(i), vcombine_f32(vcvt_f32_f64(low), vcvt_f32_f64(high)));
+        }
+        kernel.convert_scalar(&input[n..], &mut output[n..]);
+    }
+
+    // As the AVX2 version; NEON rounds half away from zero natively.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn window_keys4(mz: &[f64], width: &[f64], out: &mut [(u32, u32, bool); 4]) {
+        let narrow = |p: *const f64| vcombine_f32(vcvt_f32_f64(vld1q_f64(p)), vcvt_f32_f64(vld1q_f64(p.add(2))));
+        let mz = narrow(mz.as_ptr());
+        let half_width = vmulq_n_f32(narrow(width.as_ptr()), 0.5);
+        let low = vmulq_n_f32(vsubq_f32(mz, half_width), WINDOW_KEY_SCALE);
+        let high = vmulq_n_f32(vaddq_f32(mz, half_width), WINDOW_KEY_SCALE);
+
+        let valid = vandq_u32(
+            vandq_u32(vcgtq_f32(high, low), vcgeq_f32(low, vdupq_n_f32(0.0))),
+            vcltq_f32(high, vdupq_n_f32(2_147_483_648.0)),
+        );
+        let (mut lows, mut highs, mut valids) = ([0u32; 4], [0u32; 4], [0u32; 4]);
+        vst1q_u32(lows.as_mut_ptr(), vcvtq_u32_f32(vrndaq_f32(low)));
+        vst1q_u32(highs.as_mut_ptr(), vcvtq_u32_f32(vrndaq_f32(high)));
+        vst1q_u32(valids.as_mut_ptr(), valid);
+        for lane in 0..4 {
+            out[lane] = (lows[lane], highs[lane], valids[lane] != 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timsrust::converters::{Scan2ImConverter, Tof2MzConverter};
+
+    const ALL: [Isa; 4] = [Isa::Scalar, Isa::Avx2, Isa::Avx512, Isa::Neon];
+
+    fn supported() -> impl Iterator<Item = Isa> {
+        ALL.into_iter().filter(|isa| isa.is_supported())
+    }
+
+    // Every residue modulo the widest vector, near 2^31 and at u32::MAX, so
+    // both the vector body and the scalar tail see edge values.
+    fn inputs() -> Vec<Vec<u32>> {
+        let mut inputs: Vec<Vec<u32>> = (0..=17).map(|len| (0..len).map(|i| i * 24_001).collect()).collect();
+        inputs.push((0..400_001).collect());
+        inputs.push(((1u32 << 31) - 9..(1 << 31) + 10).collect());
+        inputs.push((u32::MAX - 12..=u32::MAX).collect());
+        inputs
+    }
+
+    fn assert_bit_identical(kernel: AffineKernel, cv: &impl ConvertableDomain) {
+        for isa in supported() {
+            let kernel = kernel.with_isa(isa);
+            for input in inputs() {
+                let mut output = vec![f32::NAN; input.len()];
+                kernel.convert(&input, &mut output);
+                for (&x, &y) in input.iter().zip(&output) {
+                    assert_eq!(y.to_bits(), (cv.convert(x as f64) as f32).to_bits(), "{} at {}", isa.name(), x);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn affine_kernels_are_bit_identical_to_converters() {
+        for (lower, upper, tof_max) in [(100.0, 1700.0, 400_000), (99.99, 1700.01, 397_921), (50.0, 4000.0, 1 << 20)] {
+            let cv = Tof2MzConverter::from_boundaries(lower, upper, tof_max);
+            assert_bit_identical(AffineKernel::tof_to_mz(&cv, lower, upper).unwrap(), &cv);
+        }
+        for (lower, upper, scan_max) in [(0.6, 1.6, 100), (0.5999, 1.4501, 927), (0.7, 1.3, 709)] {
+            let cv = Scan2ImConverter::from_boundaries(lower, upper, scan_max);
+            assert_bit_identical(AffineKernel::scan_to_mobility(&cv, lower, upper).unwrap(), &cv);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_converter_when_it_cannot_be_reproduced() {
+        // Bounds that do not match the converter's.
+        let cv = Tof2MzConverter::from_boundaries(100.0, 1700.0, 400_000);
+        assert!(AffineKernel::tof_to_mz(&cv, 100.0, 1600.0).is_none());
+        let batch = BatchConverter::tof_to_mz(cv, 100.0, 1600.0);
+        assert!(!batch.is_vectorized());
+        let mut output = vec![1.0];
+        batch.extend(&[0, 7, 400_000], &mut output);
+        assert_eq!(output, [1.0, cv.convert(0.0) as f32, cv.convert(7.0) as f32, cv.convert(400_000.0) as f32]);
+    }
+
+    #[test]
+    fn window_keys_match_window_key() {
+        let mz = [
+            400.0, 412.5, 1000.123456, 0.25, 1234.567891, 87.654321, 49.99995, 5000.0,
+            // Invalid or beyond the kernel's range.
+            500.0, 600.0, 1e9, f64::NAN, 300.0, 2e5, 250.0, f64::INFINITY, 700.0,
+        ];
+        let width = [
+            25.0, 12.5, 0.00005, 0.5, 3.333333, 1.0, 0.0001, 100.0,
+            -1.0, 0.0, 1.0, 2.0, 700.0, 1.0, f64::INFINITY, 1.0, 2.0,
+        ];
+        let expected: Vec<Result<WindowKey, WindowKeyError>> = mz.iter().zip(&width).map(|(&m, &w)| window_key(m, w)).collect();
+        for isa in supported() {
+            for start in 0..mz.len() {
+                for end in start..=mz.len() {
+                    let actual = window_keys_with(isa, &mz[start..end], &width[start..end]);
+                    let reference = expected[start..end].iter().copied().collect::<Result<Vec<_>, _>>();
+                    match (&actual, &reference) {
+                        // NaN payloads make `WindowKeyError` not `Eq`.
+                        (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string(), "{} {}..{}", isa.name(), start, end),
+                        _ => assert_eq!(actual, reference, "{} {}..{}", isa.name(), start, end),
+                    }
+                }
+            }
+        }
+    }
+}
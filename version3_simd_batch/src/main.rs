@@ -1,13 +1,14 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::path::Path;
-use std::sync::Arc;
 use std::time::Instant;
-use timsrust::{converters::ConvertableDomain, readers::{FrameReader, MetadataReader}, MSLevel};
+use timsrust::{converters::Tof2MzConverter, readers::{FrameReader, MetadataReader}, MSLevel};
 use rayon::prelude::*;
+use timstof_common::{scan_ranges, window_bounds, Threads, WindowKeyError, WindowSegments};
 
-const NUM_THREADS: usize = 32;
-const BATCH_SIZE: usize = 8;
+mod kernels;
+
+use kernels::{BatchConverter, Isa};
 
 #[derive(Debug, Clone)]
 pub struct TimsTOFData {
@@ -64,57 +65,42 @@ struct FrameSplit {
     pub ms2: Vec<((u32, u32), TimsTOFData)>,
 }
 
-#[inline(always)]
-fn quantize_batch(values: &[f32]) -> Vec<u32> {
-    values.iter().map(|&x| (x * 10_000.0).round() as u32).collect()
-}
-
-#[inline(always)]
-fn find_scan_for_index_binary(index: usize, scan_offsets: &[usize]) -> usize {
-    let mut left = 0;
-    let mut right = scan_offsets.len() - 1;
-    
-    while left < right {
-        let mid = left + (right - left + 1) / 2;
-        if scan_offsets[mid] <= index {
-            left = mid;
-        } else {
-            right = mid - 1;
-        }
-    }
-    left
+// Per-frame values shared by every batch of the frame.
+struct FrameContext {
+    rt_min: f32,
+    frame_index: u32,
+    // Mobility of every scan of the frame, converted in one batch.
+    mobility: Vec<f32>,
 }
 
+// One batch holds the peaks of a single scan. Its m/z values are converted
+// once into `mz_buffer` and appended to every output in `targets` (several
+// when quadrupole windows overlap).
 fn process_peaks_batch(
     tof_batch: &[u32],
     intensity_batch: &[u32],
-    indices: &[usize],
-    scan_offsets: &[usize],
-    rt_min: f32,
-    frame_index: u32,
-    mz_cv: &impl ConvertableDomain,
-    im_cv: &impl ConvertableDomain,
-    output: &mut TimsTOFData,
+    scan: usize,
+    frame: &FrameContext,
+    mz_cv: &BatchConverter<Tof2MzConverter>,
+    mz_buffer: &mut Vec<f32>,
+    outputs: &mut [TimsTOFData],
+    targets: &[usize],
 ) {
     let batch_size = tof_batch.len();
     
-    let mut mz_buffer = Vec::with_capacity(batch_size);
-    let mut im_buffer = Vec::with_capacity(batch_size);
-    let mut scan_buffer = Vec::with_capacity(batch_size);
+    mz_buffer.clear();
+    mz_cv.extend(tof_batch, mz_buffer);
+    let im = frame.mobility[scan];
     
-    for (i, &tof) in tof_batch.iter().enumerate() {
-        mz_buffer.push(mz_cv.convert(tof as f64) as f32);
-        let scan = find_scan_for_index_binary(indices[i], scan_offsets);
-        scan_buffer.push(scan as u32);
-        im_buffer.push(im_cv.convert(scan as f64) as f32);
+    for &target in targets {
+        let output = &mut outputs[target];
+        output.rt_values_min.extend(std::iter::repeat_n(frame.rt_min, batch_size));
+        output.mobility_values.extend(std::iter::repeat_n(im, batch_size));
+        output.mz_values.extend_from_slice(mz_buffer);
+        output.intensity_values.extend_from_slice(intensity_batch);
+        output.frame_indices.extend(std::iter::repeat_n(frame.frame_index, batch_size));
+        output.scan_indices.extend(std::iter::repeat_n(scan as u32, batch_size));
     }
-    
-    output.rt_values_min.extend(vec![rt_min; batch_size]);
-    output.mobility_values.extend(im_buffer);
-    output.mz_values.extend(mz_buffer);
-    output.intensity_values.extend_from_slice(intensity_batch);
-    output.frame_indices.extend(vec![frame_index; batch_size]);
-    output.scan_indices.extend(scan_buffer);
 }
 
 pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
@@ -124,8 +110,11 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     let meta_start = Instant::now();
     let tdf_path = d_folder.join("analysis.tdf");
     let meta = MetadataReader::new(&tdf_path)?;
-    let mz_cv = Arc::new(meta.mz_converter);
-    let im_cv = Arc::new(meta.im_converter);
+    let mz_cv = BatchConverter::tof_to_mz(meta.mz_converter, meta.lower_mz, meta.upper_mz);
+    let im_cv = BatchConverter::scan_to_mobility(meta.im_converter, meta.lower_im, meta.upper_im);
+    println!("  SIMD kernels: {} (m/z {}, mobility {})", Isa::detect().name(),
+            if mz_cv.is_vectorized() { "vectorized" } else { "per peak" },
+            if im_cv.is_vectorized() { "vectorized" } else { "per scan" });
     println!("  Metadata initialization: {:.3}s", meta_start.elapsed().as_secs_f32());
     
     println!("Initializing frame reader...");
@@ -135,13 +124,21 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     println!("  Frame reader initialization: {:.3}s", frame_reader_start.elapsed().as_secs_f32());
     println!("  Total frames to process: {}", n_frames);
     
-    println!("Processing frames with SIMD batch processing ({} threads, batch size {})...", 
-            NUM_THREADS, BATCH_SIZE);
+    let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
+    
+    println!("Processing frames with SIMD batch processing ({} threads, one batch per scan)...", pool.threads());
     let process_start = Instant::now();
     
-    let splits: Vec<FrameSplit> = (0..n_frames).into_par_iter().map(|idx| {
+    let splits: Vec<FrameSplit> = pool.install(|| (0..n_frames).into_par_iter().map(|idx| -> Result<FrameSplit, WindowKeyError> {
         let frame = frames.get(idx).expect("frame read");
-        let rt_min = frame.rt_in_seconds as f32 / 60.0;
+        let scan_numbers: Vec<u32> = (0..frame.scan_offsets.len().max(1) as u32).collect();
+        let mut context = FrameContext {
+            rt_min: frame.rt_in_seconds as f32 / 60.0,
+            frame_index: frame.index as u32,
+            mobility: Vec::with_capacity(scan_numbers.len()),
+        };
+        im_cv.extend(&scan_numbers, &mut context.mobility);
+        let mut mz_buffer = Vec::new();
         let mut ms1 = TimsTOFData::new();
         let mut ms2_pairs: Vec<((u32,u32), TimsTOFData)> = Vec::new();
         
@@ -150,102 +147,59 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
                 let n_peaks = frame.tof_indices.len();
                 ms1 = TimsTOFData::with_capacity(n_peaks);
                 
-                let mut i = 0;
-                while i < n_peaks {
-                    let batch_end = std::cmp::min(i + BATCH_SIZE, n_peaks);
-                    let batch_indices: Vec<usize> = (i..batch_end).collect();
-                    
+                for (scan, peaks) in scan_ranges(&frame.scan_offsets, n_peaks) {
                     process_peaks_batch(
-                        &frame.tof_indices[i..batch_end],
-                        &frame.intensities[i..batch_end],
-                        &batch_indices,
-                        &frame.scan_offsets,
-                        rt_min,
-                        frame.index as u32,
-                        &*mz_cv,
-                        &*im_cv,
-                        &mut ms1,
+                        &frame.tof_indices[peaks.clone()],
+                        &frame.intensities[peaks],
+                        scan,
+                        &context,
+                        &mz_cv,
+                        &mut mz_buffer,
+                        std::slice::from_mut(&mut ms1),
+                        &[0],
                     );
-                    
-                    i = batch_end;
                 }
             }
             MSLevel::MS2 => {
                 let qs = &frame.quadrupole_settings;
-                ms2_pairs.reserve(qs.isolation_mz.len());
+                let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+                let keys = kernels::window_keys(&qs.isolation_mz[..n_windows], &qs.isolation_width[..n_windows])?;
                 
-                for win in 0..qs.isolation_mz.len() {
-                    if win >= qs.isolation_width.len() { break; }
-                    let prec_mz = qs.isolation_mz[win] as f32;
-                    let width = qs.isolation_width[win] as f32;
-                    let low = prec_mz - width * 0.5;
-                    let high = prec_mz + width * 0.5;
-                    let key = ((low * 10_000.0).round() as u32, (high * 10_000.0).round() as u32);
-                    
-                    let mut td = TimsTOFData::new();
-                    let mut batch_tof = Vec::with_capacity(BATCH_SIZE);
-                    let mut batch_intensity = Vec::with_capacity(BATCH_SIZE);
-                    let mut batch_indices = Vec::with_capacity(BATCH_SIZE);
-                    
-                    for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter()
-                        .zip(frame.intensities.iter()).enumerate() {
-                        let scan = find_scan_for_index_binary(p_idx, &frame.scan_offsets);
-                        if scan < qs.scan_starts[win] || scan > qs.scan_ends[win] { continue; }
-                        
-                        batch_tof.push(tof);
-                        batch_intensity.push(intensity);
-                        batch_indices.push(p_idx);
-                        
-                        if batch_tof.len() == BATCH_SIZE {
-                            process_peaks_batch(
-                                &batch_tof,
-                                &batch_intensity,
-                                &batch_indices,
-                                &frame.scan_offsets,
-                                rt_min,
-                                frame.index as u32,
-                                &*mz_cv,
-                                &*im_cv,
-                                &mut td,
-                            );
-                            batch_tof.clear();
-                            batch_intensity.clear();
-                            batch_indices.clear();
-                        }
-                    }
-                    
-                    if !batch_tof.is_empty() {
+                // One pass over the scans; peaks in overlapping windows go to each of them.
+                let mut windows: Vec<TimsTOFData> = (0..n_windows).map(|_| TimsTOFData::new()).collect();
+                for (first, last, targets) in WindowSegments::new(&qs.scan_starts[..n_windows], &qs.scan_ends[..n_windows]).iter() {
+                    for (scan, peaks) in scan_ranges(&frame.scan_offsets, frame.tof_indices.len()).within(first, last) {
                         process_peaks_batch(
-                            &batch_tof,
-                            &batch_intensity,
-                            &batch_indices,
-                            &frame.scan_offsets,
-                            rt_min,
-                            frame.index as u32,
-                            &*mz_cv,
-                            &*im_cv,
-                            &mut td,
+                            &frame.tof_indices[peaks.clone()],
+                            &frame.intensities[peaks],
+                            scan,
+                            &context,
+                            &mz_cv,
+                            &mut mz_buffer,
+                            &mut windows,
+                            targets,
                         );
                     }
-                    
-                    if !td.mz_values.is_empty() {
-                        ms2_pairs.push((key, td));
-                    }
                 }
+                
+                ms2_pairs = keys.into_iter().zip(windows)
+                    .filter(|(_, td)| !td.mz_values.is_empty())
+                    .collect();
             }
             _ => {}
         }
-        FrameSplit { ms1, ms2: ms2_pairs }
-    }).collect();
+        Ok(FrameSplit { ms1, ms2: ms2_pairs })
+    }).collect::<Result<_, _>>())?;
     
     println!("  Frame processing: {:.3}s", process_start.elapsed().as_secs_f32());
     
     println!("Merging data...");
     let merge_start = Instant::now();
     
-    let ms1_size_estimate: usize = splits.par_iter().map(|s| s.ms1.mz_values.len()).sum();
+    let ms1_size_estimate: usize = splits.iter().map(|s| s.ms1.mz_values.len()).sum();
     let mut global_ms1 = TimsTOFData::with_capacity(ms1_size_estimate);
-    let mut ms2_hash: HashMap<(u32,u32), TimsTOFData> = HashMap::new();
+    // Keyed merge in frame order; windows come out sorted by key.
+    let mut ms2_hash: BTreeMap<(u32,u32), TimsTOFData> = BTreeMap::new();
     
     for mut split in splits {
         global_ms1.merge_from(&mut split.ms1);
@@ -256,10 +210,8 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     }
     
     let mut ms2_vec = Vec::with_capacity(ms2_hash.len());
-    for ((q_low, q_high), td) in ms2_hash {
-        let low = q_low as f32 / 10_000.0;
-        let high = q_high as f32 / 10_000.0;
-        ms2_vec.push(((low, high), td));
+    for (key, td) in ms2_hash {
+        ms2_vec.push((window_bounds(key), td));
     }
     
     println!("  Data merging: {:.3}s", merge_start.elapsed().as_secs_f32());
@@ -279,11 +231,6 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(NUM_THREADS)
-        .build_global()
-        .unwrap();
-    
     let d_folder_path = "/storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/test_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d";
     
     let d_path = Path::new(d_folder_path);
@@ -293,8 +240,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     
     println!("========== TimsTOF .d File Reader V3 (SIMD + Batch Processing) ==========");
     println!("Data folder: {}", d_folder_path);
-    println!("Parallel threads: {}", NUM_THREADS);
-    println!("Batch size: {}", BATCH_SIZE);
+    println!("Parallel threads: auto ({} CPUs available)", timstof_common::threads::available_cpus());
+    println!("SIMD kernels: {}", Isa::detect().name());
     println!();
     
     let _raw_data = read_timstof_data(d_path)?;
@@ -302,4 +249,34 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("\n========== Processing Complete ==========");
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticRun};
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("small", "v3", &digest!(data));
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("overlapping", "v3", &digest!(data));
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        determinism::assert_deterministic(
+            "v3",
+            || read_timstof_data(dir.path()).unwrap(),
+            |data| fingerprint!(data),
+        );
+    }
+}
@@ -1,17 +1,17 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
-use timsrust::{converters::ConvertableDomain, readers::{FrameReader, MetadataReader}, MSLevel};
+use timsrust::{converters::{ConvertableDomain, Scan2ImConverter, Tof2MzConverter}, readers::{FrameReader, MetadataReader}, MSLevel};
 use rayon::prelude::*;
+use timstof_common::{scan_ranges, window_bounds, window_key, Threads, WindowKeyError, WindowSegments};
 use bumpalo::Bump;
 use mimalloc::MiMalloc;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-const NUM_THREADS: usize = 32;
 const ARENA_SIZE: usize = 64 * 1024 * 1024; // 64MB per arena
 
 #[derive(Debug, Clone)]
@@ -111,33 +111,10 @@ struct FrameSplit {
     pub ms2: Vec<((u32, u32), TimsTOFData)>,
 }
 
-#[inline(always)]
-fn quantize(x: f32) -> u32 { 
-    unsafe { (x * 10_000.0).to_int_unchecked::<u32>() }
-}
-
-#[inline(always)]
-fn find_scan_for_index_binary_unsafe(index: usize, scan_offsets: &[usize]) -> usize {
-    unsafe {
-        let mut left = 0;
-        let mut right = scan_offsets.len() - 1;
-        
-        while left < right {
-            let mid = left + ((right - left + 1) >> 1);
-            if *scan_offsets.get_unchecked(mid) <= index {
-                left = mid;
-            } else {
-                right = mid - 1;
-            }
-        }
-        left
-    }
-}
-
 struct FrameProcessor<'a> {
     arena: &'a Bump,
-    mz_cv: Arc<dyn ConvertableDomain>,
-    im_cv: Arc<dyn ConvertableDomain>,
+    mz_cv: Arc<Tof2MzConverter>,
+    im_cv: Arc<Scan2ImConverter>,
 }
 
 impl<'a> FrameProcessor<'a> {
@@ -149,7 +126,6 @@ impl<'a> FrameProcessor<'a> {
         scan_offsets: &[usize],
         rt_min: f32,
         frame_index: u32,
-        scan_filter: Option<(usize, usize)>,
     ) -> TimsTOFData {
         let n_peaks = tof_indices.len();
         let mut data = TimsTOFData::with_capacity(n_peaks);
@@ -170,24 +146,21 @@ impl<'a> FrameProcessor<'a> {
             let scan_ptr = data.scan_indices.as_mut_ptr();
             
             let mut out_idx = 0;
-            for (i, (&tof, &intensity)) in tof_indices.iter().zip(intensities.iter()).enumerate() {
-                let scan = find_scan_for_index_binary_unsafe(i, scan_offsets);
-                
-                if let Some((start, end)) = scan_filter {
-                    if scan < start || scan > end { continue; }
-                }
-                
-                let mz = self.mz_cv.convert(tof as f64) as f32;
+            for (scan, peaks) in scan_ranges(scan_offsets, n_peaks) {
                 let im = self.im_cv.convert(scan as f64) as f32;
                 
-                *rt_ptr.add(out_idx) = rt_min;
-                *im_ptr.add(out_idx) = im;
-                *mz_ptr.add(out_idx) = mz;
-                *int_ptr.add(out_idx) = intensity;
-                *frame_ptr.add(out_idx) = frame_index;
-                *scan_ptr.add(out_idx) = scan as u32;
-                
-                out_idx += 1;
+                for (&tof, &intensity) in tof_indices[peaks.clone()].iter().zip(&intensities[peaks]) {
+                    let mz = self.mz_cv.convert(tof as f64) as f32;
+                    
+                    *rt_ptr.add(out_idx) = rt_min;
+                    *im_ptr.add(out_idx) = im;
+                    *mz_ptr.add(out_idx) = mz;
+                    *int_ptr.add(out_idx) = intensity;
+                    *frame_ptr.add(out_idx) = frame_index;
+                    *scan_ptr.add(out_idx) = scan as u32;
+                    
+                    out_idx += 1;
+                }
             }
             
             if out_idx < n_peaks {
@@ -202,6 +175,61 @@ impl<'a> FrameProcessor<'a> {
         
         data
     }
+    
+    // MS2 frames: one pass over the scans. Each window's buffers are sized
+    // exactly up front; a peak in overlapping windows is written to each.
+    #[inline(always)]
+    fn process_windows_unchecked(
+        &self,
+        tof_indices: &[u32],
+        intensities: &[u32],
+        scan_offsets: &[usize],
+        rt_min: f32,
+        frame_index: u32,
+        segments: &WindowSegments,
+    ) -> Vec<TimsTOFData> {
+        let n_peaks = tof_indices.len();
+        let mut windows: Vec<TimsTOFData> = segments.peak_counts(scan_offsets, n_peaks)
+            .into_iter()
+            .map(TimsTOFData::with_capacity)
+            .collect();
+        let mut filled = vec![0usize; windows.len()];
+        
+        unsafe {
+            for (first, last, targets) in segments.iter() {
+                for (scan, peaks) in scan_ranges(scan_offsets, n_peaks).within(first, last) {
+                    let im = self.im_cv.convert(scan as f64) as f32;
+                    
+                    for (&tof, &intensity) in tof_indices[peaks.clone()].iter().zip(&intensities[peaks]) {
+                        let mz = self.mz_cv.convert(tof as f64) as f32;
+                        
+                        for &win in targets {
+                            let data = windows.get_unchecked_mut(win);
+                            let out_idx = *filled.get_unchecked(win);
+                            *data.rt_values_min.as_mut_ptr().add(out_idx) = rt_min;
+                            *data.mobility_values.as_mut_ptr().add(out_idx) = im;
+                            *data.mz_values.as_mut_ptr().add(out_idx) = mz;
+                            *data.intensity_values.as_mut_ptr().add(out_idx) = intensity;
+                            *data.frame_indices.as_mut_ptr().add(out_idx) = frame_index;
+                            *data.scan_indices.as_mut_ptr().add(out_idx) = scan as u32;
+                            *filled.get_unchecked_mut(win) = out_idx + 1;
+                        }
+                    }
+                }
+            }
+            
+            for (data, &len) in windows.iter_mut().zip(&filled) {
+                data.rt_values_min.set_len(len);
+                data.mobility_values.set_len(len);
+                data.mz_values.set_len(len);
+                data.intensity_values.set_len(len);
+                data.frame_indices.set_len(len);
+                data.scan_indices.set_len(len);
+            }
+        }
+        
+        windows
+    }
 }
 
 pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
@@ -222,10 +250,12 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     println!("  Frame reader initialization: {:.3}s", frame_reader_start.elapsed().as_secs_f32());
     println!("  Total frames to process: {}", n_frames);
     
-    println!("Processing frames with zero-copy and custom allocator ({} threads)...", NUM_THREADS);
+    let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
+    
+    println!("Processing frames with zero-copy and custom allocator ({} threads)...", pool.threads());
     let process_start = Instant::now();
     
-    let splits: Vec<FrameSplit> = (0..n_frames).into_par_iter().map(|idx| {
+    let splits: Vec<FrameSplit> = pool.install(|| (0..n_frames).into_par_iter().map(|idx| -> Result<FrameSplit, WindowKeyError> {
         let arena = Bump::with_capacity(ARENA_SIZE);
         let processor = FrameProcessor {
             arena: &arena,
@@ -246,49 +276,43 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
                     &frame.scan_offsets,
                     rt_min,
                     frame.index as u32,
-                    None,
                 );
             }
             MSLevel::MS2 => {
                 let qs = &frame.quadrupole_settings;
-                ms2_pairs.reserve(qs.isolation_mz.len());
+                let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+                let keys = (0..n_windows)
+                    .map(|win| window_key(qs.isolation_mz[win], qs.isolation_width[win]))
+                    .collect::<Result<Vec<_>, _>>()?;
                 
-                for win in 0..qs.isolation_mz.len() {
-                    if win >= qs.isolation_width.len() { break; }
-                    let prec_mz = qs.isolation_mz[win] as f32;
-                    let width = qs.isolation_width[win] as f32;
-                    let low = prec_mz - width * 0.5;
-                    let high = prec_mz + width * 0.5;
-                    let key = (quantize(low), quantize(high));
-                    
-                    let td = processor.process_peaks_unchecked(
-                        &frame.tof_indices,
-                        &frame.intensities,
-                        &frame.scan_offsets,
-                        rt_min,
-                        frame.index as u32,
-                        Some((qs.scan_starts[win], qs.scan_ends[win])),
-                    );
-                    
-                    if !td.mz_values.is_empty() {
-                        ms2_pairs.push((key, td));
-                    }
-                }
+                let windows = processor.process_windows_unchecked(
+                    &frame.tof_indices,
+                    &frame.intensities,
+                    &frame.scan_offsets,
+                    rt_min,
+                    frame.index as u32,
+                    &WindowSegments::new(&qs.scan_starts[..n_windows], &qs.scan_ends[..n_windows]),
+                );
+                
+                ms2_pairs = keys.into_iter().zip(windows)
+                    .filter(|(_, td)| !td.mz_values.is_empty())
+                    .collect();
             }
             _ => {}
         }
         
-        FrameSplit { ms1, ms2: ms2_pairs }
-    }).collect();
+        Ok(FrameSplit { ms1, ms2: ms2_pairs })
+    }).collect::<Result<_, _>>())?;
     
     println!("  Frame processing: {:.3}s", process_start.elapsed().as_secs_f32());
     
     println!("Merging data with zero-copy operations...");
     let merge_start = Instant::now();
     
-    let ms1_size_estimate: usize = splits.par_iter().map(|s| s.ms1.mz_values.len()).sum();
+    let ms1_size_estimate: usize = splits.iter().map(|s| s.ms1.mz_values.len()).sum();
     let mut global_ms1 = TimsTOFData::with_capacity(ms1_size_estimate);
-    let mut ms2_hash: HashMap<(u32,u32), TimsTOFData> = HashMap::new();
+    // Keyed merge in frame order; windows come out sorted by key.
+    let mut ms2_hash: BTreeMap<(u32,u32), TimsTOFData> = BTreeMap::new();
     
     for mut split in splits {
         unsafe { global_ms1.append_unchecked(&mut split.ms1); }
@@ -302,10 +326,8 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     }
     
     let mut ms2_vec = Vec::with_capacity(ms2_hash.len());
-    for ((q_low, q_high), td) in ms2_hash {
-        let low = q_low as f32 / 10_000.0;
-        let high = q_high as f32 / 10_000.0;
-        ms2_vec.push(((low, high), td));
+    for (key, td) in ms2_hash {
+        ms2_vec.push((window_bounds(key), td));
     }
     
     println!("  Data merging: {:.3}s", merge_start.elapsed().as_secs_f32());
@@ -325,11 +347,6 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(NUM_THREADS)
-        .build_global()
-        .unwrap();
-    
     let d_folder_path = "/storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/test_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d";
     
     let d_path = Path::new(d_folder_path);
@@ -339,7 +356,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     
     println!("========== TimsTOF .d File Reader V4 (Zero-copy + Custom Allocator) ==========");
     println!("Data folder: {}", d_folder_path);
-    println!("Parallel threads: {}", NUM_THREADS);
+    println!("Parallel threads: auto ({} CPUs available)", timstof_common::threads::available_cpus());
     println!("Using mimalloc allocator + bump allocators");
     println!();
     
@@ -348,4 +365,34 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("\n========== Processing Complete ==========");
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticRun};
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("small", "v4", &digest!(data));
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("overlapping", "v4", &digest!(data));
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        determinism::assert_deterministic(
+            "v4",
+            || read_timstof_data(dir.path()).unwrap(),
+            |data| fingerprint!(data),
+        );
+    }
+}
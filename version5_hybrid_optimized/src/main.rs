@@ -1,24 +1,42 @@
 use std::error::Error;
+use std::ops::Range;
 use std::path::Path;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 use timsrust::{converters::{ConvertableDomain, Tof2MzConverter, Scan2ImConverter}, readers::{FrameReader, MetadataReader}, MSLevel};
 use rayon::prelude::*;
-use dashmap::DashMap;
+use timstof_common::{concat_parallel, group_by_window, scan_ranges, window_bounds, window_key, PeakLayout, Threads, WindowSegments};
 use bumpalo::Bump;
 use mimalloc::MiMalloc;
-use crossbeam_channel::bounded;
-use parking_lot::Mutex;
 
+mod sizing;
+
+#[cfg(not(test))]
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-const NUM_THREADS: usize = 32;
 const BATCH_SIZE: usize = 16;
-const CHANNEL_BUFFER_SIZE: usize = 2000;
 const ARENA_SIZE: usize = 32 * 1024 * 1024;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MzStorage {
+    Converted,
+    ConvertedWithTof,
+    TofOnly,
+}
+
+impl MzStorage {
+    #[inline(always)]
+    fn keeps_mz(self) -> bool {
+        self != MzStorage::TofOnly
+    }
+    
+    #[inline(always)]
+    fn keeps_tof(self) -> bool {
+        self != MzStorage::Converted
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TimsTOFData {
     pub rt_values_min: Vec<f32>,
@@ -27,6 +45,7 @@ pub struct TimsTOFData {
     pub intensity_values: Vec<u32>,
     pub frame_indices: Vec<u32>,
     pub scan_indices: Vec<u32>,
+    pub tof_indices: Vec<u32>,
 }
 
 impl TimsTOFData {
@@ -38,91 +57,98 @@ impl TimsTOFData {
             intensity_values: Vec::new(),
             frame_indices: Vec::new(),
             scan_indices: Vec::new(),
+            tof_indices: Vec::new(),
         }
     }
     
-    pub fn with_capacity(capacity: usize) -> Self {
+    pub fn with_capacity(capacity: usize, storage: MzStorage) -> Self {
         let aligned_cap = ((capacity + 15) / 16) * 16;
+        let mz_cap = if storage.keeps_mz() { aligned_cap } else { 0 };
+        let tof_cap = if storage.keeps_tof() { aligned_cap } else { 0 };
         Self {
             rt_values_min: Vec::with_capacity(aligned_cap),
             mobility_values: Vec::with_capacity(aligned_cap),
-            mz_values: Vec::with_capacity(aligned_cap),
+            mz_values: Vec::with_capacity(mz_cap),
             intensity_values: Vec::with_capacity(aligned_cap),
             frame_indices: Vec::with_capacity(aligned_cap),
             scan_indices: Vec::with_capacity(aligned_cap),
+            tof_indices: Vec::with_capacity(tof_cap),
         }
     }
     
-    pub fn preallocate_exact(capacity: usize) -> Self {
-        let mut data = Self::with_capacity(capacity);
-        data.rt_values_min.reserve_exact(capacity);
-        data.mobility_values.reserve_exact(capacity);
-        data.mz_values.reserve_exact(capacity);
-        data.intensity_values.reserve_exact(capacity);
-        data.frame_indices.reserve_exact(capacity);
-        data.scan_indices.reserve_exact(capacity);
-        data
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.intensity_values.len()
     }
     
     #[inline(always)]
-    unsafe fn append_unchecked(&mut self, other: &mut Self) {
-        let len = self.rt_values_min.len();
-        let other_len = other.rt_values_min.len();
-        let new_len = len + other_len;
-        
-        self.rt_values_min.reserve(other_len);
-        self.mobility_values.reserve(other_len);
-        self.mz_values.reserve(other_len);
-        self.intensity_values.reserve(other_len);
-        self.frame_indices.reserve(other_len);
-        self.scan_indices.reserve(other_len);
-        
-        std::ptr::copy_nonoverlapping(
-            other.rt_values_min.as_ptr(),
-            self.rt_values_min.as_mut_ptr().add(len),
-            other_len
-        );
-        std::ptr::copy_nonoverlapping(
-            other.mobility_values.as_ptr(),
-            self.mobility_values.as_mut_ptr().add(len),
-            other_len
-        );
-        std::ptr::copy_nonoverlapping(
-            other.mz_values.as_ptr(),
-            self.mz_values.as_mut_ptr().add(len),
-            other_len
-        );
-        std::ptr::copy_nonoverlapping(
-            other.intensity_values.as_ptr(),
-            self.intensity_values.as_mut_ptr().add(len),
-            other_len
-        );
-        std::ptr::copy_nonoverlapping(
-            other.frame_indices.as_ptr(),
-            self.frame_indices.as_mut_ptr().add(len),
-            other_len
-        );
-        std::ptr::copy_nonoverlapping(
-            other.scan_indices.as_ptr(),
-            self.scan_indices.as_mut_ptr().add(len),
-            other_len
-        );
-        
-        self.rt_values_min.set_len(new_len);
-        self.mobility_values.set_len(new_len);
-        self.mz_values.set_len(new_len);
-        self.intensity_values.set_len(new_len);
-        self.frame_indices.set_len(new_len);
-        self.scan_indices.set_len(new_len);
+    pub fn is_empty(&self) -> bool {
+        self.intensity_values.is_empty()
+    }
+    
+    /// `len` zeroed peaks, to be overwritten in place frame by frame.
+    pub fn zeroed(len: usize, storage: MzStorage) -> Self {
+        let mz_len = if storage.keeps_mz() { len } else { 0 };
+        let tof_len = if storage.keeps_tof() { len } else { 0 };
+        Self {
+            rt_values_min: vec![0.0; len],
+            mobility_values: vec![0.0; len],
+            mz_values: vec![0.0; mz_len],
+            intensity_values: vec![0; len],
+            frame_indices: vec![0; len],
+            scan_indices: vec![0; len],
+            tof_indices: vec![0; tof_len],
+        }
     }
     
-    fn merge_from(&mut self, other: TimsTOFData) {
-        self.rt_values_min.extend(other.rt_values_min);
-        self.mobility_values.extend(other.mobility_values);
-        self.mz_values.extend(other.mz_values);
-        self.intensity_values.extend(other.intensity_values);
-        self.frame_indices.extend(other.frame_indices);
-        self.scan_indices.extend(other.scan_indices);
+    fn split_frames(&mut self, layout: &PeakLayout, storage: MzStorage) -> Vec<FramePeaks<'_>> {
+        let mut rt_values_min = layout.split_mut(&mut self.rt_values_min).into_iter();
+        let mut mobility_values = layout.split_mut(&mut self.mobility_values).into_iter();
+        let mut mz_values = if storage.keeps_mz() { layout.split_mut(&mut self.mz_values) } else { Vec::new() }.into_iter();
+        let mut intensity_values = layout.split_mut(&mut self.intensity_values).into_iter();
+        let mut frame_indices = layout.split_mut(&mut self.frame_indices).into_iter();
+        let mut scan_indices = layout.split_mut(&mut self.scan_indices).into_iter();
+        let mut tof_indices = if storage.keeps_tof() { layout.split_mut(&mut self.tof_indices) } else { Vec::new() }.into_iter();
+        (0..layout.n_frames())
+            .map(|_| FramePeaks {
+                rt_values_min: rt_values_min.next().unwrap(),
+                mobility_values: mobility_values.next().unwrap(),
+                mz_values: mz_values.next().unwrap_or_default(),
+                intensity_values: intensity_values.next().unwrap(),
+                frame_indices: frame_indices.next().unwrap(),
+                scan_indices: scan_indices.next().unwrap(),
+                tof_indices: tof_indices.next().unwrap_or_default(),
+            })
+            .collect()
+    }
+    
+    // Concatenates chunks column by column into exactly sized columns, each
+    // copied in parallel; a chunk's column is freed as soon as it is copied.
+    fn concat(mut chunks: Vec<TimsTOFData>) -> Self {
+        macro_rules! column {
+            ($name:ident) => {
+                concat_parallel(chunks.iter_mut().map(|chunk| std::mem::take(&mut chunk.$name)).collect())
+            };
+        }
+        Self {
+            rt_values_min: column!(rt_values_min),
+            mobility_values: column!(mobility_values),
+            mz_values: column!(mz_values),
+            intensity_values: column!(intensity_values),
+            frame_indices: column!(frame_indices),
+            scan_indices: column!(scan_indices),
+            tof_indices: column!(tof_indices),
+        }
+    }
+    
+    pub fn convert_tof_to_mz(&mut self, mz_cv: &Tof2MzConverter) -> Result<(), String> {
+        if self.tof_indices.len() != self.len() {
+            return Err(format!("TOF indices were not retained ({} of {} peaks)", self.tof_indices.len(), self.len()));
+        }
+        self.mz_values = self.tof_indices.par_iter()
+            .map(|&tof| mz_cv.convert(tof as f64) as f32)
+            .collect();
+        Ok(())
     }
 }
 
@@ -132,129 +158,130 @@ pub struct TimsTOFRawData {
     pub ms2_windows: Vec<((f32, f32), TimsTOFData)>,
 }
 
-enum ProcessedFrame {
-    MS1(TimsTOFData),
-    MS2(Vec<((u32, u32), TimsTOFData)>),
+// One frame's slots in the MS1 columns; `mz_values`/`tof_indices` are empty
+// when the MzStorage drops that column.
+struct FramePeaks<'a> {
+    rt_values_min: &'a mut [f32],
+    mobility_values: &'a mut [f32],
+    mz_values: &'a mut [f32],
+    intensity_values: &'a mut [u32],
+    frame_indices: &'a mut [u32],
+    scan_indices: &'a mut [u32],
+    tof_indices: &'a mut [u32],
 }
 
-#[inline(always)]
-fn quantize(x: f32) -> u32 { 
-    unsafe { (x * 10_000.0).to_int_unchecked::<u32>() }
-}
-
-#[inline(always)]
-fn find_scan_binary_unsafe(index: usize, scan_offsets: &[usize]) -> usize {
-    unsafe {
-        let mut left = 0;
-        let mut right = scan_offsets.len() - 1;
-        
-        while left < right {
-            let mid = left + ((right - left + 1) >> 1);
-            if *scan_offsets.get_unchecked(mid) <= index {
-                left = mid;
-            } else {
-                right = mid - 1;
-            }
-        }
-        left
-    }
-}
+// One MS2 frame's peaks, split by window key.
+type WindowChunks = Vec<((u32, u32), TimsTOFData)>;
 
 struct FrameProcessor {
     mz_cv: Arc<Tof2MzConverter>,
     im_cv: Arc<Scan2ImConverter>,
+    storage: MzStorage,
 }
 
 impl FrameProcessor {
+    // MS1 frames: fills the frame's slots column by column, scan by scan.
     #[inline(always)]
-    fn process_peaks_batch(
+    fn fill_peaks(
         &self,
+        out: FramePeaks<'_>,
         tof_indices: &[u32],
         intensities: &[u32],
         scan_offsets: &[usize],
         rt_min: f32,
         frame_index: u32,
-        scan_filter: Option<(usize, usize)>,
-    ) -> TimsTOFData {
+    ) {
         let n_peaks = tof_indices.len();
-        let mut data = TimsTOFData::with_capacity(n_peaks);
-        
-        let mut i = 0;
-        while i < n_peaks {
-            let batch_end = std::cmp::min(i + BATCH_SIZE, n_peaks);
-            
-            let mut mz_batch = Vec::with_capacity(BATCH_SIZE);
-            let mut im_batch = Vec::with_capacity(BATCH_SIZE);
-            let mut scan_batch = Vec::with_capacity(BATCH_SIZE);
-            let mut int_batch = Vec::with_capacity(BATCH_SIZE);
-            
-            for j in i..batch_end {
-                let scan = find_scan_binary_unsafe(j, scan_offsets);
-                
-                if let Some((start, end)) = scan_filter {
-                    if scan < start || scan > end { continue; }
-                }
-                
-                let tof = unsafe { *tof_indices.get_unchecked(j) };
-                let intensity = unsafe { *intensities.get_unchecked(j) };
-                
-                mz_batch.push(self.mz_cv.convert(tof as f64) as f32);
-                im_batch.push(self.im_cv.convert(scan as f64) as f32);
-                scan_batch.push(scan as u32);
-                int_batch.push(intensity);
-            }
-            
-            let batch_len = mz_batch.len();
-            if batch_len > 0 {
-                data.rt_values_min.extend(vec![rt_min; batch_len]);
-                data.mobility_values.extend(im_batch);
-                data.mz_values.extend(mz_batch);
-                data.intensity_values.extend(int_batch);
-                data.frame_indices.extend(vec![frame_index; batch_len]);
-                data.scan_indices.extend(scan_batch);
+        out.rt_values_min.fill(rt_min);
+        out.frame_indices.fill(frame_index);
+        out.intensity_values.copy_from_slice(intensities);
+        if self.storage.keeps_tof() {
+            out.tof_indices.copy_from_slice(tof_indices);
+        }
+        if self.storage.keeps_mz() {
+            for (mz, &tof) in out.mz_values.iter_mut().zip(tof_indices) {
+                *mz = self.mz_cv.convert(tof as f64) as f32;
             }
-            
-            i = batch_end;
         }
         
-        data
+        for (scan, peaks) in scan_ranges(scan_offsets, n_peaks) {
+            out.mobility_values[peaks.clone()].fill(self.im_cv.convert(scan as f64) as f32);
+            out.scan_indices[peaks].fill(scan as u32);
+        }
     }
-}
-
-fn estimate_total_peaks(frames: &FrameReader) -> (usize, usize) {
-    let sample_size = std::cmp::min(50, frames.len());
-    let mut ms1_sum = 0;
-    let mut ms2_sum = 0;
-    let mut ms1_count = 0;
-    let mut ms2_count = 0;
-    
-    for idx in 0..sample_size {
-        if let Ok(frame) = frames.get(idx) {
-            match frame.ms_level {
-                MSLevel::MS1 => {
-                    ms1_sum += frame.tof_indices.len();
-                    ms1_count += 1;
+    
+    // MS2 frames: one pass over the scans instead of one per window; a peak in
+    // overlapping windows is appended to each of them.
+    #[inline(always)]
+    fn process_windows_batch(
+        &self,
+        tof_indices: &[u32],
+        intensities: &[u32],
+        scan_offsets: &[usize],
+        rt_min: f32,
+        frame_index: u32,
+        segments: &WindowSegments,
+    ) -> Vec<TimsTOFData> {
+        let n_peaks = tof_indices.len();
+        let mut windows: Vec<TimsTOFData> = segments.peak_counts(scan_offsets, n_peaks)
+            .into_iter()
+            .map(|capacity| TimsTOFData::with_capacity(capacity, self.storage))
+            .collect();
+        
+        let scans = segments.iter().flat_map(|(first, last, targets)| {
+            scan_ranges(scan_offsets, n_peaks).within(first, last).map(move |(scan, peaks)| (scan, peaks, targets))
+        });
+        self.append_scans(&mut windows, scans, tof_indices, intensities, rt_min, frame_index);
+        
+        windows
+    }
+    
+    // Converts each scan's peaks once, batch by batch, and appends them to
+    // every output listed for that scan.
+    #[inline(always)]
+    fn append_scans<'a>(
+        &self,
+        outputs: &mut [TimsTOFData],
+        scans: impl Iterator<Item = (usize, Range<usize>, &'a [usize])>,
+        tof_indices: &[u32],
+        intensities: &[u32],
+        rt_min: f32,
+        frame_index: u32,
+    ) {
+        let mut mz_batch = Vec::with_capacity(BATCH_SIZE);
+        
+        for (scan, peaks, targets) in scans {
+            let im = self.im_cv.convert(scan as f64) as f32;
+            
+            for (tof_batch, int_batch) in tof_indices[peaks.clone()].chunks(BATCH_SIZE)
+                .zip(intensities[peaks].chunks(BATCH_SIZE)) {
+                let batch_len = tof_batch.len();
+                
+                if self.storage.keeps_mz() {
+                    mz_batch.clear();
+                    mz_batch.extend(tof_batch.iter().map(|&tof| self.mz_cv.convert(tof as f64) as f32));
                 }
-                MSLevel::MS2 => {
-                    ms2_sum += frame.tof_indices.len();
-                    ms2_count += 1;
+                
+                for &target in targets {
+                    let data = &mut outputs[target];
+                    if self.storage.keeps_mz() {
+                        data.mz_values.extend_from_slice(&mz_batch);
+                    }
+                    if self.storage.keeps_tof() {
+                        data.tof_indices.extend_from_slice(tof_batch);
+                    }
+                    data.rt_values_min.extend(vec![rt_min; batch_len]);
+                    data.mobility_values.extend(vec![im; batch_len]);
+                    data.intensity_values.extend_from_slice(int_batch);
+                    data.frame_indices.extend(vec![frame_index; batch_len]);
+                    data.scan_indices.extend(vec![scan as u32; batch_len]);
                 }
-                _ => {}
             }
         }
     }
-    
-    let avg_ms1 = if ms1_count > 0 { ms1_sum / ms1_count } else { 10000 };
-    let avg_ms2 = if ms2_count > 0 { ms2_sum / ms2_count } else { 10000 };
-    
-    let total_frames = frames.len();
-    let estimated_ms1 = avg_ms1 * (total_frames * 2 / 5);
-    let estimated_ms2 = avg_ms2 * (total_frames * 3 / 5);
-    
-    (estimated_ms1, estimated_ms2)
 }
 
-pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
+pub fn read_timstof_data(d_folder: &Path, storage: MzStorage) -> Result<TimsTOFRawData, Box<dyn Error>> {
     let total_start = Instant::now();
     
     println!("Initializing metadata readers...");
@@ -272,147 +299,102 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     println!("  Frame reader initialization: {:.3}s", frame_reader_start.elapsed().as_secs_f32());
     println!("  Total frames to process: {}", n_frames);
     
-    println!("Estimating data size for pre-allocation...");
-    let (ms1_estimate, _ms2_estimate) = estimate_total_peaks(&frames);
-    println!("  Estimated MS1 peaks: ~{}", ms1_estimate);
-    
-    println!("Processing frames with hybrid optimizations ({} threads)...", NUM_THREADS);
-    let process_start = Instant::now();
-    
-    let (sender, receiver) = bounded(CHANNEL_BUFFER_SIZE);
-    let processed_count = Arc::new(AtomicUsize::new(0));
-    
-    let ms1_accumulator = Arc::new(Mutex::new(Vec::with_capacity(n_frames / 10)));
-    let ms2_map = Arc::new(DashMap::with_capacity(100));
+    // Frames.NumPeaks sizes the MS1 columns exactly before any frame is
+    // decoded; each worker fills its frame's slots in place.
+    println!("Sizing MS1 output from Frames.NumPeaks...");
+    let ms1_layout = sizing::ms1_layout(d_folder)?;
+    if ms1_layout.n_frames() != n_frames {
+        return Err(format!("Frames table has {} rows, frame reader {} frames", ms1_layout.n_frames(), n_frames).into());
+    }
+    let mut global_ms1 = TimsTOFData::zeroed(ms1_layout.total(), storage);
+    println!("  MS1 peaks: {}", ms1_layout.total());
     
-    let ms1_acc_clone = Arc::clone(&ms1_accumulator);
-    let ms2_map_clone = Arc::clone(&ms2_map);
-    let processed_clone = Arc::clone(&processed_count);
+    let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
     
-    let aggregator_handle = std::thread::spawn(move || {
-        while let Ok(frame_data) = receiver.recv() {
-            match frame_data {
-                ProcessedFrame::MS1(data) => {
-                    if !data.mz_values.is_empty() {
-                        ms1_acc_clone.lock().push(data);
-                    }
-                }
-                ProcessedFrame::MS2(pairs) => {
-                    for (key, data) in pairs {
-                        if !data.mz_values.is_empty() {
-                            ms2_map_clone.entry(key)
-                                .or_insert_with(|| Arc::new(Mutex::new(TimsTOFData::new())))
-                                .lock()
-                                .merge_from(data);
-                        }
-                    }
-                }
-            }
-            processed_clone.fetch_add(1, Ordering::Relaxed);
-        }
-    });
+    println!("Processing frames with hybrid optimizations ({} threads)...", pool.threads());
+    let process_start = Instant::now();
     
-    (0..n_frames).into_par_iter().for_each(|idx| {
+    let frame_slots = global_ms1.split_frames(&ms1_layout, storage);
+    // No aggregator thread: MS1 frames are written in place and each MS2
+    // frame's window chunks come back through an indexed collect, in frame
+    // order for any thread count.
+    let ms2_frames = pool.install(|| frame_slots.into_par_iter().enumerate().map(|(idx, ms1)| -> Result<WindowChunks, Box<dyn Error + Send + Sync>> {
         let processor = FrameProcessor {
             mz_cv: Arc::clone(&mz_cv),
             im_cv: Arc::clone(&im_cv),
+            storage,
         };
         
+        // An unreadable MS1 frame would leave its slots zeroed, so it is an error.
         let frame = match frames.get(idx) {
             Ok(f) => f,
-            Err(_) => return,
+            Err(e) if !ms1.intensity_values.is_empty() => return Err(e.into()),
+            Err(_) => return Ok(Vec::new()),
         };
         
         let rt_min = frame.rt_in_seconds as f32 / 60.0;
         
         match frame.ms_level {
             MSLevel::MS1 => {
-                let ms1 = processor.process_peaks_batch(
+                if frame.tof_indices.len() != ms1.intensity_values.len() {
+                    return Err(format!("frame {}: decoded {} peaks, Frames.NumPeaks has {}",
+                        frame.index, frame.tof_indices.len(), ms1.intensity_values.len()).into());
+                }
+                processor.fill_peaks(
+                    ms1,
                     &frame.tof_indices,
                     &frame.intensities,
                     &frame.scan_offsets,
                     rt_min,
                     frame.index as u32,
-                    None,
                 );
-                
-                if !ms1.mz_values.is_empty() {
-                    let _ = sender.send(ProcessedFrame::MS1(ms1));
-                }
+                Ok(Vec::new())
             }
             MSLevel::MS2 => {
                 let qs = &frame.quadrupole_settings;
-                let mut ms2_pairs = Vec::with_capacity(qs.isolation_mz.len());
-                
-                for win in 0..qs.isolation_mz.len() {
-                    if win >= qs.isolation_width.len() { break; }
-                    
-                    let prec_mz = qs.isolation_mz[win] as f32;
-                    let width = qs.isolation_width[win] as f32;
-                    let low = prec_mz - width * 0.5;
-                    let high = prec_mz + width * 0.5;
-                    let key = (quantize(low), quantize(high));
-                    
-                    let td = processor.process_peaks_batch(
-                        &frame.tof_indices,
-                        &frame.intensities,
-                        &frame.scan_offsets,
-                        rt_min,
-                        frame.index as u32,
-                        Some((qs.scan_starts[win], qs.scan_ends[win])),
-                    );
-                    
-                    if !td.mz_values.is_empty() {
-                        ms2_pairs.push((key, td));
-                    }
-                }
+                let n_windows = qs.isolation_mz.len().min(qs.isolation_width.len());
+                let keys = (0..n_windows)
+                    .map(|win| window_key(qs.isolation_mz[win], qs.isolation_width[win]))
+                    .collect::<Result<Vec<_>, _>>()?;
                 
-                if !ms2_pairs.is_empty() {
-                    let _ = sender.send(ProcessedFrame::MS2(ms2_pairs));
-                }
+                let windows = processor.process_windows_batch(
+                    &frame.tof_indices,
+                    &frame.intensities,
+                    &frame.scan_offsets,
+                    rt_min,
+                    frame.index as u32,
+                    &WindowSegments::new(&qs.scan_starts[..n_windows], &qs.scan_ends[..n_windows]),
+                );
+                Ok(keys.into_iter().zip(windows)
+                    .filter(|(_, td)| !td.is_empty())
+                    .collect())
             }
-            _ => {}
+            _ => Ok(Vec::new()),
         }
-    });
-    
-    drop(sender);
-    aggregator_handle.join().unwrap();
+    }).collect::<Result<Vec<_>, _>>()).map_err(|e| e as Box<dyn Error>)?;
     
     println!("  Frame processing: {:.3}s", process_start.elapsed().as_secs_f32());
-    println!("  Frames processed: {}", processed_count.load(Ordering::Relaxed));
+    println!("  Frames processed: {}", ms2_frames.len());
     
-    println!("Finalizing data structures with zero-copy merge...");
+    println!("Merging MS2 windows in parallel...");
     let finalize_start = Instant::now();
     
-    let ms1_chunks = ms1_accumulator.lock();
-    let actual_ms1_size: usize = ms1_chunks.iter().map(|c| c.mz_values.len()).sum();
-    let mut global_ms1 = TimsTOFData::preallocate_exact(actual_ms1_size);
-    
-    for mut chunk in ms1_chunks.clone() {
-        unsafe { global_ms1.append_unchecked(&mut chunk); }
-    }
-    
-    let mut ms2_vec = Vec::with_capacity(ms2_map.len());
-    ms2_map.iter().for_each(|entry| {
-        let (q_low, q_high) = *entry.key();
-        let low = q_low as f32 / 10_000.0;
-        let high = q_high as f32 / 10_000.0;
-        let data = entry.value().lock().clone();
-        ms2_vec.push(((low, high), data));
-    });
-    
-    ms2_vec.par_sort_unstable_by(|a, b| {
-        a.0.0.partial_cmp(&b.0.0).unwrap()
-            .then(a.0.1.partial_cmp(&b.0.1).unwrap())
-    });
+    // Chunks are moved, never cloned, into their windows. Windows and their
+    // columns are concatenated one at a time, each in parallel, so at most one
+    // column exists twice (chunks and destination): peak memory is the output
+    // plus one column of the largest window.
+    let ms2_vec: Vec<_> = pool.install(|| group_by_window(ms2_frames)
+        .into_iter()
+        .map(|(key, chunks)| (window_bounds(key), TimsTOFData::concat(chunks)))
+        .collect());
     
     println!("  Data finalization: {:.3}s", finalize_start.elapsed().as_secs_f32());
     
     println!("\n========== Data Summary ==========");
-    println!("MS1 data points: {}", global_ms1.mz_values.len());
+    println!("MS1 data points: {}", global_ms1.len());
     println!("MS2 windows: {}", ms2_vec.len());
     
-    let total_ms2_points: usize = ms2_vec.iter().map(|(_, td)| td.mz_values.len()).sum();
+    let total_ms2_points: usize = ms2_vec.iter().map(|(_, td)| td.len()).sum();
     println!("MS2 data points: {}", total_ms2_points);
     println!("Total processing time: {:.3}s", total_start.elapsed().as_secs_f32());
     
@@ -423,11 +405,6 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(NUM_THREADS)
-        .build_global()
-        .unwrap();
-    
     let d_folder_path = "/storage/guotiannanLab/wangshuaiyao/006.DIABERT_TimsTOF_Rust/test_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d";
     
     let d_path = Path::new(d_folder_path);
@@ -437,13 +414,179 @@ fn main() -> Result<(), Box<dyn Error>> {
     
     println!("========== TimsTOF .d File Reader V5 (Hybrid Optimized) ==========");
     println!("Data folder: {}", d_folder_path);
-    println!("Parallel threads: {}", NUM_THREADS);
-    println!("Optimizations: DashMap + SIMD batching + Zero-copy + MiMalloc");
+    println!("Parallel threads: auto ({} CPUs available)", timstof_common::threads::available_cpus());
+    println!("Optimizations: Parallel window merge + SIMD batching + In-place MS1 + MiMalloc");
+    let mz_storage = MzStorage::Converted;
+    println!("m/z storage: {:?}", mz_storage);
     println!();
     
-    let _raw_data = read_timstof_data(d_path)?;
+    let _raw_data = read_timstof_data(d_path, mz_storage)?;
     
     println!("\n========== Processing Complete ==========");
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timstof_synthetic::memory::{self, PeakAlloc};
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticConfig, SyntheticFrame, SyntheticRun};
+    
+    #[global_allocator]
+    static ALLOC: PeakAlloc<MiMalloc> = PeakAlloc::new(MiMalloc);
+    
+    const STORAGES: [MzStorage; 3] = [MzStorage::Converted, MzStorage::ConvertedWithTof, MzStorage::TofOnly];
+    
+    // Loads with `storage` and converts the retained TOF indices, so every mode
+    // is compared against the same output; where m/z was converted during the
+    // load as well, both conversions must agree.
+    fn read_with_mz(d_folder: &Path, storage: MzStorage) -> TimsTOFRawData {
+        let mut data = read_timstof_data(d_folder, storage).unwrap();
+        if storage.keeps_tof() {
+            let mz_cv = MetadataReader::new(d_folder.join("analysis.tdf")).unwrap().mz_converter;
+            for td in std::iter::once(&mut data.ms1_data).chain(data.ms2_windows.iter_mut().map(|(_, td)| td)) {
+                let loaded = std::mem::take(&mut td.mz_values);
+                td.convert_tof_to_mz(&mz_cv).unwrap();
+                if storage.keeps_mz() {
+                    assert_eq!(td.mz_values, loaded, "{:?}", storage);
+                }
+            }
+        }
+        data
+    }
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        for storage in STORAGES {
+            let data = read_with_mz(dir.path(), storage);
+            golden::assert_golden("small", "v5", &digest!(data));
+        }
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        for storage in STORAGES {
+            let data = read_with_mz(dir.path(), storage);
+            golden::assert_golden("overlapping", "v5", &digest!(data));
+        }
+    }
+    
+    #[test]
+    fn converting_requires_retained_tof_indices() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let mut data = read_timstof_data(dir.path(), MzStorage::Converted).unwrap();
+        let mz_cv = MetadataReader::new(dir.path().join("analysis.tdf")).unwrap().mz_converter;
+        let loaded = data.ms1_data.mz_values.clone();
+        let err = data.ms1_data.convert_tof_to_mz(&mz_cv).unwrap_err();
+        assert!(err.starts_with("TOF indices were not retained"), "{}", err);
+        assert_eq!(data.ms1_data.mz_values, loaded);
+    }
+    
+    fn assert_exactly_sized(data: &TimsTOFData, storage: MzStorage) {
+        let n = data.len();
+        let mz = if storage.keeps_mz() { n } else { 0 };
+        let tof = if storage.keeps_tof() { n } else { 0 };
+        for (column, capacity, len, expected) in [
+            ("rt_values_min", data.rt_values_min.capacity(), data.rt_values_min.len(), n),
+            ("mobility_values", data.mobility_values.capacity(), data.mobility_values.len(), n),
+            ("mz_values", data.mz_values.capacity(), data.mz_values.len(), mz),
+            ("intensity_values", data.intensity_values.capacity(), data.intensity_values.len(), n),
+            ("frame_indices", data.frame_indices.capacity(), data.frame_indices.len(), n),
+            ("scan_indices", data.scan_indices.capacity(), data.scan_indices.len(), n),
+            ("tof_indices", data.tof_indices.capacity(), data.tof_indices.len(), tof),
+        ] {
+            assert_eq!((len, capacity), (expected, expected), "{} ({:?})", column, storage);
+        }
+    }
+    
+    // A long MS1-only wash at the end breaks any MS1:MS2 ratio guessed from
+    // the first frames.
+    #[test]
+    fn sizes_columns_exactly_with_an_ms1_only_wash() {
+        let mut run = SyntheticRun::small();
+        let ms1: Vec<_> = run.ms1_frames().cloned().collect();
+        let period = run.frames[1].rt_seconds - run.frames[0].rt_seconds;
+        for template in ms1.iter().cycle().take(30) {
+            let last = run.frames.last().unwrap();
+            let (id, rt_seconds) = (last.id + 1, last.rt_seconds + period);
+            run.frames.push(SyntheticFrame { id, rt_seconds, ..template.clone() });
+        }
+        let dir = run.write_temp().unwrap();
+        for storage in STORAGES {
+            let data = read_timstof_data(dir.path(), storage).unwrap();
+            
+            assert_exactly_sized(&data.ms1_data, storage);
+            for frame in run.ms1_frames() {
+                let loaded = data.ms1_data.frame_indices.iter().filter(|&&f| f == frame.id).count();
+                assert_eq!(loaded, frame.peaks.len(), "frame {}", frame.id);
+            }
+            assert_eq!(data.ms1_data.len(), run.ms1_frames().map(|f| f.peaks.len()).sum::<usize>());
+            for (_, window) in &data.ms2_windows {
+                assert_exactly_sized(window, storage);
+            }
+        }
+    }
+    
+    #[test]
+    fn rejects_a_negative_peak_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let conn = rusqlite::Connection::open(dir.path().join("analysis.tdf")).unwrap();
+        conn.execute("UPDATE Frames SET NumPeaks = -1 WHERE Id = (SELECT MIN(Id) FROM Frames WHERE MsMsType = 0)", []).unwrap();
+        drop(conn);
+        let err = read_timstof_data(dir.path(), MzStorage::Converted).unwrap_err();
+        assert!(err.to_string().contains("invalid Frames.NumPeaks -1"), "{}", err);
+    }
+    
+    fn heap_bytes(data: &TimsTOFData) -> usize {
+        4 * (data.rt_values_min.capacity() + data.mobility_values.capacity() + data.mz_values.capacity()
+            + data.intensity_values.capacity() + data.frame_indices.capacity() + data.scan_indices.capacity()
+            + data.tof_indices.capacity())
+    }
+    
+    // Finalizing used to clone every accumulated chunk, doubling the peak.
+    #[test]
+    fn peak_memory_stays_close_to_the_output_size() {
+        if !memory::isolated("tests::peak_memory_stays_close_to_the_output_size") {
+            return;
+        }
+        let dir = SyntheticRun::generate(&SyntheticConfig {
+            cycles: 24,
+            windows_per_group: 4,
+            peaks_per_frame: 20_000,
+            ..SyntheticConfig::default()
+        }).write_temp().unwrap();
+        
+        // Few workers, so frames in flight stay small next to the output.
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let baseline = ALLOC.current();
+        ALLOC.reset_peak();
+        let data = pool.install(|| read_timstof_data(dir.path(), MzStorage::Converted).map_err(|e| e.to_string())).unwrap();
+        let peak = ALLOC.peak() - baseline;
+        
+        let ms1 = heap_bytes(&data.ms1_data);
+        let windows: Vec<usize> = data.ms2_windows.iter().map(|(_, td)| heap_bytes(td)).collect();
+        let output = ms1 + windows.iter().sum::<usize>();
+        let largest_window = windows.iter().copied().max().unwrap();
+        println!("output {} B, largest window {} B, peak {} B", output, largest_window, peak);
+        assert!(windows.len() >= 8);
+        assert!(
+            peak <= output + largest_window + output / 10,
+            "peak {} B for {} B of output (largest window {} B)", peak, output, largest_window,
+        );
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        for storage in STORAGES {
+            determinism::assert_deterministic(
+                &format!("v5 {:?}", storage),
+                || read_with_mz(dir.path(), storage),
+                |data| fingerprint!(data),
+            );
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! Exact output sizes read from `analysis.tdf` before any frame is decoded.
+
+use std::error::Error;
+use std::path::Path;
+use rusqlite::{Connection, OpenFlags};
+use timstof_common::PeakLayout;
+
+/// `Frames.MsMsType` of MS1 frames.
+const MSMS_TYPE_MS1: i64 = 0;
+
+/// MS1 slots of every frame in `FrameReader` order: `Frames.NumPeaks` for
+/// MS1 frames, 0 for the others.
+pub fn ms1_layout(d_folder: &Path) -> Result<PeakLayout, Box<dyn Error>> {
+    let conn = Connection::open_with_flags(d_folder.join("analysis.tdf"), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let rows = conn.prepare("SELECT Id, MsMsType, NumPeaks FROM Frames ORDER BY Id")?
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    // A corrupt count must not reach `TimsTOFData::zeroed` as a huge length.
+    let counts = rows.into_iter()
+        .map(|(id, msms_type, num_peaks)| match msms_type {
+            MSMS_TYPE_MS1 => usize::try_from(num_peaks)
+                .map_err(|_| format!("frame {}: invalid Frames.NumPeaks {}", id, num_peaks)),
+            _ => Ok(0),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PeakLayout::new(counts))
+}
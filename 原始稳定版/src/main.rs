@@ -1,10 +1,11 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
 use timsrust::{converters::ConvertableDomain, readers::{FrameReader, MetadataReader}, MSLevel};
 use rayon::prelude::*;
+use timstof_common::{scan_for_index, threads::available_cpus, window_bounds, window_key, Threads, WindowKeyError};
 
 // Data structure for raw TimsTOF data
 #[derive(Debug, Clone)]
@@ -61,21 +62,6 @@ struct FrameSplit {
     pub ms2: Vec<((u32, u32), TimsTOFData)>,
 }
 
-// Helper functions
-#[inline]
-fn quantize(x: f32) -> u32 { 
-    (x * 10_000.0).round() as u32 
-}
-
-fn find_scan_for_index(index: usize, scan_offsets: &[usize]) -> usize {
-    for (scan, window) in scan_offsets.windows(2).enumerate() {
-        if index >= window[0] && index < window[1] {
-            return scan;
-        }
-    }
-    scan_offsets.len() - 1
-}
-
 /// Read TimsTOF .d folder and return raw data with detailed timing
 pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Error>> {
     let total_start = Instant::now();
@@ -97,10 +83,12 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     println!("  Frame reader initialization: {:.3}s", frame_reader_start.elapsed().as_secs_f32());
     println!("  Total frames to process: {}", n_frames);
     
+    let pool = Threads::Auto.pool_for_frames(n_frames, |idx| { let _ = frames.get(idx); })?;
+    
     // Process frames in parallel
-    println!("Processing frames in parallel...");
+    println!("Processing frames in parallel ({} threads)...", pool.threads());
     let process_start = Instant::now();
-    let splits: Vec<FrameSplit> = (0..n_frames).into_par_iter().map(|idx| {
+    let splits: Vec<FrameSplit> = pool.install(|| (0..n_frames).into_par_iter().map(|idx| -> Result<FrameSplit, WindowKeyError> {
         let frame = frames.get(idx).expect("frame read");
         let rt_min = frame.rt_in_seconds as f32 / 60.0;
         let mut ms1 = TimsTOFData::new();
@@ -112,7 +100,7 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
                 ms1 = TimsTOFData::with_capacity(n_peaks);
                 for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
                     let mz = mz_cv.convert(tof as f64) as f32;
-                    let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
+                    let scan = scan_for_index(p_idx, &frame.scan_offsets);
                     let im = im_cv.convert(scan as f64) as f32;
                     ms1.rt_values_min.push(rt_min);
                     ms1.mobility_values.push(im);
@@ -127,15 +115,11 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
                 ms2_pairs.reserve(qs.isolation_mz.len());
                 for win in 0..qs.isolation_mz.len() {
                     if win >= qs.isolation_width.len() { break; }
-                    let prec_mz = qs.isolation_mz[win] as f32;
-                    let width = qs.isolation_width[win] as f32;
-                    let low = prec_mz - width * 0.5;
-                    let high = prec_mz + width * 0.5;
-                    let key = (quantize(low), quantize(high));
+                    let key = window_key(qs.isolation_mz[win], qs.isolation_width[win])?;
                     
                     let mut td = TimsTOFData::new();
                     for (p_idx, (&tof, &intensity)) in frame.tof_indices.iter().zip(frame.intensities.iter()).enumerate() {
-                        let scan = find_scan_for_index(p_idx, &frame.scan_offsets);
+                        let scan = scan_for_index(p_idx, &frame.scan_offsets);
                         if scan < qs.scan_starts[win] || scan > qs.scan_ends[win] { continue; }
                         let mz = mz_cv.convert(tof as f64) as f32;
                         let im = im_cv.convert(scan as f64) as f32;
@@ -151,16 +135,17 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
             }
             _ => {}
         }
-        FrameSplit { ms1, ms2: ms2_pairs }
-    }).collect();
+        Ok(FrameSplit { ms1, ms2: ms2_pairs })
+    }).collect::<Result<_, _>>())?;
     println!("  Frame processing: {:.3}s", process_start.elapsed().as_secs_f32());
     
     // Merge data
     println!("Merging data...");
     let merge_start = Instant::now();
-    let ms1_size_estimate: usize = splits.par_iter().map(|s| s.ms1.mz_values.len()).sum();
+    let ms1_size_estimate: usize = splits.iter().map(|s| s.ms1.mz_values.len()).sum();
     let mut global_ms1 = TimsTOFData::with_capacity(ms1_size_estimate);
-    let mut ms2_hash: HashMap<(u32,u32), TimsTOFData> = HashMap::new();
+    // Keyed merge in frame order; windows come out sorted by key.
+    let mut ms2_hash: BTreeMap<(u32,u32), TimsTOFData> = BTreeMap::new();
     
     for split in splits {
         global_ms1.rt_values_min.extend(split.ms1.rt_values_min);
@@ -176,10 +161,8 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
     }
     
     let mut ms2_vec = Vec::with_capacity(ms2_hash.len());
-    for ((q_low, q_high), td) in ms2_hash {
-        let low = q_low as f32 / 10_000.0;
-        let high = q_high as f32 / 10_000.0;
-        ms2_vec.push(((low, high), td));
+    for (key, td) in ms2_hash {
+        ms2_vec.push((window_bounds(key), td));
     }
     println!("  Data merging: {:.3}s", merge_start.elapsed().as_secs_f32());
     
@@ -199,12 +182,6 @@ pub fn read_timstof_data(d_folder: &Path) -> Result<TimsTOFRawData, Box<dyn Erro
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    // Configure parallel processing
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(16)
-        .build_global()
-        .unwrap();
-    
     // Set path to your .d folder
     let d_folder_path = if cfg!(target_os = "macos") {
         "/Users/augustsirius/Desktop/DIA_peak_group_extraction/输入数据文件/raw_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d"
@@ -219,7 +196,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     
     println!("========== TimsTOF .d File Reader ==========");
     println!("Data folder: {}", d_folder_path);
-    println!("Parallel threads: 16");
+    println!("Parallel threads: auto ({} CPUs available)", available_cpus());
     println!();
     
     // Read the data with timing
@@ -230,6 +207,74 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timstof_synthetic::{determinism, digest, fingerprint, golden, SyntheticRun};
+    
+    // (frame, scan, intensity, m/z bits), sorted, for order-independent comparison
+    fn rows(data: &TimsTOFData) -> Vec<(u32, u32, u32, u32)> {
+        let mut rows: Vec<_> = (0..data.mz_values.len())
+            .map(|i| (data.frame_indices[i], data.scan_indices[i], data.intensity_values[i], data.mz_values[i].to_bits()))
+            .collect();
+        rows.sort_unstable();
+        rows
+    }
+    
+    #[test]
+    fn reads_synthetic_run_end_to_end() {
+        let run = SyntheticRun::small();
+        let dir = run.write_temp().unwrap();
+        let meta = MetadataReader::new(dir.path().join("analysis.tdf")).unwrap();
+        let mz = |tof: u32| meta.mz_converter.convert(tof as f64) as f32;
+        
+        let data = read_timstof_data(dir.path()).unwrap();
+        
+        let mut expected_ms1: Vec<_> = run.ms1_frames()
+            .flat_map(|f| f.peaks.iter().map(move |p| (f.id, p.scan, p.intensity, mz(p.tof).to_bits())))
+            .collect();
+        expected_ms1.sort_unstable();
+        assert_eq!(rows(&data.ms1_data), expected_ms1);
+        
+        assert_eq!(data.ms2_windows.len(), run.windows.len());
+        for window in &run.windows {
+            let low = (window.isolation_mz - window.isolation_width / 2.0) as f32;
+            let (_, td) = data.ms2_windows.iter()
+                .find(|((l, _), _)| (l - low).abs() < 1e-3)
+                .expect("window present");
+            let mut expected: Vec<_> = run.window_peaks(window).into_iter()
+                .map(|(frame, p)| (frame, p.scan, p.intensity, mz(p.tof).to_bits()))
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(rows(td), expected);
+        }
+    }
+    
+    #[test]
+    fn matches_golden_output() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("small", "original", &digest!(data));
+    }
+    
+    #[test]
+    fn matches_golden_output_with_overlapping_windows() {
+        let dir = SyntheticRun::overlapping().write_temp().unwrap();
+        let data = read_timstof_data(dir.path()).unwrap();
+        golden::assert_golden("overlapping", "original", &digest!(data));
+    }
+    
+    #[test]
+    fn output_is_independent_of_thread_count() {
+        let dir = SyntheticRun::small().write_temp().unwrap();
+        determinism::assert_deterministic(
+            "original",
+            || read_timstof_data(dir.path()).unwrap(),
+            |data| fingerprint!(data),
+        );
+    }
+}
+
 // Cargo.toml dependencies needed:
 /*
 [dependencies]